@@ -0,0 +1,183 @@
+use crate::net::{
+    eth_output, eth_payload, get16, get32, net_mac, put16, put32, IpAddr, ETH_BROADCAST,
+    ETH_TYPE_ARP, ETH_TYPE_IP, IP_MAX, NET_ADDR,
+};
+use crate::trap::TICKS;
+
+// address resolution protocol, rfc 826.
+//
+// the cache maps ip addresses on the local network to ethernet
+// addresses. a packet for an address not in it is held in a pending
+// entry, one per address, while requests go out once a tick. when
+// the reply comes the packet is sent, after ARP_TRIES requests the
+// entry and its packet are dropped. resolved entries are forgotten
+// after ARP_TTL ticks, the oldest entry makes room for a new one.
+
+const NARP: usize = 8;
+const ARP_TTL: u64 = 600;
+const ARP_TRIES: u32 = 3;
+
+const ARP_LEN: usize = 28;
+const ARP_HW_ETHER: u16 = 1;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+#[derive(Copy, Clone, PartialEq)]
+enum ArpState {
+    Free,
+    Pending,
+    Resolved,
+}
+
+#[derive(Copy, Clone)]
+struct ArpEntry {
+    state: ArpState,
+    ip: IpAddr,
+    mac: [u8; 6],
+    time: u64,  // tick of the last request or reply
+    tries: u32, // requests sent while pending
+    hold: [u8; IP_MAX],
+    hold_len: usize, // 0 if no packet waits
+}
+
+static mut ARP: [ArpEntry; NARP] = [ArpEntry {
+    state: ArpState::Free,
+    ip: 0,
+    mac: [0; 6],
+    time: 0,
+    tries: 0,
+    hold: [0; IP_MAX],
+    hold_len: 0,
+}; NARP];
+
+fn ticks() -> u64 {
+    unsafe { TICKS }
+}
+
+fn find(ip: IpAddr) -> Option<&'static mut ArpEntry> {
+    unsafe {
+        ARP.iter_mut()
+            .find(|e| e.state != ArpState::Free && e.ip == ip)
+    }
+}
+
+// a free entry, or the oldest one
+fn alloc(ip: IpAddr) -> &'static mut ArpEntry {
+    let e = unsafe {
+        let mut e = &mut ARP[0];
+        for x in ARP.iter_mut() {
+            if x.state == ArpState::Free {
+                e = x;
+                break;
+            }
+            if x.time < e.time {
+                e = x;
+            }
+        }
+        e
+    };
+    e.state = ArpState::Pending;
+    e.ip = ip;
+    e.tries = 0;
+    e.hold_len = 0;
+    e
+}
+
+// the ethernet address of ip, None if it is not known yet
+pub fn arp_lookup(ip: IpAddr) -> Option<[u8; 6]> {
+    match find(ip) {
+        Some(e) if e.state == ArpState::Resolved => Some(e.mac),
+        _ => None,
+    }
+}
+
+// keep pkt until ip is resolved, in place of any packet already
+// waiting for it
+pub fn arp_hold(ip: IpAddr, pkt: &[u8]) {
+    let e = match find(ip) {
+        Some(e) => e,
+        None => alloc(ip),
+    };
+    e.hold[..pkt.len()].copy_from_slice(pkt);
+    e.hold_len = pkt.len();
+
+    if e.tries == 0 {
+        e.tries = 1;
+        e.time = ticks();
+        arp_send(ARP_REQUEST, ETH_BROADCAST, [0; 6], ip);
+    }
+}
+
+fn arp_send(op: u16, eth_dst: [u8; 6], tha: [u8; 6], tpa: IpAddr) {
+    let p = eth_payload();
+    put16(p, 0, ARP_HW_ETHER);
+    put16(p, 2, ETH_TYPE_IP);
+    p[4] = 6;
+    p[5] = 4;
+    put16(p, 6, op);
+    p[8..14].copy_from_slice(&net_mac());
+    put32(p, 14, NET_ADDR);
+    p[18..24].copy_from_slice(&tha);
+    put32(p, 24, tpa);
+    eth_output(eth_dst, ETH_TYPE_ARP, ARP_LEN);
+}
+
+pub fn arp_input(pkt: &[u8]) {
+    if pkt.len() < ARP_LEN
+        || get16(pkt, 0) != ARP_HW_ETHER
+        || get16(pkt, 2) != ETH_TYPE_IP
+        || pkt[4] != 6
+        || pkt[5] != 4
+    {
+        return;
+    }
+    let op = get16(pkt, 6);
+    let mut sha: [u8; 6] = [0; 6];
+    sha.copy_from_slice(&pkt[8..14]);
+    let spa = get32(pkt, 14);
+    let tpa = get32(pkt, 24);
+
+    // learn the sender if it's known already or talks to us
+    let e = match find(spa) {
+        Some(e) => Some(e),
+        None if tpa == NET_ADDR => Some(alloc(spa)),
+        None => None,
+    };
+    if let Some(e) = e {
+        e.state = ArpState::Resolved;
+        e.mac = sha;
+        e.time = ticks();
+        if e.hold_len > 0 {
+            let len = e.hold_len;
+            e.hold_len = 0;
+            eth_payload()[..len].copy_from_slice(&e.hold[..len]);
+            eth_output(sha, ETH_TYPE_IP, len);
+        }
+    }
+
+    if op == ARP_REQUEST && tpa == NET_ADDR {
+        arp_send(ARP_REPLY, sha, sha, spa);
+    }
+}
+
+// called every tick
+pub fn arp_timer() {
+    let now = ticks();
+    for e in unsafe { ARP.iter_mut() } {
+        match e.state {
+            ArpState::Pending if now > e.time => {
+                if e.tries >= ARP_TRIES {
+                    e.state = ArpState::Free;
+                    continue;
+                }
+                e.tries += 1;
+                e.time = now;
+                arp_send(ARP_REQUEST, ETH_BROADCAST, [0; 6], e.ip);
+            }
+            ArpState::Resolved if now - e.time > ARP_TTL => {
+                e.state = ArpState::Free;
+            }
+            _ => {}
+        }
+    }
+}
@@ -1,12 +1,13 @@
 use crate::fs::BLOCK_SIZE;
-use crate::param::NBUF;
-use crate::virtio_disk::virtio_disk_rw;
+use crate::param::{NBUF, NSEG};
+use crate::virtio_disk::{virtio_disk_rw, virtio_disk_rw_n};
 use core::fmt::Write;
 use core::ptr::null_mut;
 
 #[derive(Copy, Clone)]
 pub struct Buf {
     pub valid: u32,
+    pub disk: bool, // owned by the disk driver while a request is in flight
     pub ref_cnt: u32,
     pub dev: u32,
     pub block_no: u32,
@@ -19,6 +20,7 @@ impl Buf {
     pub const fn new() -> Self {
         Buf {
             valid: 0,
+            disk: false,
             ref_cnt: 0,
             dev: 0,
             block_no: 0,
@@ -32,13 +34,22 @@ impl Buf {
 struct Bcache {
     head: *mut Buf,
     buf: [Buf; NBUF],
+    hit: u64, // lookups found in the cache
+    miss: u64,
 }
 
 static mut BCACHE: Bcache = Bcache {
     head: null_mut(),
     buf: [Buf::new(); NBUF],
+    hit: 0,
+    miss: 0,
 };
 
+// return (hits, misses) of bget
+pub fn bcache_stat() -> (u64, u64) {
+    unsafe { (BCACHE.hit, BCACHE.miss) }
+}
+
 pub fn binit() {
     unsafe {
         for i in 0..NBUF - 1 {
@@ -62,12 +73,14 @@ fn bget(dev: u32, block_no: u32) -> *mut Buf {
         for _ in 0..NBUF {
             if (*b).dev == dev && (*b).block_no == block_no {
                 (*b).ref_cnt += 1;
+                BCACHE.hit += 1;
                 return b;
             }
             b = (*b).next;
         }
 
         // if not cached, picks the least recently used buffer
+        BCACHE.miss += 1;
         b = (*BCACHE.head).prev;
         for _ in 0..NBUF {
             if (*b).ref_cnt == 0 {
@@ -83,6 +96,59 @@ fn bget(dev: u32, block_no: u32) -> *mut Buf {
     panicc!("bget: no buffer available");
 }
 
+// like bget, but only for a block not in the cache yet, and
+// returns null instead of panicking if no buffer is free
+fn bget_new(dev: u32, block_no: u32) -> *mut Buf {
+    unsafe {
+        let mut b = BCACHE.head;
+        for _ in 0..NBUF {
+            if (*b).dev == dev && (*b).block_no == block_no {
+                return null_mut();
+            }
+            b = (*b).next;
+        }
+
+        b = (*BCACHE.head).prev;
+        for _ in 0..NBUF {
+            if (*b).ref_cnt == 0 {
+                (*b).valid = 0;
+                (*b).ref_cnt = 1;
+                (*b).dev = dev;
+                (*b).block_no = block_no;
+                return b;
+            }
+            b = (*b).prev;
+        }
+    }
+    null_mut()
+}
+
+// read up to n blocks from block_no into the cache with one disk
+// request. best effort: stops at the first block already cached or
+// when no buffer is free
+pub fn bread_ahead(dev: u32, block_no: u32, n: usize) {
+    let mut bufs: [*mut Buf; NSEG] = [null_mut(); NSEG];
+    let mut cnt = 0;
+    while cnt < n && cnt < NSEG {
+        let b = bget_new(dev, block_no + cnt as u32);
+        if b.is_null() {
+            break;
+        }
+        bufs[cnt] = b;
+        cnt += 1;
+    }
+    if cnt == 0 {
+        return;
+    }
+    virtio_disk_rw_n(&bufs[..cnt], 0);
+    for &b in &bufs[..cnt] {
+        unsafe {
+            (*b).valid = 1;
+        }
+        brelse(b);
+    }
+}
+
 pub fn bread(dev: u32, block_no: u32) -> *mut Buf {
     let b = bget(dev, block_no);
     unsafe {
@@ -94,10 +160,53 @@ pub fn bread(dev: u32, block_no: u32) -> *mut Buf {
     b
 }
 
+// like bread, for a block the caller overwrites whole: the
+// buffer is taken as valid without reading the block from disk
+pub fn bget_valid(dev: u32, block_no: u32) -> *mut Buf {
+    let b = bget(dev, block_no);
+    unsafe {
+        (*b).valid = 1;
+    }
+    b
+}
+
+// the disk zeroed n blocks from block_no by itself, make the
+// copies in the cache match
+pub fn bzeroed(dev: u32, block_no: u32, n: u32) {
+    unsafe {
+        for b in BCACHE.buf.iter_mut() {
+            if b.dev == dev && b.block_no >= block_no && b.block_no - block_no < n {
+                b.data = [0; BLOCK_SIZE as usize];
+                b.valid = 1;
+            }
+        }
+    }
+}
+
 pub fn bwrite(b: *mut Buf) {
     virtio_disk_rw(b, 1);
 }
 
+// write bufs of consecutive blocks, NSEG at a time
+pub fn bwrite_n(bufs: &[*mut Buf]) {
+    for chunk in bufs.chunks(NSEG) {
+        virtio_disk_rw_n(chunk, 1);
+    }
+}
+
+// keep b in the cache until bunpin, used by the log
+pub fn bpin(b: *mut Buf) {
+    unsafe {
+        (*b).ref_cnt += 1;
+    }
+}
+
+pub fn bunpin(b: *mut Buf) {
+    unsafe {
+        (*b).ref_cnt -= 1;
+    }
+}
+
 pub fn brelse(b: *mut Buf) {
     unsafe {
         (*b).ref_cnt -= 1;
@@ -0,0 +1,200 @@
+use crate::mem_layout::UART;
+use crate::param::NVPORT;
+use crate::proc::{either_copy_in, either_copy_out, sleep, wakeup};
+use crate::uart::Uart;
+use crate::virtio_console::virtio_console_write;
+use core::cmp::min;
+use core::fmt::{Error, Write};
+
+// console input and output, on a number of ttys.
+//
+// tty 0 is the uart, tty 1 + i is port i of the virtio console.
+// input is line buffered: the drivers pass each character to
+// console_intr(), which echoes it and handles erasing, and readers
+// are woken up once a whole line (or ^D) has arrived.
+//
+// the kernel log, what print! writes, goes to the uart unless
+// console_set_log() picked another tty.
+
+pub const NTTY: usize = 1 + NVPORT;
+
+const INPUT_BUF: usize = 128;
+
+#[derive(Copy, Clone)]
+struct Cons {
+    buf: [u8; INPUT_BUF],
+    r: usize, // read index
+    w: usize, // write index
+    e: usize, // edit index
+}
+
+impl Cons {
+    const fn new() -> Self {
+        Cons {
+            buf: [0; INPUT_BUF],
+            r: 0,
+            w: 0,
+            e: 0,
+        }
+    }
+}
+
+static mut CONS: [Cons; NTTY] = [Cons::new(); NTTY];
+
+// the tty of the kernel log
+static mut LOG_TTY: usize = 0;
+
+const fn ctrl(x: u8) -> u8 {
+    x - b'@'
+}
+
+const BACKSPACE: u8 = 8;
+
+// output to tty, false if it has gone away
+fn tty_put(tty: usize, data: &[u8]) -> bool {
+    if tty == 0 {
+        let mut uart = Uart::new(UART as usize);
+        for &c in data {
+            uart.put(c);
+        }
+        return true;
+    }
+
+    virtio_console_write(tty - 1, data)
+}
+
+fn cons_putc(tty: usize, c: u8) {
+    if c == BACKSPACE {
+        // overwrite with a space
+        tty_put(tty, &[BACKSPACE, b' ', BACKSPACE]);
+    } else {
+        tty_put(tty, &[c]);
+    }
+}
+
+// write n bytes from src to tty
+pub fn console_write(tty: usize, is_uaddr: u32, src: u64, n: u32) -> i32 {
+    if tty >= NTTY {
+        return -1;
+    }
+
+    let mut buf: [u8; 64] = [0; 64];
+    let mut cnt: u32 = 0;
+    while cnt < n {
+        let len = min(n - cnt, buf.len() as u32);
+        if either_copy_in(&mut buf as *mut u8, is_uaddr, src + cnt as u64, len as u64) == -1 {
+            break;
+        }
+        tty_put(tty, &buf[..len as usize]);
+        cnt += len;
+    }
+
+    cnt as i32
+}
+
+// read at most one line of at most n bytes from tty to dst,
+// return 0 at end of file (^D at the start of a line)
+pub fn console_read(tty: usize, is_uaddr: u32, mut dst: u64, n: u32) -> i32 {
+    if tty >= NTTY {
+        return -1;
+    }
+
+    let mut left = n;
+    unsafe {
+        let cons = &mut CONS[tty];
+        while left > 0 {
+            // wait until the interrupt handler has put some input
+            while cons.r == cons.w {
+                sleep(&cons.r as *const usize as u64);
+            }
+
+            let c = cons.buf[cons.r % INPUT_BUF];
+            cons.r += 1;
+
+            if c == ctrl(b'D') {
+                // end of file
+                if left < n {
+                    // save ^D for next time, so that
+                    // the caller gets a 0-byte result
+                    cons.r -= 1;
+                }
+                break;
+            }
+
+            if either_copy_out(is_uaddr, dst, &c, 1) == -1 {
+                break;
+            }
+            dst += 1;
+            left -= 1;
+
+            if c == b'\n' {
+                break;
+            }
+        }
+    }
+
+    (n - left) as i32
+}
+
+// handle a character that came in on tty
+pub fn console_intr(tty: usize, c: u8) {
+    unsafe {
+        let cons = &mut CONS[tty];
+        match c {
+            // kill line
+            x if x == ctrl(b'U') => {
+                while cons.e != cons.w && cons.buf[(cons.e - 1) % INPUT_BUF] != b'\n' {
+                    cons.e -= 1;
+                    cons_putc(tty, BACKSPACE);
+                }
+            }
+            // backspace or delete
+            BACKSPACE | 0x7f => {
+                if cons.e != cons.w {
+                    cons.e -= 1;
+                    cons_putc(tty, BACKSPACE);
+                }
+            }
+            _ => {
+                if c != 0 && cons.e - cons.r < INPUT_BUF {
+                    let c = match c {
+                        b'\r' => b'\n',
+                        _ => c,
+                    };
+
+                    cons_putc(tty, c);
+                    cons.buf[cons.e % INPUT_BUF] = c;
+                    cons.e += 1;
+
+                    if c == b'\n' || c == ctrl(b'D') || cons.e - cons.r == INPUT_BUF {
+                        // a whole line (or end of file) has arrived
+                        cons.w = cons.e;
+                        wakeup(&cons.r as *const usize as u64);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// send the kernel log to tty from now on
+pub fn console_set_log(tty: usize) {
+    if tty < NTTY {
+        unsafe {
+            LOG_TTY = tty;
+        }
+    }
+}
+
+// the kernel log, for print!
+pub struct Log;
+
+impl Write for Log {
+    fn write_str(&mut self, out: &str) -> Result<(), Error> {
+        // the uart is always there
+        if !tty_put(unsafe { LOG_TTY }, out.as_bytes()) {
+            tty_put(0, out.as_bytes());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,447 @@
+use crate::block_cache::{bread, brelse, bwrite};
+use crate::console::{console_read, console_write};
+use crate::fs::{name_is, DirEnt, DirIter, FileSystem, Inode, BLOCK_SIZE};
+use crate::param::{NDEV, NDEVNODE, NDISK, NVPORT, ROOT_DEV};
+use crate::proc::{either_copy_in, either_copy_out, sleep};
+use crate::random::random_read;
+use crate::stat::{mode_to_dtype, Stat, DT_DIR, S_IFBLK, S_IFCHR, S_IFDIR, S_IFMT};
+use crate::virtio_console::virtio_console_nport;
+use crate::virtio_disk::{virtio_disk_capacity, virtio_disk_flush, virtio_disk_present};
+use crate::virtio_net::{
+    virtio_net_mac, virtio_net_recv, virtio_net_rx_chan, virtio_net_send, virtio_net_tx_chan,
+};
+use core::cmp::min;
+use core::fmt::Write;
+use core::mem::size_of;
+
+// device file system, mounted on /dev.
+//
+// each file names a driver by its major number and a unit of the
+// driver by its minor number. drivers put their read and write
+// functions in DEVSW, and their files with dev_register().
+
+// major numbers
+pub const CONSOLE: u16 = 1; // minor is the tty, see console.rs
+pub const MEM: u16 = 2;
+pub const DISK: u16 = 3; // minor is the device number of the disk
+pub const NET: u16 = 4; // raw ethernet frames
+
+// minor numbers of MEM
+const NULL_MINOR: u16 = 0;
+const ZERO_MINOR: u16 = 1;
+const URANDOM_MINOR: u16 = 2;
+
+const DEVFS_DEV: u32 = 0x200;
+
+// return the number of bytes read or written, -1 on error
+type DevRw = fn(minor: u16, is_uaddr: u32, addr: u64, off: u32, n: u32) -> i32;
+
+#[derive(Copy, Clone)]
+pub struct DevSw {
+    pub read: DevRw,
+    pub write: DevRw,
+}
+
+// indexed by major number
+static mut DEVSW: [Option<DevSw>; NDEV] = [None; NDEV];
+
+pub fn devsw_register(major: u16, sw: DevSw) {
+    unsafe {
+        if major as usize >= NDEV || DEVSW[major as usize].is_some() {
+            panicc!("devsw_register");
+        }
+        DEVSW[major as usize] = Some(sw);
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct DevNode {
+    name: &'static str, // "" if the slot is free
+    ino: u32,           // index in DEV_NODE + 1
+    mode: u16,
+    major: u16,
+    minor: u16,
+}
+
+impl DevNode {
+    const fn new() -> Self {
+        DevNode {
+            name: "",
+            ino: 0,
+            mode: 0,
+            major: 0,
+            minor: 0,
+        }
+    }
+}
+
+// DEV_NODE[0] is the root directory
+static mut DEV_NODE: [DevNode; NDEVNODE] = [DevNode::new(); NDEVNODE];
+
+// add /dev/name, mode is S_IFCHR or S_IFBLK with the permission bits
+pub fn dev_register(name: &'static str, mode: u16, major: u16, minor: u16) {
+    unsafe {
+        for i in 1..NDEVNODE {
+            if DEV_NODE[i].name.is_empty() {
+                DEV_NODE[i] = DevNode {
+                    name,
+                    ino: i as u32 + 1,
+                    mode,
+                    major,
+                    minor,
+                };
+                return;
+            }
+        }
+    }
+
+    panicc!("dev_register: no free node");
+}
+
+pub struct DevFs {}
+
+static mut DEVFS: DevFs = DevFs {};
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn dev(&self) -> u32 {
+        DEVFS_DEV
+    }
+
+    fn root(&mut self) -> *mut dyn Inode {
+        unsafe { &mut DEV_NODE[0] as *mut DevNode }
+    }
+}
+
+// register the built-in drivers and their files
+pub fn devfs_init() -> *mut dyn FileSystem {
+    unsafe {
+        DEV_NODE[0] = DevNode {
+            name: "/",
+            ino: 1,
+            mode: S_IFDIR | 0o755,
+            major: 0,
+            minor: 0,
+        };
+    }
+
+    devsw_register(
+        CONSOLE,
+        DevSw {
+            read: cons_read,
+            write: cons_write,
+        },
+    );
+    devsw_register(
+        MEM,
+        DevSw {
+            read: mem_read,
+            write: mem_write,
+        },
+    );
+    devsw_register(
+        DISK,
+        DevSw {
+            read: disk_read,
+            write: disk_write,
+        },
+    );
+    devsw_register(
+        NET,
+        DevSw {
+            read: net_read,
+            write: net_write,
+        },
+    );
+
+    dev_register("console", S_IFCHR | 0o666, CONSOLE, 0);
+    dev_register("null", S_IFCHR | 0o666, MEM, NULL_MINOR);
+    dev_register("zero", S_IFCHR | 0o666, MEM, ZERO_MINOR);
+    dev_register("urandom", S_IFCHR | 0o666, MEM, URANDOM_MINOR);
+
+    // the ports of the virtio console, tty 1 and up
+    const PORT_NAME: [&str; NVPORT] = ["hvc0", "hvc1", "hvc2", "hvc3"];
+    for i in 0..virtio_console_nport() {
+        dev_register(PORT_NAME[i], S_IFCHR | 0o620, CONSOLE, 1 + i as u16);
+    }
+
+    // a file for every disk, vda is the one with ROOT_DEV
+    const DISK_NAME: [&str; NDISK] = ["vda", "vdb", "vdc", "vdd", "vde", "vdf", "vdg", "vdh"];
+    for i in 0..NDISK {
+        let dev = ROOT_DEV + i as u32;
+        if virtio_disk_present(dev) {
+            dev_register(DISK_NAME[i], S_IFBLK | 0o660, DISK, dev as u16);
+        }
+    }
+
+    if virtio_net_mac().is_some() {
+        dev_register("net0", S_IFCHR | 0o600, NET, 0);
+    }
+
+    unsafe { &mut DEVFS as *mut DevFs }
+}
+
+// minor is the tty
+fn cons_read(minor: u16, is_uaddr: u32, dst: u64, _off: u32, n: u32) -> i32 {
+    console_read(minor as usize, is_uaddr, dst, n)
+}
+
+fn cons_write(minor: u16, is_uaddr: u32, src: u64, _off: u32, n: u32) -> i32 {
+    console_write(minor as usize, is_uaddr, src, n)
+}
+
+fn mem_read(minor: u16, is_uaddr: u32, dst: u64, _off: u32, n: u32) -> i32 {
+    if minor == NULL_MINOR {
+        return 0;
+    }
+    if minor == URANDOM_MINOR {
+        return random_read(is_uaddr, dst, n);
+    }
+
+    let chunk: [u64; 8] = [0; 8];
+    let mut cnt: u32 = 0;
+    while cnt < n {
+        let len = min(n - cnt, size_of::<[u64; 8]>() as u32);
+        if either_copy_out(
+            is_uaddr,
+            dst + cnt as u64,
+            &chunk as *const u64 as *const u8,
+            len as u64,
+        ) == -1
+        {
+            return -1;
+        }
+        cnt += len;
+    }
+
+    n as i32
+}
+
+// everything written is discarded
+fn mem_write(_minor: u16, _is_uaddr: u32, _src: u64, _off: u32, n: u32) -> i32 {
+    n as i32
+}
+
+// size of the disk in bytes
+fn disk_size(minor: u16) -> u64 {
+    virtio_disk_capacity(minor as u32) * 512
+}
+
+// read the raw disk through the buffer cache
+fn disk_read(minor: u16, is_uaddr: u32, mut dst: u64, mut off: u32, n: u32) -> i32 {
+    if off as u64 >= disk_size(minor) {
+        return 0;
+    }
+    let n = min(n as u64, disk_size(minor) - off as u64) as u32;
+
+    let mut cnt: u32 = 0;
+    while cnt < n {
+        let b = bread(minor as u32, off / BLOCK_SIZE);
+        let len = min(n - cnt, BLOCK_SIZE - off % BLOCK_SIZE);
+        let r = unsafe {
+            either_copy_out(
+                is_uaddr,
+                dst,
+                (&(*b).data as *const u8).add((off % BLOCK_SIZE) as usize),
+                len as u64,
+            )
+        };
+        brelse(b);
+        if r == -1 {
+            return -1;
+        }
+
+        cnt += len;
+        off += len;
+        dst += len as u64;
+    }
+
+    cnt as i32
+}
+
+// write the raw disk, bypassing the log
+fn disk_write(minor: u16, is_uaddr: u32, mut src: u64, mut off: u32, n: u32) -> i32 {
+    if off as u64 + n as u64 > disk_size(minor) {
+        return -1;
+    }
+
+    let mut cnt: u32 = 0;
+    while cnt < n {
+        let b = bread(minor as u32, off / BLOCK_SIZE);
+        let len = min(n - cnt, BLOCK_SIZE - off % BLOCK_SIZE);
+        let r = unsafe {
+            either_copy_in(
+                (&mut (*b).data as *mut u8).add((off % BLOCK_SIZE) as usize),
+                is_uaddr,
+                src,
+                len as u64,
+            )
+        };
+        if r == -1 {
+            brelse(b);
+            return -1;
+        }
+        bwrite(b);
+        brelse(b);
+
+        cnt += len;
+        off += len;
+        src += len as u64;
+    }
+
+    cnt as i32
+}
+
+// a read returns one frame, cut to n bytes
+fn net_read(_minor: u16, is_uaddr: u32, dst: u64, _off: u32, n: u32) -> i32 {
+    loop {
+        let r = virtio_net_recv(is_uaddr, dst, n);
+        if r != 0 {
+            return r;
+        }
+        sleep(virtio_net_rx_chan());
+    }
+}
+
+// a write sends one frame
+fn net_write(_minor: u16, is_uaddr: u32, src: u64, _off: u32, n: u32) -> i32 {
+    loop {
+        let r = virtio_net_send(is_uaddr, src, n);
+        if r != 0 {
+            return r;
+        }
+        sleep(virtio_net_tx_chan());
+    }
+}
+
+fn is_dir(m: u16) -> bool {
+    m & S_IFMT == S_IFDIR
+}
+
+fn devsw(major: u16) -> Option<DevSw> {
+    unsafe { *DEVSW.get(major as usize)? }
+}
+
+impl DirIter for DevNode {
+    // pos 0 is ".", 1 is "..", pos n+1 is DEV_NODE[n]
+    fn next(&mut self, pos: &mut u32, ent: &mut DirEnt) -> bool {
+        if *pos < 2 {
+            let name: &[u8] = match *pos {
+                0 => b".",
+                _ => b"..",
+            };
+            ent.set(self.ino, name, DT_DIR);
+            *pos += 1;
+            return true;
+        }
+
+        unsafe {
+            while ((*pos - 1) as usize) < NDEVNODE {
+                let node = &DEV_NODE[(*pos - 1) as usize];
+                *pos += 1;
+                if !node.name.is_empty() {
+                    ent.set(node.ino, node.name.as_bytes(), mode_to_dtype(node.mode));
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+}
+
+// the nodes are never freed, so references aren't counted
+impl Inode for DevNode {
+    fn dev(&self) -> u32 {
+        DEVFS_DEV
+    }
+
+    fn ino(&self) -> u32 {
+        self.ino
+    }
+
+    fn mode(&self) -> u16 {
+        self.mode
+    }
+
+    fn stat(&self, st: &mut Stat) {
+        *st = Stat::new();
+        st.dev = DEVFS_DEV;
+        st.ino = self.ino;
+        st.mode = self.mode;
+        st.nlink = 1;
+        if !is_dir(self.mode) {
+            st.rdev = (self.major as u32) << 8 | self.minor as u32;
+        }
+        if self.major == DISK {
+            st.size = disk_size(self.minor);
+        }
+    }
+
+    fn dup(&mut self) -> *mut dyn Inode {
+        self as *mut DevNode
+    }
+
+    fn put(&mut self) {}
+
+    fn read(&mut self, is_uaddr: u32, dst: u64, off: u32, n: u32) -> i32 {
+        match devsw(self.major) {
+            Some(sw) if !is_dir(self.mode) => (sw.read)(self.minor, is_uaddr, dst, off, n),
+            _ => -1,
+        }
+    }
+
+    fn write(&mut self, is_uaddr: u32, src: u64, off: u32, n: u32) -> i32 {
+        match devsw(self.major) {
+            Some(sw) if !is_dir(self.mode) => (sw.write)(self.minor, is_uaddr, src, off, n),
+            _ => -1,
+        }
+    }
+
+    // nothing to discard, so that opening with O_TRUNC works
+    fn truncate(&mut self) -> i32 {
+        match is_dir(self.mode) {
+            true => -1,
+            false => 0,
+        }
+    }
+
+    // raw disk writes may sit in the device's write-back cache
+    fn sync(&mut self) -> i32 {
+        match self.major {
+            DISK => virtio_disk_flush(self.minor as u32),
+            _ => 0,
+        }
+    }
+
+    fn lookup(&mut self, name: *const u8) -> Option<*mut dyn Inode> {
+        if !is_dir(self.mode) {
+            return None;
+        }
+
+        // the only directory is the root
+        if name_is(name, ".") || name_is(name, "..") {
+            return Some(self.dup());
+        }
+
+        unsafe {
+            for i in 1..NDEVNODE {
+                if !DEV_NODE[i].name.is_empty() && name_is(name, DEV_NODE[i].name) {
+                    return Some(&mut DEV_NODE[i] as *mut DevNode);
+                }
+            }
+        }
+
+        None
+    }
+
+    fn dir_iter(&mut self) -> Option<&mut dyn DirIter> {
+        match is_dir(self.mode) {
+            true => Some(self),
+            false => None,
+        }
+    }
+}
@@ -0,0 +1,14 @@
+// system calls return -1 on most errors, or -errno where the caller
+// has to tell an error apart from the others
+pub const EAGAIN: i32 = 11; // try again
+pub const EINVAL: i32 = 22; // invalid argument
+pub const EPIPE: i32 = 32; // broken pipe
+pub const EMSGSIZE: i32 = 90; // message too long
+pub const EOPNOTSUPP: i32 = 95; // operation not supported
+pub const EAFNOSUPPORT: i32 = 97; // address family not supported
+pub const EADDRINUSE: i32 = 98; // address already in use
+pub const EADDRNOTAVAIL: i32 = 99; // address not available
+pub const ECONNRESET: i32 = 104; // connection reset by peer
+pub const ENOTCONN: i32 = 107; // not connected
+pub const ETIMEDOUT: i32 = 110; // connection timed out
+pub const ECONNREFUSED: i32 = 111; // connection refused
@@ -0,0 +1,207 @@
+use crate::fs::{getdents, is_dir, Inode};
+use crate::param::NFILE;
+use crate::pipe::{pipe_close, pipe_read, pipe_size, pipe_write, Pipe};
+use crate::proc::either_copy_out;
+use crate::socket::{socket_close, socket_recv, socket_send, Socket};
+use crate::stat::{Stat, S_IFIFO, S_IFSOCK};
+use core::fmt::Write;
+use core::mem::size_of;
+use core::ptr::null_mut;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum FileType {
+    None,
+    Pipe,
+    Inode,
+    Socket,
+}
+
+#[derive(Copy, Clone)]
+pub struct File {
+    pub ftype: FileType,
+    pub ref_cnt: u32,
+    pub readable: bool,
+    pub writable: bool,
+    pub pipe: *mut Pipe,               // FileType::Pipe
+    pub inode: Option<*mut dyn Inode>, // FileType::Inode, or the FIFO of a pipe
+    pub sock: *mut Socket,             // FileType::Socket
+    pub off: u32,
+}
+
+impl File {
+    const fn new() -> Self {
+        File {
+            ftype: FileType::None,
+            ref_cnt: 0,
+            readable: false,
+            writable: false,
+            pipe: null_mut(),
+            inode: None,
+            sock: null_mut(),
+            off: 0,
+        }
+    }
+}
+
+struct Ftable {
+    file: [File; NFILE],
+}
+
+static mut FTABLE: Ftable = Ftable {
+    file: [File::new(); NFILE],
+};
+
+// alloc a file structure, return null if the table is full
+pub fn file_alloc() -> *mut File {
+    unsafe {
+        for i in 0..NFILE {
+            if FTABLE.file[i].ref_cnt == 0 {
+                FTABLE.file[i].ref_cnt = 1;
+                return &mut FTABLE.file[i] as *mut File;
+            }
+        }
+    }
+
+    null_mut()
+}
+
+// increment ref count for file f
+pub fn file_dup(f: *mut File) -> *mut File {
+    unsafe {
+        if (*f).ref_cnt < 1 {
+            panicc!("file_dup");
+        }
+        (*f).ref_cnt += 1;
+    }
+    f
+}
+
+// decrement ref count, close when reaches 0
+pub fn file_close(f: *mut File) {
+    unsafe {
+        if (*f).ref_cnt < 1 {
+            panicc!("file_close");
+        }
+
+        (*f).ref_cnt -= 1;
+        if (*f).ref_cnt > 0 {
+            return;
+        }
+
+        match (*f).ftype {
+            FileType::Pipe => {
+                pipe_close((*f).pipe, (*f).readable, (*f).writable);
+                if let Some(inode) = (*f).inode {
+                    (*inode).put();
+                }
+            }
+            FileType::Inode => (*(*f).inode.unwrap()).put(),
+            FileType::Socket => socket_close((*f).sock),
+            FileType::None => {}
+        }
+
+        (*f).ftype = FileType::None;
+        (*f).pipe = null_mut();
+        (*f).inode = None;
+        (*f).sock = null_mut();
+        (*f).off = 0;
+    }
+}
+
+// copy metadata of f to the user address addr
+pub fn file_stat(f: *mut File, addr: u64) -> i32 {
+    let mut st = Stat::new();
+    unsafe {
+        match (*f).ftype {
+            FileType::Pipe => {
+                match (*f).inode {
+                    Some(inode) => (*inode).stat(&mut st),
+                    None => st.mode = S_IFIFO | 0o600,
+                }
+                st.size = pipe_size((*f).pipe) as u64;
+            }
+            FileType::Inode => (*(*f).inode.unwrap()).stat(&mut st),
+            FileType::Socket => st.mode = S_IFSOCK | 0o777,
+            _ => return -1,
+        }
+    }
+
+    either_copy_out(
+        1,
+        addr,
+        &st as *const Stat as *const u8,
+        size_of::<Stat>() as u64,
+    )
+}
+
+// make the content of f durable
+pub fn file_sync(f: *mut File) -> i32 {
+    unsafe {
+        match (*f).ftype {
+            FileType::Inode => (*(*f).inode.unwrap()).sync(),
+            _ => -1,
+        }
+    }
+}
+
+// read from file f to the user address addr
+pub fn file_read(f: *mut File, addr: u64, n: u32) -> i32 {
+    unsafe {
+        if !(*f).readable {
+            return -1;
+        }
+
+        match (*f).ftype {
+            FileType::Pipe => pipe_read((*f).pipe, addr, n),
+            FileType::Socket => socket_recv((*f).sock, addr, n, 0, 0, None),
+            FileType::Inode => {
+                let inode = (*f).inode.unwrap();
+                if is_dir(inode) {
+                    return -1;
+                }
+                let r = (*inode).read(1, addr, (*f).off, n);
+                if r > 0 {
+                    (*f).off += r as u32;
+                }
+                r
+            }
+            _ => {
+                panicc!("file_read");
+            }
+        }
+    }
+}
+
+// write to file f from the user address addr
+pub fn file_write(f: *mut File, addr: u64, n: u32) -> i32 {
+    unsafe {
+        if !(*f).writable {
+            return -1;
+        }
+
+        match (*f).ftype {
+            FileType::Pipe => pipe_write((*f).pipe, addr, n),
+            FileType::Socket => socket_send((*f).sock, addr, n, 0, 0, None),
+            FileType::Inode => {
+                let r = (*(*f).inode.unwrap()).write(1, addr, (*f).off, n);
+                if r > 0 {
+                    (*f).off += r as u32;
+                }
+                r
+            }
+            _ => {
+                panicc!("file_write");
+            }
+        }
+    }
+}
+
+// read directory entries of f to the user address addr
+pub fn file_getdents(f: *mut File, addr: u64, n: u32) -> i32 {
+    unsafe {
+        match (*f).ftype {
+            FileType::Inode => getdents((*f).inode.unwrap(), 1, addr, &mut (*f).off, n),
+            _ => -1,
+        }
+    }
+}
@@ -1,572 +1,675 @@
-use crate::block_cache::{bread, brelse, bwrite, Buf};
-use crate::param::{NINODE, ROOT_DEV};
-use crate::string::{mem_copy, str_cmp};
-use core::cmp::min;
+use crate::devfs::devfs_init;
+use crate::minix::minix_mount;
+use crate::param::{MAX_PATH, NMOUNT, TMPFS_PAGES};
+use crate::proc::either_copy_out;
+use crate::procfs::procfs_init;
+use crate::stat::{
+    dirent_reclen, Dirent, Stat, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFREG, S_IFSOCK,
+};
+use crate::string::{mem_copy, mem_set, str_cmp};
+use crate::tmpfs::tmpfs_new;
+use crate::virtio_disk::virtio_disk_flush_all;
 use core::fmt::Write;
 use core::mem::size_of;
-use core::ptr::null_mut;
-
-// some troublesome things need to be handled...
-pub const BLOCK_SIZE: u32 = 1024;
-const ROOT_INO: u32 = 1;
-const MAGIC: u16 = 0x4d5a;
-
-#[repr(C)]
-struct SuperBlock {
-    ninode: u32,
-    pad0: u16, // unused
-    imap_blk_num: u16,
-    zmap_blk_num: u16,
-    first_data_zone: u16,
-    log2_bz: u16, // log2(block/zone)
-    pad1: u16,
-    max_fsize: u32,
-    nzone: u32,
-    magic: u16,
-    pad2: u16,
-    block_size: u16,
-    fsv: u8, // FS sub-version
-}
-
-static mut SB: SuperBlock = SuperBlock {
-    ninode: 0,
-    pad0: 0,
-    imap_blk_num: 0,
-    zmap_blk_num: 0,
-    first_data_zone: 0,
-    log2_bz: 0,
-    pad1: 0,
-    max_fsize: 0,
-    nzone: 0,
-    magic: 0,
-    pad2: 0,
-    block_size: 0,
-    fsv: 0,
-};
 
-pub fn fs_init(dev: u32) {
-    // the superblock is loaded from the second 1 KB of the disk device
-    let b = bread(dev, 1);
-    unsafe {
-        mem_copy(
-            &mut SB as *mut SuperBlock as u64 as *mut u64,
-            &mut (*b).data as *mut u8 as *mut u64,
-            size_of::<SuperBlock>() as u64,
-        );
-
-        if SB.magic != MAGIC {
-            panicc!("fs_init: magic number invalid");
-        }
+// virtual file system layer.
+//
+// a file system type implements FileSystem, its in-memory inodes
+// implement Inode (and DirIter for directories). the rest of the
+// kernel only deals with *mut dyn Inode, whose references are
+// managed with dup() and put().
+// file systems are attached to the tree through the mount table,
+// which knows a mount point by the (dev, ino) of its inode, and
+// path lookup crosses mount points in both directions.
 
-        // nightmare...
-        if SB.log2_bz != 0 {
-            println!("{}", SB.log2_bz);
-            panicc!("fs_init: log2_bz");
-        }
+pub const BLOCK_SIZE: u32 = 1024;
+pub const NAME_MAX: usize = 60; // longest file name of all file systems
+
+// a directory entry as seen by the vfs
+pub struct DirEnt {
+    pub ino: u32,
+    pub name: [u8; NAME_MAX + 1], // '\0' terminated
+    pub namelen: usize,
+    pub dtype: u8,
+}
 
-        if SB.block_size != BLOCK_SIZE as u16 {
-            println!("{}", SB.block_size);
-            panicc!("fs_init: block_size");
+impl DirEnt {
+    pub const fn new() -> Self {
+        DirEnt {
+            ino: 0,
+            name: [0; NAME_MAX + 1],
+            namelen: 0,
+            dtype: 0,
         }
     }
 
-    brelse(b);
+    pub fn set(&mut self, ino: u32, name: &[u8], dtype: u8) {
+        self.ino = ino;
+        self.namelen = name.len();
+        self.name[..name.len()].copy_from_slice(name);
+        self.name[name.len()] = 0;
+        self.dtype = dtype;
+    }
 }
 
-const BPERB: u32 = 8 * BLOCK_SIZE; // bits per block
+pub trait DirIter {
+    // read the entry at *pos into ent and move *pos to the next one,
+    // return false at the end of the directory.
+    // pos is opaque to callers, but stays valid across calls
+    fn next(&mut self, pos: &mut u32, ent: &mut DirEnt) -> bool;
+}
 
-// alloc a block
-fn balloc(dev: u32) -> u32 {
-    unsafe {
-        let zmap_start = 2 + SB.imap_blk_num as u32;
-        for i in 0..SB.zmap_blk_num as usize {
-            let b = bread(dev, zmap_start + i as u32);
-
-            for j in 0..BPERB as usize {
-                let bits = 1 << (j % 8);
-                if (*b).data[j / 8] & bits == 0 {
-                    (*b).data[j / 8] |= bits;
-                    brelse(b);
-                    return i as u32 * BPERB + j as u32;
-                }
-            }
+pub trait Inode {
+    // (dev, ino) identifies an inode among all mounted file systems
+    fn dev(&self) -> u32;
+    fn ino(&self) -> u32;
+    fn mode(&self) -> u16;
+    fn stat(&self, st: &mut Stat);
+
+    // take another reference
+    fn dup(&mut self) -> *mut dyn Inode;
+    // drop a reference
+    fn put(&mut self);
+
+    // return the number of bytes read or written, -1 on error
+    fn read(&mut self, is_uaddr: u32, dst: u64, off: u32, n: u32) -> i32;
+    fn write(&mut self, is_uaddr: u32, src: u64, off: u32, n: u32) -> i32;
+    // discard the content, return -1 on error
+    fn truncate(&mut self) -> i32;
+
+    // look up name in a directory, return the child with a reference held
+    fn lookup(&mut self, name: *const u8) -> Option<*mut dyn Inode>;
+    // create name in a directory, the file type comes from mode.
+    // name doesn't exist yet, return the new inode with a reference held
+    fn create(&mut self, _name: *const u8, _mode: u16) -> Option<*mut dyn Inode> {
+        None
+    }
+    // remove name from a directory, a directory to remove must be empty
+    fn unlink(&mut self, _name: *const u8) -> i32 {
+        -1
+    }
+    // None if not a directory
+    fn dir_iter(&mut self) -> Option<&mut dyn DirIter> {
+        None
+    }
+    // make the content durable, return -1 on error
+    fn sync(&mut self) -> i32 {
+        0
+    }
+}
 
-            brelse(b);
-        }
+pub trait FileSystem {
+    fn name(&self) -> &'static str;
+    fn dev(&self) -> u32;
+    // the root directory, with a reference held
+    fn root(&mut self) -> *mut dyn Inode;
+    // release the file system once it's no longer mounted,
+    // return -1 and keep it if any of its inodes is still in use
+    fn unmount(&mut self) -> i32 {
+        -1
+    }
+    // make everything written so far durable, return -1 on error
+    fn sync(&mut self) -> i32 {
+        0
     }
+}
 
-    panicc!("balloc: no free block");
+pub fn is_dir(inode: *mut dyn Inode) -> bool {
+    unsafe { (*inode).mode() & S_IFMT == S_IFDIR }
 }
 
-// free a block
-fn bfree(dev: u32, block_no: u32) {
-    unsafe {
-        let map_bno = 2 + SB.imap_blk_num as u32 + block_no / BPERB;
-        let b = bread(dev, map_bno);
-        let byte_no = (block_no % BPERB / 8) as usize;
-        let bit_no = block_no % BPERB % 8;
-        if (*b).data[byte_no] & 1 << bit_no == 0 {
-            panicc!("bfree: block is free");
-        }
-        (*b).data[byte_no] &= !(1 << bit_no);
-        brelse(b);
-    }
+pub fn is_fifo(inode: *mut dyn Inode) -> bool {
+    unsafe { (*inode).mode() & S_IFMT == S_IFIFO }
 }
 
-// from https://github.com/Stichting-MINIX-Research-Foundation/minix
-const TYPE: u16 = 0o170000; // this field gives inode type
-const SYMBOLIC_LINK: u16 = 0o140000;
-const REGULAR: u16 = 0o100000; // regular file, not dir or special
-const DIRECTORY: u16 = 0o040000;
-const NAMED_PIPE: u16 = 0o010000; // named pipe (FIFO)
-const NOT_ALLOC: u16 = 0o000000; // this node is free
+pub fn is_symlink(inode: *mut dyn Inode) -> bool {
+    unsafe { (*inode).mode() & S_IFMT == S_IFLNK }
+}
 
-const fn is_reg(m: u16) -> bool {
-    m & TYPE == REGULAR
+pub fn is_socket(inode: *mut dyn Inode) -> bool {
+    unsafe { (*inode).mode() & S_IFMT == S_IFSOCK }
 }
 
-const fn is_dir(m: u16) -> bool {
-    m & TYPE == DIRECTORY
+// compare the '\0' terminated name with s
+pub fn name_is(name: *const u8, s: &str) -> bool {
+    unsafe {
+        for (i, c) in s.bytes().enumerate() {
+            if *name.add(i) != c {
+                return false;
+            }
+        }
+        *name.add(s.len()) == 0
+    }
 }
 
-const fn not_alloc(m: u16) -> bool {
-    m & TYPE == NOT_ALLOC
+fn same_inode(a: *mut dyn Inode, b: *mut dyn Inode) -> bool {
+    unsafe { (*a).dev() == (*b).dev() && (*a).ino() == (*b).ino() }
 }
 
-const NDIRECT: usize = 7; // direct block num in an inode
-const NINDIRECT: usize = BLOCK_SIZE as usize / size_of::<u32>(); // indirect block num
-const NDINDIRECT: usize = (BLOCK_SIZE as usize / size_of::<u32>()) * NINDIRECT; // double indirect block num
-const IPERB: u32 = (BLOCK_SIZE as usize / size_of::<InodeDisk>()) as u32;
+#[derive(Copy, Clone)]
+struct Mount {
+    fs: Option<*mut dyn FileSystem>,
+    root: Option<*mut dyn Inode>, // root of the mounted fs, referenced
+    covered: Option<*mut dyn Inode>, // the mount point, referenced, None for "/"
+}
 
-// block number for inode
-const fn iblock(ino: u32, istart: u32) -> u32 {
-    ino / IPERB + istart
+impl Mount {
+    const fn new() -> Self {
+        Mount {
+            fs: None,
+            root: None,
+            covered: None,
+        }
+    }
 }
 
-#[repr(C)]
-struct InodeDisk {
-    mode: u16, // file type and rwx bits
-    nlink: u16,
-    uid: u16, // identifies user who owns file
-    gid: u16, // owner's group
-    fsize: u32,
-    atime: u32, // access time
-    mtime: u32, // modification time
-    ctime: u32, // status change time
+// MOUNT[0] is the root file system
+static mut MOUNT: [Mount; NMOUNT] = [Mount::new(); NMOUNT];
 
-    // 0-6 first seven data zones
-    // 7 indirect zone
-    // 8 double indirect zone
-    // 9 unused (could be used for triple indirect zone)
-    zone: [u32; NDIRECT + 3],
+// mount the minix file system on dev as the root,
+// then devfs on /dev, procfs on /proc and a tmpfs on /tmp
+// if the root has those directories
+pub fn fs_init(dev: u32) {
+    match minix_mount(dev) {
+        Some(fs) => mount_root(fs),
+        None => {
+            panicc!("fs_init: no root file system");
+        }
+    }
+    mount_boot(Some(devfs_init()), "/dev\0");
+    mount_boot(Some(procfs_init()), "/proc\0");
+    mount_boot(tmpfs_new(TMPFS_PAGES), "/tmp\0");
 }
 
-// reprC?
-#[derive(Copy, Clone)]
-pub struct InodeMem {
-    dev: u32,
-    ino: u32,
-    ref_cnt: u32,
-    valid: u32,
-
-    mode: u16,
-    nlink: u16,
-    uid: u16,
-    gid: u16,
-    fsize: u32,
-    atime: u32,
-    mtime: u32,
-    ctime: u32,
-    zone: [u32; NDIRECT + 3],
-}
-
-impl InodeMem {
-    pub const fn new() -> Self {
-        InodeMem {
-            dev: 0,
-            ino: 0,
-            ref_cnt: 0,
-            valid: 0,
-
-            mode: 0,
-            nlink: 0,
-            uid: 0,
-            gid: 0,
-            fsize: 0,
-            atime: 0,
-            mtime: 0,
-            ctime: 0,
-            zone: [0; NDIRECT + 3],
+fn mount_boot(fs: Option<*mut dyn FileSystem>, path: &str) {
+    match fs {
+        Some(fs) if mount(fs, path.as_ptr() as *mut u8) == 0 => {}
+        _ => {
+            println!(
+                "fs_init: nothing mounted on {}",
+                path.trim_end_matches('\0')
+            );
         }
     }
 }
 
-struct Icache {
-    inode: [InodeMem; NINODE],
+pub fn mount_root(fs: *mut dyn FileSystem) {
+    unsafe {
+        if MOUNT[0].fs.is_some() {
+            panicc!("mount_root: already mounted");
+        }
+        MOUNT[0].fs = Some(fs);
+        MOUNT[0].root = Some((*fs).root());
+    }
 }
 
-static mut ICACHE: Icache = Icache {
-    inode: [InodeMem::new(); NINODE],
-};
+// mount fs on the directory at path, return -1 on error
+pub fn mount(fs: *mut dyn FileSystem, path: *mut u8) -> i32 {
+    let dir = match path_lookup(path, true) {
+        Some(dir) => dir,
+        None => return -1,
+    };
+    if !is_dir(dir) || find_mount_covering(dir).is_some() {
+        unsafe { (*dir).put() };
+        return -1;
+    }
 
-// write inode to disk
-pub fn iupdate(inode: *const InodeMem) {
-    unsafe {
-        let b = bread(
-            (*inode).dev,
-            iblock((*inode).ino, 2 + (SB.imap_blk_num + SB.zmap_blk_num) as u32),
-        );
-        let mut dinode =
-            (&mut (*b).data as *mut u8 as *mut InodeDisk).add(((*inode).ino % IPERB) as usize);
-        (*dinode).mode = (*inode).mode;
-        (*dinode).nlink = (*inode).nlink;
-        (*dinode).uid = (*inode).uid;
-        (*dinode).gid = (*inode).gid;
-        (*dinode).fsize = (*inode).fsize;
-        (*dinode).atime = (*inode).atime;
-        (*dinode).mtime = (*inode).mtime;
-        (*dinode).ctime = (*inode).ctime;
-        mem_copy(
-            &mut (*dinode).zone as *mut u32 as *mut u64,
-            &(*inode).zone as *const u32 as *const u64,
-            (size_of::<u32>() * (NDIRECT + 3)) as u64,
-        );
-        bwrite(b);
-        brelse(b);
-    }
-}
-
-// read from disk if necessary
-pub fn iget(dev: u32, ino: u32) -> *mut InodeMem {
-    let mut empty_idx = NINODE;
     unsafe {
-        for i in 0..NINODE {
-            if ICACHE.inode[i].ref_cnt != 0
-                && ICACHE.inode[i].dev == dev
-                && ICACHE.inode[i].ino == ino
-            {
-                ICACHE.inode[i].ref_cnt += 1;
-                return &mut ICACHE.inode[i] as *mut InodeMem;
-            }
-
-            if empty_idx == NINODE && ICACHE.inode[i].ref_cnt == 0 {
-                empty_idx = i;
+        for i in 1..NMOUNT {
+            if MOUNT[i].fs.is_none() {
+                MOUNT[i].fs = Some(fs);
+                MOUNT[i].root = Some((*fs).root());
+                MOUNT[i].covered = Some(dir);
+                return 0;
             }
         }
+        (*dir).put();
+    }
+
+    -1
+}
+
+// unmount the file system whose root is at path. it is busy, and stays
+// mounted, while something is mounted on it or its inodes are in use
+pub fn umount(path: *mut u8) -> i32 {
+    let inode = match path_lookup(path, true) {
+        Some(inode) => inode,
+        None => return -1,
+    };
+    let found = find_mount_rooted(inode);
+    unsafe { (*inode).put() };
+
+    // the root file system can't be unmounted
+    let i = match found {
+        Some(i) if i > 0 => i,
+        _ => return -1,
+    };
 
-        if empty_idx == NINODE {
-            panicc!("iget: no free inodes");
+    unsafe {
+        let fs = MOUNT[i].fs.unwrap();
+        for j in 1..NMOUNT {
+            if let Some(covered) = MOUNT[j].covered {
+                if (*covered).dev() == (*fs).dev() {
+                    return -1;
+                }
+            }
         }
 
-        ICACHE.inode[empty_idx].dev = dev;
-        ICACHE.inode[empty_idx].ino = ino;
-        ICACHE.inode[empty_idx].ref_cnt = 1;
-
-        let b = bread(
-            dev,
-            iblock(ino, 2 + (SB.imap_blk_num + SB.zmap_blk_num) as u32),
-        );
-
-        let dinode = (&(*b).data as *const u8 as *const InodeDisk).add((ino % IPERB) as usize);
-        ICACHE.inode[empty_idx].mode = (*dinode).mode;
-        ICACHE.inode[empty_idx].nlink = (*dinode).nlink;
-        ICACHE.inode[empty_idx].uid = (*dinode).uid;
-        ICACHE.inode[empty_idx].gid = (*dinode).gid;
-        ICACHE.inode[empty_idx].fsize = (*dinode).fsize;
-        ICACHE.inode[empty_idx].atime = (*dinode).atime;
-        ICACHE.inode[empty_idx].mtime = (*dinode).mtime;
-        ICACHE.inode[empty_idx].ctime = (*dinode).ctime;
-        mem_copy(
-            &mut ICACHE.inode[empty_idx].zone as *mut u32 as *mut u64,
-            &(*dinode).zone as *const u32 as *const u64,
-            (size_of::<u32>() * (NDIRECT + 3)) as u64,
-        );
-        brelse(b);
-
-        ICACHE.inode[empty_idx].valid = 1;
-        if not_alloc((*dinode).mode) {
-            panicc!("iget: inode not alloc");
+        let root = MOUNT[i].root.unwrap();
+        (*root).put();
+        if (*fs).unmount() < 0 {
+            MOUNT[i].root = Some((*fs).root());
+            return -1;
         }
 
-        &mut ICACHE.inode[empty_idx] as *mut InodeMem
+        (*MOUNT[i].covered.unwrap()).put();
+        MOUNT[i] = Mount::new();
     }
+
+    0
 }
 
-// get the addr of the nth block in inode
-// return 0 if not exist and alloc==0
-fn bmap(inode: *mut InodeMem, mut bn: usize, alloc: u32) -> u32 {
+// sync every mounted file system, then flush every disk for what
+// was written to it raw. return -1 if any failed
+pub fn sync() -> i32 {
+    let mut r = 0;
     unsafe {
-        if bn < NDIRECT {
-            let addr = (*inode).zone[bn as usize];
-            if addr == 0 && alloc != 0 {
-                (*inode).zone[bn as usize] = balloc((*inode).dev);
-            }
-            return addr;
-        }
-
-        bn -= NDIRECT;
-        let mut b: *mut Buf;
-        let mut addr: u32;
-        let mut ap: *mut u32;
-        if bn < NINDIRECT {
-            addr = (*inode).zone[NDIRECT];
-            if addr == 0 {
-                if alloc == 0 {
-                    return 0;
+        for i in 0..NMOUNT {
+            if let Some(fs) = MOUNT[i].fs {
+                if (*fs).sync() < 0 {
+                    r = -1;
                 }
-
-                addr = balloc((*inode).dev);
-                (*inode).zone[NDIRECT] = addr;
             }
+        }
+    }
+    if virtio_disk_flush_all() < 0 {
+        r = -1;
+    }
 
-            b = bread((*inode).dev, addr);
-            ap = &mut (*b).data as *mut u8 as *mut u32;
-            addr = *ap.add(bn);
-            brelse(b);
+    r
+}
 
-            if addr == 0 && alloc != 0 {
-                addr = balloc((*inode).dev);
-                *ap.add(bn) = addr;
+// the mount whose mount point is inode
+fn find_mount_covering(inode: *mut dyn Inode) -> Option<usize> {
+    unsafe {
+        for i in 1..NMOUNT {
+            if let Some(covered) = MOUNT[i].covered {
+                if same_inode(covered, inode) {
+                    return Some(i);
+                }
             }
-
-            return addr;
         }
+    }
 
-        bn -= NINDIRECT;
-        if bn < NDINDIRECT {
-            addr = (*inode).zone[NDIRECT + 1];
-            if addr == 0 {
-                if alloc == 0 {
-                    return 0;
-                }
-
-                addr = balloc((*inode).dev);
-                (*inode).zone[NDIRECT + 1] = addr;
-            }
+    None
+}
 
-            b = bread((*inode).dev, addr);
-            ap = &mut (*b).data as *mut u8 as *mut u32;
-            addr = *ap.add(bn / NINDIRECT);
-            if addr == 0 {
-                if alloc == 0 {
-                    return 0;
+// the mount whose root is inode
+fn find_mount_rooted(inode: *mut dyn Inode) -> Option<usize> {
+    unsafe {
+        for i in 0..NMOUNT {
+            if let Some(root) = MOUNT[i].root {
+                if same_inode(root, inode) {
+                    return Some(i);
                 }
-
-                addr = balloc((*inode).dev);
-                *ap.add(bn / NINDIRECT) = addr;
             }
+        }
+    }
 
-            b = bread((*inode).dev, addr);
-            ap = &mut (*b).data as *mut u8 as *mut u32;
-            addr = *ap.add(bn % NINDIRECT);
-            brelse(b);
+    None
+}
 
-            if addr == 0 && alloc != 0 {
-                addr = balloc((*inode).dev);
-                *ap.add(bn % NINDIRECT) = addr;
+// the root directory of the whole tree, with a reference held
+pub fn root_inode() -> *mut dyn Inode {
+    unsafe {
+        match MOUNT[0].root {
+            Some(root) => (*root).dup(),
+            None => {
+                panicc!("root_inode: no root file system");
             }
-
-            return addr;
         }
     }
-
-    panicc!("bmap: bn out of range");
 }
 
-// read file content from inode
-pub fn readi(inode: *mut InodeMem, is_uaddr: u32, mut dst: u64, mut off: u32, mut n: u32) -> u32 {
-    if is_uaddr != 0 {
-        panicc!("readi: not support user addr");
-    }
+// move path ptr, and copy filename to name.
+// a name longer than NAME_MAX is returned empty so that the lookup fails
+fn eat_path(mut path: *mut u8, name: *mut u8) -> *mut u8 {
     unsafe {
-        if off > (*inode).fsize || n > 0xffffffff - off {
-            return 0;
+        while (*path) == '/' as u8 {
+            path = path.add(1);
         }
 
-        if off + n > (*inode).fsize {
-            n = (*inode).fsize - off;
+        if (*path) == 0 {
+            return core::ptr::null_mut();
         }
-    }
 
-    let mut cnt: u32 = 0;
-    let mut b: *mut Buf;
-    let mut data_size;
-    let mut block_no;
-    while cnt < n {
-        block_no = bmap(inode, (off / BLOCK_SIZE) as usize, 0);
-        if block_no == 0 {
-            panicc!("readi: block not exist");
+        let s = path;
+        let mut len: usize = 0;
+        while (*path) != '/' as u8 && (*path) != 0 {
+            path = path.add(1);
+            len += 1;
         }
-        unsafe {
-            b = bread((*inode).dev, block_no);
 
-            data_size = min(n - cnt, BLOCK_SIZE - off % BLOCK_SIZE);
-            // not for uaddr yet
-            mem_copy(
-                dst as *mut u64,
-                (&mut (*b).data as *mut u8).add((off % BLOCK_SIZE) as usize) as *mut u64,
-                data_size as u64,
-            );
+        if len > NAME_MAX {
+            len = 0;
         }
-        brelse(b);
 
-        cnt += data_size;
-        off += data_size;
-        dst += data_size as u64;
+        mem_copy(name as *mut u64, s as *mut u64, len as u64);
+        *name.add(len) = 0;
     }
 
-    cnt
+    path
 }
 
-// wirte file content in inode
-pub fn writei(inode: *mut InodeMem, is_uaddr: u32, mut src: u64, mut off: u32, mut n: u32) -> u32 {
-    if is_uaddr != 0 {
-        panicc!("writei: not support user addr");
-    }
-
-    let mut cnt: u32 = 0;
-    let mut b: *mut Buf;
-    let mut data_size;
-    let mut block_no;
-    while cnt < n {
-        block_no = bmap(inode, (off / BLOCK_SIZE) as usize, 1);
-        unsafe {
-            b = bread((*inode).dev, block_no);
-            data_size = min(BLOCK_SIZE - off % BLOCK_SIZE, n - cnt);
-            mem_copy(
-                (&mut (*b).data as *mut u8).add((off % BLOCK_SIZE) as usize) as *mut u64,
-                src as *mut u64,
-                data_size as u64,
-            );
-        }
-        bwrite(b);
-        brelse(b);
-
-        cnt += data_size;
-        off += data_size;
-        src += data_size as u64;
-    }
+const MAX_SYMLINK_DEPTH: u32 = 8;
 
+// true if nothing but '/' is left in path
+fn path_end(mut path: *const u8) -> bool {
     unsafe {
-        if cnt > (*inode).fsize {
-            (*inode).fsize = cnt;
+        while (*path) == '/' as u8 {
+            path = path.add(1);
         }
+        (*path) == 0
     }
+}
 
-    iupdate(inode);
+// resolve the symbolic link found in dir
+fn follow_link(dir: *mut dyn Inode, link: *mut dyn Inode, depth: u32) -> Option<*mut dyn Inode> {
+    if depth >= MAX_SYMLINK_DEPTH {
+        return None;
+    }
 
-    cnt
+    // a target too long for a path fails rather than being cut short
+    let mut target: [u8; MAX_PATH] = [0; MAX_PATH];
+    let n = unsafe { (*link).read(0, &mut target as *mut u8 as u64, 0, MAX_PATH as u32) };
+    if n <= 0 || n as usize >= MAX_PATH - 1 {
+        return None;
+    }
+    target[n as usize] = 0;
+
+    // a relative target starts from the directory containing the link
+    let start = match target[0] == '/' as u8 {
+        true => root_inode(),
+        false => unsafe { (*dir).dup() },
+    };
+    path_walk(start, &mut target as *mut u8, None, true, depth + 1)
 }
 
-const FNAME_SIZE: usize = 60; // include '\0'
+// look up name in dir, crossing mount points
+fn lookup_cross(dir: *mut dyn Inode, name: *const u8) -> Option<*mut dyn Inode> {
+    unsafe {
+        let mut dir = (*dir).dup();
+
+        // ".." of a mounted root is ".." of its mount point
+        if str_cmp(name, "..\0".as_ptr(), 3) == 0 {
+            while let Some(i) = find_mount_rooted(dir) {
+                match MOUNT[i].covered {
+                    Some(covered) => {
+                        (*dir).put();
+                        dir = (*covered).dup();
+                    }
+                    None => break,
+                }
+            }
+        }
 
-#[repr(C)]
-struct DirEntry {
-    ino: u32,
-    name: [u8; FNAME_SIZE],
-}
+        let child = (*dir).lookup(name);
+        (*dir).put();
+        let mut child = child?;
 
-impl DirEntry {
-    pub const fn new() -> Self {
-        DirEntry {
-            ino: 0,
-            name: [0; FNAME_SIZE],
+        // go down to the root of file systems mounted on child
+        while let Some(i) = find_mount_covering(child) {
+            let root = MOUNT[i].root.unwrap();
+            (*child).put();
+            child = (*root).dup();
         }
+
+        Some(child)
     }
 }
 
-// look up for name in directory, set off (offp points to)
-fn dir_lookup(inode: *mut InodeMem, name: *mut u8, offp: *mut u32) -> *mut InodeMem {
+// walk path from inode dir (whose reference is consumed),
+// symbolic links in the middle are always followed,
+// the last one only if follow is true.
+// if parent is given, stop one level early and copy the last name to it
+fn path_walk(
+    dir: *mut dyn Inode,
+    mut path: *mut u8,
+    parent: Option<*mut u8>,
+    follow: bool,
+    depth: u32,
+) -> Option<*mut dyn Inode> {
+    let mut name: [u8; NAME_MAX + 1] = [0; NAME_MAX + 1];
+    let mut inode = dir;
     unsafe {
-        if !is_dir((*inode).mode) {
-            panicc!("dir_lookup: not dir");
-        }
-
-        let mut de = DirEntry::new();
-        let mut off = 0;
-        while off < (*inode).fsize {
-            let ret = readi(
-                inode,
-                0,
-                &mut de as *mut DirEntry as u64,
-                off,
-                size_of::<DirEntry>() as u32,
-            );
-            if ret == 0 {
-                panicc!("dir_lookup: read inode");
+        path = eat_path(path, &mut name as *mut u8);
+        while !path.is_null() {
+            if !is_dir(inode) {
+                (*inode).put();
+                return None;
             }
-            off += size_of::<DirEntry>() as u32;
 
-            if de.ino == 0 {
-                continue;
+            if let Some(last) = parent {
+                if path_end(path) {
+                    mem_copy(
+                        last as *mut u64,
+                        &name as *const u8 as *const u64,
+                        (NAME_MAX + 1) as u64,
+                    );
+                    return Some(inode);
+                }
             }
 
-            if str_cmp(name, &de.name as *const u8, FNAME_SIZE as u32) == 0 {
-                if !offp.is_null() {
-                    (*offp) = off;
+            let mut child = match lookup_cross(inode, &name as *const u8) {
+                Some(child) => child,
+                None => {
+                    (*inode).put();
+                    return None;
                 }
-                return iget((*inode).dev, de.ino);
+            };
+
+            if is_symlink(child) && (follow || !path_end(path)) {
+                let target = follow_link(inode, child, depth);
+                (*child).put();
+                child = match target {
+                    Some(target) => target,
+                    None => {
+                        (*inode).put();
+                        return None;
+                    }
+                };
             }
+
+            (*inode).put();
+            inode = child;
+            path = eat_path(path, &mut name as *mut u8);
+        }
+
+        if parent.is_some() {
+            // path is "/"
+            (*inode).put();
+            return None;
         }
     }
 
-    null_mut()
+    Some(inode)
 }
 
-// move path ptr, and copy filename to name
-fn eat_path(mut path: *mut u8, name: *mut u8) -> *mut u8 {
+// return the inode of path with a reference held, or None if not found,
+// a symbolic link at the end of path is followed only if follow is true
+pub fn path_lookup(path: *mut u8, follow: bool) -> Option<*mut dyn Inode> {
     unsafe {
-        while (*path) == '/' as u8 {
-            path = path.add(1);
+        // not support relative path yet
+        if (*path) != '/' as u8 {
+            return None;
         }
+    }
 
-        if (*path) == 0 {
-            return null_mut();
+    path_walk(root_inode(), path, None, follow, 0)
+}
+
+// return the directory containing the last element of path,
+// and copy that element to name, which holds NAME_MAX+1 bytes
+pub fn path_parent(path: *mut u8, name: *mut u8) -> Option<*mut dyn Inode> {
+    unsafe {
+        if (*path) != '/' as u8 {
+            return None;
         }
+    }
 
-        let s = path;
-        let mut len: usize = 0;
-        while (*path) != '/' as u8 && (*path) != 0 {
-            path = path.add(1);
-            len += 1;
+    let dir = path_walk(root_inode(), path, Some(name), true, 0)?;
+    if unsafe { *name } == 0 {
+        // name too long
+        unsafe { (*dir).put() };
+        return None;
+    }
+
+    Some(dir)
+}
+
+fn is_dot(name: *const u8) -> bool {
+    str_cmp(name, ".\0".as_ptr(), 2) == 0 || str_cmp(name, "..\0".as_ptr(), 3) == 0
+}
+
+// create the file at path with the type and permission in mode,
+// an existing regular file is returned as is if mode asks for one.
+// return the inode with a reference held
+pub fn create(path: *mut u8, mode: u16) -> Option<*mut dyn Inode> {
+    let mut name: [u8; NAME_MAX + 1] = [0; NAME_MAX + 1];
+    let dir = path_parent(path, &mut name as *mut u8)?;
+    unsafe {
+        if let Some(inode) = lookup_cross(dir, &name as *const u8) {
+            (*dir).put();
+            if mode & S_IFMT == S_IFREG && (*inode).mode() & S_IFMT == S_IFREG {
+                return Some(inode);
+            }
+            (*inode).put();
+            return None;
         }
 
-        if len >= FNAME_SIZE {
-            panicc!("eat_path: file name too long");
+        if is_dot(&name as *const u8) {
+            (*dir).put();
+            return None;
         }
 
-        mem_copy(name as *mut u64, s as *mut u64, len as u64);
-        *name.add(len) = 0;
+        let inode = (*dir).create(&name as *const u8, mode);
+        (*dir).put();
+        inode
     }
+}
 
-    path
+// create a symbolic link at path pointing to target
+pub fn symlink(target: *const u8, path: *mut u8) -> i32 {
+    let mut len = 0;
+    unsafe {
+        while *target.add(len) != 0 {
+            len += 1;
+        }
+    }
+    // follow_link() refuses longer targets
+    if len == 0 || len >= MAX_PATH - 1 {
+        return -1;
+    }
+
+    let link = match create(path, S_IFLNK | 0o777) {
+        Some(link) => link,
+        None => return -1,
+    };
+    unsafe {
+        let r = (*link).write(0, target as u64, 0, len as u32);
+        (*link).put();
+        if r != len as i32 {
+            unlink(path);
+            return -1;
+        }
+    }
+
+    0
 }
 
-fn path_lookup(mut path: *mut u8) -> *mut InodeMem {
-    // not support relative path yet
-    let mut name: [u8; FNAME_SIZE] = [0; FNAME_SIZE];
-    let mut inode = iget(ROOT_DEV, ROOT_INO);
-    let mut child;
+// remove the name at path, a mount point can't be removed
+pub fn unlink(path: *mut u8) -> i32 {
+    let mut name: [u8; NAME_MAX + 1] = [0; NAME_MAX + 1];
+    let dir = match path_parent(path, &mut name as *mut u8) {
+        Some(dir) => dir,
+        None => return -1,
+    };
+
     unsafe {
-        if (*path) != '/' as u8 {
-            panicc!("path_lookup: not support relative path yet");
+        if is_dot(&name as *const u8) {
+            (*dir).put();
+            return -1;
         }
 
-        path = eat_path(path, &mut name as *mut u8);
-        while !path.is_null() {
-            if !is_dir((*inode).mode) {
-                return null_mut();
+        let inode = match (*dir).lookup(&name as *const u8) {
+            Some(inode) => inode,
+            None => {
+                (*dir).put();
+                return -1;
             }
+        };
+        let busy = find_mount_covering(inode).is_some();
+        (*inode).put();
+
+        let r = match busy {
+            true => -1,
+            false => (*dir).unlink(&name as *const u8),
+        };
+        (*dir).put();
+        r
+    }
+}
 
-            child = dir_lookup(inode, &mut name as *mut u8, null_mut());
-            if child.is_null() {
-                return null_mut();
+// fill dst with packed Dirent records read from directory position *posp,
+// advance *posp past the entries returned.
+// return the number of bytes filled, 0 at the end of directory,
+// or -1 if n is too small for the next record or copying failed
+pub fn getdents(inode: *mut dyn Inode, is_uaddr: u32, dst: u64, posp: *mut u32, n: u32) -> i32 {
+    let mut ent = DirEnt::new();
+    let mut rec: [u8; dirent_reclen(NAME_MAX)] = [0; dirent_reclen(NAME_MAX)];
+    let mut cnt: u32 = 0;
+    let mut too_small = false;
+    unsafe {
+        let it = match (*inode).dir_iter() {
+            Some(it) => it,
+            None => return -1,
+        };
+
+        loop {
+            let mut pos = *posp;
+            if !it.next(&mut pos, &mut ent) {
+                break;
             }
 
-            inode = child;
-            path = eat_path(path, &mut name as *mut u8);
+            let reclen = dirent_reclen(ent.namelen);
+            if cnt + reclen as u32 > n {
+                too_small = true;
+                break;
+            }
+
+            mem_set(&mut rec as *mut u8 as *mut u64, 0, reclen as u64);
+            let d = &mut rec as *mut u8 as *mut Dirent;
+            (*d).ino = ent.ino;
+            (*d).reclen = reclen as u16;
+            (*d).namelen = ent.namelen as u8;
+            (*d).dtype = ent.dtype;
+            mem_copy(
+                (&mut rec as *mut u8).add(size_of::<Dirent>()) as *mut u64,
+                &ent.name as *const u8 as *const u64,
+                ent.namelen as u64,
+            );
+
+            if either_copy_out(is_uaddr, dst + cnt as u64, &rec as *const u8, reclen as u64) == -1 {
+                return -1;
+            }
+            cnt += reclen as u32;
+            *posp = pos;
         }
     }
 
-    inode
+    if cnt == 0 && too_small {
+        return -1;
+    }
+
+    cnt as i32
 }
@@ -13,18 +13,28 @@ struct Run {
 
 struct Kmem {
     free_list: *mut Run,
+    nfree: u64, // pages in free_list
+    npage: u64, // pages managed in total
 }
 
 static mut KMEM: Kmem = Kmem {
     free_list: null_mut(),
+    nfree: 0,
+    npage: 0,
 };
 
 pub fn km_init() {
     unsafe {
         free_range(HEAP_START as *mut u64, PHY_STOP as *mut u64);
+        KMEM.npage = KMEM.nfree;
     }
 }
 
+// return (total, free) pages
+pub fn kmem_stat() -> (u64, u64) {
+    unsafe { (KMEM.npage, KMEM.nfree) }
+}
+
 fn free_range(pa_start: *mut u64, pa_end: *mut u64) {
     let mut p = page_round_up(pa_start as u64) as *mut u8;
     unsafe {
@@ -46,6 +56,7 @@ pub fn kfree(pa: *mut u64) {
         let r = pa as *mut Run;
         (*r).next = KMEM.free_list;
         KMEM.free_list = r;
+        KMEM.nfree += 1;
     }
 }
 
@@ -55,6 +66,7 @@ pub fn kalloc() -> *mut u64 {
         r = KMEM.free_list;
         if !r.is_null() {
             KMEM.free_list = (*r).next;
+            KMEM.nfree -= 1;
         }
     }
 
@@ -0,0 +1,474 @@
+use crate::block_cache::{bget_valid, bpin, bread, brelse, bunpin, bwrite, bwrite_n, Buf};
+use crate::fs::BLOCK_SIZE;
+use crate::param::{LOG_SIZE, MAX_OP_BLOCK, MAX_OP_FREE, NMINIX, NSEG};
+use crate::proc::{sleep, wakeup};
+use crate::string::mem_copy;
+use crate::virtio_disk::{virtio_disk_discard, virtio_disk_flush};
+use core::cmp::min;
+use core::fmt::Write;
+use core::mem::size_of;
+use core::ptr::null_mut;
+
+// simple write-ahead logging in the way of xv6.
+//
+// a system call that modifies the file system wraps its updates in
+// begin_op()/end_op(), and writes modified buffers with log_write()
+// instead of bwrite(). the blocks stay pinned in the buffer cache
+// until the commit, which happens when no fs system call is active
+// (group commit): the blocks are first copied to the log region,
+// then the header is written (the commit point), then the blocks are
+// installed to their home locations and the header is cleared.
+// after a crash, minix_mount() replays committed transactions.
+// every mounted minix file system has a log of its own.
+//
+// the disk may cache writes, so the commit flushes it before and
+// after the header is written, and before the header is cleared.
+// blocks freed by a transaction are discarded once it's committed,
+// unless the transaction allocates them again. the log remembers
+// all of them, as an op frees at most MAX_OP_FREE zones, so that
+// balloc() can tell which zones are free only since the last commit.
+//
+// the log region is the content of a file that minix_mount() reserves
+// in the root directory, so any image made by mkfs.minix can hold it.
+// its blocks need not be consecutive, the log keeps their numbers:
+//   [ header | log block 0 | ... | log block LOG_SIZE-1 ]
+// until log_start() gives it a region, and for good if the file system
+// has no room for one, a log writes updates in place instead.
+
+pub const LOG_BLOCKS: u32 = 1 + LOG_SIZE as u32;
+const LOG_MAGIC: u32 = 0x4c4f4721; // "LOG!", tells a header from garbage
+const NFREED: usize = MAX_OP_FREE as usize * 3; // freed extents per transaction
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct LogHeader {
+    magic: u32,
+    n: u32,
+    block: [u32; LOG_SIZE],
+}
+
+#[derive(Copy, Clone)]
+struct Log {
+    map: [u32; LOG_BLOCKS as usize], // disk block of each log block
+    size: u32,                       // 0 if there is no log region
+    outstanding: u32,                // how many fs system calls are executing
+    committing: bool,
+    dev: u32, // 0 if the slot is free
+    lh: LogHeader,
+    freed: [Extent; NFREED], // to discard after the commit
+    nfreed: usize,
+}
+
+// n blocks from start
+#[derive(Copy, Clone)]
+struct Extent {
+    start: u32,
+    n: u32,
+}
+
+impl Log {
+    const fn new() -> Self {
+        Log {
+            map: [0; LOG_BLOCKS as usize],
+            size: 0,
+            outstanding: 0,
+            committing: false,
+            dev: 0,
+            lh: LogHeader {
+                magic: 0,
+                n: 0,
+                block: [0; LOG_SIZE],
+            },
+            freed: [Extent { start: 0, n: 0 }; NFREED],
+            nfreed: 0,
+        }
+    }
+}
+
+static mut LOG: [Log; NMINIX] = [Log::new(); NMINIX];
+
+// the log of dev
+fn log_of(dev: u32) -> *mut Log {
+    unsafe {
+        for i in 0..NMINIX {
+            if LOG[i].dev == dev {
+                return &mut LOG[i] as *mut Log;
+            }
+        }
+    }
+
+    panicc!("log_of: no log for dev");
+}
+
+// set up a log for dev without a region, which writes updates in
+// place, return false if all logs are in use
+pub fn log_init(dev: u32) -> bool {
+    if size_of::<LogHeader>() > BLOCK_SIZE as usize {
+        panicc!("log_init: too big log header");
+    }
+
+    unsafe {
+        for i in 0..NMINIX {
+            if LOG[i].dev == 0 {
+                LOG[i] = Log::new();
+                LOG[i].dev = dev;
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+// give the log of dev the LOG_BLOCKS blocks in map as its region and
+// recover it. nothing may be in flight
+pub fn log_start(dev: u32, map: &[u32; LOG_BLOCKS as usize]) {
+    let log = log_of(dev);
+    unsafe {
+        if (*log).outstanding > 0 || (*log).committing {
+            panicc!("log_start: busy");
+        }
+        (*log).map = *map;
+        (*log).size = LOG_BLOCKS;
+        recover_from_log(log);
+    }
+}
+
+// release the log of dev, nothing may be in flight
+pub fn log_free(dev: u32) {
+    let log = log_of(dev);
+    unsafe {
+        if (*log).outstanding > 0 || (*log).committing {
+            panicc!("log_free: busy");
+        }
+        *log = Log::new();
+    }
+}
+
+// the disk block holding log block i, after the header
+fn log_block(log: *mut Log, i: u32) -> u32 {
+    unsafe { (*log).map[i as usize + 1] }
+}
+
+// copy committed blocks from log to their home location
+fn install_trans(log: *mut Log, recovering: bool) {
+    unsafe {
+        for tail in 0..(*log).lh.n {
+            let lbuf = bread((*log).dev, log_block(log, tail));
+            let dbuf = bread((*log).dev, (*log).lh.block[tail as usize]);
+            mem_copy(
+                &mut (*dbuf).data as *mut u8 as *mut u64,
+                &(*lbuf).data as *const u8 as *const u64,
+                BLOCK_SIZE as u64,
+            );
+            bwrite(dbuf);
+            if !recovering {
+                bunpin(dbuf);
+            }
+            brelse(lbuf);
+            brelse(dbuf);
+        }
+    }
+}
+
+// read the log header from disk into the in-memory log header
+fn read_head(log: *mut Log) {
+    unsafe {
+        let b = bread((*log).dev, (*log).map[0]);
+        let lh = &(*b).data as *const u8 as *const LogHeader;
+        if (*lh).magic != LOG_MAGIC || (*lh).n as usize > LOG_SIZE {
+            // a fresh log region
+            (*log).lh.n = 0;
+        } else {
+            (*log).lh.n = (*lh).n;
+            for i in 0..(*log).lh.n as usize {
+                (*log).lh.block[i] = (*lh).block[i];
+            }
+        }
+        brelse(b);
+    }
+}
+
+// write in-memory log header to disk,
+// this is the true point at which the current transaction commits
+fn write_head(log: *mut Log) {
+    unsafe {
+        let b = bread((*log).dev, (*log).map[0]);
+        let hb = &mut (*b).data as *mut u8 as *mut LogHeader;
+        (*hb).magic = LOG_MAGIC;
+        (*hb).n = (*log).lh.n;
+        for i in 0..(*log).lh.n as usize {
+            (*hb).block[i] = (*log).lh.block[i];
+        }
+        bwrite(b);
+        brelse(b);
+    }
+}
+
+fn recover_from_log(log: *mut Log) {
+    read_head(log);
+    install_trans(log, true); // if committed, copy from log to disk
+    flush(log);
+    unsafe {
+        (*log).lh.n = 0;
+    }
+    write_head(log); // clear the log
+}
+
+// called at the start of each fs system call on dev
+pub fn begin_op(dev: u32) {
+    let log = log_of(dev);
+    unsafe {
+        loop {
+            if (*log).committing {
+                sleep(log as u64);
+            } else if (*log).size > 0
+                && (*log).lh.n + ((*log).outstanding + 1) * MAX_OP_BLOCK > LOG_SIZE as u32
+            {
+                // this op might exhaust log space, wait for commit
+                sleep(log as u64);
+            } else if (*log).nfreed + ((*log).outstanding as usize + 1) * MAX_OP_FREE as usize
+                > NFREED
+            {
+                // this op might free more zones than can be remembered
+                sleep(log as u64);
+            } else {
+                (*log).outstanding += 1;
+                break;
+            }
+        }
+    }
+}
+
+// called at the end of each fs system call on dev,
+// commits if this was the last outstanding operation
+pub fn end_op(dev: u32) {
+    let log = log_of(dev);
+    let mut do_commit = false;
+    unsafe {
+        (*log).outstanding -= 1;
+        if (*log).committing {
+            panicc!("end_op: committing");
+        }
+
+        if (*log).outstanding == 0 {
+            do_commit = true;
+            (*log).committing = true;
+        } else {
+            // begin_op() may be waiting for log space,
+            // and decrementing outstanding has decreased
+            // the amount of reserved space
+            wakeup(log as u64);
+        }
+    }
+
+    if do_commit {
+        commit(log);
+        unsafe {
+            (*log).committing = false;
+        }
+        wakeup(log as u64);
+    }
+}
+
+// copy modified blocks from cache to log
+fn write_log(log: *mut Log) {
+    unsafe {
+        // write the log blocks NSEG at a time as far as they are
+        // consecutive on disk
+        let mut tail = 0;
+        while tail < (*log).lh.n {
+            let mut cnt = 1;
+            while cnt < min(NSEG as u32, (*log).lh.n - tail)
+                && log_block(log, tail + cnt) == log_block(log, tail) + cnt
+            {
+                cnt += 1;
+            }
+            // the log blocks are overwritten whole, so not read
+            let mut to: [*mut Buf; NSEG] = [null_mut(); NSEG];
+            for i in 0..cnt {
+                to[i as usize] = bget_valid((*log).dev, log_block(log, tail + i));
+                let from = bread((*log).dev, (*log).lh.block[(tail + i) as usize]);
+                mem_copy(
+                    &mut (*to[i as usize]).data as *mut u8 as *mut u64,
+                    &(*from).data as *const u8 as *const u64,
+                    BLOCK_SIZE as u64,
+                );
+                brelse(from);
+            }
+            bwrite_n(&to[..cnt as usize]);
+            for &b in &to[..cnt as usize] {
+                brelse(b);
+            }
+            tail += cnt;
+        }
+    }
+}
+
+fn commit(log: *mut Log) {
+    unsafe {
+        if (*log).lh.n > 0 {
+            write_log(log); // write modified blocks from cache to log
+            flush(log);
+            write_head(log); // write header to disk -- the real commit
+            flush(log);
+            install_trans(log, false); // now install writes to home locations
+            flush(log);
+            (*log).lh.n = 0;
+            write_head(log); // erase the transaction from the log
+        }
+
+        // the freed blocks are garbage now. best effort, the disk
+        // may not support discard
+        for i in 0..(*log).nfreed {
+            let e = (*log).freed[i];
+            virtio_disk_discard((*log).dev, e.start, e.n);
+        }
+        (*log).nfreed = 0;
+    }
+}
+
+// wait until every transaction begun on dev is committed, then
+// make the disk durable. return -1 if the flush failed
+pub fn log_sync(dev: u32) -> i32 {
+    let log = log_of(dev);
+    unsafe {
+        while (*log).outstanding > 0 || (*log).committing {
+            sleep(log as u64);
+        }
+    }
+
+    virtio_disk_flush(dev)
+}
+
+// make the writes to the disk of log durable
+fn flush(log: *mut Log) {
+    if virtio_disk_flush(unsafe { (*log).dev }) < 0 {
+        panicc!("log: disk flush failed");
+    }
+}
+
+// record that the current transaction on dev frees n blocks from
+// start, to be discarded after the commit
+pub fn log_discard(dev: u32, start: u32, n: u32) {
+    let log = log_of(dev);
+    unsafe {
+        for i in 0..(*log).nfreed {
+            let e = &mut (*log).freed[i];
+            if e.start + e.n == start {
+                e.n += n;
+                return;
+            }
+            if start + n == e.start {
+                e.start = start;
+                e.n += n;
+                return;
+            }
+        }
+
+        if (*log).nfreed == NFREED {
+            panicc!("log_discard: too many freed zones");
+        }
+        (*log).freed[(*log).nfreed] = Extent { start, n };
+        (*log).nfreed += 1;
+    }
+}
+
+// has the current transaction on dev freed any of the n blocks
+// from start
+pub fn log_freed(dev: u32, start: u32, n: u32) -> bool {
+    let log = log_of(dev);
+    unsafe {
+        for i in 0..(*log).nfreed {
+            let e = (*log).freed[i];
+            if start < e.start + e.n && e.start < start + n {
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+// the current transaction on dev reuses n blocks from start, so
+// they must not be discarded
+pub fn log_reuse(dev: u32, start: u32, n: u32) {
+    let log = log_of(dev);
+    unsafe {
+        let mut i = 0;
+        while i < (*log).nfreed {
+            let e = (*log).freed[i];
+            if start + n <= e.start || e.start + e.n <= start {
+                i += 1;
+                continue;
+            }
+
+            // keep what lies before and after the reused blocks
+            let head = Extent {
+                start: e.start,
+                n: start.saturating_sub(e.start),
+            };
+            let end = start + n;
+            let tail = Extent {
+                start: end,
+                n: (e.start + e.n).saturating_sub(end),
+            };
+            match (head.n, tail.n) {
+                (0, 0) => {
+                    (*log).nfreed -= 1;
+                    (*log).freed[i] = (*log).freed[(*log).nfreed];
+                    continue;
+                }
+                (0, _) => (*log).freed[i] = tail,
+                (_, 0) => (*log).freed[i] = head,
+                _ => {
+                    // splitting takes a slot, only one that no open
+                    // transaction may need. without it the tail
+                    // isn't discarded
+                    (*log).freed[i] = head;
+                    let promised = (*log).outstanding as usize * MAX_OP_FREE as usize;
+                    if (*log).nfreed + promised < NFREED {
+                        (*log).freed[(*log).nfreed] = tail;
+                        (*log).nfreed += 1;
+                    }
+                }
+            }
+            i += 1;
+        }
+    }
+}
+
+// record the modification of b in the log, used instead of bwrite().
+// the buffer is pinned in the cache until the commit
+pub fn log_write(b: *mut Buf) {
+    let log = unsafe { log_of((*b).dev) };
+    unsafe {
+        if (*log).outstanding < 1 {
+            panicc!("log_write: outside of trans");
+        }
+        if (*log).size == 0 {
+            // no log region, in place
+            bwrite(b);
+            return;
+        }
+        if (*log).lh.n as usize >= LOG_SIZE || (*log).lh.n >= (*log).size - 1 {
+            panicc!("log_write: too big a transaction");
+        }
+
+        let mut i = 0;
+        while i < (*log).lh.n {
+            // log absorption
+            if (*log).lh.block[i as usize] == (*b).block_no {
+                break;
+            }
+            i += 1;
+        }
+
+        (*log).lh.block[i as usize] = (*b).block_no;
+        if i == (*log).lh.n {
+            // add new block to log
+            bpin(b);
+            (*log).lh.n += 1;
+        }
+    }
+}
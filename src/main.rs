@@ -11,7 +11,7 @@ use core::fmt::Write;
 #[macro_export]
 macro_rules! print {
     ($($args:tt)+) => {
-        let _ = write!(crate::uart::Uart::new(0x1000_0000),$($args)+);
+        let _ = write!(crate::console::Log,$($args)+);
     };
 }
 
@@ -61,34 +61,22 @@ extern "C" fn kinit() {
     plic::plic_init(); // set irq priority
     plic::plic_init_hart(); // enable intr and set hart's priority
     virtio_disk::virtio_disk_init(); // intialize the device
+    virtio_rng::virtio_rng_init(); // the entropy device, if any
+    virtio_console::virtio_console_init(); // more ttys, if any
+    virtio_net::virtio_net_init(); // the network device, if any
+    net::net_init(); // the stack on top of it
     block_cache::binit(); // set the linked list of buffers
 
     riscv::wsstatus(riscv::SSTATUS_SIE);
 
-    fs::fs_init(param::ROOT_DEV); // main() not call it in xv6, since need sleep
-
-    let inode = fs::iget(1, 1);
-    let mut b = block_cache::Buf::new();
-    fs::readi(inode, 0, &mut b.data as *mut u8 as u64, 0, 40);
-
-    println!("read ok");
-    for i in 0..40 {
-        print!("{}", b.data[i] as char);
-    }
-    println!("");
+    random::random_init(); // needs interrupts for virtio-rng
 
-    b.data[0]='h' as u8;
-    b.data[1]='i' as u8;
-    b.data[2]=',' as u8;
-    fs::writei(inode,0,&mut b.data as *mut u8 as u64,0,40);
-
-    println!("write ok");
+    fs::fs_init(param::ROOT_DEV); // main() not call it in xv6, since need sleep
 
-    fs::readi(inode, 0, &mut b.data as *mut u8 as u64, 0, 40);
-    for i in 0..40 {
-        print!("{}", b.data[i] as char);
-    }
-    println!(""); 
+    #[cfg(feature = "selftest")]
+    selftest::selftest(); // never returns
+    #[cfg(feature = "crashtest")]
+    selftest::crashtest(); // never returns
 
     proc::user_init(); // set first proc
 
@@ -98,18 +86,45 @@ extern "C" fn kinit() {
 }
 
 mod assembly;
+mod arp;
 mod block_cache;
+mod console;
 mod cpu;
+mod devfs;
+mod errno;
+mod file;
 mod fs;
 mod kalloc;
+mod log;
 mod mem_layout;
+mod minix;
+mod net;
 mod param;
+mod pipe;
 mod plic;
 mod proc;
+mod procfs;
+mod random;
+mod ring;
 mod riscv;
+#[cfg(any(feature = "selftest", feature = "crashtest"))]
+mod selftest;
+mod socket;
+mod stat;
 mod string;
+mod syscall;
+mod sysfile;
+mod sysproc;
+mod tcp;
 mod timer;
+mod tmpfs;
 mod trap;
 mod uart;
+mod udp;
+mod unix;
+mod virtio;
+mod virtio_console;
 mod virtio_disk;
+mod virtio_net;
+mod virtio_rng;
 mod vm;
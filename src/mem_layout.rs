@@ -3,9 +3,19 @@ use crate::riscv::{MAX_VA, PAGE_SIZE};
 pub const UART: u64 = 0x1000_0000;
 pub const UART_IRQ: u64 = 10;
 
-// only block device
-pub const VIRTIO: u64 = 0x1000_1000;
-pub const VIRTIO_IRQ: u64 = 1;
+// virtio mmio slots, qemu virt has eight of them,
+// slot n is at VIRTIO0 + n * 0x1000 and interrupts with irq 1 + n
+pub const VIRTIO0: u64 = 0x1000_1000;
+pub const VIRTIO0_IRQ: u64 = 1;
+pub const VIRTIO_NSLOT: u64 = 8;
+
+pub const fn virtio_mmio(slot: u64) -> u64 {
+    VIRTIO0 + slot * 0x1000
+}
+
+pub const fn virtio_irq(slot: u64) -> u64 {
+    VIRTIO0_IRQ + slot
+}
 
 // core local interruptor (CLINT), which contains the timer.
 pub const CLINT: u64 = 0x0200_0000;
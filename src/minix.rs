@@ -0,0 +1,1656 @@
+use crate::block_cache::{bget_valid, bread, bread_ahead, brelse, bwrite, bzeroed, Buf};
+use crate::fs::{DirEnt, DirIter, FileSystem, Inode, BLOCK_SIZE, NAME_MAX};
+use crate::log::{
+    begin_op, end_op, log_discard, log_free, log_freed, log_init, log_reuse, log_start, log_sync,
+    log_write, LOG_BLOCKS,
+};
+use crate::param::{MAX_OP_BLOCK, MAX_OP_FREE, NINODE, NMINIX, NSEG};
+use crate::proc::{either_copy_in, either_copy_out};
+use crate::stat::{mode_to_dtype, Stat};
+use crate::string::{mem_copy, mem_set, str_cmp};
+use crate::virtio_disk::{virtio_disk_flush, virtio_disk_write_zeroes};
+use core::cmp::min;
+use core::fmt::Write;
+use core::mem::size_of;
+use core::ptr::null_mut;
+
+// some troublesome things need to be handled...
+const ROOT_INO: u32 = 1;
+const MAGIC: u16 = 0x4d5a;
+const MAGIC_V1: u16 = 0x137f; // 14 char names
+const MAGIC_V1_30: u16 = 0x138f; // 30 char names
+const MAGIC_V2: u16 = 0x2468; // 14 char names
+const MAGIC_V2_30: u16 = 0x2478; // 30 char names
+
+// v3 superblock, also the in-memory form for all versions
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct SuperBlock {
+    ninode: u32,
+    pad0: u16, // unused
+    imap_blk_num: u16,
+    zmap_blk_num: u16,
+    first_data_zone: u16,
+    log2_bz: u16, // log2(block/zone)
+    pad1: u16,
+    max_fsize: u32,
+    nzone: u32,
+    magic: u16,
+    pad2: u16,
+    block_size: u16,
+    fsv: u8, // FS sub-version
+}
+
+impl SuperBlock {
+    const fn new() -> Self {
+        SuperBlock {
+            ninode: 0,
+            pad0: 0,
+            imap_blk_num: 0,
+            zmap_blk_num: 0,
+            first_data_zone: 0,
+            log2_bz: 0,
+            pad1: 0,
+            max_fsize: 0,
+            nzone: 0,
+            magic: 0,
+            pad2: 0,
+            block_size: 0,
+            fsv: 0,
+        }
+    }
+}
+
+// v1 and v2 superblock
+#[repr(C)]
+struct SuperBlockV12 {
+    ninode: u16,
+    nzone_v1: u16,
+    imap_blk_num: u16,
+    zmap_blk_num: u16,
+    first_data_zone: u16,
+    log2_bz: u16,
+    max_fsize: u32,
+    magic: u16,
+    state: u16,
+    nzone_v2: u32,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum FsVersion {
+    V1,
+    V2,
+    V3,
+}
+
+// sizes of the on-disk structures, which differ between versions
+#[derive(Copy, Clone)]
+struct Layout {
+    version: FsVersion,
+    inode_size: u32,
+    zone_ptr_size: u32, // zone number size in inodes and indirect blocks
+    dirent_size: u32,
+    name_len: u32,
+}
+
+const fn layout(version: FsVersion, name_len: u32) -> Layout {
+    match version {
+        FsVersion::V1 => Layout {
+            version,
+            inode_size: size_of::<InodeDiskV1>() as u32,
+            zone_ptr_size: size_of::<u16>() as u32,
+            dirent_size: size_of::<u16>() as u32 + name_len,
+            name_len,
+        },
+        FsVersion::V2 => Layout {
+            version,
+            inode_size: size_of::<InodeDisk>() as u32,
+            zone_ptr_size: size_of::<u32>() as u32,
+            dirent_size: size_of::<u16>() as u32 + name_len,
+            name_len,
+        },
+        FsVersion::V3 => Layout {
+            version,
+            inode_size: size_of::<InodeDisk>() as u32,
+            zone_ptr_size: size_of::<u32>() as u32,
+            dirent_size: size_of::<DirEntry>() as u32,
+            name_len: FNAME_SIZE as u32,
+        },
+    }
+}
+
+// detect the version and fill the superblock and layout of fs,
+// return false if b holds no minix superblock
+fn read_super(fs: *mut MinixFs, b: *mut Buf) -> bool {
+    unsafe {
+        let sbp = &mut (*fs).sb as *mut SuperBlock;
+        mem_copy(
+            sbp as u64 as *mut u64,
+            &mut (*b).data as *mut u8 as *mut u64,
+            size_of::<SuperBlock>() as u64,
+        );
+        if (*sbp).magic == MAGIC {
+            (*fs).layout = layout(FsVersion::V3, FNAME_SIZE as u32);
+            return true;
+        }
+
+        let sb = &(*b).data as *const u8 as *const SuperBlockV12;
+        let (version, name_len) = match (*sb).magic {
+            MAGIC_V1 => (FsVersion::V1, 14),
+            MAGIC_V1_30 => (FsVersion::V1, 30),
+            MAGIC_V2 => (FsVersion::V2, 14),
+            MAGIC_V2_30 => (FsVersion::V2, 30),
+            _ => return false,
+        };
+        (*fs).layout = layout(version, name_len);
+
+        (*sbp).ninode = (*sb).ninode as u32;
+        (*sbp).imap_blk_num = (*sb).imap_blk_num;
+        (*sbp).zmap_blk_num = (*sb).zmap_blk_num;
+        (*sbp).first_data_zone = (*sb).first_data_zone;
+        (*sbp).log2_bz = (*sb).log2_bz;
+        (*sbp).max_fsize = (*sb).max_fsize;
+        (*sbp).nzone = match version {
+            FsVersion::V1 => (*sb).nzone_v1 as u32,
+            _ => (*sb).nzone_v2,
+        };
+        (*sbp).magic = (*sb).magic;
+        (*sbp).block_size = BLOCK_SIZE as u16; // fixed before v3
+        (*sbp).fsv = 0;
+    }
+
+    true
+}
+
+// a mounted minix file system
+#[derive(Copy, Clone)]
+pub struct MinixFs {
+    dev: u32, // 0 if the slot is free
+    sb: SuperBlock,
+    layout: Layout,
+    journal: u32, // inode of the log file, 0 if there is none
+}
+
+impl MinixFs {
+    const fn new() -> Self {
+        MinixFs {
+            dev: 0,
+            sb: SuperBlock::new(),
+            layout: layout(FsVersion::V3, FNAME_SIZE as u32),
+            journal: 0,
+        }
+    }
+}
+
+static mut MINIX_FS: [MinixFs; NMINIX] = [MinixFs::new(); NMINIX];
+
+// the mounted file system on dev
+fn minix(dev: u32) -> *mut MinixFs {
+    unsafe {
+        for i in 0..NMINIX {
+            if MINIX_FS[i].dev == dev && dev != 0 {
+                return &mut MINIX_FS[i] as *mut MinixFs;
+            }
+        }
+    }
+
+    panicc!("minix: dev not mounted");
+}
+
+// the superblock of dev
+fn sb(dev: u32) -> &'static SuperBlock {
+    unsafe { &(*minix(dev)).sb }
+}
+
+// the layout of dev
+fn fs_layout(dev: u32) -> &'static Layout {
+    unsafe { &(*minix(dev)).layout }
+}
+
+impl FileSystem for MinixFs {
+    fn name(&self) -> &'static str {
+        "minix"
+    }
+
+    fn dev(&self) -> u32 {
+        self.dev
+    }
+
+    fn root(&mut self) -> *mut dyn Inode {
+        iget(self.dev, ROOT_INO)
+    }
+
+    // busy while any of its inodes is in use
+    fn unmount(&mut self) -> i32 {
+        unsafe {
+            for i in 0..NINODE {
+                if ICACHE.inode[i].ref_cnt > 0 && ICACHE.inode[i].dev == self.dev {
+                    return -1;
+                }
+            }
+        }
+
+        // every transaction has been committed by its end_op()
+        log_free(self.dev);
+        *self = MinixFs::new();
+        0
+    }
+
+    fn sync(&mut self) -> i32 {
+        log_sync(self.dev)
+    }
+}
+
+// read the file system on dev and recover it from the log,
+// return None if it isn't a usable minix file system or is mounted
+pub fn minix_mount(dev: u32) -> Option<*mut dyn FileSystem> {
+    let mut fs: *mut MinixFs = null_mut();
+    unsafe {
+        for i in 0..NMINIX {
+            if MINIX_FS[i].dev == dev {
+                println!("minix: dev {} already mounted", dev);
+                return None;
+            }
+            if fs.is_null() && MINIX_FS[i].dev == 0 {
+                fs = &mut MINIX_FS[i] as *mut MinixFs;
+            }
+        }
+    }
+    if fs.is_null() {
+        return None;
+    }
+
+    // the superblock is loaded from the second 1 KB of the disk device
+    let b = bread(dev, 1);
+    let ok = read_super(fs, b);
+    brelse(b);
+    if !ok {
+        println!("minix: dev {} magic number invalid", dev);
+        return None;
+    }
+
+    let sb = unsafe { &(*fs).sb };
+    // a zone is 2^log2_bz blocks, keep it within a bitmap block
+    if sb.log2_bz > 8 || sb.block_size != BLOCK_SIZE as u16 {
+        println!(
+            "minix: dev {} log2_bz {} block_size {}",
+            dev, sb.log2_bz, sb.block_size
+        );
+        return None;
+    }
+
+    unsafe {
+        (*fs).dev = dev;
+        if !log_init(dev) {
+            *fs = MinixFs::new();
+            return None;
+        }
+    }
+    journal_open(fs);
+    Some(fs)
+}
+
+// the log file in the root directory
+const JOURNAL: &str = ".journal\0";
+
+// find the log file of fs, or make it, and start the log in its
+// blocks. the file is made before there is a log, with updates
+// written in place, so a crash meanwhile may leave the file short,
+// to be completed by the next mount. without room for it, the file
+// system is used without a log
+fn journal_open(fs: *mut MinixFs) {
+    let dev = unsafe { (*fs).dev };
+    let root = iget(dev, ROOT_INO);
+    let mut inode = dir_lookup(root, JOURNAL.as_ptr(), null_mut());
+    if inode.is_null() && has_free_zones(dev, journal_zones(dev)) {
+        begin_op(dev);
+        inode = ialloc(dev, REGULAR | 0o600);
+        if !inode.is_null() {
+            unsafe {
+                (*inode).nlink = 1;
+                if !dir_link(root, JOURNAL.as_ptr(), (*inode).ino) {
+                    (*inode).nlink = 0;
+                }
+            }
+            iupdate(inode);
+        }
+        end_op(dev);
+    }
+    iput(root);
+
+    let size = LOG_BLOCKS * BLOCK_SIZE;
+    let ok = !inode.is_null()
+        && unsafe { (*inode).nlink > 0 && is_reg((*inode).mode) }
+        && match unsafe { (*inode).fsize } {
+            n if n == size => true,
+            n if n < size => journal_fill(inode),
+            _ => false,
+        };
+    if !ok {
+        println!(
+            "minix: dev {} has no room for a log, crashes may corrupt it",
+            dev
+        );
+        if !inode.is_null() {
+            iput(inode);
+        }
+        return;
+    }
+
+    // after the lookup above, which doesn't depend on what the log
+    // holds, as the log file never changes once complete
+    let mut map = [0; LOG_BLOCKS as usize];
+    for i in 0..LOG_BLOCKS as usize {
+        map[i] = bmap(inode, i, 0);
+    }
+    unsafe {
+        (*fs).journal = (*inode).ino;
+    }
+    iput(inode);
+    log_start(dev, &map);
+}
+
+// is inode the log file, which only the log may write
+fn is_journal(inode: *const InodeMem) -> bool {
+    unsafe { (*minix((*inode).dev)).journal == (*inode).ino }
+}
+
+// zones of a log file, counting its indirect zones
+fn journal_zones(dev: u32) -> u32 {
+    let data = (LOG_BLOCKS + zone_blocks(dev) - 1) / zone_blocks(dev);
+    match data as usize > NDIRECT {
+        true => data + 1,
+        false => data,
+    }
+}
+
+// give the log file inode all its blocks, return false without room
+fn journal_fill(inode: *mut InodeMem) -> bool {
+    let dev = unsafe { (*inode).dev };
+    let have = unsafe { ((*inode).fsize + BLOCK_SIZE - 1) / BLOCK_SIZE };
+    if !has_free_zones(dev, journal_zones(dev) - have / zone_blocks(dev)) {
+        return false;
+    }
+
+    begin_op(dev);
+    for i in 0..LOG_BLOCKS as usize {
+        bmap(inode, i, 1); // zeroed, so the header is no commit
+    }
+    unsafe {
+        (*inode).fsize = LOG_BLOCKS * BLOCK_SIZE;
+    }
+    iupdate(inode);
+    end_op(dev);
+
+    // on disk before anything is committed to it
+    virtio_disk_flush(dev) == 0
+}
+
+// the first block of a zone
+fn zone_to_block(dev: u32, zone_no: u32) -> u32 {
+    zone_no << sb(dev).log2_bz
+}
+
+// blocks per zone
+fn zone_blocks(dev: u32) -> u32 {
+    1 << sb(dev).log2_bz
+}
+
+// zero the blocks of a zone.
+// a one block zone is zeroed through the log like any other update,
+// a bigger one may not fit in a transaction, so it is written in place,
+// by the disk itself if it can write zeroes.
+// that is safe as balloc() doesn't hand out a zone that the current
+// transaction has freed, so nothing committed refers to the zone
+fn zzero(dev: u32, zone_no: u32) {
+    let start = zone_to_block(dev, zone_no);
+    let n = zone_blocks(dev);
+    if n > 1 && virtio_disk_write_zeroes(dev, start, n) == 0 {
+        bzeroed(dev, start, n);
+        return;
+    }
+
+    for i in 0..n {
+        let b = bget_valid(dev, start + i);
+        unsafe {
+            mem_set(&mut (*b).data as *mut u8 as *mut u64, 0, BLOCK_SIZE as u64);
+        }
+        match n {
+            1 => log_write(b),
+            _ => bwrite(b),
+        }
+        brelse(b);
+    }
+}
+
+const BPERB: u32 = 8 * BLOCK_SIZE; // bits per block
+
+// alloc a zeroed zone, return the zone number.
+// bit 0 of the zone bitmap is reserved, bit n is for zone first_data_zone-1+n
+fn balloc(dev: u32) -> u32 {
+    let sb = sb(dev);
+    unsafe {
+        let zmap_start = 2 + sb.imap_blk_num as u32;
+        for i in 0..sb.zmap_blk_num as usize {
+            let b = bread(dev, zmap_start + i as u32);
+
+            for j in 0..BPERB as usize {
+                let zone_no = i as u32 * BPERB + j as u32 + sb.first_data_zone as u32 - 1;
+                if zone_no < sb.first_data_zone as u32 {
+                    continue;
+                }
+                if zone_no >= sb.nzone {
+                    break;
+                }
+
+                let bits = 1 << (j % 8);
+                if (*b).data[j / 8] & bits == 0
+                    && (zone_blocks(dev) == 1
+                        || !log_freed(dev, zone_to_block(dev, zone_no), zone_blocks(dev)))
+                {
+                    (*b).data[j / 8] |= bits;
+                    log_write(b);
+                    brelse(b);
+                    log_reuse(dev, zone_to_block(dev, zone_no), zone_blocks(dev));
+                    zzero(dev, zone_no);
+                    return zone_no;
+                }
+            }
+
+            brelse(b);
+        }
+    }
+
+    panicc!("balloc: no free zone");
+}
+
+// are there at least n free zones
+fn has_free_zones(dev: u32, n: u32) -> bool {
+    let sb = sb(dev);
+    let mut nfree = 0;
+    for i in 0..sb.zmap_blk_num as u32 {
+        let b = bread(dev, 2 + sb.imap_blk_num as u32 + i);
+        for j in 0..BPERB {
+            let zone_no = i * BPERB + j + sb.first_data_zone as u32 - 1;
+            if zone_no < sb.first_data_zone as u32 {
+                continue;
+            }
+            if zone_no >= sb.nzone {
+                break;
+            }
+            if unsafe { (*b).data[(j / 8) as usize] } & 1 << (j % 8) == 0 {
+                nfree += 1;
+            }
+        }
+        brelse(b);
+        if nfree >= n {
+            return true;
+        }
+    }
+
+    false
+}
+
+// the zone bitmap block with the bit of zone_no
+fn zmap_block(dev: u32, zone_no: u32) -> u32 {
+    let sb = sb(dev);
+    2 + sb.imap_blk_num as u32 + (zone_no - sb.first_data_zone as u32 + 1) / BPERB
+}
+
+// free a zone
+fn bfree(dev: u32, zone_no: u32) {
+    let sb = sb(dev);
+    unsafe {
+        if zone_no < sb.first_data_zone as u32 || zone_no >= sb.nzone {
+            panicc!("bfree: zone out of range");
+        }
+
+        let bit = zone_no - sb.first_data_zone as u32 + 1;
+        let b = bread(dev, zmap_block(dev, zone_no));
+        let byte_no = (bit % BPERB / 8) as usize;
+        let bit_no = bit % BPERB % 8;
+        if (*b).data[byte_no] & 1 << bit_no == 0 {
+            panicc!("bfree: zone is free");
+        }
+        (*b).data[byte_no] &= !(1 << bit_no);
+        log_write(b);
+        brelse(b);
+    }
+
+    log_discard(dev, zone_to_block(dev, zone_no), zone_blocks(dev));
+}
+
+// from https://github.com/Stichting-MINIX-Research-Foundation/minix
+const TYPE: u16 = 0o170000; // this field gives inode type
+const UNIX_SOCKET: u16 = 0o140000; // unix domain socket
+const SYMBOLIC_LINK: u16 = 0o120000;
+const REGULAR: u16 = 0o100000; // regular file, not dir or special
+const DIRECTORY: u16 = 0o040000;
+const NAMED_PIPE: u16 = 0o010000; // named pipe (FIFO)
+const NOT_ALLOC: u16 = 0o000000; // this node is free
+
+const fn is_reg(m: u16) -> bool {
+    m & TYPE == REGULAR
+}
+
+const fn is_dir(m: u16) -> bool {
+    m & TYPE == DIRECTORY
+}
+
+const fn is_symlink(m: u16) -> bool {
+    m & TYPE == SYMBOLIC_LINK
+}
+
+const fn not_alloc(m: u16) -> bool {
+    m & TYPE == NOT_ALLOC
+}
+
+const fn is_fifo(m: u16) -> bool {
+    m & TYPE == NAMED_PIPE
+}
+
+const fn is_socket(m: u16) -> bool {
+    m & TYPE == UNIX_SOCKET
+}
+
+// only the first block of an indirect zone is used, as minix does
+const NDIRECT: usize = 7; // direct zone num in an inode
+
+// indirect zone num
+fn nindirect(dev: u32) -> usize {
+    (BLOCK_SIZE / fs_layout(dev).zone_ptr_size) as usize
+}
+
+// levels of indirect zones in an inode,
+// v1 has no triple indirect zone
+fn nlevel(dev: u32) -> usize {
+    match fs_layout(dev).version {
+        FsVersion::V1 => 2,
+        _ => 3,
+    }
+}
+
+// inodes per block
+fn iperb(dev: u32) -> u32 {
+    BLOCK_SIZE / fs_layout(dev).inode_size
+}
+
+// the first inode block
+fn istart(dev: u32) -> u32 {
+    2 + (sb(dev).imap_blk_num + sb(dev).zmap_blk_num) as u32
+}
+
+// block number for inode, inode numbers start from 1
+fn iblock(dev: u32, ino: u32) -> u32 {
+    (ino - 1) / iperb(dev) + istart(dev)
+}
+
+// the nth zone number in an indirect block
+fn ind_get(b: *const Buf, n: usize) -> u32 {
+    unsafe {
+        match fs_layout((*b).dev).zone_ptr_size {
+            2 => *(&(*b).data as *const u8 as *const u16).add(n) as u32,
+            _ => *(&(*b).data as *const u8 as *const u32).add(n),
+        }
+    }
+}
+
+fn ind_set(b: *mut Buf, n: usize, zone_no: u32) {
+    unsafe {
+        match fs_layout((*b).dev).zone_ptr_size {
+            2 => *(&mut (*b).data as *mut u8 as *mut u16).add(n) = zone_no as u16,
+            _ => *(&mut (*b).data as *mut u8 as *mut u32).add(n) = zone_no,
+        }
+    }
+}
+
+// v1 inode
+#[repr(C)]
+struct InodeDiskV1 {
+    mode: u16,
+    uid: u16,
+    fsize: u32,
+    time: u32,
+    gid: u8,
+    nlink: u8,
+
+    // 0-6 first seven data zones
+    // 7 indirect zone
+    // 8 double indirect zone
+    zone: [u16; NDIRECT + 2],
+}
+
+// v2 and v3 inode
+#[repr(C)]
+struct InodeDisk {
+    mode: u16, // file type and rwx bits
+    nlink: u16,
+    uid: u16, // identifies user who owns file
+    gid: u16, // owner's group
+    fsize: u32,
+    atime: u32, // access time
+    mtime: u32, // modification time
+    ctime: u32, // status change time
+
+    // 0-6 first seven data zones
+    // 7 indirect zone
+    // 8 double indirect zone
+    // 9 triple indirect zone
+    zone: [u32; NDIRECT + 3],
+}
+
+// reprC?
+#[derive(Copy, Clone)]
+pub struct InodeMem {
+    dev: u32,
+    ino: u32,
+    ref_cnt: u32,
+    valid: u32,
+
+    mode: u16,
+    nlink: u16,
+    uid: u16,
+    gid: u16,
+    fsize: u32,
+    atime: u32,
+    mtime: u32,
+    ctime: u32,
+    zone: [u32; NDIRECT + 3],
+}
+
+impl InodeMem {
+    pub const fn new() -> Self {
+        InodeMem {
+            dev: 0,
+            ino: 0,
+            ref_cnt: 0,
+            valid: 0,
+
+            mode: 0,
+            nlink: 0,
+            uid: 0,
+            gid: 0,
+            fsize: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+            zone: [0; NDIRECT + 3],
+        }
+    }
+}
+
+struct Icache {
+    inode: [InodeMem; NINODE],
+}
+
+static mut ICACHE: Icache = Icache {
+    inode: [InodeMem::new(); NINODE],
+};
+
+// copy the on-disk inode in b to inode
+fn dinode_read(b: *const Buf, inode: *mut InodeMem) {
+    unsafe {
+        let idx = (((*inode).ino - 1) % iperb((*inode).dev)) as usize;
+        match fs_layout((*inode).dev).version {
+            FsVersion::V1 => {
+                let dinode = (&(*b).data as *const u8 as *const InodeDiskV1).add(idx);
+                (*inode).mode = (*dinode).mode;
+                (*inode).nlink = (*dinode).nlink as u16;
+                (*inode).uid = (*dinode).uid;
+                (*inode).gid = (*dinode).gid as u16;
+                (*inode).fsize = (*dinode).fsize;
+                (*inode).atime = (*dinode).time;
+                (*inode).mtime = (*dinode).time;
+                (*inode).ctime = (*dinode).time;
+                for i in 0..NDIRECT + 3 {
+                    (*inode).zone[i] = match i < NDIRECT + 2 {
+                        true => (*dinode).zone[i] as u32,
+                        false => 0,
+                    };
+                }
+            }
+            _ => {
+                let dinode = (&(*b).data as *const u8 as *const InodeDisk).add(idx);
+                (*inode).mode = (*dinode).mode;
+                (*inode).nlink = (*dinode).nlink;
+                (*inode).uid = (*dinode).uid;
+                (*inode).gid = (*dinode).gid;
+                (*inode).fsize = (*dinode).fsize;
+                (*inode).atime = (*dinode).atime;
+                (*inode).mtime = (*dinode).mtime;
+                (*inode).ctime = (*dinode).ctime;
+                mem_copy(
+                    &mut (*inode).zone as *mut u32 as *mut u64,
+                    &(*dinode).zone as *const u32 as *const u64,
+                    (size_of::<u32>() * (NDIRECT + 3)) as u64,
+                );
+            }
+        }
+    }
+}
+
+// copy inode to its on-disk slot in b
+fn dinode_write(b: *mut Buf, inode: *const InodeMem) {
+    unsafe {
+        let idx = (((*inode).ino - 1) % iperb((*inode).dev)) as usize;
+        match fs_layout((*inode).dev).version {
+            FsVersion::V1 => {
+                let dinode = (&mut (*b).data as *mut u8 as *mut InodeDiskV1).add(idx);
+                (*dinode).mode = (*inode).mode;
+                (*dinode).nlink = (*inode).nlink as u8;
+                (*dinode).uid = (*inode).uid;
+                (*dinode).gid = (*inode).gid as u8;
+                (*dinode).fsize = (*inode).fsize;
+                (*dinode).time = (*inode).mtime;
+                for i in 0..NDIRECT + 2 {
+                    (*dinode).zone[i] = (*inode).zone[i] as u16;
+                }
+            }
+            _ => {
+                let dinode = (&mut (*b).data as *mut u8 as *mut InodeDisk).add(idx);
+                (*dinode).mode = (*inode).mode;
+                (*dinode).nlink = (*inode).nlink;
+                (*dinode).uid = (*inode).uid;
+                (*dinode).gid = (*inode).gid;
+                (*dinode).fsize = (*inode).fsize;
+                (*dinode).atime = (*inode).atime;
+                (*dinode).mtime = (*inode).mtime;
+                (*dinode).ctime = (*inode).ctime;
+                mem_copy(
+                    &mut (*dinode).zone as *mut u32 as *mut u64,
+                    &(*inode).zone as *const u32 as *const u64,
+                    (size_of::<u32>() * (NDIRECT + 3)) as u64,
+                );
+            }
+        }
+    }
+}
+
+// write inode to disk
+pub fn iupdate(inode: *const InodeMem) {
+    unsafe {
+        let b = bread((*inode).dev, iblock((*inode).dev, (*inode).ino));
+        dinode_write(b, inode);
+        log_write(b);
+        brelse(b);
+    }
+}
+
+// alloc an inode of the type in mode on dev, must be called inside a transaction.
+// bit 0 of the inode bitmap is reserved, bit n is for inode n.
+// return it with a reference held, or null if there is no free inode
+fn ialloc(dev: u32, mode: u16) -> *mut InodeMem {
+    let sb = sb(dev);
+    unsafe {
+        for i in 0..sb.imap_blk_num as u32 {
+            let b = bread(dev, 2 + i);
+
+            for j in 0..BPERB {
+                let ino = i * BPERB + j;
+                if ino == 0 {
+                    continue;
+                }
+                if ino > sb.ninode {
+                    break;
+                }
+
+                let bits = 1 << (j % 8);
+                if (*b).data[(j / 8) as usize] & bits == 0 {
+                    (*b).data[(j / 8) as usize] |= bits;
+                    log_write(b);
+                    brelse(b);
+
+                    // clear what the last owner left on disk
+                    let mut inode = InodeMem::new();
+                    inode.dev = dev;
+                    inode.ino = ino;
+                    inode.mode = mode;
+                    let ib = bread(dev, iblock(dev, ino));
+                    dinode_write(ib, &inode);
+                    log_write(ib);
+                    brelse(ib);
+
+                    return iget(dev, ino);
+                }
+            }
+
+            brelse(b);
+        }
+    }
+
+    null_mut()
+}
+
+// free an inode in the bitmap
+fn ifree(dev: u32, ino: u32) {
+    unsafe {
+        if ino == 0 || ino > sb(dev).ninode {
+            panicc!("ifree: inode out of range");
+        }
+
+        let b = bread(dev, 2 + ino / BPERB);
+        let byte_no = (ino % BPERB / 8) as usize;
+        let bit_no = ino % BPERB % 8;
+        if (*b).data[byte_no] & 1 << bit_no == 0 {
+            panicc!("ifree: inode is free");
+        }
+        (*b).data[byte_no] &= !(1 << bit_no);
+        log_write(b);
+        brelse(b);
+    }
+}
+
+// read from disk if necessary
+pub fn iget(dev: u32, ino: u32) -> *mut InodeMem {
+    let mut empty_idx = NINODE;
+    unsafe {
+        for i in 0..NINODE {
+            if ICACHE.inode[i].ref_cnt != 0
+                && ICACHE.inode[i].dev == dev
+                && ICACHE.inode[i].ino == ino
+            {
+                ICACHE.inode[i].ref_cnt += 1;
+                return &mut ICACHE.inode[i] as *mut InodeMem;
+            }
+
+            if empty_idx == NINODE && ICACHE.inode[i].ref_cnt == 0 {
+                empty_idx = i;
+            }
+        }
+
+        if empty_idx == NINODE {
+            panicc!("iget: no free inodes");
+        }
+
+        ICACHE.inode[empty_idx].dev = dev;
+        ICACHE.inode[empty_idx].ino = ino;
+        ICACHE.inode[empty_idx].ref_cnt = 1;
+
+        let b = bread(dev, iblock(dev, ino));
+
+        dinode_read(b, &mut ICACHE.inode[empty_idx]);
+        brelse(b);
+
+        ICACHE.inode[empty_idx].valid = 1;
+        if not_alloc(ICACHE.inode[empty_idx].mode) {
+            panicc!("iget: inode not alloc");
+        }
+
+        &mut ICACHE.inode[empty_idx] as *mut InodeMem
+    }
+}
+
+// increment the ref count, return inode to enable inode = idup(inode1)
+fn idup(inode: *mut InodeMem) -> *mut InodeMem {
+    unsafe {
+        (*inode).ref_cnt += 1;
+    }
+    inode
+}
+
+// drop a reference to an in-memory inode.
+// the last reference to an inode without links frees it on disk,
+// so iput() must not be called inside a transaction
+pub fn iput(inode: *mut InodeMem) {
+    unsafe {
+        if (*inode).ref_cnt == 0 {
+            panicc!("iput: ref_cnt");
+        }
+
+        if (*inode).ref_cnt == 1 && (*inode).valid != 0 && (*inode).nlink == 0 {
+            begin_op((*inode).dev);
+            itrunc(inode);
+            (*inode).mode = NOT_ALLOC;
+            iupdate(inode);
+            ifree((*inode).dev, (*inode).ino);
+            end_op((*inode).dev);
+            (*inode).valid = 0;
+        }
+
+        (*inode).ref_cnt -= 1;
+    }
+}
+
+// copy stat information from inode
+fn stati(inode: *const InodeMem, st: *mut Stat) {
+    unsafe {
+        (*st).dev = (*inode).dev;
+        (*st).ino = (*inode).ino;
+        (*st).mode = (*inode).mode;
+        (*st).nlink = (*inode).nlink;
+        (*st).uid = (*inode).uid;
+        (*st).gid = (*inode).gid;
+        (*st).size = (*inode).fsize as u64;
+        (*st).atime = (*inode).atime;
+        (*st).mtime = (*inode).mtime;
+        (*st).ctime = (*inode).ctime;
+        (*st).rdev = 0;
+    }
+}
+
+// get the zone number at index zn under the indirect zone in slot,
+// which is depth levels above the data zones
+// return 0 if not exist and alloc==0
+fn zmap_indirect(inode: *mut InodeMem, slot: usize, depth: u32, mut zn: usize, alloc: u32) -> u32 {
+    unsafe {
+        let mut addr = (*inode).zone[slot];
+        if addr == 0 {
+            if alloc == 0 {
+                return 0;
+            }
+
+            addr = balloc((*inode).dev);
+            (*inode).zone[slot] = addr;
+        }
+
+        // zones covered by an entry at this level
+        let dev = (*inode).dev;
+        let mut span = nindirect(dev).pow(depth - 1);
+        for _ in 0..depth {
+            let b = bread(dev, zone_to_block(dev, addr));
+            let idx = zn / span;
+            zn %= span;
+
+            let mut next = ind_get(b, idx);
+            if next == 0 {
+                if alloc == 0 {
+                    brelse(b);
+                    return 0;
+                }
+
+                next = balloc(dev);
+                ind_set(b, idx, next);
+                log_write(b);
+            }
+            brelse(b);
+
+            addr = next;
+            span /= nindirect(dev);
+        }
+
+        addr
+    }
+}
+
+// get the zone number of the nth zone in inode
+// return 0 if not exist and alloc==0
+fn zmap(inode: *mut InodeMem, mut zn: usize, alloc: u32) -> u32 {
+    unsafe {
+        if zn < NDIRECT {
+            let mut addr = (*inode).zone[zn as usize];
+            if addr == 0 && alloc != 0 {
+                addr = balloc((*inode).dev);
+                (*inode).zone[zn as usize] = addr;
+            }
+            return addr;
+        }
+    }
+
+    // indirect, double indirect and triple indirect zone
+    let dev = unsafe { (*inode).dev };
+    zn -= NDIRECT;
+    for level in 0..nlevel(dev) {
+        let nzone = nindirect(dev).pow(level as u32 + 1);
+        if zn < nzone {
+            return zmap_indirect(inode, NDIRECT + level, level as u32 + 1, zn, alloc);
+        }
+        zn -= nzone;
+    }
+
+    panicc!("zmap: zn out of range");
+}
+
+// get the block number of the nth block in inode
+// return 0 if not exist and alloc==0
+fn bmap(inode: *mut InodeMem, bn: usize, alloc: u32) -> u32 {
+    let dev = unsafe { (*inode).dev };
+    let scale = sb(dev).log2_bz;
+    let zone_no = zmap(inode, bn >> scale, alloc);
+    if zone_no == 0 {
+        return 0;
+    }
+
+    zone_to_block(dev, zone_no) + (bn & ((1 << scale) - 1)) as u32
+}
+
+// the blocks an itrunc() transaction has logged, which stops
+// before there are more than MAX_OP_BLOCK-1 of them, leaving
+// one for iput() to free the inode in the bitmap, and the zones
+// it has freed, at most MAX_OP_FREE
+struct Chunk {
+    block: [u32; MAX_OP_BLOCK as usize - 1],
+    n: usize,
+    nfree: u32,
+}
+
+impl Chunk {
+    fn new() -> Self {
+        Chunk {
+            block: [0; MAX_OP_BLOCK as usize - 1],
+            n: 0,
+            nfree: 0,
+        }
+    }
+
+    // record that the transaction frees a zone, logging blocks,
+    // return false if that's more than it may
+    fn free(&mut self, blocks: &[u32]) -> bool {
+        if self.nfree == MAX_OP_FREE || !self.take(blocks) {
+            return false;
+        }
+        self.nfree += 1;
+        true
+    }
+
+    // record that the transaction logs blocks too,
+    // return false if that's more than it may
+    fn take(&mut self, blocks: &[u32]) -> bool {
+        let mut n = self.n;
+        let mut block = self.block;
+        for &b in blocks {
+            if !block[..n].contains(&b) {
+                if n == block.len() {
+                    return false;
+                }
+                block[n] = b;
+                n += 1;
+            }
+        }
+
+        self.block = block;
+        self.n = n;
+        true
+    }
+}
+
+// free the zones under the indirect zone_no, which is depth levels
+// above the data zones, as far as c allows. clear their entries.
+// return true if all are freed
+fn itrunc_indirect(c: &mut Chunk, dev: u32, zone_no: u32, depth: u32) -> bool {
+    let block_no = zone_to_block(dev, zone_no);
+    let b = bread(dev, block_no);
+    for i in 0..nindirect(dev) {
+        let z = ind_get(b, i);
+        if z == 0 {
+            continue;
+        }
+        if depth > 1 && !itrunc_indirect(c, dev, z, depth - 1)
+            || !c.free(&[zmap_block(dev, z), block_no])
+        {
+            brelse(b);
+            return false;
+        }
+
+        bfree(dev, z);
+        ind_set(b, i, 0);
+        log_write(b);
+    }
+    brelse(b);
+
+    true
+}
+
+// free the zones of inode as far as c allows, return true if all are
+fn itrunc_chunk(c: &mut Chunk, inode: *mut InodeMem) -> bool {
+    unsafe {
+        let dev = (*inode).dev;
+        for i in 0..NDIRECT + nlevel(dev) {
+            let z = (*inode).zone[i];
+            if z == 0 {
+                continue;
+            }
+            if i >= NDIRECT && !itrunc_indirect(c, dev, z, (i - NDIRECT) as u32 + 1)
+                || !c.free(&[zmap_block(dev, z)])
+            {
+                return false;
+            }
+
+            bfree(dev, z);
+            (*inode).zone[i] = 0;
+        }
+    }
+
+    true
+}
+
+// discard the content of inode, must be called inside a transaction.
+// a big file has more zones than a transaction can free, so they
+// go a chunk per transaction, which may commit in between. the size
+// is 0 from the first one on, so that a crash leaves a short file
+fn itrunc(inode: *mut InodeMem) {
+    let dev = unsafe { (*inode).dev };
+    let iblk = unsafe { iblock(dev, (*inode).ino) };
+    unsafe {
+        (*inode).fsize = 0;
+    }
+    loop {
+        let mut c = Chunk::new();
+        c.take(&[iblk]);
+        let done = itrunc_chunk(&mut c, inode);
+        iupdate(inode);
+        if done {
+            break;
+        }
+
+        end_op(dev);
+        begin_op(dev);
+    }
+}
+
+// the largest file size, limited by the superblock and the zone pointers
+fn max_fsize(dev: u32) -> u64 {
+    let mut nzone = NDIRECT;
+    for level in 0..nlevel(dev) {
+        nzone += nindirect(dev).pow(level as u32 + 1);
+    }
+
+    let size = ((nzone as u64) << sb(dev).log2_bz) * BLOCK_SIZE as u64;
+    min(size, sb(dev).max_fsize as u64)
+}
+
+// read file content from inode
+// return the number of bytes read, or -1 if copying to dst failed
+pub fn readi(inode: *mut InodeMem, is_uaddr: u32, mut dst: u64, mut off: u32, mut n: u32) -> i32 {
+    unsafe {
+        if off > (*inode).fsize || n > 0xffffffff - off {
+            return 0;
+        }
+
+        if off + n > (*inode).fsize {
+            n = (*inode).fsize - off;
+        }
+    }
+
+    let mut cnt: u32 = 0;
+    let mut b: *mut Buf;
+    let mut data_size;
+    let mut block_no;
+    let mut ahead = 0; // logical blocks before this one are read ahead
+    while cnt < n {
+        let bn = (off / BLOCK_SIZE) as usize;
+        block_no = bmap(inode, bn, 0);
+        if block_no == 0 {
+            panicc!("readi: block not exist");
+        }
+        if bn >= ahead {
+            // read the rest of the range in one request as far
+            // as it is contiguous on disk
+            let last = ((off + n - cnt - 1) / BLOCK_SIZE) as usize;
+            let mut run = 1;
+            while run < NSEG
+                && bn + run <= last
+                && bmap(inode, bn + run, 0) == block_no + run as u32
+            {
+                run += 1;
+            }
+            if run > 1 {
+                bread_ahead(unsafe { (*inode).dev }, block_no, run);
+            }
+            ahead = bn + run;
+        }
+        unsafe {
+            b = bread((*inode).dev, block_no);
+
+            data_size = min(n - cnt, BLOCK_SIZE - off % BLOCK_SIZE);
+            if either_copy_out(
+                is_uaddr,
+                dst,
+                (&(*b).data as *const u8).add((off % BLOCK_SIZE) as usize),
+                data_size as u64,
+            ) == -1
+            {
+                brelse(b);
+                return -1;
+            }
+        }
+        brelse(b);
+
+        cnt += data_size;
+        off += data_size;
+        dst += data_size as u64;
+    }
+
+    cnt as i32
+}
+
+// wirte file content in inode, must be called inside a transaction
+// return the number of bytes written, or -1 if copying from src failed
+pub fn writei(inode: *mut InodeMem, is_uaddr: u32, mut src: u64, mut off: u32, n: u32) -> i32 {
+    if off as u64 + n as u64 > max_fsize(unsafe { (*inode).dev }) {
+        return -1;
+    }
+
+    let mut cnt: u32 = 0;
+    let mut b: *mut Buf;
+    let mut data_size;
+    let mut block_no;
+    while cnt < n {
+        block_no = bmap(inode, (off / BLOCK_SIZE) as usize, 1);
+        unsafe {
+            b = bread((*inode).dev, block_no);
+            data_size = min(BLOCK_SIZE - off % BLOCK_SIZE, n - cnt);
+            if either_copy_in(
+                (&mut (*b).data as *mut u8).add((off % BLOCK_SIZE) as usize),
+                is_uaddr,
+                src,
+                data_size as u64,
+            ) == -1
+            {
+                brelse(b);
+                break;
+            }
+        }
+        log_write(b);
+        brelse(b);
+
+        cnt += data_size;
+        off += data_size;
+        src += data_size as u64;
+    }
+
+    unsafe {
+        if off > (*inode).fsize {
+            (*inode).fsize = off;
+        }
+    }
+
+    iupdate(inode);
+
+    cnt as i32
+}
+
+const FNAME_SIZE: usize = 60; // include '\0'
+
+// v3 directory entry, also the in-memory form for all versions.
+// v1 and v2 entries are a u16 inode number and a 14 or 30 byte name
+#[repr(C)]
+struct DirEntry {
+    ino: u32,
+    name: [u8; FNAME_SIZE],
+}
+
+impl DirEntry {
+    pub const fn new() -> Self {
+        DirEntry {
+            ino: 0,
+            name: [0; FNAME_SIZE],
+        }
+    }
+}
+
+// the size of a directory entry on disk
+fn dirent_size(dev: u32) -> u32 {
+    fs_layout(dev).dirent_size
+}
+
+// read the directory entry at off into de, whatever the on-disk version,
+// return false if it can't be read
+fn read_dirent(dir: *mut InodeMem, off: u32, de: *mut DirEntry) -> bool {
+    unsafe {
+        if fs_layout((*dir).dev).version == FsVersion::V3 {
+            return readi(dir, 0, de as u64, off, dirent_size((*dir).dev))
+                == dirent_size((*dir).dev) as i32;
+        }
+
+        let mut raw: [u8; 2 + 30] = [0; 2 + 30];
+        if readi(
+            dir,
+            0,
+            &mut raw as *mut u8 as u64,
+            off,
+            dirent_size((*dir).dev),
+        ) != dirent_size((*dir).dev) as i32
+        {
+            return false;
+        }
+        (*de).ino = raw[0] as u32 | (raw[1] as u32) << 8;
+        mem_set(&mut (*de).name as *mut u8 as *mut u64, 0, FNAME_SIZE as u64);
+        mem_copy(
+            &mut (*de).name as *mut u8 as *mut u64,
+            (&raw as *const u8).add(2) as *const u64,
+            fs_layout((*dir).dev).name_len as u64,
+        );
+    }
+
+    true
+}
+
+// write de as the directory entry at off, whatever the on-disk version,
+// must be called inside a transaction
+fn write_dirent(dir: *mut InodeMem, off: u32, de: *const DirEntry) -> bool {
+    unsafe {
+        if fs_layout((*dir).dev).version == FsVersion::V3 {
+            return writei(dir, 0, de as u64, off, dirent_size((*dir).dev))
+                == dirent_size((*dir).dev) as i32;
+        }
+
+        let mut raw: [u8; 2 + 30] = [0; 2 + 30];
+        raw[0] = (*de).ino as u8;
+        raw[1] = ((*de).ino >> 8) as u8;
+        mem_copy(
+            (&mut raw as *mut u8).add(2) as *mut u64,
+            &(*de).name as *const u8 as *const u64,
+            fs_layout((*dir).dev).name_len as u64,
+        );
+        writei(
+            dir,
+            0,
+            &raw as *const u8 as u64,
+            off,
+            dirent_size((*dir).dev),
+        ) == dirent_size((*dir).dev) as i32
+    }
+}
+
+// add the entry name for ino to directory dir,
+// must be called inside a transaction.
+// return false if name doesn't fit in an entry
+fn dir_link(dir: *mut InodeMem, name: *const u8, ino: u32) -> bool {
+    let mut len = 0;
+    unsafe {
+        while *name.add(len) != 0 {
+            len += 1;
+        }
+
+        // a v3 name keeps its '\0', shorter versions can fill the field
+        let max = match fs_layout((*dir).dev).version {
+            FsVersion::V3 => FNAME_SIZE - 1,
+            _ => fs_layout((*dir).dev).name_len as usize,
+        };
+        if len == 0 || len > max {
+            return false;
+        }
+
+        // reuse an empty slot, or append
+        let mut de = DirEntry::new();
+        let mut off = 0;
+        while off < (*dir).fsize {
+            if !read_dirent(dir, off, &mut de) {
+                panicc!("dir_link: read inode");
+            }
+            if de.ino == 0 {
+                break;
+            }
+            off += dirent_size((*dir).dev);
+        }
+
+        de = DirEntry::new();
+        de.ino = ino;
+        mem_copy(
+            &mut de.name as *mut u8 as *mut u64,
+            name as *const u64,
+            len as u64,
+        );
+        write_dirent(dir, off, &de)
+    }
+}
+
+// is dir empty except for "." and ".."
+fn dir_empty(dir: *mut InodeMem) -> bool {
+    let mut de = DirEntry::new();
+    let mut off = 0;
+    unsafe {
+        while off < (*dir).fsize {
+            if !read_dirent(dir, off, &mut de) {
+                panicc!("dir_empty: read inode");
+            }
+            off += dirent_size((*dir).dev);
+
+            let dot = &de.name as *const u8;
+            if de.ino != 0
+                && str_cmp(dot, ".\0".as_ptr(), 2) != 0
+                && str_cmp(dot, "..\0".as_ptr(), 3) != 0
+            {
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+// look up for name in directory, set the offset of its entry (offp points to)
+fn dir_lookup(inode: *mut InodeMem, name: *const u8, offp: *mut u32) -> *mut InodeMem {
+    unsafe {
+        if !is_dir((*inode).mode) {
+            panicc!("dir_lookup: not dir");
+        }
+
+        let mut de = DirEntry::new();
+        let mut off = 0;
+        while off < (*inode).fsize {
+            if !read_dirent(inode, off, &mut de) {
+                panicc!("dir_lookup: read inode");
+            }
+            let ent_off = off;
+            off += dirent_size((*inode).dev);
+
+            if de.ino == 0 {
+                continue;
+            }
+
+            if str_cmp(name, &de.name as *const u8, FNAME_SIZE as u32) == 0 {
+                if !offp.is_null() {
+                    (*offp) = ent_off;
+                }
+                return iget((*inode).dev, de.ino);
+            }
+        }
+    }
+
+    null_mut()
+}
+
+impl DirIter for InodeMem {
+    fn next(&mut self, pos: &mut u32, ent: &mut DirEnt) -> bool {
+        let mut de = DirEntry::new();
+        while *pos < self.fsize {
+            if !read_dirent(self, *pos, &mut de) {
+                return false;
+            }
+            *pos += dirent_size(self.dev);
+
+            if de.ino == 0 {
+                continue;
+            }
+
+            let mut namelen = 0;
+            while namelen < min(FNAME_SIZE, NAME_MAX) && de.name[namelen] != 0 {
+                namelen += 1;
+            }
+            let child = iget(self.dev, de.ino);
+            let dtype = mode_to_dtype(unsafe { (*child).mode });
+            iput(child);
+            ent.set(de.ino, &de.name[..namelen], dtype);
+            return true;
+        }
+
+        false
+    }
+}
+
+impl Inode for InodeMem {
+    fn dev(&self) -> u32 {
+        self.dev
+    }
+
+    fn ino(&self) -> u32 {
+        self.ino
+    }
+
+    fn mode(&self) -> u16 {
+        self.mode
+    }
+
+    fn stat(&self, st: &mut Stat) {
+        stati(self, st);
+    }
+
+    fn dup(&mut self) -> *mut dyn Inode {
+        idup(self)
+    }
+
+    fn put(&mut self) {
+        iput(self);
+    }
+
+    fn read(&mut self, is_uaddr: u32, dst: u64, off: u32, n: u32) -> i32 {
+        readi(self, is_uaddr, dst, off, n)
+    }
+
+    fn write(&mut self, is_uaddr: u32, src: u64, off: u32, n: u32) -> i32 {
+        if is_journal(self) {
+            return -1;
+        }
+
+        // write a few blocks at a time to avoid exceeding
+        // the maximum log transaction size. a chunk of n blocks
+        // takes n+1 data blocks if not aligned, the inode, 2 zone
+        // bitmap blocks, and an indirect block per level plus one
+        // more at each level for crossing a boundary
+        let max = (MAX_OP_BLOCK - 1 - 1 - 2 - 2 * nlevel(self.dev) as u32) * BLOCK_SIZE;
+        let mut i: u32 = 0;
+        while i < n {
+            let n1 = min(n - i, max);
+
+            begin_op(self.dev);
+            let r = writei(self, is_uaddr, src + i as u64, off + i, n1);
+            end_op(self.dev);
+
+            if r != n1 as i32 {
+                // error from writei
+                break;
+            }
+            i += n1;
+        }
+
+        match i == n {
+            true => n as i32,
+            false => -1,
+        }
+    }
+
+    fn truncate(&mut self) -> i32 {
+        if is_dir(self.mode) || is_journal(self) {
+            return -1;
+        }
+        if is_fifo(self.mode) {
+            // the data is in the pipe, not on disk
+            return 0;
+        }
+
+        begin_op(self.dev);
+        itrunc(self);
+        end_op(self.dev);
+        0
+    }
+
+    // the log covers data and metadata alike
+    fn sync(&mut self) -> i32 {
+        log_sync(self.dev)
+    }
+
+    fn lookup(&mut self, name: *const u8) -> Option<*mut dyn Inode> {
+        if !is_dir(self.mode) {
+            return None;
+        }
+
+        let child = dir_lookup(self, name, null_mut());
+        match child.is_null() {
+            true => None,
+            false => Some(child),
+        }
+    }
+
+    fn create(&mut self, name: *const u8, mode: u16) -> Option<*mut dyn Inode> {
+        if !is_dir(self.mode) {
+            return None;
+        }
+        if !is_reg(mode) && !is_dir(mode) && !is_symlink(mode) && !is_fifo(mode) && !is_socket(mode)
+        {
+            return None;
+        }
+
+        begin_op(self.dev);
+        let inode = ialloc(self.dev, mode);
+        if inode.is_null() {
+            end_op(self.dev);
+            return None;
+        }
+
+        let mut ok = true;
+        unsafe {
+            (*inode).nlink = 1;
+            if is_dir(mode) {
+                // "." links to the new directory, ".." back to this one
+                (*inode).nlink = 2;
+                ok = dir_link(inode, ".\0".as_ptr(), (*inode).ino)
+                    && dir_link(inode, "..\0".as_ptr(), self.ino);
+            }
+            ok = ok && dir_link(self, name, (*inode).ino);
+
+            if ok && is_dir(mode) {
+                self.nlink += 1;
+                iupdate(self);
+            }
+            if !ok {
+                // freed by iput() below
+                (*inode).nlink = 0;
+            }
+            iupdate(inode);
+        }
+        end_op(self.dev);
+
+        if !ok {
+            iput(inode);
+            return None;
+        }
+        Some(inode)
+    }
+
+    fn unlink(&mut self, name: *const u8) -> i32 {
+        if !is_dir(self.mode) {
+            return -1;
+        }
+
+        let mut off: u32 = 0;
+        let child = dir_lookup(self, name, &mut off);
+        if child.is_null() {
+            return -1;
+        }
+
+        unsafe {
+            if is_dir((*child).mode) && !dir_empty(child) || is_journal(child) {
+                iput(child);
+                return -1;
+            }
+
+            begin_op(self.dev);
+            let de = DirEntry::new();
+            if !write_dirent(self, off, &de) {
+                panicc!("unlink: write_dirent");
+            }
+            if is_dir((*child).mode) {
+                // its "." and the ".." back to self are gone with it
+                (*child).nlink = 0;
+                self.nlink -= 1;
+                iupdate(self);
+            } else {
+                (*child).nlink -= 1;
+            }
+            iupdate(child);
+            end_op(self.dev);
+        }
+
+        // frees child if that was the last link and nobody has it open
+        iput(child);
+        0
+    }
+
+    fn dir_iter(&mut self) -> Option<&mut dyn DirIter> {
+        match is_dir(self.mode) {
+            true => Some(self),
+            false => None,
+        }
+    }
+}
@@ -0,0 +1,386 @@
+use crate::arp::{arp_hold, arp_input, arp_lookup, arp_timer};
+use crate::proc::sleep;
+use crate::tcp::{tcp_input, tcp_timer};
+use crate::udp::udp_input;
+use crate::virtio_net::{virtio_net_mac, virtio_net_send, ETH_FRAME_MAX};
+use core::fmt::Write;
+
+// the network stack: ethernet, ipv4 and icmp, see arp.rs, udp.rs
+// and tcp.rs for the rest.
+//
+// frames come in through net_input(), called by the interrupt handler
+// of the network device, and are handled right there, replies and
+// all. packets go out from a single frame buffer: the caller fills
+// ip_payload() and hands it to ip_output(). the kernel runs one thing
+// at a time with interrupts off while in here, so nothing else can
+// use the buffer meanwhile.
+//
+// packets to the loopback network 127.0.0.0/8, or to NET_ADDR, don't
+// go to the device but wait in the LOOP queue, to be taken in by
+// loop_drain() once the stack has been left: at the end of a socket
+// call, before sleeping in one (see net_sleep()), and at the end of
+// the interrupt and timer handlers. taking them in right away would
+// run the receiving side in the middle of the sending one.
+//
+// the addresses are fixed to those of qemu's user networking, which
+// puts the host at NET_GATEWAY.
+//
+// addresses and ports are kept in host byte order.
+
+pub type IpAddr = u32;
+
+pub const fn ip_addr(a: u8, b: u8, c: u8, d: u8) -> IpAddr {
+    (a as u32) << 24 | (b as u32) << 16 | (c as u32) << 8 | d as u32
+}
+
+pub const NET_ADDR: IpAddr = ip_addr(10, 0, 2, 15);
+pub const NET_MASK: IpAddr = ip_addr(255, 255, 255, 0);
+pub const NET_GATEWAY: IpAddr = ip_addr(10, 0, 2, 2);
+pub const IP_BROADCAST: IpAddr = ip_addr(255, 255, 255, 255);
+pub const LOOPBACK: IpAddr = ip_addr(127, 0, 0, 1);
+const LOOP_NET: IpAddr = ip_addr(127, 0, 0, 0);
+const LOOP_MASK: IpAddr = ip_addr(255, 0, 0, 0);
+
+pub const ETH_HDR_LEN: usize = 14;
+pub const IP_HDR_LEN: usize = 20; // without options
+pub const IP_MAX: usize = ETH_FRAME_MAX - ETH_HDR_LEN; // the largest packet
+
+pub const ETH_TYPE_IP: u16 = 0x0800;
+pub const ETH_TYPE_ARP: u16 = 0x0806;
+pub const ETH_BROADCAST: [u8; 6] = [0xff; 6];
+
+pub const IP_PROTO_ICMP: u8 = 1;
+pub const IP_PROTO_TCP: u8 = 6;
+pub const IP_PROTO_UDP: u8 = 17;
+
+const IP_TTL: u8 = 64;
+const IP_DF: u16 = 0x4000; // don't fragment
+const IP_MF: u16 = 0x2000; // more fragments
+const IP_OFFSET: u16 = 0x1fff;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO: u8 = 8;
+
+// network statistics, in /proc/net
+pub struct NetStat {
+    pub rx_frames: u64,
+    pub tx_frames: u64,
+    pub rx_dropped: u64, // bad or not for us
+    pub tx_dropped: u64, // the device had no room
+    pub lo_packets: u64,
+    pub lo_dropped: u64, // the queue was full
+}
+
+pub static mut NET_STAT: NetStat = NetStat {
+    rx_frames: 0,
+    tx_frames: 0,
+    rx_dropped: 0,
+    tx_dropped: 0,
+    lo_packets: 0,
+    lo_dropped: 0,
+};
+
+static mut MAC: [u8; 6] = [0; 6];
+
+// the frame being built
+static mut TX_FRAME: [u8; ETH_FRAME_MAX] = [0; ETH_FRAME_MAX];
+
+// the ip id of the next packet
+static mut IP_ID: u16 = 0;
+
+const NLOOP: usize = 16;
+
+struct Loop {
+    pkt: [[u8; IP_MAX]; NLOOP],
+    len: [usize; NLOOP],
+    r: usize,
+    w: usize,
+    busy: bool, // loop_drain() is running
+}
+
+static mut LOOP: Loop = Loop {
+    pkt: [[0; IP_MAX]; NLOOP],
+    len: [0; NLOOP],
+    r: 0,
+    w: 0,
+    busy: false,
+};
+
+pub fn get16(b: &[u8], off: usize) -> u16 {
+    u16::from_be_bytes([b[off], b[off + 1]])
+}
+
+pub fn get32(b: &[u8], off: usize) -> u32 {
+    u32::from_be_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]])
+}
+
+pub fn put16(b: &mut [u8], off: usize, v: u16) {
+    b[off..off + 2].copy_from_slice(&v.to_be_bytes());
+}
+
+pub fn put32(b: &mut [u8], off: usize, v: u32) {
+    b[off..off + 4].copy_from_slice(&v.to_be_bytes());
+}
+
+// the one's complement sum of data, added to sum
+fn sum16(data: &[u8], mut sum: u32) -> u32 {
+    let mut i = 0;
+    while i + 1 < data.len() {
+        sum += get16(data, i) as u32;
+        i += 2;
+    }
+    if i < data.len() {
+        sum += (data[i] as u32) << 8;
+    }
+    sum
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// the internet checksum of data, rfc 1071. 0 if the checksum
+// field in data is right
+pub fn checksum(data: &[u8]) -> u16 {
+    fold(sum16(data, 0))
+}
+
+// the checksum of a tcp or udp segment and its pseudo header
+pub fn transport_checksum(src: IpAddr, dst: IpAddr, proto: u8, seg: &[u8]) -> u16 {
+    let mut sum = (src >> 16) + (src & 0xffff) + (dst >> 16) + (dst & 0xffff);
+    sum += proto as u32 + seg.len() as u32;
+    fold(sum16(seg, sum))
+}
+
+pub fn net_init() {
+    if let Some(mac) = virtio_net_mac() {
+        unsafe {
+            MAC = mac;
+        }
+        let a = NET_ADDR.to_be_bytes();
+        println!("net: address {}.{}.{}.{}", a[0], a[1], a[2], a[3]);
+    }
+}
+
+pub fn net_mac() -> [u8; 6] {
+    unsafe { MAC }
+}
+
+// called every tick
+pub fn net_timer() {
+    arp_timer();
+    tcp_timer();
+    loop_drain();
+}
+
+pub fn is_loopback(addr: IpAddr) -> bool {
+    addr & LOOP_MASK == LOOP_NET
+}
+
+// the source address of packets to dst
+pub fn ip_src(dst: IpAddr) -> IpAddr {
+    match is_loopback(dst) {
+        true => LOOPBACK,
+        false => NET_ADDR,
+    }
+}
+
+// queue a packet to ourselves
+fn loop_output(pkt: &[u8]) {
+    unsafe {
+        if LOOP.w - LOOP.r == NLOOP {
+            NET_STAT.lo_dropped += 1;
+            return;
+        }
+        let i = LOOP.w % NLOOP;
+        LOOP.pkt[i][..pkt.len()].copy_from_slice(pkt);
+        LOOP.len[i] = pkt.len();
+        LOOP.w += 1;
+        NET_STAT.lo_packets += 1;
+    }
+}
+
+// take in the packets queued to ourselves, and those their
+// replies queue meanwhile. return whether there were any
+pub fn loop_drain() -> bool {
+    unsafe {
+        if LOOP.busy || LOOP.r == LOOP.w {
+            return false;
+        }
+        LOOP.busy = true;
+        while LOOP.r != LOOP.w {
+            // the slot stays taken until the packet is handled
+            let i = LOOP.r % NLOOP;
+            let pkt = &LOOP.pkt[i][..LOOP.len[i]];
+            ip_input(pkt, true);
+            LOOP.r += 1;
+        }
+        LOOP.busy = false;
+    }
+    true
+}
+
+// sleep on chan in a blocking socket call, unless packets to
+// ourselves were waiting: they may be what the caller waits for,
+// so it has to look again
+pub fn net_sleep(chan: u64) {
+    if !loop_drain() {
+        sleep(chan);
+    }
+}
+
+// handle a frame from the device. false if no protocol wants it
+pub fn net_input(frame: &[u8]) -> bool {
+    unsafe {
+        NET_STAT.rx_frames += 1;
+    }
+    if frame.len() < ETH_HDR_LEN {
+        unsafe {
+            NET_STAT.rx_dropped += 1;
+        }
+        return true;
+    }
+
+    let payload = &frame[ETH_HDR_LEN..];
+    match get16(frame, 12) {
+        ETH_TYPE_ARP => arp_input(payload),
+        ETH_TYPE_IP => ip_input(payload, false),
+        _ => return false,
+    }
+    loop_drain();
+    true
+}
+
+// send the ethernet payload of len bytes at TX_FRAME[ETH_HDR_LEN..]
+pub fn eth_output(dst: [u8; 6], typ: u16, len: usize) {
+    unsafe {
+        TX_FRAME[0..6].copy_from_slice(&dst);
+        TX_FRAME[6..12].copy_from_slice(&MAC);
+        put16(&mut TX_FRAME, 12, typ);
+        let n = (ETH_HDR_LEN + len) as u32;
+        if virtio_net_send(0, &TX_FRAME as *const u8 as u64, n) > 0 {
+            NET_STAT.tx_frames += 1;
+        } else {
+            NET_STAT.tx_dropped += 1;
+        }
+    }
+}
+
+// where the ethernet payload is built
+pub fn eth_payload() -> &'static mut [u8] {
+    unsafe { &mut TX_FRAME[ETH_HDR_LEN..] }
+}
+
+// where the payload of the next ip packet is built
+pub fn ip_payload() -> &'static mut [u8] {
+    unsafe { &mut TX_FRAME[ETH_HDR_LEN + IP_HDR_LEN..] }
+}
+
+// take in a packet, from the device or the loopback queue (lo)
+fn ip_input(pkt: &[u8], lo: bool) {
+    if pkt.len() < IP_HDR_LEN || pkt[0] >> 4 != 4 {
+        return drop_input();
+    }
+    let hdr_len = (pkt[0] & 0xf) as usize * 4;
+    let len = get16(pkt, 2) as usize;
+    if hdr_len < IP_HDR_LEN || len < hdr_len || len > pkt.len() {
+        return drop_input();
+    }
+    if checksum(&pkt[..hdr_len]) != 0 {
+        return drop_input();
+    }
+    // fragments aren't reassembled
+    if get16(pkt, 6) & (IP_MF | IP_OFFSET) != 0 {
+        return drop_input();
+    }
+    let src = get32(pkt, 12);
+    let dst = get32(pkt, 16);
+    let ours = dst == NET_ADDR || dst == IP_BROADCAST || (lo && is_loopback(dst));
+    if !ours || (!lo && is_loopback(src)) {
+        return drop_input();
+    }
+
+    // the frame may be padded past the end of the packet
+    let payload = &pkt[hdr_len..len];
+    match pkt[9] {
+        IP_PROTO_ICMP => icmp_input(src, payload),
+        IP_PROTO_TCP => tcp_input(src, dst, payload),
+        IP_PROTO_UDP => udp_input(src, dst, payload),
+        _ => drop_input(),
+    }
+}
+
+fn drop_input() {
+    unsafe {
+        NET_STAT.rx_dropped += 1;
+    }
+}
+
+// the next hop towards dst
+fn route(dst: IpAddr) -> IpAddr {
+    if dst & NET_MASK == NET_ADDR & NET_MASK {
+        dst
+    } else {
+        NET_GATEWAY
+    }
+}
+
+// send the len bytes at ip_payload() to dst. if the hardware address
+// of the next hop is unknown yet, the packet waits for the arp reply.
+// packets to ourselves are queued, see the top of this file
+pub fn ip_output(dst: IpAddr, proto: u8, len: usize) {
+    let total = IP_HDR_LEN + len;
+    if total > IP_MAX {
+        panicc!("ip_output: too long");
+    }
+
+    let pkt = eth_payload();
+    pkt[0] = 0x45; // version 4, 5 words of header
+    pkt[1] = 0;
+    put16(pkt, 2, total as u16);
+    unsafe {
+        put16(pkt, 4, IP_ID);
+        IP_ID = IP_ID.wrapping_add(1);
+    }
+    put16(pkt, 6, IP_DF);
+    pkt[8] = IP_TTL;
+    pkt[9] = proto;
+    put16(pkt, 10, 0);
+    put32(pkt, 12, ip_src(dst));
+    put32(pkt, 16, dst);
+    let sum = checksum(&pkt[..IP_HDR_LEN]);
+    put16(pkt, 10, sum);
+
+    if is_loopback(dst) || dst == NET_ADDR {
+        return loop_output(&pkt[..total]);
+    }
+    if dst == IP_BROADCAST {
+        return eth_output(ETH_BROADCAST, ETH_TYPE_IP, total);
+    }
+    let hop = route(dst);
+    match arp_lookup(hop) {
+        Some(mac) => eth_output(mac, ETH_TYPE_IP, total),
+        None => arp_hold(hop, &pkt[..total]),
+    }
+}
+
+fn icmp_input(src: IpAddr, msg: &[u8]) {
+    if msg.len() < 8 || checksum(msg) != 0 {
+        return drop_input();
+    }
+    if msg[0] != ICMP_ECHO || msg.len() > IP_MAX - IP_HDR_LEN {
+        return;
+    }
+
+    // the same message back, as a reply
+    let out = ip_payload();
+    out[..msg.len()].copy_from_slice(msg);
+    out[0] = ICMP_ECHO_REPLY;
+    put16(out, 2, 0);
+    let sum = checksum(&out[..msg.len()]);
+    put16(out, 2, sum);
+    ip_output(src, IP_PROTO_ICMP, msg.len());
+}
@@ -3,5 +3,26 @@ pub const NPROC: u32 = 64;
 
 pub const NINODE: usize = 50;
 pub const ROOT_DEV: u32 = 1; // device number of file system root disk
-pub const MAX_OP_BLOCK: u32 = 10;
-pub const NBUF: usize = MAX_OP_BLOCK as usize * 3;
+pub const MAX_OP_BLOCK: u32 = 16; // max blocks written by an fs op
+pub const MAX_OP_FREE: u32 = 64; // max zones freed by an fs op
+// every mounted minix file system's log may pin LOG_SIZE buffers,
+// the rest are for the blocks ops hold and for read ahead
+pub const NBUF: usize = NMINIX * LOG_SIZE + MAX_OP_BLOCK as usize * 3 + NSEG;
+pub const NSEG: usize = 8; // max blocks moved by one disk request
+pub const LOG_SIZE: usize = MAX_OP_BLOCK as usize * 3; // max data blocks in on-disk log
+pub const NOFILE: usize = 16; // open files per process
+pub const NFILE: usize = 100; // open files per system
+pub const MAX_PATH: usize = 128; // maximum file path name
+pub const NMOUNT: usize = 8; // mounted file systems
+pub const NTMPFS: usize = 4; // tmpfs instances
+pub const NTMPNODE: usize = 128; // files in all tmpfs instances
+pub const TMP_NPAGE: usize = 32; // max pages of a tmpfs file
+pub const TMPFS_PAGES: u32 = 256; // size limit of /tmp in pages
+pub const NDEV: usize = 10; // device major numbers
+pub const NDEVNODE: usize = 24; // files in /dev
+pub const NPROCNODE: usize = 32; // /proc files in use
+pub const NMINIX: usize = 4; // mounted minix file systems
+pub const NDISK: usize = 8; // virtio disks, one per mmio slot at most
+pub const NFIFO: usize = 16; // FIFOs open at once
+pub const NVPORT: usize = 4; // virtio console ports
+pub const NSOCKET: usize = 32; // sockets open at once
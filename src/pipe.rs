@@ -0,0 +1,270 @@
+use crate::errno::EPIPE;
+use crate::file::{file_alloc, file_close, File, FileType};
+use crate::fs::Inode;
+use crate::kalloc::{kalloc, kfree};
+use crate::param::NFIFO;
+use crate::proc::{either_copy_in, either_copy_out, my_proc, sleep, wakeup};
+use core::ptr::null_mut;
+
+// pipes, anonymous ones from pipe() and named ones (FIFOs).
+//
+// an open FIFO is backed by a pipe found through FIFO by the device
+// and inode number, so every open of it shares the same data. the
+// pipe lives until its last reader and writer close, whatever is
+// left unread is then discarded, as it is never stored on disk.
+
+const PIPE_SIZE: usize = 512;
+
+pub struct Pipe {
+    data: [u8; PIPE_SIZE],
+    nread: u32,   // number of bytes read
+    nwrite: u32,  // number of bytes written
+    readers: u32, // open read ends
+    writers: u32, // open write ends
+    nropen: u32,  // times opened for reading, FIFO openers wait on it
+    nwopen: u32,  // times opened for writing
+}
+
+// allocate an empty pipe with no ends open
+fn pipe_new() -> *mut Pipe {
+    let pi = kalloc() as *mut Pipe;
+    if !pi.is_null() {
+        unsafe {
+            (*pi).nread = 0;
+            (*pi).nwrite = 0;
+            (*pi).readers = 0;
+            (*pi).writers = 0;
+            (*pi).nropen = 0;
+            (*pi).nwopen = 0;
+        }
+    }
+    pi
+}
+
+// create a pipe, return its read and write ends
+pub fn pipe_alloc() -> Option<(*mut File, *mut File)> {
+    let f0 = file_alloc();
+    if f0.is_null() {
+        return None;
+    }
+    let f1 = file_alloc();
+    if f1.is_null() {
+        file_close(f0);
+        return None;
+    }
+
+    let pi = pipe_new();
+    if pi.is_null() {
+        file_close(f0);
+        file_close(f1);
+        return None;
+    }
+
+    unsafe {
+        (*pi).readers = 1;
+        (*pi).writers = 1;
+
+        (*f0).ftype = FileType::Pipe;
+        (*f0).readable = true;
+        (*f0).writable = false;
+        (*f0).pipe = pi;
+
+        (*f1).ftype = FileType::Pipe;
+        (*f1).readable = false;
+        (*f1).writable = true;
+        (*f1).pipe = pi;
+    }
+
+    Some((f0, f1))
+}
+
+// close the ends a file has open, a FIFO opened O_RDWR has both.
+// free the pipe when no end is open any more
+pub fn pipe_close(pi: *mut Pipe, readable: bool, writable: bool) {
+    unsafe {
+        if writable {
+            (*pi).writers -= 1;
+            wakeup(&(*pi).nread as *const u32 as u64);
+        }
+        if readable {
+            (*pi).readers -= 1;
+            wakeup(&(*pi).nwrite as *const u32 as u64);
+        }
+
+        if (*pi).readers == 0 && (*pi).writers == 0 {
+            fifo_forget(pi);
+            kfree(pi as *mut u64);
+        }
+    }
+}
+
+// the bytes waiting to be read
+pub fn pipe_size(pi: *mut Pipe) -> u32 {
+    unsafe { (*pi).nwrite - (*pi).nread }
+}
+
+// write n bytes from the user address addr, block while the pipe is full.
+// return -EPIPE if the read end is closed before anything is written
+pub fn pipe_write(pi: *mut Pipe, addr: u64, n: u32) -> i32 {
+    let p = my_proc();
+    let mut i: u32 = 0;
+    unsafe {
+        while i < n {
+            if (*pi).readers == 0 {
+                return match i {
+                    0 => -EPIPE,
+                    _ => i as i32,
+                };
+            }
+            if (*p).killed != 0 {
+                return -1;
+            }
+
+            if (*pi).nwrite == (*pi).nread + PIPE_SIZE as u32 {
+                // full, let the readers drain it
+                wakeup(&(*pi).nread as *const u32 as u64);
+                sleep(&(*pi).nwrite as *const u32 as u64);
+            } else {
+                let mut c: u8 = 0;
+                if either_copy_in(&mut c, 1, addr + i as u64, 1) == -1 {
+                    break;
+                }
+                (*pi).data[(*pi).nwrite as usize % PIPE_SIZE] = c;
+                (*pi).nwrite += 1;
+                i += 1;
+            }
+        }
+        wakeup(&(*pi).nread as *const u32 as u64);
+    }
+
+    i as i32
+}
+
+// read at most n bytes to the user address addr, block while the pipe
+// is empty. return 0 at end of file, when the write end is closed
+pub fn pipe_read(pi: *mut Pipe, addr: u64, n: u32) -> i32 {
+    let p = my_proc();
+    let mut i: u32 = 0;
+    unsafe {
+        while (*pi).nread == (*pi).nwrite && (*pi).writers > 0 {
+            if (*p).killed != 0 {
+                return -1;
+            }
+            sleep(&(*pi).nread as *const u32 as u64);
+        }
+
+        while i < n && (*pi).nread != (*pi).nwrite {
+            let c = (*pi).data[(*pi).nread as usize % PIPE_SIZE];
+            if either_copy_out(1, addr + i as u64, &c, 1) == -1 {
+                break;
+            }
+            (*pi).nread += 1;
+            i += 1;
+        }
+        wakeup(&(*pi).nwrite as *const u32 as u64);
+    }
+
+    i as i32
+}
+
+#[derive(Copy, Clone)]
+struct Fifo {
+    dev: u32,
+    ino: u32,
+    pipe: *mut Pipe, // null if the slot is free
+}
+
+static mut FIFO: [Fifo; NFIFO] = [Fifo {
+    dev: 0,
+    ino: 0,
+    pipe: null_mut(),
+}; NFIFO];
+
+// the pipe of the FIFO inode, allocated on its first open
+fn fifo_pipe(dev: u32, ino: u32) -> *mut Pipe {
+    let mut empty = NFIFO;
+    unsafe {
+        for i in 0..NFIFO {
+            if FIFO[i].pipe.is_null() {
+                if empty == NFIFO {
+                    empty = i;
+                }
+            } else if FIFO[i].dev == dev && FIFO[i].ino == ino {
+                return FIFO[i].pipe;
+            }
+        }
+
+        if empty == NFIFO {
+            return null_mut();
+        }
+        let pi = pipe_new();
+        if !pi.is_null() {
+            FIFO[empty] = Fifo { dev, ino, pipe: pi };
+        }
+        pi
+    }
+}
+
+// drop the FIFO entry of a pipe being freed, if it has one
+fn fifo_forget(pi: *mut Pipe) {
+    unsafe {
+        for i in 0..NFIFO {
+            if FIFO[i].pipe == pi {
+                FIFO[i].pipe = null_mut();
+            }
+        }
+    }
+}
+
+// attach f, with readable and writable already set, to the pipe of the
+// FIFO inode. f takes over the reference to inode.
+// opening only for reading blocks until the FIFO is opened for writing,
+// and the other way round, an open for both never blocks.
+// return -1 if killed while waiting, f must then be closed by the caller
+pub fn fifo_open(f: *mut File, inode: *mut dyn Inode) -> i32 {
+    unsafe {
+        let pi = fifo_pipe((*inode).dev(), (*inode).ino());
+        if pi.is_null() {
+            (*inode).put();
+            return -1;
+        }
+
+        (*f).ftype = FileType::Pipe;
+        (*f).pipe = pi;
+        (*f).inode = Some(inode);
+        if (*f).readable {
+            (*pi).readers += 1;
+            (*pi).nropen += 1;
+            wakeup(&(*pi).nropen as *const u32 as u64);
+        }
+        if (*f).writable {
+            (*pi).writers += 1;
+            (*pi).nwopen += 1;
+            wakeup(&(*pi).nwopen as *const u32 as u64);
+        }
+
+        // a peer that opens and closes again while we sleep
+        // still counts, so wait for the open count to change
+        let p = my_proc();
+        if (*f).readable && !(*f).writable {
+            let n = (*pi).nwopen;
+            while (*pi).writers == 0 && (*pi).nwopen == n {
+                if (*p).killed != 0 {
+                    return -1;
+                }
+                sleep(&(*pi).nwopen as *const u32 as u64);
+            }
+        }
+        if (*f).writable && !(*f).readable {
+            let n = (*pi).nropen;
+            while (*pi).readers == 0 && (*pi).nropen == n {
+                if (*p).killed != 0 {
+                    return -1;
+                }
+                sleep(&(*pi).nropen as *const u32 as u64);
+            }
+        }
+    }
+
+    0
+}
@@ -1,22 +1,37 @@
-use crate::mem_layout::{plic_sclaim, plic_senable, plic_spriority, PLIC, UART_IRQ, VIRTIO_IRQ};
+use crate::mem_layout::{
+    plic_sclaim, plic_senable, plic_spriority, virtio_irq, PLIC, UART_IRQ, VIRTIO0_IRQ,
+    VIRTIO_NSLOT,
+};
 use crate::proc::cpu_id;
+use crate::random::random_add_jitter;
 use crate::uart::uart_intr;
-use crate::virtio_disk::virtio_disk_intr;
+use crate::virtio::virtio_intr;
 
 use core::fmt::Write;
 
+pub const NIRQ: usize = 64;
+
+// interrupts taken per irq
+pub static mut IRQ_COUNT: [u64; NIRQ] = [0; NIRQ];
+
 pub fn plic_init() {
     unsafe {
         // set desired IRQ priorities
         *((PLIC + UART_IRQ * 4) as *mut u32) = 1;
-        *((PLIC + VIRTIO_IRQ * 4) as *mut u32) = 1;
+        for slot in 0..VIRTIO_NSLOT {
+            *((PLIC + virtio_irq(slot) * 4) as *mut u32) = 1;
+        }
     }
 }
 
 pub fn plic_init_hart() {
     let hart = cpu_id();
+    let mut enable: u32 = 1 << UART_IRQ;
+    for slot in 0..VIRTIO_NSLOT {
+        enable |= 1 << virtio_irq(slot);
+    }
     unsafe {
-        *(plic_senable(hart) as *mut u32) = (1 << UART_IRQ) | (1 << VIRTIO_IRQ);
+        *(plic_senable(hart) as *mut u32) = enable;
         // set this hart's S-mode priority threshold
         *(plic_spriority(hart) as *mut u32) = 0;
     }
@@ -46,12 +61,21 @@ fn plic_complete(irq: u32) {
 // called in trap
 pub fn plic_intr() {
     if let Some(irq) = plic_claim() {
+        // when devices interrupt is a little unpredictable
+        random_add_jitter();
+
+        if (irq as usize) < NIRQ {
+            unsafe {
+                IRQ_COUNT[irq as usize] += 1;
+            }
+        }
+
         match irq as u64 {
             UART_IRQ => {
                 uart_intr();
             }
-            VIRTIO_IRQ => {
-                virtio_disk_intr();
+            x if x >= VIRTIO0_IRQ && x < VIRTIO0_IRQ + VIRTIO_NSLOT => {
+                virtio_intr(irq);
             }
             _ => {
                 println!("unexpected interrupt id irq={}", irq);
@@ -1,10 +1,11 @@
+use crate::file::{file_close, file_dup, File};
 use crate::kalloc::{kalloc, kfree};
 use crate::mem_layout::{kstack, TRAMPOLINE, TRAP_FRAME};
-use crate::param::{NCPU, NPROC};
+use crate::param::{NCPU, NOFILE, NPROC};
 use crate::riscv::{intr_get, intr_off, intr_on, rtp, PageTable, PAGE_SIZE, PTE_R, PTE_W, PTE_X};
-use crate::string::mem_set;
+use crate::string::{mem_copy, mem_set};
 use crate::trap::user_trap_ret;
-use crate::vm::{kvm_map, map_pages, uvm_free, uvm_init, uvm_unmap};
+use crate::vm::{copy_in, copy_out, kvm_map, map_pages, uvm_copy, uvm_free, uvm_init, uvm_unmap};
 use core::fmt::Write;
 use core::mem::size_of;
 use core::ptr::null_mut;
@@ -133,18 +134,34 @@ pub enum ProcState {
     Zombie,
 }
 
+impl ProcState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProcState::Unused => "unused",
+            ProcState::Sleeping => "sleep",
+            ProcState::Runnable => "runnable",
+            ProcState::Running => "run",
+            ProcState::Zombie => "zombie",
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct Proc {
     pub state: ProcState,
     pub parent: *mut Proc,
     pub killed: i32,
+    pub xstate: i32, // exit status to be returned to the parent's wait
     pub pid: i32,
+    pub chan: u64,      // if non-zero, sleeping on chan
+    pub name: [u8; 16], // for debugging, '\0' terminated
 
     pub kstack: u64,
     pub size: u64,
     pub page_table: PageTable,
     pub trap_frame: *mut TrapFrame,
     pub context: Context,
+    pub ofile: [*mut File; NOFILE],
 }
 
 impl Proc {
@@ -153,13 +170,17 @@ impl Proc {
             state: ProcState::Unused,
             parent: null_mut(),
             killed: 0,
+            xstate: 0,
             pid: 0,
+            chan: 0,
+            name: [0; 16],
 
             kstack: 0,
             size: 0,
             page_table: null_mut(),
             trap_frame: null_mut(),
             context: Context::new(),
+            ofile: [null_mut(); NOFILE],
         }
     }
 }
@@ -168,6 +189,8 @@ static mut PROC: [Proc; NPROC as usize] = [Proc::new(); NPROC as usize];
 
 static mut NEXT_PID: i32 = 1;
 
+static mut INIT_PROC: *mut Proc = null_mut();
+
 pub fn my_proc() -> *mut Proc {
     let old = intr_get();
     // if don't disable intr here, then when the process
@@ -286,6 +309,7 @@ fn alloc_proc() -> *mut Proc {
 
         (*p).trap_frame = kalloc() as *mut TrapFrame;
         if (*p).trap_frame.is_null() {
+            free_proc(p);
             return null_mut();
         }
 
@@ -313,6 +337,13 @@ fn alloc_proc() -> *mut Proc {
 
 fn free_proc(p: *mut Proc) {
     unsafe {
+        for fd in 0..NOFILE {
+            if !(*p).ofile[fd].is_null() {
+                file_close((*p).ofile[fd]);
+                (*p).ofile[fd] = null_mut();
+            }
+        }
+
         if !(*p).trap_frame.is_null() {
             kfree((*p).trap_frame as *mut u64)
         }
@@ -326,7 +357,9 @@ fn free_proc(p: *mut Proc) {
         (*p).size = 0;
         (*p).parent = null_mut();
         (*p).killed = 0;
+        (*p).xstate = 0;
         (*p).pid = 0;
+        (*p).name = [0; 16];
         (*p).state = ProcState::Unused;
     }
 }
@@ -346,17 +379,144 @@ pub fn user_init() {
     let p = alloc_proc();
 
     unsafe {
+        INIT_PROC = p;
+
         // !--dangerous--!
         uvm_init((*p).page_table, first_proc as *const u64, PAGE_SIZE);
         (*p).size = PAGE_SIZE;
 
         (*(*p).trap_frame).epc = 0;
         (*(*p).trap_frame).sp = PAGE_SIZE;
+        mem_copy(
+            &mut (*p).name as *mut u8 as *mut u64,
+            "initcode".as_ptr() as *const u64,
+            8,
+        );
 
         (*p).state = ProcState::Runnable;
     }
 }
 
+// create a child process copying the current one,
+// return the pid of the child to the parent, and 0 to the child
+pub fn fork() -> i32 {
+    let p = my_proc();
+    let np = alloc_proc();
+    if np.is_null() {
+        return -1;
+    }
+
+    unsafe {
+        if uvm_copy((*p).page_table, (*np).page_table, (*p).size) < 0 {
+            free_proc(np);
+            return -1;
+        }
+        (*np).size = (*p).size;
+
+        mem_copy(
+            (*np).trap_frame as *mut u64,
+            (*p).trap_frame as *const u64,
+            size_of::<TrapFrame>() as u64,
+        );
+        // fork returns 0 in the child
+        (*(*np).trap_frame).a0 = 0;
+
+        // open files, pipe ends included, are shared with the child
+        for fd in 0..NOFILE {
+            if !(*p).ofile[fd].is_null() {
+                (*np).ofile[fd] = file_dup((*p).ofile[fd]);
+            }
+        }
+        (*np).name = (*p).name;
+        (*np).parent = p;
+
+        (*np).state = ProcState::Runnable;
+        (*np).pid
+    }
+}
+
+// pass the children of p to init
+fn reparent(p: *mut Proc) {
+    for i in 0..NPROC as usize {
+        unsafe {
+            if PROC[i].parent == p {
+                PROC[i].parent = INIT_PROC;
+                wakeup(INIT_PROC as u64);
+            }
+        }
+    }
+}
+
+// exit the current process, it remains a zombie
+// until its parent calls wait()
+pub fn exit(status: i32) -> ! {
+    let p = my_proc();
+    unsafe {
+        if p == INIT_PROC {
+            panicc!("init exiting");
+        }
+
+        // close all open files
+        for fd in 0..NOFILE {
+            if !(*p).ofile[fd].is_null() {
+                file_close((*p).ofile[fd]);
+                (*p).ofile[fd] = null_mut();
+            }
+        }
+
+        reparent(p);
+
+        // the parent might be sleeping in wait()
+        wakeup((*p).parent as u64);
+
+        (*p).xstate = status;
+        (*p).state = ProcState::Zombie;
+    }
+
+    sched();
+    panicc!("zombie exit");
+}
+
+// wait for a child to exit, copy its exit status to the user address
+// addr if not 0, and return its pid. return -1 if there is no child
+pub fn wait(addr: u64) -> i32 {
+    let p = my_proc();
+    loop {
+        let mut have_kids = false;
+        for i in 0..NPROC as usize {
+            unsafe {
+                if PROC[i].parent != p {
+                    continue;
+                }
+                have_kids = true;
+
+                if let ProcState::Zombie = PROC[i].state {
+                    let pid = PROC[i].pid;
+                    if addr != 0
+                        && copy_out(
+                            (*p).page_table,
+                            addr,
+                            &PROC[i].xstate as *const i32 as *const u8,
+                            size_of::<i32>() as u64,
+                        ) < 0
+                    {
+                        return -1;
+                    }
+                    free_proc(&mut PROC[i]);
+                    return pid;
+                }
+            }
+        }
+
+        if !have_kids || unsafe { (*p).killed } != 0 {
+            return -1;
+        }
+
+        // wait for a child to exit
+        sleep(p as u64);
+    }
+}
+
 extern "C" {
     fn switch(old: *const Context, new: *const Context);
 }
@@ -387,6 +547,58 @@ pub fn yield_cpu() {
     sched();
 }
 
+// the process in slot i of the process table, null if the slot is unused
+pub fn proc_at(i: usize) -> *mut Proc {
+    unsafe {
+        match PROC[i].state {
+            ProcState::Unused => null_mut(),
+            _ => &mut PROC[i] as *mut Proc,
+        }
+    }
+}
+
+// the process with pid, null if none
+pub fn find_proc(pid: i32) -> *mut Proc {
+    for i in 0..NPROC as usize {
+        let p = proc_at(i);
+        if !p.is_null() && unsafe { (*p).pid } == pid {
+            return p;
+        }
+    }
+
+    null_mut()
+}
+
+// give up the cpu until wakeup(chan) is called
+pub fn sleep(chan: u64) {
+    let p = my_proc();
+    if p.is_null() {
+        panicc!("sleep: no proc");
+    }
+
+    unsafe {
+        (*p).chan = chan;
+        (*p).state = ProcState::Sleeping;
+    }
+    sched();
+    unsafe {
+        (*p).chan = 0;
+    }
+}
+
+// wake up all processes sleeping on chan
+pub fn wakeup(chan: u64) {
+    for i in 0..NPROC as usize {
+        unsafe {
+            if let ProcState::Sleeping = PROC[i].state {
+                if PROC[i].chan == chan {
+                    PROC[i].state = ProcState::Runnable;
+                }
+            }
+        }
+    }
+}
+
 fn fork_ret() {
     user_trap_ret();
 }
@@ -396,7 +608,11 @@ pub fn scheduler() {
     println!("addr 0x{:x}", first_proc as u64);
 
     loop {
-        // avoid dead lock by intr_on()?
+        // avoid deadlock by letting devices interrupt, sleeping processes
+        // are woken up from interrupt handlers. processes run with
+        // interrupts off, which is what keeps sleep() free of lost wakeups
+        intr_on();
+        intr_off();
 
         for i in 0..NPROC as usize {
             unsafe {
@@ -413,3 +629,25 @@ pub fn scheduler() {
         }
     }
 }
+
+// copy to either a user address (of the current process) or a kernel address
+pub fn either_copy_out(user_dst: u32, dst: u64, src: *const u8, len: u64) -> i32 {
+    if user_dst != 0 {
+        let p = my_proc();
+        unsafe { copy_out((*p).page_table, dst, src, len) }
+    } else {
+        mem_copy(dst as *mut u64, src as *const u64, len);
+        0
+    }
+}
+
+// copy from either a user address (of the current process) or a kernel address
+pub fn either_copy_in(dst: *mut u8, user_src: u32, src: u64, len: u64) -> i32 {
+    if user_src != 0 {
+        let p = my_proc();
+        unsafe { copy_in((*p).page_table, dst, src, len) }
+    } else {
+        mem_copy(dst as *mut u64, src as *const u64, len);
+        0
+    }
+}
@@ -0,0 +1,585 @@
+use crate::block_cache::bcache_stat;
+use crate::file::FileType;
+use crate::fs::{name_is, DirEnt, DirIter, FileSystem, Inode};
+use crate::kalloc::{kalloc, kfree, kmem_stat};
+use crate::mem_layout::{TRAMPOLINE, TRAP_FRAME, UART_IRQ, VIRTIO0_IRQ, VIRTIO_NSLOT};
+use crate::net::{IpAddr, NET_STAT};
+use crate::param::{NBUF, NDISK, NOFILE, NPROC, NPROCNODE, ROOT_DEV};
+use crate::plic::{IRQ_COUNT, NIRQ};
+use crate::proc::{either_copy_out, find_proc, proc_at, Proc};
+use crate::riscv::{PAGE_SIZE, PTE_R, PTE_U, PTE_V, PTE_W, PTE_X};
+use crate::stat::{mode_to_dtype, Stat, DT_DIR, S_IFDIR, S_IFREG};
+use crate::tcp::{tcp_count, tcp_stat};
+use crate::timer::{FREQ, INTERVAL};
+use crate::trap::TICKS;
+use crate::virtio_disk::virtio_disk_stat;
+use crate::vm::walk_flags;
+use core::cmp::min;
+use core::fmt::{self, Write};
+use core::ptr::null_mut;
+
+// process file system, mounted on /proc.
+//
+// the files hold no data, their content is generated on every read
+// from the kernel state:
+//   /proc/meminfo, bcache, interrupts, uptime, diskstats, net
+//   /proc/<pid>/status, maps, fd, cmdline
+
+const PROCFS_DEV: u32 = 0x300;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Kind {
+    Root = 1,
+    Meminfo,
+    Bcache,
+    Interrupts,
+    Uptime,
+    Diskstats,
+    Net,
+    PidDir,
+    Status,
+    Maps,
+    Fd,
+    Cmdline,
+}
+
+const KIND_BITS: u32 = 4; // ino is pid << KIND_BITS | kind
+
+const TOP: [(&str, Kind); 6] = [
+    ("meminfo", Kind::Meminfo),
+    ("bcache", Kind::Bcache),
+    ("interrupts", Kind::Interrupts),
+    ("uptime", Kind::Uptime),
+    ("diskstats", Kind::Diskstats),
+    ("net", Kind::Net),
+];
+
+const PER_PID: [(&str, Kind); 4] = [
+    ("status", Kind::Status),
+    ("maps", Kind::Maps),
+    ("fd", Kind::Fd),
+    ("cmdline", Kind::Cmdline),
+];
+
+#[derive(Copy, Clone)]
+pub struct ProcNode {
+    kind: Kind,
+    pid: i32, // 0 for the files not in a <pid> directory
+    ref_cnt: u32,
+}
+
+impl ProcNode {
+    const fn new() -> Self {
+        ProcNode {
+            kind: Kind::Root,
+            pid: 0,
+            ref_cnt: 0,
+        }
+    }
+}
+
+static mut PROC_NODE: [ProcNode; NPROCNODE] = [ProcNode::new(); NPROCNODE];
+
+// get a node with a reference held, sharing the one in use if any
+fn node_get(kind: Kind, pid: i32) -> Option<*mut ProcNode> {
+    let mut empty = None;
+    unsafe {
+        for i in 0..NPROCNODE {
+            let node = &mut PROC_NODE[i];
+            if node.ref_cnt > 0 && node.kind == kind && node.pid == pid {
+                node.ref_cnt += 1;
+                return Some(node as *mut ProcNode);
+            }
+            if empty.is_none() && node.ref_cnt == 0 {
+                empty = Some(i);
+            }
+        }
+
+        let i = empty?;
+        PROC_NODE[i] = ProcNode {
+            kind,
+            pid,
+            ref_cnt: 1,
+        };
+        Some(&mut PROC_NODE[i] as *mut ProcNode)
+    }
+}
+
+pub struct ProcFs {
+    root: *mut ProcNode,
+}
+
+static mut PROCFS: ProcFs = ProcFs { root: null_mut() };
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "procfs"
+    }
+
+    fn dev(&self) -> u32 {
+        PROCFS_DEV
+    }
+
+    fn root(&mut self) -> *mut dyn Inode {
+        unsafe { (*self.root).dup() }
+    }
+}
+
+pub fn procfs_init() -> *mut dyn FileSystem {
+    unsafe {
+        PROCFS.root = match node_get(Kind::Root, 0) {
+            Some(root) => root,
+            None => {
+                panicc!("procfs_init");
+            }
+        };
+        &mut PROCFS as *mut ProcFs
+    }
+}
+
+// formats text into a page, what doesn't fit is dropped
+struct PageWriter {
+    buf: *mut u8,
+    len: usize,
+}
+
+impl Write for PageWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = min(s.len(), PAGE_SIZE as usize - self.len);
+        unsafe {
+            for (i, c) in s.bytes().take(n).enumerate() {
+                *self.buf.add(self.len + i) = c;
+            }
+        }
+        self.len += n;
+        Ok(())
+    }
+}
+
+fn is_dir_kind(kind: Kind) -> bool {
+    kind == Kind::Root || kind == Kind::PidDir
+}
+
+// the '\0' terminated name of p
+fn proc_name(p: *const Proc) -> &'static str {
+    unsafe {
+        let name = &(*p).name;
+        let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        core::str::from_utf8(&name[..len]).unwrap_or("?")
+    }
+}
+
+fn gen_status(p: *mut Proc, w: &mut PageWriter) -> fmt::Result {
+    unsafe {
+        let ppid = match (*p).parent.is_null() {
+            true => 0,
+            false => (*(*p).parent).pid,
+        };
+        write!(w, "Name:\t{}\n", proc_name(p))?;
+        write!(w, "State:\t{}\n", (*p).state.name())?;
+        write!(w, "Pid:\t{}\n", (*p).pid)?;
+        write!(w, "PPid:\t{}\n", ppid)?;
+        write!(w, "Size:\t{} bytes\n", (*p).size)
+    }
+}
+
+fn write_range(w: &mut PageWriter, start: u64, end: u64, flags: u64, what: &str) -> fmt::Result {
+    let perm = |bit: u64, c: char| match flags & bit {
+        0 => '-',
+        _ => c,
+    };
+    write!(
+        w,
+        "{:08x}-{:08x} {}{}{}{} {}\n",
+        start,
+        end,
+        perm(PTE_R, 'r'),
+        perm(PTE_W, 'w'),
+        perm(PTE_X, 'x'),
+        perm(PTE_U, 'u'),
+        what
+    )
+}
+
+// the user memory, merging pages with the same permission
+fn gen_maps(p: *mut Proc, w: &mut PageWriter) -> fmt::Result {
+    let (page_table, size) = unsafe { ((*p).page_table, (*p).size) };
+    let mut start = 0;
+    let mut start_flags = 0;
+    let mut va = 0;
+    while va <= size {
+        let flags = match va < size {
+            true => walk_flags(page_table, va) & (PTE_V | PTE_R | PTE_W | PTE_X | PTE_U),
+            false => 0,
+        };
+        if flags != start_flags {
+            if start_flags & PTE_V != 0 {
+                write_range(w, start, va, start_flags, "")?;
+            }
+            start = va;
+            start_flags = flags;
+        }
+        va += PAGE_SIZE;
+    }
+
+    let flags = walk_flags(page_table, TRAP_FRAME);
+    write_range(w, TRAP_FRAME, TRAP_FRAME + PAGE_SIZE, flags, "[trapframe]")?;
+    let flags = walk_flags(page_table, TRAMPOLINE);
+    write_range(w, TRAMPOLINE, TRAMPOLINE + PAGE_SIZE, flags, "[trampoline]")
+}
+
+// one line per open file descriptor
+fn gen_fd(p: *mut Proc, w: &mut PageWriter) -> fmt::Result {
+    for fd in 0..NOFILE {
+        unsafe {
+            let f = (*p).ofile[fd];
+            if f.is_null() {
+                continue;
+            }
+
+            write!(
+                w,
+                "{}\t{}{}\t",
+                fd,
+                if (*f).readable { 'r' } else { '-' },
+                if (*f).writable { 'w' } else { '-' }
+            )?;
+            match ((*f).ftype, (*f).inode) {
+                (FileType::Inode, Some(inode)) => write!(
+                    w,
+                    "inode {:x}:{}\tpos {}\n",
+                    (*inode).dev(),
+                    (*inode).ino(),
+                    (*f).off
+                )?,
+                (FileType::Pipe, _) => write!(w, "pipe\n")?,
+                (FileType::Socket, _) => write!(w, "socket\n")?,
+                _ => write!(w, "none\n")?,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// arguments are '\0' separated, there is no exec yet so it's the name
+fn gen_cmdline(p: *mut Proc, w: &mut PageWriter) -> fmt::Result {
+    write!(w, "{}\0", proc_name(p))
+}
+
+fn gen_meminfo(w: &mut PageWriter) -> fmt::Result {
+    let (total, free) = kmem_stat();
+    let kb = PAGE_SIZE / 1024;
+    write!(w, "MemTotal:\t{} kB\n", total * kb)?;
+    write!(w, "MemFree:\t{} kB\n", free * kb)?;
+    write!(w, "MemUsed:\t{} kB\n", (total - free) * kb)
+}
+
+fn gen_bcache(w: &mut PageWriter) -> fmt::Result {
+    let (hit, miss) = bcache_stat();
+    write!(w, "buffers\t{}\n", NBUF)?;
+    write!(w, "hits\t{}\n", hit)?;
+    write!(w, "misses\t{}\n", miss)
+}
+
+fn gen_interrupts(w: &mut PageWriter) -> fmt::Result {
+    write!(w, "timer\t{}\n", unsafe { TICKS })?;
+    for irq in 1..NIRQ {
+        let cnt = unsafe { IRQ_COUNT[irq] };
+        if cnt == 0 {
+            continue;
+        }
+
+        let name = match irq as u64 {
+            UART_IRQ => "uart",
+            x if x >= VIRTIO0_IRQ && x < VIRTIO0_IRQ + VIRTIO_NSLOT => "virtio",
+            _ => "",
+        };
+        write!(w, "{}\t{}\t{}\n", irq, cnt, name)?;
+    }
+
+    Ok(())
+}
+
+// per disk: requests, notifications, completions, interrupts
+fn gen_diskstats(w: &mut PageWriter) -> fmt::Result {
+    write!(w, "dev\trequests\tnotifies\tcompletions\tintrs\n")?;
+    for dev in ROOT_DEV..ROOT_DEV + NDISK as u32 {
+        if let Some(st) = virtio_disk_stat(dev) {
+            write!(
+                w,
+                "{}\t{}\t{}\t{}\t{}\n",
+                dev, st.requests, st.notifies, st.completions, st.intrs
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+// frame and loopback counters, then a line per tcp connection
+fn gen_net(w: &mut PageWriter) -> fmt::Result {
+    let st = unsafe { &NET_STAT };
+    write!(w, "rx\t{}\tdropped\t{}\n", st.rx_frames, st.rx_dropped)?;
+    write!(w, "tx\t{}\tdropped\t{}\n", st.tx_frames, st.tx_dropped)?;
+    write!(w, "lo\t{}\tdropped\t{}\n", st.lo_packets, st.lo_dropped)?;
+    for i in 0..tcp_count() {
+        if let Some((state, lport, raddr, rport)) = tcp_stat(i) {
+            write!(w, "tcp\t{}\t{}\t", lport, state.name())?;
+            write_addr(w, raddr)?;
+            write!(w, ":{}\n", rport)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_addr(w: &mut PageWriter, addr: IpAddr) -> fmt::Result {
+    let a = addr.to_be_bytes();
+    write!(w, "{}.{}.{}.{}", a[0], a[1], a[2], a[3])
+}
+
+// seconds since boot, in hundredths
+fn gen_uptime(w: &mut PageWriter) -> fmt::Result {
+    let cycles = unsafe { TICKS } * INTERVAL;
+    write!(w, "{}.{:02}\n", cycles / FREQ, cycles % FREQ * 100 / FREQ)
+}
+
+// generate the content of a file, false if the process is gone
+fn generate(kind: Kind, pid: i32, w: &mut PageWriter) -> bool {
+    let p = match pid {
+        0 => null_mut(),
+        _ => find_proc(pid),
+    };
+    if pid != 0 && p.is_null() {
+        return false;
+    }
+
+    // a full page only truncates the text
+    let _ = match kind {
+        Kind::Meminfo => gen_meminfo(w),
+        Kind::Bcache => gen_bcache(w),
+        Kind::Interrupts => gen_interrupts(w),
+        Kind::Uptime => gen_uptime(w),
+        Kind::Diskstats => gen_diskstats(w),
+        Kind::Net => gen_net(w),
+        Kind::Status => gen_status(p, w),
+        Kind::Maps => gen_maps(p, w),
+        Kind::Fd => gen_fd(p, w),
+        Kind::Cmdline => gen_cmdline(p, w),
+        Kind::Root | Kind::PidDir => return false,
+    };
+
+    true
+}
+
+// the decimal digits of n into buf, return the length
+fn fmt_pid(mut n: i32, buf: &mut [u8; 11]) -> usize {
+    let mut tmp: [u8; 11] = [0; 11];
+    let mut len = 0;
+    loop {
+        tmp[len] = b'0' + (n % 10) as u8;
+        len += 1;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    for i in 0..len {
+        buf[i] = tmp[len - 1 - i];
+    }
+    len
+}
+
+// parse a positive decimal number
+fn parse_pid(name: *const u8) -> Option<i32> {
+    let mut pid: i32 = 0;
+    let mut i = 0;
+    unsafe {
+        while *name.add(i) != 0 {
+            let c = *name.add(i);
+            if c < b'0' || c > b'9' || i >= 9 {
+                return None;
+            }
+            pid = pid * 10 + (c - b'0') as i32;
+            i += 1;
+        }
+    }
+
+    match i {
+        0 => None,
+        _ => Some(pid),
+    }
+}
+
+impl ProcNode {
+    fn ino_of(kind: Kind, pid: i32) -> u32 {
+        (pid as u32) << KIND_BITS | kind as u32
+    }
+}
+
+impl DirIter for ProcNode {
+    // pos 0 is ".", 1 is "..", then the files, then the <pid> directories
+    fn next(&mut self, pos: &mut u32, ent: &mut DirEnt) -> bool {
+        if *pos < 2 {
+            let name: &[u8] = match *pos {
+                0 => b".",
+                _ => b"..",
+            };
+            ent.set(self.ino(), name, DT_DIR);
+            *pos += 1;
+            return true;
+        }
+
+        let files: &[(&str, Kind)] = match self.kind {
+            Kind::Root => &TOP,
+            _ => &PER_PID,
+        };
+        let i = (*pos - 2) as usize;
+        if i < files.len() {
+            let (name, kind) = files[i];
+            let ino = ProcNode::ino_of(kind, self.pid);
+            ent.set(ino, name.as_bytes(), mode_to_dtype(S_IFREG));
+            *pos += 1;
+            return true;
+        }
+
+        if self.kind != Kind::Root {
+            return false;
+        }
+
+        let mut slot = i - files.len();
+        while slot < NPROC as usize {
+            let p = proc_at(slot);
+            slot += 1;
+            *pos += 1;
+            if p.is_null() {
+                continue;
+            }
+
+            let pid = unsafe { (*p).pid };
+            let mut name: [u8; 11] = [0; 11];
+            let len = fmt_pid(pid, &mut name);
+            ent.set(ProcNode::ino_of(Kind::PidDir, pid), &name[..len], DT_DIR);
+            return true;
+        }
+
+        false
+    }
+}
+
+impl Inode for ProcNode {
+    fn dev(&self) -> u32 {
+        PROCFS_DEV
+    }
+
+    fn ino(&self) -> u32 {
+        ProcNode::ino_of(self.kind, self.pid)
+    }
+
+    fn mode(&self) -> u16 {
+        match is_dir_kind(self.kind) {
+            true => S_IFDIR | 0o555,
+            false => S_IFREG | 0o444,
+        }
+    }
+
+    fn stat(&self, st: &mut Stat) {
+        *st = Stat::new();
+        st.dev = PROCFS_DEV;
+        st.ino = self.ino();
+        st.mode = self.mode();
+        st.nlink = 1;
+    }
+
+    fn dup(&mut self) -> *mut dyn Inode {
+        self.ref_cnt += 1;
+        self as *mut ProcNode
+    }
+
+    fn put(&mut self) {
+        if self.ref_cnt == 0 {
+            panicc!("procfs put: ref_cnt");
+        }
+        self.ref_cnt -= 1;
+    }
+
+    fn read(&mut self, is_uaddr: u32, dst: u64, off: u32, n: u32) -> i32 {
+        if is_dir_kind(self.kind) {
+            return -1;
+        }
+
+        // generated into a page, the kernel stack is too small
+        let page = kalloc() as *mut u8;
+        if page.is_null() {
+            return -1;
+        }
+        let mut w = PageWriter { buf: page, len: 0 };
+
+        let mut r = -1;
+        if generate(self.kind, self.pid, &mut w) {
+            let start = min(off as usize, w.len);
+            let len = min(n as usize, w.len - start);
+            r = unsafe { either_copy_out(is_uaddr, dst, page.add(start), len as u64) };
+            if r == 0 {
+                r = len as i32;
+            }
+        }
+
+        kfree(page as *mut u64);
+        r
+    }
+
+    fn write(&mut self, _is_uaddr: u32, _src: u64, _off: u32, _n: u32) -> i32 {
+        -1
+    }
+
+    fn truncate(&mut self) -> i32 {
+        -1
+    }
+
+    fn lookup(&mut self, name: *const u8) -> Option<*mut dyn Inode> {
+        if !is_dir_kind(self.kind) {
+            return None;
+        }
+
+        if name_is(name, ".") {
+            return Some(self.dup());
+        }
+        if name_is(name, "..") {
+            return Some(node_get(Kind::Root, 0)?);
+        }
+
+        if self.kind == Kind::Root {
+            for (file, kind) in TOP.iter() {
+                if name_is(name, file) {
+                    return Some(node_get(*kind, 0)?);
+                }
+            }
+
+            let pid = parse_pid(name)?;
+            if find_proc(pid).is_null() {
+                return None;
+            }
+            return Some(node_get(Kind::PidDir, pid)?);
+        }
+
+        if find_proc(self.pid).is_null() {
+            return None;
+        }
+        for (file, kind) in PER_PID.iter() {
+            if name_is(name, file) {
+                return Some(node_get(*kind, self.pid)?);
+            }
+        }
+
+        None
+    }
+
+    fn dir_iter(&mut self) -> Option<&mut dyn DirIter> {
+        match is_dir_kind(self.kind) {
+            true => Some(self),
+            false => None,
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use crate::mem_layout::clint_mtime;
+use crate::proc::either_copy_out;
+use crate::timer::FREQ;
+use crate::virtio_rng::{virtio_rng_busy, virtio_rng_request};
+use core::cmp::min;
+use core::fmt::Write;
+use core::ptr::{read_volatile, write_volatile};
+
+// the kernel random number generator.
+//
+// a ChaCha20 key and nonce (RFC 8439) make up the whole state. to
+// produce output the keystream is generated with block counters from
+// 1 on, and block 0 becomes the next key and nonce, so state that
+// leaks later tells nothing about earlier output. entropy is mixed
+// in by xoring it into the key and nonce and rekeying the same way.
+//
+// entropy comes from a virtio-rng device at boot and again after
+// every RESEED_BYTES of output, and from the timing of interrupts.
+// without the device, random_init() seeds from the jitter of the
+// timer alone.
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const STATE_LEN: usize = KEY_LEN + NONCE_LEN;
+const BLOCK_LEN: usize = 64;
+
+// ask the device for more after this much output
+const RESEED_BYTES: u64 = 1 << 20;
+
+// how long random_init() waits for the device
+const SEED_TIMEOUT: u64 = FREQ / 10;
+
+struct Rng {
+    state: [u8; STATE_LEN],
+    output: u64, // bytes since the last reseed
+    jitter: u64, // timings not mixed in yet
+    njitter: u32,
+    ready: bool,
+}
+
+static mut RNG: Rng = Rng {
+    state: [0; STATE_LEN],
+    output: 0,
+    jitter: 0,
+    njitter: 0,
+    ready: false,
+};
+
+fn mtime() -> u64 {
+    unsafe { read_volatile(clint_mtime() as *const u64) }
+}
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+// the keystream block counter of the key and nonce in state, RFC 8439 2.3
+fn chacha20_block(state: &[u8; STATE_LEN], counter: u32, out: &mut [u8; BLOCK_LEN]) {
+    let mut init: [u32; 16] = [0; 16];
+    // "expand 32-byte k"
+    init[0] = 0x6170_7865;
+    init[1] = 0x3320_646e;
+    init[2] = 0x7962_2d32;
+    init[3] = 0x6b20_6574;
+    for i in 0..8 {
+        init[4 + i] = word(state, 4 * i);
+    }
+    init[12] = counter;
+    for i in 0..3 {
+        init[13 + i] = word(state, KEY_LEN + 4 * i);
+    }
+
+    let mut s = init;
+    for _ in 0..10 {
+        // column rounds
+        quarter_round(&mut s, 0, 4, 8, 12);
+        quarter_round(&mut s, 1, 5, 9, 13);
+        quarter_round(&mut s, 2, 6, 10, 14);
+        quarter_round(&mut s, 3, 7, 11, 15);
+        // diagonal rounds
+        quarter_round(&mut s, 0, 5, 10, 15);
+        quarter_round(&mut s, 1, 6, 11, 12);
+        quarter_round(&mut s, 2, 7, 8, 13);
+        quarter_round(&mut s, 3, 4, 9, 14);
+    }
+
+    for i in 0..16 {
+        let w = s[i].wrapping_add(init[i]).to_le_bytes();
+        out[4 * i..4 * i + 4].copy_from_slice(&w);
+    }
+}
+
+fn word(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]])
+}
+
+// replace the key and nonce with block 0 of their keystream
+fn rekey(rng: &mut Rng) {
+    let mut block: [u8; BLOCK_LEN] = [0; BLOCK_LEN];
+    chacha20_block(&rng.state, 0, &mut block);
+    rng.state.copy_from_slice(&block[..STATE_LEN]);
+    wipe(&mut block);
+}
+
+// clear what was a key or output, in a way the compiler keeps
+fn wipe(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        unsafe { write_volatile(b, 0) };
+    }
+}
+
+// mix data into the state
+pub fn random_add(data: &[u8]) {
+    let rng = unsafe { &mut RNG };
+    for chunk in data.chunks(STATE_LEN) {
+        for i in 0..chunk.len() {
+            rng.state[i] ^= chunk[i];
+        }
+        rekey(rng);
+    }
+}
+
+// called at each device interrupt. the time is folded in now and
+// mixed in at the next output
+pub fn random_add_jitter() {
+    unsafe {
+        RNG.jitter = RNG.jitter.rotate_left(7) ^ mtime();
+        RNG.njitter += 1;
+    }
+}
+
+// seed from how many loop iterations fit in a tick of mtime, which
+// varies with caches, the host and whatever else qemu is doing
+fn jitter_seed() {
+    let mut sample: u64 = 0;
+    for i in 0..256 {
+        let t = mtime();
+        let mut n: u64 = 0;
+        while mtime() == t {
+            n += 1;
+        }
+        sample = sample.rotate_left(5) ^ n ^ t;
+        if i % 8 == 7 {
+            random_add(&sample.to_le_bytes());
+        }
+    }
+}
+
+// seed the generator, before the first process runs.
+// interrupts must be on for the virtio-rng answer to come in
+pub fn random_init() {
+    if virtio_rng_request() {
+        let start = mtime();
+        while virtio_rng_busy() && mtime() - start < SEED_TIMEOUT {}
+        if virtio_rng_busy() {
+            println!("random: virtio-rng timed out");
+        }
+    } else {
+        println!("random: no virtio-rng, seeding from timer jitter");
+    }
+
+    // never hurts, and it is all there is without the device
+    jitter_seed();
+    unsafe {
+        RNG.ready = true;
+    }
+}
+
+// has random_init() seeded the generator
+pub fn random_ready() -> bool {
+    unsafe { RNG.ready }
+}
+
+// fill buf with random bytes
+pub fn random_bytes(buf: &mut [u8]) {
+    let rng = unsafe { &mut RNG };
+    if rng.njitter > 0 {
+        let jitter = rng.jitter;
+        rng.jitter = 0;
+        rng.njitter = 0;
+        random_add(&jitter.to_le_bytes());
+    }
+
+    let mut block: [u8; BLOCK_LEN] = [0; BLOCK_LEN];
+    for (i, chunk) in buf.chunks_mut(BLOCK_LEN).enumerate() {
+        chacha20_block(&rng.state, i as u32 + 1, &mut block);
+        chunk.copy_from_slice(&block[..chunk.len()]);
+    }
+    wipe(&mut block);
+    rekey(rng);
+
+    rng.output += buf.len() as u64;
+    if rng.output >= RESEED_BYTES {
+        // the answer is mixed in by the interrupt handler
+        virtio_rng_request();
+        rng.output = 0;
+    }
+}
+
+// copy n random bytes to dst, user or kernel address.
+// return n, or -1 on a bad address
+pub fn random_read(is_uaddr: u32, dst: u64, n: u32) -> i32 {
+    let mut buf: [u8; 256] = [0; 256];
+    let mut cnt: u32 = 0;
+    while cnt < n {
+        let len = min(n - cnt, buf.len() as u32);
+        random_bytes(&mut buf[..len as usize]);
+        let r = either_copy_out(is_uaddr, dst + cnt as u64, &buf as *const u8, len as u64);
+        if r == -1 {
+            wipe(&mut buf);
+            return -1;
+        }
+        cnt += len;
+    }
+    wipe(&mut buf);
+
+    n as i32
+}
@@ -0,0 +1,96 @@
+use crate::proc::{either_copy_in, either_copy_out};
+use crate::riscv::PAGE_SIZE;
+use core::cmp::min;
+use core::ptr::{copy_nonoverlapping, null_mut};
+
+// a byte ring in a page, the buffers of tcp connections and unix
+// domain sockets. the owner kallocs the page and sets buf.
+
+pub const RING_SIZE: usize = PAGE_SIZE as usize;
+
+#[derive(Copy, Clone)]
+pub struct Ring {
+    pub buf: *mut u8,
+    r: usize,     // where the first byte is
+    pub n: usize, // bytes in the ring
+}
+
+impl Ring {
+    pub const fn new() -> Self {
+        Ring {
+            buf: null_mut(),
+            r: 0,
+            n: 0,
+        }
+    }
+
+    pub fn space(&self) -> usize {
+        RING_SIZE - self.n
+    }
+
+    // the two pieces of the len bytes starting off bytes in,
+    // as (index, length)
+    fn pieces(&self, off: usize, len: usize) -> [(usize, usize); 2] {
+        let start = (self.r + off) % RING_SIZE;
+        let first = min(len, RING_SIZE - start);
+        [(start, first), (0, len - first)]
+    }
+
+    // append what fits of data, return how much did
+    pub fn put(&mut self, data: &[u8]) -> usize {
+        let len = min(data.len(), self.space());
+        let mut done = 0;
+        for (i, n) in self.pieces(self.n, len).iter() {
+            unsafe {
+                copy_nonoverlapping(data[done..].as_ptr(), self.buf.add(*i), *n);
+            }
+            done += n;
+        }
+        self.n += len;
+        len
+    }
+
+    // append n bytes from src, return -1 if src is bad
+    pub fn put_from(&mut self, is_uaddr: u32, src: u64, n: usize) -> i32 {
+        let mut done = 0;
+        for (i, len) in self.pieces(self.n, n).iter() {
+            let dst = unsafe { self.buf.add(*i) };
+            if either_copy_in(dst, is_uaddr, src + done as u64, *len as u64) == -1 {
+                return -1;
+            }
+            done += len;
+        }
+        self.n += n;
+        0
+    }
+
+    // copy the bytes starting off bytes in to dst, keeping them
+    pub fn peek(&self, off: usize, dst: &mut [u8]) {
+        let mut done = 0;
+        for (i, n) in self.pieces(off, dst.len()).iter() {
+            unsafe {
+                copy_nonoverlapping(self.buf.add(*i), dst[done..].as_mut_ptr(), *n);
+            }
+            done += n;
+        }
+    }
+
+    // move the first n bytes to dst, return -1 if dst is bad
+    pub fn get_to(&mut self, is_uaddr: u32, dst: u64, n: usize) -> i32 {
+        let mut done = 0;
+        for (i, len) in self.pieces(0, n).iter() {
+            let src = unsafe { self.buf.add(*i) };
+            if either_copy_out(is_uaddr, dst + done as u64, src, *len as u64) == -1 {
+                return -1;
+            }
+            done += len;
+        }
+        self.consume(n);
+        0
+    }
+
+    pub fn consume(&mut self, n: usize) {
+        self.r = (self.r + n) % RING_SIZE;
+        self.n -= n;
+    }
+}
@@ -0,0 +1,263 @@
+use crate::fs::{create, mount, path_lookup, symlink, umount, unlink, BLOCK_SIZE};
+use crate::param::{MAX_PATH, ROOT_DEV};
+use crate::stat::Stat;
+use crate::stat::{S_IFDIR, S_IFREG};
+use crate::tmpfs::tmpfs_new;
+use crate::virtio_disk::virtio_disk_capacity;
+use core::fmt::Write;
+
+// file system tests run by kinit() in a kernel built with the
+// selftest feature, on a fresh image made by mkfs.minix.
+// test/selftest.sh builds and boots such a kernel, waits for the
+// "selftest:" result line and checks the image with fsck.minix.
+// everything a test creates is removed again, so that fsck sees
+// the image as mkfs left it plus whatever the log replayed.
+//
+// a kernel built with the crashtest feature runs crashtest()
+// instead, which test/crash.sh kills in the middle of its writes
+
+static mut FAILED: u32 = 0;
+
+static mut DATA: [u8; 4 * BLOCK_SIZE as usize] = [0; 4 * BLOCK_SIZE as usize];
+
+fn check(ok: bool, what: &str) {
+    if !ok {
+        println!("selftest: FAIL {}", what);
+        unsafe {
+            FAILED += 1;
+        }
+    }
+}
+
+// dir and name joined into a '\0' terminated path
+fn path(dir: &str, name: &str) -> [u8; MAX_PATH] {
+    let mut p: [u8; MAX_PATH] = [0; MAX_PATH];
+    let mut n = 0;
+    for &c in dir.as_bytes().iter().chain(b"/").chain(name.as_bytes()) {
+        p[n] = c;
+        n += 1;
+    }
+    p
+}
+
+// the inode number at dir/name, 0 if there is none
+fn ino_of(dir: &str, name: &str) -> u32 {
+    match path_lookup(&mut path(dir, name) as *mut u8, true) {
+        Some(inode) => unsafe {
+            let ino = (*inode).ino();
+            (*inode).put();
+            ino
+        },
+        None => 0,
+    }
+}
+
+// create dir/name, return its inode number, 0 if that failed
+fn make(dir: &str, name: &str, mode: u16) -> u32 {
+    match create(&mut path(dir, name) as *mut u8, mode) {
+        Some(inode) => unsafe {
+            let ino = (*inode).ino();
+            (*inode).put();
+            ino
+        },
+        None => 0,
+    }
+}
+
+fn remove(dir: &str, name: &str) -> i32 {
+    unlink(&mut path(dir, name) as *mut u8)
+}
+
+// a name must not match an entry it is a prefix of, or the reverse
+fn test_lookup(dir: &str) {
+    let abc = make(dir, "abc", S_IFREG | 0o644);
+    check(abc != 0, "lookup: create abc");
+    check(ino_of(dir, "a") == 0, "lookup: a matches abc");
+    check(ino_of(dir, "ab") == 0, "lookup: ab matches abc");
+    check(ino_of(dir, "abcd") == 0, "lookup: abcd matches abc");
+
+    // create and unlink act on a, not on abc
+    let a = make(dir, "a", S_IFREG | 0o644);
+    check(a != 0 && a != abc, "lookup: create a");
+    check(remove(dir, "a") == 0, "lookup: unlink a");
+    check(ino_of(dir, "abc") == abc, "lookup: abc after unlink a");
+    check(remove(dir, "abc") == 0, "lookup: unlink abc");
+    check(ino_of(dir, "abc") == 0, "lookup: abc after unlink");
+}
+
+// a link resolves to its target, one too long for a path is refused
+fn test_symlink(dir: &str) {
+    let f = make(dir, "f", S_IFREG | 0o644);
+    let mut target = path(dir, "f");
+    check(
+        symlink(&target as *const u8, &mut path(dir, "l") as *mut u8) == 0,
+        "symlink: create l",
+    );
+    check(f != 0 && ino_of(dir, "l") == f, "symlink: l is f");
+    check(remove(dir, "l") == 0, "symlink: unlink l");
+
+    for c in target.iter_mut().take(MAX_PATH - 1) {
+        *c = b'x';
+    }
+    target[MAX_PATH - 1] = 0;
+    check(
+        symlink(&target as *const u8, &mut path(dir, "l") as *mut u8) == -1,
+        "symlink: too long target",
+    );
+    check(ino_of(dir, "l") == 0, "symlink: l after too long target");
+    check(remove(dir, "f") == 0, "symlink: unlink f");
+}
+
+// blocks of the big file, a few past what the direct, indirect
+// and double indirect zones of a file with 1 KB zones map
+const BIG_BLOCKS: u32 = 7 + 256 + 65536 + 64;
+
+// fill DATA with the 4 blocks from bn, each holding its number
+fn fill_blocks(bn: u32) {
+    unsafe {
+        for (i, blk) in DATA.chunks_mut(BLOCK_SIZE as usize).enumerate() {
+            let n = bn + i as u32;
+            for c in blk.iter_mut() {
+                *c = (n % 251) as u8;
+            }
+            blk[..4].copy_from_slice(&n.to_le_bytes());
+        }
+    }
+}
+
+// does DATA hold the 4 blocks from bn, as fill_blocks() makes them
+fn blocks_ok(bn: u32) -> bool {
+    unsafe {
+        for (i, blk) in DATA.chunks(BLOCK_SIZE as usize).enumerate() {
+            let n = bn + i as u32;
+            if blk[..4] != n.to_le_bytes() || blk[4..].iter().any(|&c| c != (n % 251) as u8) {
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+// write a file that takes the triple indirect zone, read it back,
+// then remove it, which truncates it a chunk at a time
+fn test_bigfile() {
+    let f = match create(&mut path("", "big") as *mut u8, S_IFREG | 0o644) {
+        Some(f) => f,
+        None => {
+            check(false, "bigfile: create");
+            return;
+        }
+    };
+
+    let n = unsafe { DATA.len() } as u32;
+    let mut ok = true;
+    let mut bn = 0;
+    while ok && bn < BIG_BLOCKS {
+        fill_blocks(bn);
+        let r = unsafe { (*f).write(0, &DATA as *const u8 as u64, bn * BLOCK_SIZE, n) };
+        ok = r == n as i32;
+        bn += n / BLOCK_SIZE;
+        if bn % 8192 == 0 {
+            println!("selftest: bigfile {} blocks written", bn);
+        }
+    }
+    check(ok, "bigfile: write");
+
+    let mut st = Stat::new();
+    unsafe { (*f).stat(&mut st) };
+    check(st.size == (bn * BLOCK_SIZE) as u64, "bigfile: size");
+
+    bn = 0;
+    while ok && bn < BIG_BLOCKS {
+        let r = unsafe { (*f).read(0, &mut DATA as *mut u8 as u64, bn * BLOCK_SIZE, n) };
+        ok = r == n as i32 && blocks_ok(bn);
+        if !ok {
+            println!("selftest: bigfile block {} read back wrong", bn);
+        }
+        bn += n / BLOCK_SIZE;
+    }
+    check(ok, "bigfile: read back");
+
+    unsafe { (*f).put() };
+    check(remove("", "big") == 0, "bigfile: unlink");
+}
+
+pub fn selftest() {
+    println!("selftest: start");
+
+    check(make("", "st", S_IFDIR | 0o755) != 0, "mkdir /st");
+    test_lookup("/st");
+    test_symlink("/st");
+
+    // the same on a tmpfs, where "." of its root isn't ".."
+    check(make("/st", "tmp", S_IFDIR | 0o755) != 0, "mkdir /st/tmp");
+    let mounted = match tmpfs_new(16) {
+        Some(fs) => mount(fs, &mut path("/st", "tmp") as *mut u8) == 0,
+        None => false,
+    };
+    check(mounted, "mount /st/tmp");
+    if mounted {
+        check(
+            ino_of("/st/tmp", ".") == ino_of("/st", "tmp"),
+            "lookup: . of a mount is ..",
+        );
+        test_lookup("/st/tmp");
+        test_symlink("/st/tmp");
+        check(
+            umount(&mut path("/st", "tmp") as *mut u8) == 0,
+            "umount /st/tmp",
+        );
+    }
+    check(remove("/st", "tmp") == 0, "rmdir /st/tmp");
+    check(remove("", "st") == 0, "rmdir /st");
+
+    // on a disk with room for it
+    if virtio_disk_capacity(ROOT_DEV) * 512 >= (BIG_BLOCKS as u64 + 4096) * BLOCK_SIZE as u64 {
+        test_bigfile();
+    }
+
+    match unsafe { FAILED } {
+        0 => {
+            println!("selftest: ok");
+        }
+        n => {
+            println!("selftest: {} failed", n);
+        }
+    }
+    loop {}
+}
+
+const CRASH_FILES: [&str; 8] = ["f0", "f1", "f2", "f3", "f4", "f5", "f6", "f7"];
+
+// replace the files in /crash one after another with new ones of
+// 4 to 12 blocks, forever. each write takes a few transactions
+pub fn crashtest() {
+    make("", "crash", S_IFDIR | 0o755); // there after the first run
+    println!("crashtest: running");
+
+    let mut i: usize = 0;
+    loop {
+        let name = CRASH_FILES[i % CRASH_FILES.len()];
+        remove("/crash", name);
+        if let Some(f) = create(&mut path("/crash", name) as *mut u8, S_IFREG | 0o644) {
+            unsafe {
+                for c in DATA.iter_mut() {
+                    *c = i as u8;
+                }
+                let mut off = 0;
+                for _ in 0..i % 3 + 1 {
+                    let n = DATA.len() as u32;
+                    (*f).write(0, &DATA as *const u8 as u64, off, n);
+                    off += n;
+                }
+                (*f).put();
+            }
+        }
+
+        i += 1;
+        if i % 100 == 0 {
+            println!("crashtest: {} files", i);
+        }
+    }
+}
@@ -0,0 +1,511 @@
+use crate::errno::{EADDRNOTAVAIL, EAFNOSUPPORT, EINVAL, ENOTCONN, EOPNOTSUPP, EPIPE};
+use crate::file::{File, FileType};
+use crate::net::{is_loopback, loop_drain, IpAddr, NET_ADDR};
+use crate::param::{MAX_PATH, NSOCKET};
+use crate::proc::{either_copy_in, either_copy_out};
+use crate::tcp::{
+    tcp_accept, tcp_alloc, tcp_bind, tcp_close, tcp_connect, tcp_listen, tcp_name, tcp_recv,
+    tcp_send, tcp_shutdown,
+};
+use crate::udp::{udp_alloc, udp_bind, udp_connect, udp_free, udp_name, udp_recv, udp_send};
+use crate::unix::{
+    unix_accept, unix_alloc, unix_bind, unix_close, unix_connect, unix_listen, unix_peer_path,
+    unix_recv, unix_send, unix_shutdown, Rights, UNIX_PATH_MAX,
+};
+use core::cmp::min;
+use core::mem::size_of;
+use core::ptr::null_mut;
+
+// sockets, the files user programs reach the network and each other
+// through.
+//
+// a socket of AF_INET is a tcp connection (SOCK_STREAM) or a udp
+// endpoint (SOCK_DGRAM) of the stack, one of AF_UNIX a socket of
+// unix.rs, this only translates the calls and the addresses. a
+// socket lives until its last file is closed, so a forked child
+// shares it with the parent.
+
+pub const AF_UNIX: i32 = 1;
+pub const AF_INET: i32 = 2;
+
+pub const SOCK_STREAM: i32 = 1;
+pub const SOCK_DGRAM: i32 = 2;
+
+// how in shutdown()
+pub const SHUT_RD: i32 = 0;
+pub const SHUT_WR: i32 = 1;
+pub const SHUT_RDWR: i32 = 2;
+
+// control messages of sendmsg() and recvmsg()
+pub const SOL_SOCKET: i32 = 1;
+pub const SCM_RIGHTS: i32 = 1; // the data is file descriptors
+pub const MSG_CTRUNC: i32 = 0x08; // some control data was lost
+
+const INADDR_ANY: IpAddr = 0;
+
+// struct sockaddr_in, the port and the address in network byte order
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct SockAddrIn {
+    pub family: u16,
+    pub port: u16,
+    pub addr: u32,
+    pub zero: [u8; 8],
+}
+
+// struct sockaddr_un
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct SockAddrUn {
+    pub family: u16,
+    pub path: [u8; UNIX_PATH_MAX], // '\0' terminated if shorter
+}
+
+// struct msghdr of sendmsg() and recvmsg()
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct MsgHdr {
+    pub name: u64, // the address, 0 if none
+    pub namelen: u32,
+    pub iov: u64, // of iovlen struct iovec
+    pub iovlen: u64,
+    pub control: u64, // of controllen bytes
+    pub controllen: u64,
+    pub flags: i32, // set by recvmsg()
+}
+
+impl MsgHdr {
+    pub const fn new() -> Self {
+        MsgHdr {
+            name: 0,
+            namelen: 0,
+            iov: 0,
+            iovlen: 0,
+            control: 0,
+            controllen: 0,
+            flags: 0,
+        }
+    }
+}
+
+// struct iovec
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct IoVec {
+    pub base: u64,
+    pub len: u64,
+}
+
+// struct cmsghdr, followed by the data of a control message
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct CmsgHdr {
+    pub len: u64, // the header included
+    pub level: i32,
+    pub ctype: i32,
+}
+
+#[derive(Copy, Clone)]
+pub struct Socket {
+    used: bool,
+    domain: i32,
+    stype: i32,
+    id: usize, // the tcp connection, udp endpoint or unix socket
+    shut_rd: bool,
+    shut_wr: bool,
+}
+
+static mut SOCKET: [Socket; NSOCKET] = [Socket {
+    used: false,
+    domain: 0,
+    stype: 0,
+    id: 0,
+    shut_rd: false,
+    shut_wr: false,
+}; NSOCKET];
+
+fn sock_alloc(domain: i32, stype: i32, id: usize) -> *mut Socket {
+    unsafe {
+        for s in SOCKET.iter_mut() {
+            if !s.used {
+                *s = Socket {
+                    used: true,
+                    domain,
+                    stype,
+                    id,
+                    shut_rd: false,
+                    shut_wr: false,
+                };
+                return s as *mut Socket;
+            }
+        }
+    }
+
+    null_mut()
+}
+
+// packets the stack sent to itself are taken in once it's left,
+// see net.rs
+fn inet_done(r: i32) -> i32 {
+    loop_drain();
+    r
+}
+
+// make f, with readable and writable already set, a new socket.
+// return 0, or -1 or -errno, f must then be closed by the caller
+pub fn socket_open(f: *mut File, domain: i32, stype: i32) -> i32 {
+    let id = match (domain, stype) {
+        (AF_INET, SOCK_STREAM) => tcp_alloc(),
+        (AF_INET, SOCK_DGRAM) => udp_alloc(),
+        (AF_UNIX, SOCK_STREAM) | (AF_UNIX, SOCK_DGRAM) => unix_alloc(stype),
+        (AF_INET, _) | (AF_UNIX, _) => return -EINVAL,
+        _ => return -EAFNOSUPPORT,
+    };
+    if id < 0 {
+        return -1;
+    }
+
+    let s = sock_alloc(domain, stype, id as usize);
+    if s.is_null() {
+        endpoint_free(domain, stype, id as usize);
+        return -1;
+    }
+    unsafe {
+        (*f).ftype = FileType::Socket;
+        (*f).sock = s;
+    }
+    0
+}
+
+fn endpoint_free(domain: i32, stype: i32, id: usize) {
+    match (domain, stype) {
+        (AF_UNIX, _) => unix_close(id),
+        (_, SOCK_STREAM) => {
+            tcp_close(id);
+            loop_drain();
+        }
+        _ => udp_free(id),
+    }
+}
+
+// the last file of s is closed
+pub fn socket_close(s: *mut Socket) {
+    unsafe {
+        endpoint_free((*s).domain, (*s).stype, (*s).id);
+        (*s).used = false;
+    }
+}
+
+// the address and port of the sockaddr_in of len bytes at addr,
+// None if it isn't one
+fn addr_in(addr: u64, len: u32) -> Option<(IpAddr, u16)> {
+    if (len as usize) < size_of::<SockAddrIn>() {
+        return None;
+    }
+    let mut sa = SockAddrIn {
+        family: 0,
+        port: 0,
+        addr: 0,
+        zero: [0; 8],
+    };
+    let dst = &mut sa as *mut SockAddrIn as *mut u8;
+    if either_copy_in(dst, 1, addr, size_of::<SockAddrIn>() as u64) == -1 {
+        return None;
+    }
+    if sa.family != AF_INET as u16 {
+        return None;
+    }
+    Some((u32::from_be(sa.addr), u16::from_be(sa.port)))
+}
+
+// copy the path of the sockaddr_un of len bytes at addr to path,
+// '\0' terminated. false if it isn't one, or has an empty path
+fn addr_un(addr: u64, len: u32, path: &mut [u8; MAX_PATH]) -> bool {
+    let len = len as usize;
+    if len <= size_of::<u16>() || len > size_of::<SockAddrUn>() {
+        return false;
+    }
+    let mut sa = SockAddrUn {
+        family: 0,
+        path: [0; UNIX_PATH_MAX],
+    };
+    let dst = &mut sa as *mut SockAddrUn as *mut u8;
+    if either_copy_in(dst, 1, addr, len as u64) == -1 {
+        return false;
+    }
+    if sa.family != AF_UNIX as u16 || sa.path[0] == 0 {
+        return false;
+    }
+
+    let n = sa
+        .path
+        .iter()
+        .position(|&c| c == 0)
+        .unwrap_or(UNIX_PATH_MAX);
+    path[..n].copy_from_slice(&sa.path[..n]);
+    path[n] = 0;
+    true
+}
+
+// copy the size bytes of the address at sa out to addr, truncated to
+// the length at lenp, which is then set to size. nothing is copied
+// if addr is 0
+fn put_addr(addr: u64, lenp: u64, sa: *const u8, size: usize) -> i32 {
+    if addr == 0 {
+        return 0;
+    }
+    let mut len: u32 = 0;
+    if either_copy_in(&mut len as *mut u32 as *mut u8, 1, lenp, 4) == -1 {
+        return -1;
+    }
+
+    let n = min(len as usize, size);
+    let size = size as u32;
+    if either_copy_out(1, addr, sa, n as u64) == -1
+        || either_copy_out(1, lenp, &size as *const u32 as *const u8, 4) == -1
+    {
+        return -1;
+    }
+    0
+}
+
+fn put_addr_in(addr: u64, lenp: u64, ip: IpAddr, port: u16) -> i32 {
+    let sa = SockAddrIn {
+        family: AF_INET as u16,
+        port: port.to_be(),
+        addr: ip.to_be(),
+        zero: [0; 8],
+    };
+    let size = size_of::<SockAddrIn>();
+    put_addr(addr, lenp, &sa as *const SockAddrIn as *const u8, size)
+}
+
+// the address of an unbound socket is only the family
+fn put_addr_un(addr: u64, lenp: u64, path: &[u8]) -> i32 {
+    let mut sa = SockAddrUn {
+        family: AF_UNIX as u16,
+        path: [0; UNIX_PATH_MAX],
+    };
+    sa.path[..path.len()].copy_from_slice(path);
+    let size = match path.len() {
+        0 => size_of::<u16>(),
+        n => size_of::<u16>() + n + 1,
+    };
+    put_addr(addr, lenp, &sa as *const SockAddrUn as *const u8, size)
+}
+
+pub fn socket_bind(s: *mut Socket, addr: u64, len: u32) -> i32 {
+    let s = unsafe { &mut *s };
+    if s.domain == AF_UNIX {
+        let mut path: [u8; MAX_PATH] = [0; MAX_PATH];
+        if !addr_un(addr, len, &mut path) {
+            return -EINVAL;
+        }
+        return unix_bind(s.id, &mut path as *mut u8);
+    }
+
+    let (ip, port) = match addr_in(addr, len) {
+        Some(a) => a,
+        None => return -EINVAL,
+    };
+    if ip != INADDR_ANY && ip != NET_ADDR && !is_loopback(ip) {
+        return -EADDRNOTAVAIL;
+    }
+    let (lport, ..) = match s.stype {
+        SOCK_STREAM => tcp_name(s.id),
+        _ => udp_name(s.id),
+    };
+    if lport != 0 {
+        return -EINVAL;
+    }
+    match s.stype {
+        SOCK_STREAM => tcp_bind(s.id, port),
+        _ => udp_bind(s.id, port),
+    }
+}
+
+pub fn socket_listen(s: *mut Socket, backlog: i32) -> i32 {
+    let s = unsafe { &mut *s };
+    let backlog = backlog.max(0) as usize;
+    match (s.domain, s.stype) {
+        (AF_UNIX, _) => unix_listen(s.id, backlog),
+        (_, SOCK_STREAM) => tcp_listen(s.id, backlog),
+        _ => -EOPNOTSUPP,
+    }
+}
+
+// wait for a connection on s and make nf its socket, with the peer's
+// address copied out as by put_addr(). return 0, or -1 or -errno,
+// nf must then be closed by the caller
+pub fn socket_accept(s: *mut Socket, nf: *mut File, addr: u64, lenp: u64) -> i32 {
+    let s = unsafe { &mut *s };
+    if s.stype != SOCK_STREAM {
+        return -EOPNOTSUPP;
+    }
+
+    // the socket first, a connection taken can't be put back
+    let ns = sock_alloc(s.domain, SOCK_STREAM, 0);
+    if ns.is_null() {
+        return -1;
+    }
+    let id = match s.domain {
+        AF_UNIX => unix_accept(s.id),
+        _ => inet_done(tcp_accept(s.id)),
+    };
+    if id < 0 {
+        unsafe { (*ns).used = false };
+        return id;
+    }
+    unsafe {
+        (*ns).id = id as usize;
+        (*nf).ftype = FileType::Socket;
+        (*nf).sock = ns;
+    }
+
+    if s.domain == AF_UNIX {
+        return put_addr_un(addr, lenp, unix_peer_path(id as usize));
+    }
+    let (_, raddr, rport) = tcp_name(id as usize);
+    put_addr_in(addr, lenp, raddr, rport)
+}
+
+// connect a stream socket, wait until the connection is established.
+// a datagram socket only takes note of where to send by default
+pub fn socket_connect(s: *mut Socket, addr: u64, len: u32) -> i32 {
+    let s = unsafe { &mut *s };
+    if s.domain == AF_UNIX {
+        let mut path: [u8; MAX_PATH] = [0; MAX_PATH];
+        if !addr_un(addr, len, &mut path) {
+            return -EINVAL;
+        }
+        return unix_connect(s.id, &mut path as *mut u8);
+    }
+
+    let (ip, port) = match addr_in(addr, len) {
+        Some((ip, port)) if port != 0 => (ip, port),
+        _ => return -EINVAL,
+    };
+    match s.stype {
+        SOCK_STREAM => inet_done(tcp_connect(s.id, ip, port)),
+        _ => udp_connect(s.id, ip, port),
+    }
+}
+
+// send n bytes from src, with the files of rights if any. a datagram
+// goes to the address of len bytes at addr, or where s is connected
+// if addr is 0. return the number of bytes sent, or -1 or -errno
+pub fn socket_send(
+    s: *mut Socket,
+    src: u64,
+    n: u32,
+    addr: u64,
+    len: u32,
+    rights: Option<&Rights>,
+) -> i32 {
+    let s = unsafe { &mut *s };
+    if s.shut_wr {
+        return -EPIPE;
+    }
+    if s.domain == AF_UNIX {
+        let mut path: [u8; MAX_PATH] = [0; MAX_PATH];
+        let path = match addr {
+            0 => null_mut(),
+            _ if s.stype == SOCK_DGRAM && addr_un(addr, len, &mut path) => &mut path as *mut u8,
+            _ if s.stype == SOCK_DGRAM => return -EINVAL,
+            _ => null_mut(),
+        };
+        return match rights {
+            Some(rights) => unix_send(s.id, src, n, path, rights),
+            None => unix_send(s.id, src, n, path, &Rights::new()),
+        };
+    }
+
+    if rights.map_or(false, |r| r.n > 0) {
+        return -EOPNOTSUPP;
+    }
+    if s.stype == SOCK_STREAM {
+        return inet_done(tcp_send(s.id, 1, src, n));
+    }
+    let (ip, port) = match addr {
+        0 => (0, 0),
+        _ => match addr_in(addr, len) {
+            Some((ip, port)) if port != 0 => (ip, port),
+            _ => return -EINVAL,
+        },
+    };
+    inet_done(udp_send(s.id, ip, port, 1, src, n))
+}
+
+// receive at most n bytes to dst, and the files sent along to rights.
+// without rights they are closed. the sender of a datagram is copied
+// out as by put_addr(), a stream has no use for addr. return the
+// number of bytes received, 0 at end of file, or -1 or -errno
+pub fn socket_recv(
+    s: *mut Socket,
+    dst: u64,
+    n: u32,
+    addr: u64,
+    lenp: u64,
+    rights: Option<&mut Rights>,
+) -> i32 {
+    let s = unsafe { &mut *s };
+    if s.shut_rd {
+        return 0;
+    }
+    if s.domain == AF_UNIX {
+        let mut got = Rights::new();
+        let mut from: [u8; UNIX_PATH_MAX] = [0; UNIX_PATH_MAX];
+        let mut r = unix_recv(s.id, dst, n, &mut got, &mut from);
+        if r >= 0 && s.stype == SOCK_DGRAM {
+            let len = from.iter().position(|&c| c == 0).unwrap_or(0);
+            if put_addr_un(addr, lenp, &from[..len]) == -1 {
+                r = -1;
+            }
+        }
+        match rights {
+            Some(rights) if r >= 0 => *rights = got,
+            _ => got.close(),
+        }
+        return r;
+    }
+
+    if let Some(rights) = rights {
+        rights.n = 0;
+    }
+    if s.stype == SOCK_STREAM {
+        return inet_done(tcp_recv(s.id, 1, dst, n));
+    }
+    let (r, ip, port) = udp_recv(s.id, 1, dst, n);
+    if r >= 0 && put_addr_in(addr, lenp, ip, port) == -1 {
+        return -1;
+    }
+    inet_done(r)
+}
+
+// stop receiving, sending or both on s. a tcp connection sends its
+// FIN once the data queued has gone, the peer of a unix stream
+// socket reads the end of file
+pub fn socket_shutdown(s: *mut Socket, how: i32) -> i32 {
+    let s = unsafe { &mut *s };
+    if how != SHUT_RD && how != SHUT_WR && how != SHUT_RDWR {
+        return -EINVAL;
+    }
+    if s.domain == AF_UNIX {
+        let r = unix_shutdown(s.id, how != SHUT_RD);
+        if r < 0 {
+            return r;
+        }
+    } else if s.stype == SOCK_STREAM {
+        let (_, _, rport) = tcp_name(s.id);
+        if rport == 0 {
+            return -ENOTCONN;
+        }
+        if how != SHUT_RD {
+            tcp_shutdown(s.id);
+            loop_drain();
+        }
+    }
+    s.shut_rd |= how != SHUT_WR;
+    s.shut_wr |= how != SHUT_RD;
+    0
+}
@@ -0,0 +1,84 @@
+// user visible file metadata, shared with user space so the layout must not change.
+// mode keeps the minix encoding (type in the high bits, rwx in the low bits),
+// which is the same as the posix one
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Stat {
+    pub dev: u32,
+    pub ino: u32,
+    pub mode: u16,
+    pub nlink: u16,
+    pub uid: u16,
+    pub gid: u16,
+    pub size: u64,
+    pub atime: u32,
+    pub mtime: u32,
+    pub ctime: u32,
+    pub rdev: u32, // major << 8 | minor of a device file, keeps the size a multiple of 8
+}
+
+impl Stat {
+    pub const fn new() -> Self {
+        Stat {
+            dev: 0,
+            ino: 0,
+            mode: 0,
+            nlink: 0,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+            rdev: 0,
+        }
+    }
+}
+
+// file types in Stat::mode
+pub const S_IFMT: u16 = 0o170000;
+pub const S_IFSOCK: u16 = 0o140000;
+pub const S_IFLNK: u16 = 0o120000;
+pub const S_IFREG: u16 = 0o100000;
+pub const S_IFDIR: u16 = 0o040000;
+pub const S_IFBLK: u16 = 0o060000;
+pub const S_IFCHR: u16 = 0o020000;
+pub const S_IFIFO: u16 = 0o010000;
+
+// file types in Dirent::dtype
+pub const DT_UNKNOWN: u8 = 0;
+pub const DT_FIFO: u8 = 1;
+pub const DT_CHR: u8 = 2;
+pub const DT_DIR: u8 = 4;
+pub const DT_BLK: u8 = 6;
+pub const DT_REG: u8 = 8;
+pub const DT_LNK: u8 = 10;
+pub const DT_SOCK: u8 = 12;
+
+// header of a record returned by getdents, followed by the
+// '\0' terminated name. records are packed back to back and
+// reclen (a multiple of 8) is the distance to the next one
+#[repr(C)]
+pub struct Dirent {
+    pub ino: u32,
+    pub reclen: u16,
+    pub namelen: u8, // not including '\0'
+    pub dtype: u8,
+}
+
+pub const fn dirent_reclen(namelen: usize) -> usize {
+    (core::mem::size_of::<Dirent>() + namelen + 1 + 7) & !7
+}
+
+pub const fn mode_to_dtype(mode: u16) -> u8 {
+    match mode & S_IFMT {
+        S_IFLNK => DT_LNK,
+        S_IFREG => DT_REG,
+        S_IFDIR => DT_DIR,
+        S_IFIFO => DT_FIFO,
+        S_IFCHR => DT_CHR,
+        S_IFBLK => DT_BLK,
+        S_IFSOCK => DT_SOCK,
+        _ => DT_UNKNOWN,
+    }
+}
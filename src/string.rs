@@ -12,6 +12,10 @@ pub fn mem_set(dst: *mut u64, c: i32, n: u64) -> *mut u64 {
 }
 
 pub fn mem_copy(dst: *mut u64, src: *const u64, size: u64) {
+    if size == 0 {
+        return;
+    }
+
     let dst_i64 = dst as i64;
     let src_i64 = src as i64;
     // overflow
@@ -28,12 +32,17 @@ pub fn mem_copy(dst: *mut u64, src: *const u64, size: u64) {
     }
 }
 
-// if not equal, return the first different char in s1
-pub fn str_cmp(mut s1: *const u8, mut s2: *const u8, mut size: u32) -> u8 {
+// compare at most size chars like strncmp, stopping at the first '\0'.
+// return 0 if equal, else the difference of the first differing chars,
+// so a proper prefix compares less than the longer string
+pub fn str_cmp(mut s1: *const u8, mut s2: *const u8, mut size: u32) -> i32 {
     unsafe {
         while size > 0 {
             if (*s1) != (*s2) {
-                break;
+                return (*s1 as i32) - (*s2 as i32);
+            }
+            if (*s1) == 0 {
+                return 0;
             }
             size -= 1;
             s1 = s1.add(1);
@@ -41,9 +50,5 @@ pub fn str_cmp(mut s1: *const u8, mut s2: *const u8, mut size: u32) -> u8 {
         }
     }
 
-    if size == 0 {
-        return 0;
-    }
-
-    unsafe { *s1 }
+    0
 }
@@ -0,0 +1,125 @@
+use crate::proc::my_proc;
+use crate::sysfile::{
+    sys_accept, sys_bind, sys_close, sys_connect, sys_fstat, sys_fsync, sys_getdents, sys_listen,
+    sys_lstat, sys_mkdir, sys_mkfifo, sys_mount, sys_open, sys_pipe, sys_read, sys_recvfrom,
+    sys_recvmsg, sys_sendmsg, sys_sendto, sys_shutdown, sys_socket, sys_stat, sys_symlink,
+    sys_sync, sys_umount, sys_unlink, sys_write,
+};
+use crate::sysproc::{sys_exit, sys_fork, sys_getrandom, sys_wait};
+use crate::vm::copy_in_str;
+use core::fmt::Write;
+
+// system call numbers, a7 holds the number and a0-a5 the arguments,
+// the return value is put in a0, negative on error (see errno.rs)
+pub const SYS_FORK: u64 = 1;
+pub const SYS_EXIT: u64 = 2;
+pub const SYS_WAIT: u64 = 3;
+pub const SYS_PIPE: u64 = 4;
+pub const SYS_READ: u64 = 5;
+pub const SYS_FSTAT: u64 = 8;
+pub const SYS_OPEN: u64 = 15;
+pub const SYS_WRITE: u64 = 16;
+pub const SYS_UNLINK: u64 = 18;
+pub const SYS_MKDIR: u64 = 20;
+pub const SYS_CLOSE: u64 = 21;
+pub const SYS_STAT: u64 = 22;
+pub const SYS_LSTAT: u64 = 23;
+pub const SYS_GETDENTS: u64 = 24;
+pub const SYS_SYMLINK: u64 = 25;
+pub const SYS_MKFIFO: u64 = 26;
+pub const SYS_MOUNT: u64 = 27;
+pub const SYS_UMOUNT: u64 = 28;
+pub const SYS_FSYNC: u64 = 29;
+pub const SYS_SYNC: u64 = 30;
+pub const SYS_GETRANDOM: u64 = 31;
+pub const SYS_SOCKET: u64 = 32;
+pub const SYS_BIND: u64 = 33;
+pub const SYS_LISTEN: u64 = 34;
+pub const SYS_ACCEPT: u64 = 35;
+pub const SYS_CONNECT: u64 = 36;
+pub const SYS_SENDTO: u64 = 37;
+pub const SYS_RECVFROM: u64 = 38;
+pub const SYS_SHUTDOWN: u64 = 39;
+pub const SYS_SENDMSG: u64 = 40;
+pub const SYS_RECVMSG: u64 = 41;
+
+fn arg_raw(n: u32) -> u64 {
+    let p = my_proc();
+    unsafe {
+        let tf = (*p).trap_frame;
+        match n {
+            0 => (*tf).a0,
+            1 => (*tf).a1,
+            2 => (*tf).a2,
+            3 => (*tf).a3,
+            4 => (*tf).a4,
+            5 => (*tf).a5,
+            _ => {
+                panicc!("arg_raw");
+            }
+        }
+    }
+}
+
+// fetch the nth 32-bit system call argument
+pub fn arg_int(n: u32) -> i32 {
+    arg_raw(n) as i32
+}
+
+// fetch the nth argument as a pointer,
+// legality is checked by copy_in/copy_out
+pub fn arg_addr(n: u32) -> u64 {
+    arg_raw(n)
+}
+
+// fetch the nth argument as a '\0' terminated string
+pub fn arg_str(n: u32, buf: *mut u8, max: usize) -> i32 {
+    let addr = arg_addr(n);
+    let p = my_proc();
+    unsafe { copy_in_str((*p).page_table, buf, addr, max as u64) }
+}
+
+pub fn syscall() {
+    let p = my_proc();
+    unsafe {
+        let num = (*(*p).trap_frame).a7;
+        let ret: i64 = match num {
+            SYS_FORK => sys_fork(),
+            SYS_EXIT => sys_exit(),
+            SYS_WAIT => sys_wait(),
+            SYS_PIPE => sys_pipe(),
+            SYS_READ => sys_read(),
+            SYS_FSTAT => sys_fstat(),
+            SYS_OPEN => sys_open(),
+            SYS_WRITE => sys_write(),
+            SYS_UNLINK => sys_unlink(),
+            SYS_MKDIR => sys_mkdir(),
+            SYS_CLOSE => sys_close(),
+            SYS_STAT => sys_stat(),
+            SYS_LSTAT => sys_lstat(),
+            SYS_GETDENTS => sys_getdents(),
+            SYS_SYMLINK => sys_symlink(),
+            SYS_MKFIFO => sys_mkfifo(),
+            SYS_MOUNT => sys_mount(),
+            SYS_UMOUNT => sys_umount(),
+            SYS_FSYNC => sys_fsync(),
+            SYS_SYNC => sys_sync(),
+            SYS_GETRANDOM => sys_getrandom(),
+            SYS_SOCKET => sys_socket(),
+            SYS_BIND => sys_bind(),
+            SYS_LISTEN => sys_listen(),
+            SYS_ACCEPT => sys_accept(),
+            SYS_CONNECT => sys_connect(),
+            SYS_SENDTO => sys_sendto(),
+            SYS_RECVFROM => sys_recvfrom(),
+            SYS_SHUTDOWN => sys_shutdown(),
+            SYS_SENDMSG => sys_sendmsg(),
+            SYS_RECVMSG => sys_recvmsg(),
+            _ => {
+                println!("{}: unknown sys call {}", (*p).pid, num);
+                -1
+            }
+        };
+        (*(*p).trap_frame).a0 = ret as u64;
+    }
+}
@@ -0,0 +1,687 @@
+use crate::errno::EINVAL;
+use crate::file::{
+    file_alloc, file_close, file_getdents, file_read, file_stat, file_sync, file_write, File,
+    FileType,
+};
+use crate::fs::{
+    create, is_dir, is_fifo, is_socket, mount, name_is, path_lookup, symlink, sync, umount, unlink,
+    FileSystem,
+};
+use crate::minix::minix_mount;
+use crate::param::{MAX_PATH, NOFILE, TMPFS_PAGES};
+use crate::pipe::{fifo_open, pipe_alloc};
+use crate::proc::{either_copy_in, either_copy_out, my_proc};
+use crate::socket::{
+    socket_accept, socket_bind, socket_connect, socket_listen, socket_open, socket_recv,
+    socket_send, socket_shutdown, CmsgHdr, IoVec, MsgHdr, Socket, MSG_CTRUNC, SCM_RIGHTS,
+    SOL_SOCKET,
+};
+use crate::stat::{Stat, S_IFBLK, S_IFDIR, S_IFIFO, S_IFMT, S_IFREG};
+use crate::syscall::{arg_addr, arg_int, arg_str};
+use crate::tmpfs::tmpfs_new;
+use crate::unix::{Rights, UNIX_MAXFD};
+use core::mem::size_of;
+use core::ptr::null_mut;
+
+// open flags
+pub const O_RDONLY: i32 = 0x000;
+pub const O_WRONLY: i32 = 0x001;
+pub const O_RDWR: i32 = 0x002;
+pub const O_CREATE: i32 = 0x200;
+pub const O_TRUNC: i32 = 0x400;
+
+// fetch the nth argument as a file descriptor of the current process
+fn arg_fd(n: u32) -> Option<(i32, *mut File)> {
+    let fd = arg_int(n);
+    if fd < 0 || fd >= NOFILE as i32 {
+        return None;
+    }
+
+    let p = my_proc();
+    let f = unsafe { (*p).ofile[fd as usize] };
+    if f.is_null() {
+        return None;
+    }
+
+    Some((fd, f))
+}
+
+// allocate a file descriptor for f in the current process
+fn fd_alloc(f: *mut File) -> i32 {
+    let p = my_proc();
+    for fd in 0..NOFILE {
+        unsafe {
+            if (*p).ofile[fd].is_null() {
+                (*p).ofile[fd] = f;
+                return fd as i32;
+            }
+        }
+    }
+
+    -1
+}
+
+pub fn sys_read() -> i64 {
+    let n = arg_int(2);
+    let addr = arg_addr(1);
+    match arg_fd(0) {
+        Some((_, f)) if n >= 0 => file_read(f, addr, n as u32) as i64,
+        _ => -1,
+    }
+}
+
+pub fn sys_write() -> i64 {
+    let n = arg_int(2);
+    let addr = arg_addr(1);
+    match arg_fd(0) {
+        Some((_, f)) if n >= 0 => file_write(f, addr, n as u32) as i64,
+        _ => -1,
+    }
+}
+
+pub fn sys_close() -> i64 {
+    match arg_fd(0) {
+        Some((fd, f)) => {
+            let p = my_proc();
+            unsafe {
+                (*p).ofile[fd as usize] = null_mut();
+            }
+            file_close(f);
+            0
+        }
+        None => -1,
+    }
+}
+
+pub fn sys_open() -> i64 {
+    let mut path: [u8; MAX_PATH] = [0; MAX_PATH];
+    if arg_str(0, &mut path as *mut u8, MAX_PATH) < 0 {
+        return -1;
+    }
+    let mode = arg_int(1);
+
+    let inode = match mode & O_CREATE {
+        0 => path_lookup(&mut path as *mut u8, true),
+        _ => create(&mut path as *mut u8, S_IFREG | 0o644),
+    };
+    let inode = match inode {
+        Some(inode) => inode,
+        None => return -1,
+    };
+    // a socket is reached through connect() instead
+    if (is_dir(inode) && mode != O_RDONLY) || is_socket(inode) {
+        unsafe { (*inode).put() };
+        return -1;
+    }
+
+    let f = file_alloc();
+    if f.is_null() {
+        unsafe { (*inode).put() };
+        return -1;
+    }
+    let fd = fd_alloc(f);
+    if fd < 0 {
+        file_close(f);
+        unsafe { (*inode).put() };
+        return -1;
+    }
+
+    unsafe {
+        (*f).readable = mode & O_WRONLY == 0;
+        (*f).writable = mode & O_WRONLY != 0 || mode & O_RDWR != 0;
+    }
+
+    if is_fifo(inode) {
+        // may block until the other end is opened
+        if fifo_open(f, inode) < 0 {
+            unsafe { (*my_proc()).ofile[fd as usize] = null_mut() };
+            file_close(f);
+            return -1;
+        }
+        return fd as i64;
+    }
+
+    if mode & O_TRUNC != 0 && !is_dir(inode) {
+        unsafe { (*inode).truncate() };
+    }
+
+    unsafe {
+        (*f).ftype = FileType::Inode;
+        (*f).inode = Some(inode);
+        (*f).off = 0;
+    }
+
+    fd as i64
+}
+
+pub fn sys_fstat() -> i64 {
+    let addr = arg_addr(1);
+    match arg_fd(0) {
+        Some((_, f)) => file_stat(f, addr) as i64,
+        None => -1,
+    }
+}
+
+pub fn sys_fsync() -> i64 {
+    match arg_fd(0) {
+        Some((_, f)) => file_sync(f) as i64,
+        None => -1,
+    }
+}
+
+pub fn sys_sync() -> i64 {
+    sync() as i64
+}
+
+fn do_stat(follow: bool) -> i64 {
+    let mut path: [u8; MAX_PATH] = [0; MAX_PATH];
+    if arg_str(0, &mut path as *mut u8, MAX_PATH) < 0 {
+        return -1;
+    }
+    let addr = arg_addr(1);
+
+    let inode = match path_lookup(&mut path as *mut u8, follow) {
+        Some(inode) => inode,
+        None => return -1,
+    };
+
+    let mut st = Stat::new();
+    unsafe {
+        (*inode).stat(&mut st);
+        (*inode).put();
+    }
+
+    either_copy_out(
+        1,
+        addr,
+        &st as *const Stat as *const u8,
+        size_of::<Stat>() as u64,
+    ) as i64
+}
+
+// stat(path, st), follow a symbolic link at the end of path
+pub fn sys_stat() -> i64 {
+    do_stat(true)
+}
+
+// lstat(path, st), report a symbolic link at the end of path itself
+pub fn sys_lstat() -> i64 {
+    do_stat(false)
+}
+
+// getdents(fd, buf, n), fill buf with packed Dirent records
+pub fn sys_getdents() -> i64 {
+    let n = arg_int(2);
+    let addr = arg_addr(1);
+    match arg_fd(0) {
+        Some((_, f)) if n >= 0 => file_getdents(f, addr, n as u32) as i64,
+        _ => -1,
+    }
+}
+
+// mkdir(path)
+pub fn sys_mkdir() -> i64 {
+    let mut path: [u8; MAX_PATH] = [0; MAX_PATH];
+    if arg_str(0, &mut path as *mut u8, MAX_PATH) < 0 {
+        return -1;
+    }
+
+    match create(&mut path as *mut u8, S_IFDIR | 0o755) {
+        Some(inode) => {
+            unsafe { (*inode).put() };
+            0
+        }
+        None => -1,
+    }
+}
+
+// mkfifo(path), create a named pipe
+pub fn sys_mkfifo() -> i64 {
+    let mut path: [u8; MAX_PATH] = [0; MAX_PATH];
+    if arg_str(0, &mut path as *mut u8, MAX_PATH) < 0 {
+        return -1;
+    }
+
+    match create(&mut path as *mut u8, S_IFIFO | 0o644) {
+        Some(inode) => {
+            unsafe { (*inode).put() };
+            0
+        }
+        None => -1,
+    }
+}
+
+// unlink(path)
+pub fn sys_unlink() -> i64 {
+    let mut path: [u8; MAX_PATH] = [0; MAX_PATH];
+    if arg_str(0, &mut path as *mut u8, MAX_PATH) < 0 {
+        return -1;
+    }
+
+    unlink(&mut path as *mut u8) as i64
+}
+
+// symlink(target, path), create path as a symbolic link to target
+pub fn sys_symlink() -> i64 {
+    let mut target: [u8; MAX_PATH] = [0; MAX_PATH];
+    let mut path: [u8; MAX_PATH] = [0; MAX_PATH];
+    if arg_str(0, &mut target as *mut u8, MAX_PATH) < 0
+        || arg_str(1, &mut path as *mut u8, MAX_PATH) < 0
+    {
+        return -1;
+    }
+
+    symlink(&target as *const u8, &mut path as *mut u8) as i64
+}
+
+// pipe(fds), fds[0] is the read end and fds[1] the write end
+pub fn sys_pipe() -> i64 {
+    let addr = arg_addr(0);
+    let (rf, wf) = match pipe_alloc() {
+        Some(ends) => ends,
+        None => return -1,
+    };
+
+    let fd0 = fd_alloc(rf);
+    let fd1 = match fd0 {
+        -1 => -1,
+        _ => fd_alloc(wf),
+    };
+    let p = my_proc();
+    if fd1 < 0 {
+        if fd0 >= 0 {
+            unsafe { (*p).ofile[fd0 as usize] = null_mut() };
+        }
+        file_close(rf);
+        file_close(wf);
+        return -1;
+    }
+
+    let fds: [i32; 2] = [fd0, fd1];
+    if either_copy_out(
+        1,
+        addr,
+        &fds as *const i32 as *const u8,
+        size_of::<[i32; 2]>() as u64,
+    ) == -1
+    {
+        unsafe {
+            (*p).ofile[fd0 as usize] = null_mut();
+            (*p).ofile[fd1 as usize] = null_mut();
+        }
+        file_close(rf);
+        file_close(wf);
+        return -1;
+    }
+
+    0
+}
+
+// the minix file system on the block device file at path
+fn minix_from(path: *mut u8) -> Option<*mut dyn FileSystem> {
+    let inode = path_lookup(path, true)?;
+    let mut st = Stat::new();
+    unsafe {
+        (*inode).stat(&mut st);
+        (*inode).put();
+    }
+    if st.mode & S_IFMT != S_IFBLK {
+        return None;
+    }
+
+    // the minor number is the device number of the disk
+    minix_mount(st.rdev & 0xff)
+}
+
+// mount(source, target, fstype), fstype is "minix" with source the
+// block device file to mount, or "tmpfs" with source ignored
+pub fn sys_mount() -> i64 {
+    let mut source: [u8; MAX_PATH] = [0; MAX_PATH];
+    let mut target: [u8; MAX_PATH] = [0; MAX_PATH];
+    let mut fstype: [u8; 16] = [0; 16];
+    if arg_str(0, &mut source as *mut u8, MAX_PATH) < 0
+        || arg_str(1, &mut target as *mut u8, MAX_PATH) < 0
+        || arg_str(2, &mut fstype as *mut u8, 16) < 0
+    {
+        return -1;
+    }
+
+    let fs = if name_is(&fstype as *const u8, "minix") {
+        minix_from(&mut source as *mut u8)
+    } else if name_is(&fstype as *const u8, "tmpfs") {
+        tmpfs_new(TMPFS_PAGES)
+    } else {
+        None
+    };
+    let fs = match fs {
+        Some(fs) => fs,
+        None => return -1,
+    };
+
+    if mount(fs, &mut target as *mut u8) < 0 {
+        // nothing of it is in use yet
+        unsafe { (*fs).unmount() };
+        return -1;
+    }
+
+    0
+}
+
+// umount(target)
+pub fn sys_umount() -> i64 {
+    let mut target: [u8; MAX_PATH] = [0; MAX_PATH];
+    if arg_str(0, &mut target as *mut u8, MAX_PATH) < 0 {
+        return -1;
+    }
+
+    umount(&mut target as *mut u8) as i64
+}
+
+// fetch the nth argument as a file descriptor of a socket
+fn arg_sock(n: u32) -> Option<*mut Socket> {
+    match arg_fd(n) {
+        Some((_, f)) if unsafe { (*f).ftype } == FileType::Socket => Some(unsafe { (*f).sock }),
+        _ => None,
+    }
+}
+
+// a file descriptor for a new file, both readable and writable,
+// to be set up by the caller
+fn fd_new() -> Option<(i32, *mut File)> {
+    let f = file_alloc();
+    if f.is_null() {
+        return None;
+    }
+    let fd = fd_alloc(f);
+    if fd < 0 {
+        file_close(f);
+        return None;
+    }
+
+    unsafe {
+        (*f).readable = true;
+        (*f).writable = true;
+    }
+    Some((fd, f))
+}
+
+// give up a file descriptor from fd_new() that couldn't be set up
+fn fd_undo(fd: i32, f: *mut File) {
+    unsafe { (*my_proc()).ofile[fd as usize] = null_mut() };
+    file_close(f);
+}
+
+// socket(domain, type)
+pub fn sys_socket() -> i64 {
+    let domain = arg_int(0);
+    let stype = arg_int(1);
+    let (fd, f) = match fd_new() {
+        Some(new) => new,
+        None => return -1,
+    };
+
+    let r = socket_open(f, domain, stype);
+    if r < 0 {
+        fd_undo(fd, f);
+        return r as i64;
+    }
+    fd as i64
+}
+
+// bind(fd, addr, addrlen)
+pub fn sys_bind() -> i64 {
+    let addr = arg_addr(1);
+    let len = arg_int(2);
+    match arg_sock(0) {
+        Some(s) => socket_bind(s, addr, len as u32) as i64,
+        None => -1,
+    }
+}
+
+// listen(fd, backlog)
+pub fn sys_listen() -> i64 {
+    let backlog = arg_int(1);
+    match arg_sock(0) {
+        Some(s) => socket_listen(s, backlog) as i64,
+        None => -1,
+    }
+}
+
+// accept(fd, addr, addrlen), addr may be 0
+pub fn sys_accept() -> i64 {
+    let addr = arg_addr(1);
+    let lenp = arg_addr(2);
+    let s = match arg_sock(0) {
+        Some(s) => s,
+        None => return -1,
+    };
+    // the descriptor first, a connection taken can't be put back
+    let (fd, nf) = match fd_new() {
+        Some(new) => new,
+        None => return -1,
+    };
+
+    // may block until a connection comes
+    let r = socket_accept(s, nf, addr, lenp);
+    if r < 0 {
+        fd_undo(fd, nf);
+        return r as i64;
+    }
+    fd as i64
+}
+
+// connect(fd, addr, addrlen)
+pub fn sys_connect() -> i64 {
+    let addr = arg_addr(1);
+    let len = arg_int(2);
+    match arg_sock(0) {
+        Some(s) => socket_connect(s, addr, len as u32) as i64,
+        None => -1,
+    }
+}
+
+// sendto(fd, buf, n, flags, addr, addrlen), addr may be 0.
+// there are no flags yet
+pub fn sys_sendto() -> i64 {
+    let buf = arg_addr(1);
+    let n = arg_int(2);
+    let flags = arg_int(3);
+    let addr = arg_addr(4);
+    let len = arg_int(5);
+    if n < 0 || flags != 0 {
+        return -EINVAL as i64;
+    }
+    match arg_sock(0) {
+        Some(s) => socket_send(s, buf, n as u32, addr, len as u32, None) as i64,
+        None => -1,
+    }
+}
+
+// recvfrom(fd, buf, n, flags, addr, addrlen), addr may be 0
+pub fn sys_recvfrom() -> i64 {
+    let buf = arg_addr(1);
+    let n = arg_int(2);
+    let flags = arg_int(3);
+    let addr = arg_addr(4);
+    let lenp = arg_addr(5);
+    if n < 0 || flags != 0 {
+        return -EINVAL as i64;
+    }
+    match arg_sock(0) {
+        Some(s) => socket_recv(s, buf, n as u32, addr, lenp, None) as i64,
+        None => -1,
+    }
+}
+
+// shutdown(fd, how)
+pub fn sys_shutdown() -> i64 {
+    let how = arg_int(1);
+    match arg_sock(0) {
+        Some(s) => socket_shutdown(s, how) as i64,
+        None => -1,
+    }
+}
+
+// the one buffer of the struct msghdr at addr, copied to msg.
+// None if msg can't be read or has more buffers
+fn msg_buf(addr: u64, msg: &mut MsgHdr) -> Option<(u64, u32)> {
+    let dst = msg as *mut MsgHdr as *mut u8;
+    if either_copy_in(dst, 1, addr, size_of::<MsgHdr>() as u64) == -1 {
+        return None;
+    }
+
+    let mut iov = IoVec { base: 0, len: 0 };
+    match msg.iovlen {
+        0 => {}
+        1 => {
+            let dst = &mut iov as *mut IoVec as *mut u8;
+            if either_copy_in(dst, 1, msg.iov, size_of::<IoVec>() as u64) == -1 {
+                return None;
+            }
+        }
+        _ => return None,
+    }
+    if iov.len > i32::MAX as u64 {
+        return None;
+    }
+    Some((iov.base, iov.len as u32))
+}
+
+// the offset of field in the struct msghdr at msg
+fn msg_off<T>(msg: &MsgHdr, field: &T) -> u64 {
+    field as *const T as u64 - msg as *const MsgHdr as u64
+}
+
+// sendmsg(fd, msg, flags), with one buffer in msg. the descriptors of
+// an SCM_RIGHTS control message are passed along, on AF_UNIX sockets
+pub fn sys_sendmsg() -> i64 {
+    let addr = arg_addr(1);
+    let flags = arg_int(2);
+    let s = match arg_sock(0) {
+        Some(s) => s,
+        None => return -1,
+    };
+    let mut msg = MsgHdr::new();
+    let (buf, n) = match msg_buf(addr, &mut msg) {
+        Some(buf) if flags == 0 => buf,
+        _ => return -EINVAL as i64,
+    };
+
+    let mut rights = Rights::new();
+    if msg.controllen > 0 {
+        let hdr_len = size_of::<CmsgHdr>() as u64;
+        let mut cmsg = CmsgHdr {
+            len: 0,
+            level: 0,
+            ctype: 0,
+        };
+        let dst = &mut cmsg as *mut CmsgHdr as *mut u8;
+        if either_copy_in(dst, 1, msg.control, hdr_len) == -1 {
+            return -1;
+        }
+        let nfd = (cmsg.len.saturating_sub(hdr_len) / 4) as usize;
+        if cmsg.level != SOL_SOCKET
+            || cmsg.ctype != SCM_RIGHTS
+            || cmsg.len < hdr_len
+            || cmsg.len > msg.controllen
+            || nfd > UNIX_MAXFD
+        {
+            return -EINVAL as i64;
+        }
+
+        let mut fds: [i32; UNIX_MAXFD] = [0; UNIX_MAXFD];
+        let dst = &mut fds as *mut i32 as *mut u8;
+        if either_copy_in(dst, 1, msg.control + hdr_len, nfd as u64 * 4) == -1 {
+            return -1;
+        }
+        let p = my_proc();
+        for fd in fds[..nfd].iter() {
+            if *fd < 0 || *fd >= NOFILE as i32 {
+                return -1;
+            }
+            let f = unsafe { (*p).ofile[*fd as usize] };
+            if f.is_null() {
+                return -1;
+            }
+            rights.file[rights.n] = f;
+            rights.n += 1;
+        }
+    }
+
+    socket_send(s, buf, n, msg.name, msg.namelen, Some(&rights)) as i64
+}
+
+// recvmsg(fd, msg, flags), with one buffer in msg. files sent along
+// get descriptors, in an SCM_RIGHTS control message. those that don't
+// fit in the control buffer or the descriptor table are closed, and
+// MSG_CTRUNC is set in the flags of msg
+pub fn sys_recvmsg() -> i64 {
+    let addr = arg_addr(1);
+    let flags = arg_int(2);
+    let s = match arg_sock(0) {
+        Some(s) => s,
+        None => return -1,
+    };
+    let mut msg = MsgHdr::new();
+    let (buf, n) = match msg_buf(addr, &mut msg) {
+        Some(buf) if flags == 0 => buf,
+        _ => return -EINVAL as i64,
+    };
+
+    let mut rights = Rights::new();
+    let lenp = addr + msg_off(&msg, &msg.namelen);
+    let r = socket_recv(s, buf, n, msg.name, lenp, Some(&mut rights));
+    if r < 0 {
+        return r as i64;
+    }
+
+    let hdr_len = size_of::<CmsgHdr>();
+    let room = (msg.controllen as usize).saturating_sub(hdr_len) / 4;
+    let mut fds: [i32; UNIX_MAXFD] = [0; UNIX_MAXFD];
+    let mut nfd = 0;
+    msg.flags = 0;
+    for f in rights.file[..rights.n].iter() {
+        let fd = match nfd < room {
+            true => fd_alloc(*f),
+            false => -1,
+        };
+        if fd < 0 {
+            file_close(*f);
+            msg.flags |= MSG_CTRUNC;
+            continue;
+        }
+        fds[nfd] = fd;
+        nfd += 1;
+    }
+
+    msg.controllen = 0;
+    if nfd > 0 {
+        let cmsg = CmsgHdr {
+            len: (hdr_len + nfd * 4) as u64,
+            level: SOL_SOCKET,
+            ctype: SCM_RIGHTS,
+        };
+        let src = &cmsg as *const CmsgHdr as *const u8;
+        if either_copy_out(1, msg.control, src, hdr_len as u64) == -1
+            || either_copy_out(
+                1,
+                msg.control + hdr_len as u64,
+                &fds as *const i32 as *const u8,
+                nfd as u64 * 4,
+            ) == -1
+        {
+            return -1;
+        }
+        msg.controllen = cmsg.len;
+    }
+    let src = &msg.controllen as *const u64 as *const u8;
+    if either_copy_out(1, addr + msg_off(&msg, &msg.controllen), src, 8) == -1 {
+        return -1;
+    }
+    let src = &msg.flags as *const i32 as *const u8;
+    if either_copy_out(1, addr + msg_off(&msg, &msg.flags), src, 4) == -1 {
+        return -1;
+    }
+
+    r as i64
+}
@@ -0,0 +1,39 @@
+use crate::errno::{EAGAIN, EINVAL};
+use crate::proc::{exit, fork, wait};
+use crate::random::{random_read, random_ready};
+use crate::syscall::{arg_addr, arg_int};
+
+// flags of getrandom()
+pub const GRND_NONBLOCK: i32 = 1;
+pub const GRND_RANDOM: i32 = 2;
+
+pub fn sys_fork() -> i64 {
+    fork() as i64
+}
+
+// exit(status), never returns
+pub fn sys_exit() -> i64 {
+    exit(arg_int(0));
+}
+
+// wait(status), status may be 0
+pub fn sys_wait() -> i64 {
+    wait(arg_addr(0)) as i64
+}
+
+// getrandom(buf, len, flags), the number of bytes written.
+// the generator is seeded at boot, so GRND_RANDOM changes nothing
+// and GRND_NONBLOCK only matters before that
+pub fn sys_getrandom() -> i64 {
+    let buf = arg_addr(0);
+    let len = arg_int(1);
+    let flags = arg_int(2);
+    if len < 0 || flags & !(GRND_NONBLOCK | GRND_RANDOM) != 0 {
+        return -EINVAL as i64;
+    }
+    if !random_ready() {
+        return -EAGAIN as i64;
+    }
+
+    random_read(1, buf, len as u32) as i64
+}
@@ -0,0 +1,1025 @@
+use crate::errno::{EADDRINUSE, ECONNREFUSED, ECONNRESET, EINVAL, ENOTCONN, EPIPE, ETIMEDOUT};
+use crate::kalloc::{kalloc, kfree};
+use crate::net::{
+    get16, get32, ip_output, ip_payload, ip_src, net_sleep, put16, put32, transport_checksum,
+    IpAddr, IP_HDR_LEN, IP_MAX, IP_PROTO_TCP,
+};
+use crate::proc::{my_proc, wakeup};
+use crate::random::random_bytes;
+use crate::ring::{Ring, RING_SIZE};
+use crate::trap::TICKS;
+use core::cmp::{max, min};
+
+// transmission control protocol, rfc 793 with the retransmission
+// timer of rfc 6298 and the congestion control of rfc 5681.
+//
+// each connection has a send and a receive buffer of a page. the
+// send buffer holds the bytes from snd_una on, those sent but not
+// acknowledged yet and those not sent yet. segments received out of
+// order are dropped, the peer sends them again.
+//
+// timers count ticks, see trap.rs. the retransmission timer of a
+// connection runs while it has something unacknowledged or is
+// waiting for the peer to open its window. when it goes off, all
+// that's unacknowledged is sent again, and the timeout doubles.
+//
+// a listening endpoint creates a connection for every SYN, which
+// tcp_accept() hands out once it is established. a connection its
+// owner closed goes on until the FIN exchange is done, and is freed
+// then.
+
+const NTCP: usize = 16;
+const NONE: usize = NTCP;
+
+const TCP_HDR_LEN: usize = 20;
+const TCP_BUF: usize = RING_SIZE;
+const TCP_MSS: usize = IP_MAX - IP_HDR_LEN - TCP_HDR_LEN; // the largest segment
+const TCP_DEFAULT_MSS: usize = 536; // if the peer doesn't tell
+const TCP_MIN_MSS: usize = 64; // a smaller mss option is taken as this
+
+const RTO_INIT: u64 = 1; // ticks
+const RTO_MAX: u64 = 60;
+const MAX_RETRIES: u32 = 8;
+const TIME_WAIT: u64 = 4; // 2 msl
+const FIN_WAIT_2: u64 = 60; // how long a closed connection waits for the peer's FIN
+
+// ports given to connections that weren't bound
+const EPHEMERAL_FIRST: u16 = 49152;
+
+// header flags
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+const OPT_END: u8 = 0;
+const OPT_NOP: u8 = 1;
+const OPT_MSS: u8 = 2;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum TcpState {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+impl TcpState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TcpState::Closed => "CLOSED",
+            TcpState::Listen => "LISTEN",
+            TcpState::SynSent => "SYN_SENT",
+            TcpState::SynReceived => "SYN_RECV",
+            TcpState::Established => "ESTABLISHED",
+            TcpState::FinWait1 => "FIN_WAIT1",
+            TcpState::FinWait2 => "FIN_WAIT2",
+            TcpState::CloseWait => "CLOSE_WAIT",
+            TcpState::Closing => "CLOSING",
+            TcpState::LastAck => "LAST_ACK",
+            TcpState::TimeWait => "TIME_WAIT",
+        }
+    }
+}
+
+// sequence number comparisons, modulo 2^32
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+#[derive(Copy, Clone)]
+struct Tcp {
+    used: bool,
+    state: TcpState,
+    orphan: bool, // closed by its owner, freed when done
+    err: i32,     // -errno the connection ended with, 0 if it didn't
+    lport: u16,   // 0 if not bound
+    raddr: IpAddr,
+    rport: u16,
+
+    // of a connection created by a listening one, the listener,
+    // until it is accepted
+    parent: usize,
+    backlog: usize, // of a listener
+
+    // send sequence space
+    iss: u32,
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_max: u32, // the highest snd_nxt, what a retransmission rewinds from
+    snd_wnd: u32, // what the peer last offered
+    snd_wl1: u32, // seq and ack of the segment it came with
+    snd_wl2: u32,
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    dupacks: u32,
+    fin_queued: bool, // send a FIN after the data
+    fin_sent: bool,
+    tx: Ring,
+
+    // receive sequence space
+    rcv_nxt: u32,
+    rcv_adv: usize, // the window last offered
+    fin_rcvd: bool,
+    rx: Ring,
+
+    // timers, in ticks
+    timer: u64, // when to retransmit or leave a waiting state, 0 if off
+    rto: u64,
+    retries: u32,
+    srtt: u64,   // times 8
+    rttvar: u64, // times 4
+    rtt_on: bool,
+    rtt_seq: u32, // the ack that ends the measurement
+    rtt_start: u64,
+}
+
+impl Tcp {
+    const fn new() -> Self {
+        Tcp {
+            used: false,
+            state: TcpState::Closed,
+            orphan: false,
+            err: 0,
+            lport: 0,
+            raddr: 0,
+            rport: 0,
+            parent: NONE,
+            backlog: 0,
+            iss: 0,
+            snd_una: 0,
+            snd_nxt: 0,
+            snd_max: 0,
+            snd_wnd: 0,
+            snd_wl1: 0,
+            snd_wl2: 0,
+            mss: TCP_DEFAULT_MSS,
+            cwnd: 0,
+            ssthresh: 0,
+            dupacks: 0,
+            fin_queued: false,
+            fin_sent: false,
+            tx: Ring::new(),
+            rcv_nxt: 0,
+            rcv_adv: 0,
+            fin_rcvd: false,
+            rx: Ring::new(),
+            timer: 0,
+            rto: RTO_INIT,
+            retries: 0,
+            srtt: 0,
+            rttvar: 0,
+            rtt_on: false,
+            rtt_seq: 0,
+            rtt_start: 0,
+        }
+    }
+
+    // sleep on this for any change
+    fn chan(&self) -> u64 {
+        self as *const Tcp as u64
+    }
+
+    // the bytes sent and not acknowledged, the FIN included
+    fn flight(&self) -> usize {
+        self.snd_nxt.wrapping_sub(self.snd_una) as usize
+    }
+}
+
+static mut TCP: [Tcp; NTCP] = [Tcp::new(); NTCP];
+
+static mut NEXT_PORT: u16 = EPHEMERAL_FIRST;
+
+fn tcp(i: usize) -> &'static mut Tcp {
+    unsafe { &mut TCP[i] }
+}
+
+fn ticks() -> u64 {
+    unsafe { TICKS }
+}
+
+fn killed() -> bool {
+    unsafe { (*my_proc()).killed != 0 }
+}
+
+fn port_in_use(port: u16) -> bool {
+    unsafe { TCP.iter().any(|t| t.used && t.lport == port) }
+}
+
+fn ephemeral() -> u16 {
+    loop {
+        let port = unsafe {
+            let port = NEXT_PORT;
+            NEXT_PORT = match NEXT_PORT {
+                u16::MAX => EPHEMERAL_FIRST,
+                _ => NEXT_PORT + 1,
+            };
+            port
+        };
+        if !port_in_use(port) {
+            return port;
+        }
+    }
+}
+
+fn new_iss() -> u32 {
+    let mut b: [u8; 4] = [0; 4];
+    random_bytes(&mut b);
+    u32::from_ne_bytes(b)
+}
+
+// a new connection with its buffers, NONE if there is no room
+fn alloc() -> usize {
+    for i in 0..NTCP {
+        let t = tcp(i);
+        if t.used {
+            continue;
+        }
+        let tx = kalloc() as *mut u8;
+        if tx.is_null() {
+            return NONE;
+        }
+        let rx = kalloc() as *mut u8;
+        if rx.is_null() {
+            kfree(tx as *mut u64);
+            return NONE;
+        }
+
+        *t = Tcp::new();
+        t.used = true;
+        t.tx.buf = tx;
+        t.rx.buf = rx;
+        return i;
+    }
+
+    NONE
+}
+
+fn release(t: &mut Tcp) {
+    kfree(t.tx.buf as *mut u64);
+    kfree(t.rx.buf as *mut u64);
+    t.used = false;
+    t.state = TcpState::Closed;
+    wakeup(t.chan());
+}
+
+// a new endpoint, -1 if there are none left
+pub fn tcp_alloc() -> i32 {
+    match alloc() {
+        NONE => -1,
+        i => i as i32,
+    }
+}
+
+// bind i to port, any free one if port is 0.
+// return 0, or -EADDRINUSE
+pub fn tcp_bind(i: usize, mut port: u16) -> i32 {
+    if port == 0 {
+        port = ephemeral();
+    } else if port_in_use(port) {
+        return -EADDRINUSE;
+    }
+    tcp(i).lport = port;
+    0
+}
+
+// the local port, and the remote address and port
+pub fn tcp_name(i: usize) -> (u16, IpAddr, u16) {
+    let t = tcp(i);
+    (t.lport, t.raddr, t.rport)
+}
+
+// the state of connection i, None if it isn't in use
+pub fn tcp_stat(i: usize) -> Option<(TcpState, u16, IpAddr, u16)> {
+    let t = tcp(i);
+    match t.used {
+        true => Some((t.state, t.lport, t.raddr, t.rport)),
+        false => None,
+    }
+}
+
+pub const fn tcp_count() -> usize {
+    NTCP
+}
+
+// send a segment of t. the len bytes of data are at
+// ip_payload()[TCP_HDR_LEN..] already
+fn send_segment(t: &mut Tcp, seq: u32, flags: u8, len: usize) {
+    let win = min(t.rx.space(), u16::MAX as usize);
+    t.rcv_adv = win;
+    let ack = match flags & ACK {
+        0 => 0,
+        _ => t.rcv_nxt,
+    };
+    segment_out(t.lport, t.raddr, t.rport, seq, ack, flags, win as u16, len);
+}
+
+// the segment of len bytes of data with a header from the arguments.
+// a SYN carries no data and gets the mss option
+fn segment_out(
+    sport: u16,
+    dst: IpAddr,
+    dport: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    win: u16,
+    len: usize,
+) {
+    let mut hdr_len = TCP_HDR_LEN;
+    let seg = ip_payload();
+    if flags & SYN != 0 {
+        seg[hdr_len] = OPT_MSS;
+        seg[hdr_len + 1] = 4;
+        put16(seg, hdr_len + 2, TCP_MSS as u16);
+        hdr_len += 4;
+    }
+    put16(seg, 0, sport);
+    put16(seg, 2, dport);
+    put32(seg, 4, seq);
+    put32(seg, 8, ack);
+    seg[12] = (hdr_len as u8 / 4) << 4;
+    seg[13] = flags;
+    put16(seg, 14, win);
+    put16(seg, 16, 0);
+    put16(seg, 18, 0); // urgent pointer
+    let total = hdr_len + len;
+    let sum = transport_checksum(ip_src(dst), dst, IP_PROTO_TCP, &seg[..total]);
+    put16(seg, 16, sum);
+    ip_output(dst, IP_PROTO_TCP, total);
+}
+
+// answer a segment no connection wants, rfc 793 3.4
+fn send_reset(src: IpAddr, sport: u16, dport: u16, seq: u32, ack: u32, flags: u8, len: usize) {
+    if flags & RST != 0 {
+        return;
+    }
+    if flags & ACK != 0 {
+        segment_out(dport, src, sport, ack, 0, RST, 0, 0);
+    } else {
+        let mut seg_len = len as u32;
+        if flags & SYN != 0 {
+            seg_len += 1;
+        }
+        if flags & FIN != 0 {
+            seg_len += 1;
+        }
+        let ack = seq.wrapping_add(seg_len);
+        segment_out(dport, src, sport, 0, ack, RST | ACK, 0, 0);
+    }
+}
+
+fn send_syn(t: &mut Tcp) {
+    let flags = match t.state {
+        TcpState::SynReceived => SYN | ACK,
+        _ => SYN,
+    };
+    send_segment(t, t.iss, flags, 0);
+    if t.timer == 0 {
+        t.timer = ticks() + t.rto;
+    }
+}
+
+// send what the windows allow of the data not sent yet, and the FIN
+// once the data is all sent. with probe, at least one byte goes out
+// even if the peer's window is closed. return whether anything was
+// sent
+fn tcp_output(t: &mut Tcp, probe: bool) -> bool {
+    match t.state {
+        TcpState::Established
+        | TcpState::CloseWait
+        | TcpState::FinWait1
+        | TcpState::Closing
+        | TcpState::LastAck => {}
+        _ => return false,
+    }
+
+    let mut sent = false;
+    while !t.fin_sent {
+        let off = t.flight();
+        let unsent = t.tx.n - off;
+        let mut win = min(t.snd_wnd as usize, t.cwnd);
+        if probe && win == 0 {
+            win = 1;
+        }
+        let len = min(min(unsent, t.mss), win.saturating_sub(off));
+        let fin = t.fin_queued && len == unsent;
+        if len == 0 && !fin {
+            break;
+        }
+
+        let mut flags = ACK;
+        if len > 0 && len == unsent {
+            flags |= PSH;
+        }
+        if fin {
+            flags |= FIN;
+        }
+        t.tx.peek(off, &mut ip_payload()[TCP_HDR_LEN..TCP_HDR_LEN + len]);
+        let seq = t.snd_nxt;
+        send_segment(t, seq, flags, len);
+        sent = true;
+
+        t.snd_nxt = t.snd_nxt.wrapping_add(len as u32);
+        if fin {
+            t.snd_nxt = t.snd_nxt.wrapping_add(1);
+            t.fin_sent = true;
+        }
+        if seq_lt(t.snd_max, t.snd_nxt) {
+            t.snd_max = t.snd_nxt;
+        }
+        if !t.rtt_on && len > 0 {
+            t.rtt_on = true;
+            t.rtt_seq = t.snd_nxt;
+            t.rtt_start = ticks();
+        }
+        if t.timer == 0 {
+            t.timer = ticks() + t.rto;
+        }
+    }
+
+    // with data waiting for the window to open, probe it later
+    if t.tx.n > t.flight() && t.timer == 0 {
+        t.timer = ticks() + t.rto;
+    }
+
+    sent
+}
+
+fn send_ack(t: &mut Tcp) {
+    let seq = t.snd_nxt;
+    send_segment(t, seq, ACK, 0);
+}
+
+// the connection is over. the owner finds out from err, an orphan or
+// a connection never accepted is freed
+fn finish(t: &mut Tcp, err: i32) {
+    t.state = TcpState::Closed;
+    t.err = err;
+    t.timer = 0;
+    if t.orphan || t.parent != NONE {
+        release(t);
+    } else {
+        wakeup(t.chan());
+    }
+}
+
+// a new round trip time sample, rfc 6298 2
+fn rtt_sample(t: &mut Tcp, r: u64) {
+    if t.srtt == 0 {
+        t.srtt = r * 8;
+        t.rttvar = r * 2;
+    } else {
+        let diff = max(t.srtt / 8, r) - min(t.srtt / 8, r);
+        t.rttvar = t.rttvar - t.rttvar / 4 + diff;
+        t.srtt = t.srtt - t.srtt / 8 + r;
+    }
+    t.rto = (t.srtt / 8 + t.rttvar).clamp(RTO_INIT, RTO_MAX);
+}
+
+// called every tick
+pub fn tcp_timer() {
+    let now = ticks();
+    for i in 0..NTCP {
+        let t = tcp(i);
+        if !t.used || t.timer == 0 || now < t.timer {
+            continue;
+        }
+        t.timer = 0;
+
+        match t.state {
+            TcpState::TimeWait | TcpState::FinWait2 => {
+                finish(t, 0);
+                continue;
+            }
+            _ => {}
+        }
+
+        // probing a shut window goes on for as long as the peer keeps
+        // it shut, only retransmissions give up
+        let probe = match t.state {
+            TcpState::SynSent | TcpState::SynReceived => false,
+            _ => t.snd_wnd == 0 && t.tx.n > 0,
+        };
+        if !probe {
+            t.retries += 1;
+            if t.retries > MAX_RETRIES {
+                finish(t, -ETIMEDOUT);
+                continue;
+            }
+        }
+        t.rto = min(t.rto * 2, RTO_MAX);
+        t.rtt_on = false;
+
+        match t.state {
+            TcpState::SynSent | TcpState::SynReceived => send_syn(t),
+            _ => {
+                // after a loss, start over slowly
+                if !probe && t.flight() > 0 {
+                    t.ssthresh = max(t.flight() / 2, 2 * t.mss);
+                    t.cwnd = t.mss;
+                }
+                t.snd_nxt = t.snd_una;
+                t.fin_sent = false;
+                tcp_output(t, true);
+            }
+        }
+    }
+}
+
+// the mss option of a SYN
+fn parse_mss(opts: &[u8]) -> usize {
+    let mut i = 0;
+    while i < opts.len() {
+        match opts[i] {
+            OPT_END => break,
+            OPT_NOP => i += 1,
+            kind => {
+                if i + 1 >= opts.len() || opts[i + 1] < 2 {
+                    break;
+                }
+                let len = opts[i + 1] as usize;
+                if kind == OPT_MSS && len == 4 && i + 4 <= opts.len() {
+                    return get16(opts, i + 2) as usize;
+                }
+                i += len;
+            }
+        }
+    }
+
+    TCP_DEFAULT_MSS
+}
+
+fn find(src: IpAddr, sport: u16, dport: u16) -> usize {
+    let mut listener = NONE;
+    for i in 0..NTCP {
+        let t = tcp(i);
+        if !t.used || t.lport != dport {
+            continue;
+        }
+        match t.state {
+            TcpState::Listen => listener = i,
+            TcpState::Closed => {}
+            _ if t.raddr == src && t.rport == sport => return i,
+            _ => {}
+        }
+    }
+
+    listener
+}
+
+// start the sequence spaces of t from a SYN
+fn synced(t: &mut Tcp, seq: u32, win: u16, mss: usize) {
+    t.rcv_nxt = seq.wrapping_add(1);
+    t.snd_wnd = win as u32;
+    t.snd_wl1 = seq;
+    t.mss = max(min(mss, TCP_MSS), TCP_MIN_MSS);
+    t.cwnd = 2 * t.mss;
+    t.ssthresh = u16::MAX as usize;
+}
+
+pub fn tcp_input(src: IpAddr, dst: IpAddr, seg: &[u8]) {
+    if seg.len() < TCP_HDR_LEN || transport_checksum(src, dst, IP_PROTO_TCP, seg) != 0 {
+        return;
+    }
+    let sport = get16(seg, 0);
+    let dport = get16(seg, 2);
+    let seq = get32(seg, 4);
+    let ack = get32(seg, 8);
+    let hdr_len = (seg[12] >> 4) as usize * 4;
+    let flags = seg[13];
+    let win = get16(seg, 14);
+    if hdr_len < TCP_HDR_LEN || hdr_len > seg.len() {
+        return;
+    }
+    let data = &seg[hdr_len..];
+
+    let i = find(src, sport, dport);
+    if i == NONE {
+        return send_reset(src, sport, dport, seq, ack, flags, data.len());
+    }
+    let t = tcp(i);
+
+    match t.state {
+        TcpState::Listen => {
+            if flags & RST != 0 {
+                return;
+            }
+            if flags & ACK != 0 || flags & SYN == 0 {
+                return send_reset(src, sport, dport, seq, ack, flags, data.len());
+            }
+            let pending = unsafe { TCP.iter().filter(|c| c.used && c.parent == i).count() };
+            if pending >= t.backlog {
+                return;
+            }
+            let c = alloc();
+            if c == NONE {
+                return;
+            }
+
+            let c = tcp(c);
+            c.state = TcpState::SynReceived;
+            c.lport = dport;
+            c.raddr = src;
+            c.rport = sport;
+            c.parent = i;
+            synced(c, seq, win, parse_mss(&seg[TCP_HDR_LEN..hdr_len]));
+            c.iss = new_iss();
+            c.snd_una = c.iss;
+            c.snd_nxt = c.iss.wrapping_add(1);
+            c.snd_max = c.snd_nxt;
+            send_syn(c);
+            return;
+        }
+        TcpState::SynSent => {
+            if flags & ACK != 0 && ack != t.snd_nxt {
+                return send_reset(src, sport, dport, seq, ack, flags, data.len());
+            }
+            if flags & RST != 0 {
+                if flags & ACK != 0 {
+                    finish(t, -ECONNREFUSED);
+                }
+                return;
+            }
+            if flags & SYN == 0 {
+                return;
+            }
+
+            synced(t, seq, win, parse_mss(&seg[TCP_HDR_LEN..hdr_len]));
+            t.timer = 0;
+            t.retries = 0;
+            if flags & ACK != 0 {
+                t.snd_una = ack;
+                t.snd_wl2 = ack;
+                t.state = TcpState::Established;
+                send_ack(t);
+                wakeup(t.chan());
+            } else {
+                // both sides opened at once
+                t.state = TcpState::SynReceived;
+                send_syn(t);
+            }
+            return;
+        }
+        TcpState::Closed => return,
+        _ => {}
+    }
+
+    // is any of the segment in the receive window, rfc 793 3.3
+    let mut seg_len = data.len() as u32;
+    if flags & SYN != 0 {
+        seg_len += 1;
+    }
+    if flags & FIN != 0 {
+        seg_len += 1;
+    }
+    let rcv_wnd = max(t.rx.space(), t.rcv_adv) as u32;
+    let in_window = |s: u32| seq_le(t.rcv_nxt, s) && seq_lt(s, t.rcv_nxt.wrapping_add(rcv_wnd));
+    let acceptable = match (seg_len, rcv_wnd) {
+        (0, 0) => seq == t.rcv_nxt,
+        (0, _) => in_window(seq),
+        (_, 0) => false,
+        _ => in_window(seq) || in_window(seq.wrapping_add(seg_len - 1)),
+    };
+    if !acceptable {
+        if flags & RST == 0 {
+            send_ack(t);
+        }
+        return;
+    }
+
+    if flags & RST != 0 {
+        return finish(t, -ECONNRESET);
+    }
+    if flags & SYN != 0 {
+        send_reset(src, sport, dport, seq, ack, flags, data.len());
+        return finish(t, -ECONNRESET);
+    }
+    if flags & ACK == 0 {
+        return;
+    }
+
+    if t.state == TcpState::SynReceived {
+        if !seq_lt(t.snd_una, ack) || !seq_le(ack, t.snd_max) {
+            return send_reset(src, sport, dport, seq, ack, flags, data.len());
+        }
+        // the SYN is acknowledged
+        t.snd_una = t.snd_una.wrapping_add(1);
+        t.snd_wl2 = ack;
+        t.timer = 0;
+        t.retries = 0;
+        t.state = TcpState::Established;
+        if t.parent != NONE {
+            wakeup(tcp(t.parent).chan());
+        }
+        wakeup(t.chan());
+    }
+
+    // after a retransmission rewound snd_nxt, the peer may still
+    // acknowledge what was sent before, up to snd_max
+    if seq_lt(t.snd_una, ack) && seq_le(ack, t.snd_max) {
+        let acked = ack.wrapping_sub(t.snd_una) as usize;
+        let fin_acked = t.fin_queued && ack == t.snd_max && acked > t.tx.n;
+        let data_acked = min(acked, t.tx.n);
+        t.tx.consume(data_acked);
+        t.snd_una = ack;
+        if seq_lt(t.snd_nxt, ack) {
+            t.snd_nxt = ack;
+        }
+        if fin_acked {
+            t.fin_sent = true;
+        }
+        t.dupacks = 0;
+        t.retries = 0;
+
+        if t.rtt_on && seq_le(t.rtt_seq, ack) {
+            t.rtt_on = false;
+            rtt_sample(t, ticks() - t.rtt_start);
+        }
+        t.timer = match t.snd_max == t.snd_una {
+            true => 0,
+            false => ticks() + t.rto,
+        };
+
+        // slow start, then congestion avoidance
+        if t.cwnd < t.ssthresh {
+            t.cwnd += min(data_acked, t.mss);
+        } else {
+            t.cwnd += max(t.mss * t.mss / t.cwnd, 1);
+        }
+        t.cwnd = min(t.cwnd, TCP_BUF);
+
+        if data_acked > 0 {
+            wakeup(t.chan());
+        }
+
+        if fin_acked {
+            match t.state {
+                TcpState::FinWait1 => {
+                    t.state = TcpState::FinWait2;
+                    if t.orphan {
+                        t.timer = ticks() + FIN_WAIT_2;
+                    }
+                }
+                TcpState::Closing => {
+                    t.state = TcpState::TimeWait;
+                    t.timer = ticks() + TIME_WAIT;
+                }
+                TcpState::LastAck => return finish(t, 0),
+                _ => {}
+            }
+        }
+    } else if ack == t.snd_una && data.is_empty() && t.flight() > 0 && win as u32 == t.snd_wnd {
+        // the third duplicate means a lost segment, send it again now
+        t.dupacks += 1;
+        if t.dupacks == 3 {
+            t.ssthresh = max(t.flight() / 2, 2 * t.mss);
+            t.cwnd = t.ssthresh;
+            t.snd_nxt = t.snd_una;
+            t.fin_sent = false;
+            t.rtt_on = false;
+        }
+    } else if seq_lt(t.snd_max, ack) {
+        // acknowledges something not sent yet
+        return send_ack(t);
+    }
+
+    if seq_lt(t.snd_wl1, seq) || (t.snd_wl1 == seq && seq_le(t.snd_wl2, ack)) {
+        t.snd_wnd = win as u32;
+        t.snd_wl1 = seq;
+        t.snd_wl2 = ack;
+    }
+
+    // the data, in order only
+    let mut need_ack = false;
+    let fin_seq = seq.wrapping_add(data.len() as u32);
+    match t.state {
+        TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2 => {
+            if !data.is_empty() {
+                if seq_le(seq, t.rcv_nxt) && seq_lt(t.rcv_nxt, fin_seq) {
+                    let skip = t.rcv_nxt.wrapping_sub(seq) as usize;
+                    let n = t.rx.put(&data[skip..]);
+                    t.rcv_nxt = t.rcv_nxt.wrapping_add(n as u32);
+                    if n > 0 {
+                        wakeup(t.chan());
+                    }
+                }
+                need_ack = true;
+            }
+        }
+        _ => {}
+    }
+
+    if flags & FIN != 0 && fin_seq == t.rcv_nxt && !t.fin_rcvd {
+        t.rcv_nxt = t.rcv_nxt.wrapping_add(1);
+        t.fin_rcvd = true;
+        need_ack = true;
+        wakeup(t.chan());
+        match t.state {
+            TcpState::Established => t.state = TcpState::CloseWait,
+            TcpState::FinWait1 => t.state = TcpState::Closing,
+            TcpState::FinWait2 => {
+                t.state = TcpState::TimeWait;
+                t.timer = ticks() + TIME_WAIT;
+            }
+            _ => {}
+        }
+    } else if flags & FIN != 0 && t.state == TcpState::TimeWait {
+        // our ACK of the FIN got lost
+        t.timer = ticks() + TIME_WAIT;
+        need_ack = true;
+    }
+
+    if !tcp_output(t, false) && need_ack {
+        send_ack(t);
+    }
+}
+
+// connect i to port on addr, and wait until the connection is
+// established. return 0, or -1 or -errno
+pub fn tcp_connect(i: usize, addr: IpAddr, port: u16) -> i32 {
+    let t = tcp(i);
+    if t.state != TcpState::Closed || t.err != 0 {
+        return -EINVAL;
+    }
+    if t.lport == 0 {
+        t.lport = ephemeral();
+    }
+    t.raddr = addr;
+    t.rport = port;
+    t.iss = new_iss();
+    t.snd_una = t.iss;
+    t.snd_nxt = t.iss.wrapping_add(1);
+    t.snd_max = t.snd_nxt;
+    t.state = TcpState::SynSent;
+    send_syn(t);
+
+    while t.state == TcpState::SynSent {
+        if killed() {
+            return -1;
+        }
+        net_sleep(t.chan());
+    }
+
+    match t.state {
+        TcpState::Closed => t.err,
+        _ => 0,
+    }
+}
+
+// accept connections on i, at most backlog of them waiting
+pub fn tcp_listen(i: usize, backlog: usize) -> i32 {
+    let t = tcp(i);
+    match t.state {
+        TcpState::Closed | TcpState::Listen => {}
+        _ => return -EINVAL,
+    }
+    if t.lport == 0 {
+        t.lport = ephemeral();
+    }
+    t.state = TcpState::Listen;
+    t.backlog = backlog.clamp(1, NTCP);
+    0
+}
+
+// wait for an established connection on the listening i.
+// return it, or -1 or -errno
+pub fn tcp_accept(i: usize) -> i32 {
+    let t = tcp(i);
+    loop {
+        if t.state != TcpState::Listen {
+            return -EINVAL;
+        }
+        for c in 0..NTCP {
+            let ct = tcp(c);
+            if ct.used && ct.parent == i && ct.state != TcpState::SynReceived {
+                ct.parent = NONE;
+                return c as i32;
+            }
+        }
+        if killed() {
+            return -1;
+        }
+        net_sleep(t.chan());
+    }
+}
+
+// queue n bytes from src for sending, waiting for room in the send
+// buffer. return the number of bytes queued, or -1 or -errno
+pub fn tcp_send(i: usize, is_uaddr: u32, src: u64, n: u32) -> i32 {
+    let t = tcp(i);
+    let mut cnt: usize = 0;
+    while cnt < n as usize {
+        match t.state {
+            TcpState::Established | TcpState::CloseWait => {}
+            _ if cnt > 0 => break,
+            TcpState::Closed if t.err != 0 => return t.err,
+            TcpState::Closed | TcpState::Listen => return -ENOTCONN,
+            _ => return -EPIPE,
+        }
+        if t.tx.space() == 0 {
+            if killed() {
+                return -1;
+            }
+            net_sleep(t.chan());
+            continue;
+        }
+
+        let len = min(n as usize - cnt, t.tx.space());
+        if t.tx.put_from(is_uaddr, src + cnt as u64, len) == -1 {
+            return -1;
+        }
+        cnt += len;
+        tcp_output(t, false);
+    }
+
+    cnt as i32
+}
+
+// read at most n bytes to dst, waiting if none have come. return the
+// number of bytes read, 0 once the peer has closed, or -1 or -errno
+pub fn tcp_recv(i: usize, is_uaddr: u32, dst: u64, n: u32) -> i32 {
+    let t = tcp(i);
+    while t.rx.n == 0 {
+        if t.fin_rcvd {
+            return 0;
+        }
+        match t.state {
+            TcpState::Closed if t.err != 0 => return t.err,
+            TcpState::Closed | TcpState::Listen => return -ENOTCONN,
+            _ => {}
+        }
+        if killed() {
+            return -1;
+        }
+        net_sleep(t.chan());
+    }
+
+    let len = min(n as usize, t.rx.n);
+    if t.rx.get_to(is_uaddr, dst, len) == -1 {
+        return -1;
+    }
+
+    // tell the peer if its window has opened by a segment or more
+    if t.rx.space() >= t.rcv_adv + t.mss && t.state != TcpState::Closed {
+        send_ack(t);
+    }
+
+    len as i32
+}
+
+// the owner is done with i
+pub fn tcp_close(i: usize) {
+    let t = tcp(i);
+    match t.state {
+        TcpState::Listen => {
+            // reset what was never accepted
+            for c in 0..NTCP {
+                let ct = tcp(c);
+                if ct.used && ct.parent == i {
+                    let seq = ct.snd_nxt;
+                    send_segment(ct, seq, RST, 0);
+                    release(ct);
+                }
+            }
+            release(t);
+        }
+        TcpState::Closed | TcpState::SynSent | TcpState::SynReceived => release(t),
+        TcpState::Established | TcpState::CloseWait => {
+            t.orphan = true;
+            tcp_shutdown(i);
+        }
+        TcpState::FinWait2 => {
+            // shut down and acked already, the timer wasn't armed then
+            t.orphan = true;
+            t.timer = ticks() + FIN_WAIT_2;
+        }
+        _ => t.orphan = true,
+    }
+}
+
+// send a FIN once the data queued has gone, nothing more can be
+// sent on i. receiving goes on until the peer's FIN
+pub fn tcp_shutdown(i: usize) {
+    let t = tcp(i);
+    t.state = match t.state {
+        TcpState::Established => TcpState::FinWait1,
+        TcpState::CloseWait => TcpState::LastAck,
+        _ => return,
+    };
+    t.fin_queued = true;
+    tcp_output(t, false);
+}
@@ -8,6 +8,9 @@ extern "C" {
     fn timer_vec();
 }
 
+pub const INTERVAL: u64 = 10_000_000; // cycles between timer interrupts
+pub const FREQ: u64 = 10_000_000; // mtime ticks per second on qemu virt
+
 static mut TIMER_SCRATCH: [[u64; 5]; NCPU as usize] = [[0; 5]; NCPU as usize];
 
 #[no_mangle]
@@ -15,7 +18,7 @@ pub extern "C" fn timer_init() {
     let hart = rmhartid();
 
     let addr = clint_mtimecmp(hart) as *mut u64;
-    let interval = 10_000_000;
+    let interval = INTERVAL;
     unsafe {
         *addr = interval + *(clint_mtime() as *const u64);
 
@@ -0,0 +1,550 @@
+use crate::fs::{DirEnt, DirIter, FileSystem, Inode, NAME_MAX};
+use crate::kalloc::{kalloc, kfree};
+use crate::param::{NTMPFS, NTMPNODE, TMP_NPAGE};
+use crate::proc::{either_copy_in, either_copy_out};
+use crate::riscv::PAGE_SIZE;
+use crate::stat::{
+    mode_to_dtype, Stat, DT_DIR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFREG, S_IFSOCK,
+};
+use crate::string::{mem_copy, mem_set, str_cmp};
+use core::cmp::min;
+use core::fmt::Write;
+use core::mem::size_of;
+use core::ptr::null_mut;
+
+// in-memory file system.
+//
+// the content of a file lives in pages from kalloc(), at most
+// TMP_NPAGE of them, and an instance uses at most max_page pages
+// in total. a directory is an array of TmpDirent kept the same way,
+// a free slot has ino 0.
+// nothing goes to disk, everything is gone on reboot.
+
+const TMPFS_DEV: u32 = 0x100; // dev of the first instance, the next is 0x101...
+
+#[repr(C)]
+struct TmpDirent {
+    ino: u32,
+    name: [u8; NAME_MAX + 1],
+}
+
+impl TmpDirent {
+    const fn new() -> Self {
+        TmpDirent {
+            ino: 0,
+            name: [0; NAME_MAX + 1],
+        }
+    }
+}
+
+const DIRENT_SIZE: u32 = size_of::<TmpDirent>() as u32;
+
+#[derive(Copy, Clone)]
+pub struct TmpNode {
+    fs: usize, // instance in TMPFS
+    ino: u32,  // index in TMP_NODE + 1
+    ref_cnt: u32,
+    mode: u16, // 0 if the node is free
+    nlink: u16,
+    size: u32,
+    parent: usize, // index of the parent directory in TMP_NODE
+    page: [*mut u8; TMP_NPAGE],
+}
+
+impl TmpNode {
+    const fn new() -> Self {
+        TmpNode {
+            fs: 0,
+            ino: 0,
+            ref_cnt: 0,
+            mode: 0,
+            nlink: 0,
+            size: 0,
+            parent: 0,
+            page: [null_mut(); TMP_NPAGE],
+        }
+    }
+}
+
+static mut TMP_NODE: [TmpNode; NTMPNODE] = [TmpNode::new(); NTMPNODE];
+
+#[derive(Copy, Clone)]
+struct TmpFs {
+    used: bool,
+    dev: u32,
+    root: usize, // index of the root directory in TMP_NODE
+    npage: u32,  // pages in use
+    max_page: u32,
+}
+
+impl TmpFs {
+    const fn new() -> Self {
+        TmpFs {
+            used: false,
+            dev: 0,
+            root: 0,
+            npage: 0,
+            max_page: 0,
+        }
+    }
+}
+
+static mut TMPFS: [TmpFs; NTMPFS] = [TmpFs::new(); NTMPFS];
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn dev(&self) -> u32 {
+        self.dev
+    }
+
+    fn root(&mut self) -> *mut dyn Inode {
+        unsafe { TMP_NODE[self.root].dup() }
+    }
+
+    // busy while any node but the root, which the instance
+    // holds a reference to, is in use
+    fn unmount(&mut self) -> i32 {
+        let fs = (self.dev - TMPFS_DEV) as usize;
+        unsafe {
+            for i in 0..NTMPNODE {
+                let node = &TMP_NODE[i];
+                let held = match i == self.root {
+                    true => 1,
+                    false => 0,
+                };
+                if node.mode != 0 && node.fs == fs && node.ref_cnt > held {
+                    return -1;
+                }
+            }
+
+            for i in 0..NTMPNODE {
+                if TMP_NODE[i].mode != 0 && TMP_NODE[i].fs == fs {
+                    node_trunc(&mut TMP_NODE[i]);
+                    TMP_NODE[i].mode = 0;
+                }
+            }
+        }
+
+        *self = TmpFs::new();
+        0
+    }
+}
+
+// create an empty tmpfs using at most max_page pages for content
+pub fn tmpfs_new(max_page: u32) -> Option<*mut dyn FileSystem> {
+    unsafe {
+        for i in 0..NTMPFS {
+            if TMPFS[i].used {
+                continue;
+            }
+
+            let root = node_alloc(i, S_IFDIR | 0o777, 0);
+            if root.is_null() {
+                return None;
+            }
+            (*root).parent = ((*root).ino - 1) as usize;
+
+            TMPFS[i].used = true;
+            TMPFS[i].dev = TMPFS_DEV + i as u32;
+            TMPFS[i].root = ((*root).ino - 1) as usize;
+            TMPFS[i].npage = 0;
+            TMPFS[i].max_page = max_page;
+            return Some(&mut TMPFS[i] as *mut TmpFs);
+        }
+    }
+
+    None
+}
+
+const fn is_dir(m: u16) -> bool {
+    m & S_IFMT == S_IFDIR
+}
+
+// alloc a node with one reference, return null if the table is full
+fn node_alloc(fs: usize, mode: u16, parent: usize) -> *mut TmpNode {
+    unsafe {
+        for i in 0..NTMPNODE {
+            if TMP_NODE[i].mode == 0 {
+                TMP_NODE[i] = TmpNode::new();
+                TMP_NODE[i].fs = fs;
+                TMP_NODE[i].ino = i as u32 + 1;
+                TMP_NODE[i].ref_cnt = 1;
+                TMP_NODE[i].mode = mode;
+                TMP_NODE[i].nlink = match is_dir(mode) {
+                    true => 2,
+                    false => 1,
+                };
+                TMP_NODE[i].parent = parent;
+                return &mut TMP_NODE[i] as *mut TmpNode;
+            }
+        }
+    }
+
+    null_mut()
+}
+
+// give the pages of node back to the system
+fn node_trunc(node: *mut TmpNode) {
+    unsafe {
+        for i in 0..TMP_NPAGE {
+            if !(*node).page[i].is_null() {
+                kfree((*node).page[i] as *mut u64);
+                (*node).page[i] = null_mut();
+                TMPFS[(*node).fs].npage -= 1;
+            }
+        }
+        (*node).size = 0;
+    }
+}
+
+// free node once it has neither names nor references
+fn node_release(node: *mut TmpNode) {
+    unsafe {
+        if (*node).nlink == 0 && (*node).ref_cnt == 0 {
+            node_trunc(node);
+            (*node).mode = 0;
+        }
+    }
+}
+
+// read the content of node
+// return the number of bytes read, or -1 if copying to dst failed
+fn node_read(node: *mut TmpNode, is_uaddr: u32, mut dst: u64, mut off: u32, mut n: u32) -> i32 {
+    unsafe {
+        if off > (*node).size {
+            return 0;
+        }
+        n = min(n, (*node).size - off);
+
+        let mut cnt: u32 = 0;
+        while cnt < n {
+            let pa = (*node).page[(off / PAGE_SIZE as u32) as usize];
+            let len = min(n - cnt, PAGE_SIZE as u32 - off % PAGE_SIZE as u32);
+            if either_copy_out(
+                is_uaddr,
+                dst,
+                pa.add((off % PAGE_SIZE as u32) as usize),
+                len as u64,
+            ) == -1
+            {
+                return -1;
+            }
+
+            cnt += len;
+            off += len;
+            dst += len as u64;
+        }
+
+        cnt as i32
+    }
+}
+
+// write the content of node, no hole is allowed.
+// return the number of bytes written, fewer than n if the file
+// system filled up or copying from src failed on the way, or -1
+// if nothing could be written
+fn node_write(node: *mut TmpNode, is_uaddr: u32, mut src: u64, mut off: u32, n: u32) -> i32 {
+    unsafe {
+        if off > (*node).size || off as u64 + n as u64 > TMP_NPAGE as u64 * PAGE_SIZE {
+            return -1;
+        }
+
+        let fs = &mut TMPFS[(*node).fs];
+        let mut cnt: u32 = 0;
+        while cnt < n {
+            let pn = (off / PAGE_SIZE as u32) as usize;
+            if (*node).page[pn].is_null() {
+                if fs.npage >= fs.max_page {
+                    break;
+                }
+                let pa = kalloc() as *mut u8;
+                if pa.is_null() {
+                    break;
+                }
+                mem_set(pa as *mut u64, 0, PAGE_SIZE);
+                (*node).page[pn] = pa;
+                fs.npage += 1;
+            }
+
+            let len = min(n - cnt, PAGE_SIZE as u32 - off % PAGE_SIZE as u32);
+            if either_copy_in(
+                (*node).page[pn].add((off % PAGE_SIZE as u32) as usize),
+                is_uaddr,
+                src,
+                len as u64,
+            ) == -1
+            {
+                break;
+            }
+
+            cnt += len;
+            off += len;
+            src += len as u64;
+        }
+
+        if off > (*node).size {
+            (*node).size = off;
+        }
+
+        match cnt {
+            0 if n > 0 => -1,
+            _ => cnt as i32,
+        }
+    }
+}
+
+// find name in directory dir, return the offset and inode number
+fn dir_find(dir: *mut TmpNode, name: *const u8) -> Option<(u32, u32)> {
+    let mut de = TmpDirent::new();
+    let mut off = 0;
+    unsafe {
+        while off < (*dir).size {
+            node_read(dir, 0, &mut de as *mut TmpDirent as u64, off, DIRENT_SIZE);
+            if de.ino != 0 && str_cmp(name, &de.name as *const u8, (NAME_MAX + 1) as u32) == 0 {
+                return Some((off, de.ino));
+            }
+            off += DIRENT_SIZE;
+        }
+    }
+
+    None
+}
+
+// add an entry to directory dir, reusing a free slot if any
+fn dir_add(dir: *mut TmpNode, name: *const u8, ino: u32) -> bool {
+    let mut de = TmpDirent::new();
+    let mut off = 0;
+    unsafe {
+        while off < (*dir).size {
+            node_read(dir, 0, &mut de as *mut TmpDirent as u64, off, DIRENT_SIZE);
+            if de.ino == 0 {
+                break;
+            }
+            off += DIRENT_SIZE;
+        }
+
+        let mut len = 0;
+        while *name.add(len) != 0 && len < NAME_MAX {
+            len += 1;
+        }
+        de.ino = ino;
+        mem_set(
+            &mut de.name as *mut u8 as *mut u64,
+            0,
+            (NAME_MAX + 1) as u64,
+        );
+        mem_copy(
+            &mut de.name as *mut u8 as *mut u64,
+            name as *const u64,
+            len as u64,
+        );
+    }
+
+    node_write(dir, 0, &de as *const TmpDirent as u64, off, DIRENT_SIZE) == DIRENT_SIZE as i32
+}
+
+fn dir_empty(dir: *mut TmpNode) -> bool {
+    let mut de = TmpDirent::new();
+    let mut off = 0;
+    unsafe {
+        while off < (*dir).size {
+            node_read(dir, 0, &mut de as *mut TmpDirent as u64, off, DIRENT_SIZE);
+            if de.ino != 0 {
+                return false;
+            }
+            off += DIRENT_SIZE;
+        }
+    }
+
+    true
+}
+
+fn node_of(ino: u32) -> *mut TmpNode {
+    unsafe { &mut TMP_NODE[(ino - 1) as usize] as *mut TmpNode }
+}
+
+impl DirIter for TmpNode {
+    // pos 0 is ".", 1 is "..", pos n+2 is the nth slot,
+    // "." and ".." don't live in the directory
+    fn next(&mut self, pos: &mut u32, ent: &mut DirEnt) -> bool {
+        let parent = unsafe { TMP_NODE[self.parent].ino };
+        match *pos {
+            0 => {
+                ent.set(self.ino, b".", DT_DIR);
+                *pos += 1;
+                return true;
+            }
+            1 => {
+                ent.set(parent, b"..", DT_DIR);
+                *pos += 1;
+                return true;
+            }
+            _ => {}
+        }
+
+        let mut de = TmpDirent::new();
+        while (*pos - 2) * DIRENT_SIZE < self.size {
+            let off = (*pos - 2) * DIRENT_SIZE;
+            node_read(self, 0, &mut de as *mut TmpDirent as u64, off, DIRENT_SIZE);
+            *pos += 1;
+
+            if de.ino == 0 {
+                continue;
+            }
+
+            let mut namelen = 0;
+            while namelen < NAME_MAX && de.name[namelen] != 0 {
+                namelen += 1;
+            }
+            let dtype = mode_to_dtype(unsafe { (*node_of(de.ino)).mode });
+            ent.set(de.ino, &de.name[..namelen], dtype);
+            return true;
+        }
+
+        false
+    }
+}
+
+impl Inode for TmpNode {
+    fn dev(&self) -> u32 {
+        unsafe { TMPFS[self.fs].dev }
+    }
+
+    fn ino(&self) -> u32 {
+        self.ino
+    }
+
+    fn mode(&self) -> u16 {
+        self.mode
+    }
+
+    fn stat(&self, st: &mut Stat) {
+        *st = Stat::new();
+        st.dev = self.dev();
+        st.ino = self.ino;
+        st.mode = self.mode;
+        st.nlink = self.nlink;
+        st.size = self.size as u64;
+    }
+
+    fn dup(&mut self) -> *mut dyn Inode {
+        self.ref_cnt += 1;
+        self as *mut TmpNode
+    }
+
+    fn put(&mut self) {
+        if self.ref_cnt == 0 {
+            panicc!("tmpfs put: ref_cnt");
+        }
+        self.ref_cnt -= 1;
+        node_release(self);
+    }
+
+    fn read(&mut self, is_uaddr: u32, dst: u64, off: u32, n: u32) -> i32 {
+        node_read(self, is_uaddr, dst, off, n)
+    }
+
+    fn write(&mut self, is_uaddr: u32, src: u64, off: u32, n: u32) -> i32 {
+        node_write(self, is_uaddr, src, off, n)
+    }
+
+    fn truncate(&mut self) -> i32 {
+        if is_dir(self.mode) {
+            return -1;
+        }
+
+        node_trunc(self);
+        0
+    }
+
+    fn lookup(&mut self, name: *const u8) -> Option<*mut dyn Inode> {
+        if !is_dir(self.mode) {
+            return None;
+        }
+
+        if str_cmp(name, ".\0".as_ptr(), 2) == 0 {
+            return Some(self.dup());
+        }
+        if str_cmp(name, "..\0".as_ptr(), 3) == 0 {
+            return Some(unsafe { TMP_NODE[self.parent].dup() });
+        }
+
+        let (_, ino) = dir_find(self, name)?;
+        Some(unsafe { (*node_of(ino)).dup() })
+    }
+
+    fn create(&mut self, name: *const u8, mode: u16) -> Option<*mut dyn Inode> {
+        if !is_dir(self.mode) {
+            return None;
+        }
+        match mode & S_IFMT {
+            S_IFREG | S_IFDIR | S_IFLNK | S_IFIFO | S_IFSOCK => {}
+            _ => return None,
+        }
+
+        let node = node_alloc(self.fs, mode, (self.ino - 1) as usize);
+        if node.is_null() {
+            return None;
+        }
+
+        unsafe {
+            if !dir_add(self, name, (*node).ino) {
+                (*node).nlink = 0;
+                (*node).put();
+                return None;
+            }
+        }
+
+        if is_dir(mode) {
+            // ".." of the new directory
+            self.nlink += 1;
+        }
+
+        Some(node)
+    }
+
+    fn unlink(&mut self, name: *const u8) -> i32 {
+        if !is_dir(self.mode) {
+            return -1;
+        }
+
+        let (off, ino) = match dir_find(self, name) {
+            Some(found) => found,
+            None => return -1,
+        };
+        let node = node_of(ino);
+
+        unsafe {
+            if is_dir((*node).mode) {
+                if !dir_empty(node) {
+                    return -1;
+                }
+                (*node).nlink = 0;
+                self.nlink -= 1;
+            } else {
+                (*node).nlink -= 1;
+            }
+        }
+
+        let zero: u32 = 0;
+        node_write(
+            self,
+            0,
+            &zero as *const u32 as u64,
+            off,
+            size_of::<u32>() as u32,
+        );
+        node_release(node);
+        0
+    }
+
+    fn dir_iter(&mut self) -> Option<&mut dyn DirIter> {
+        match is_dir(self.mode) {
+            true => Some(self),
+            false => None,
+        }
+    }
+}
@@ -1,10 +1,12 @@
 use crate::mem_layout::{TRAMPOLINE, TRAP_FRAME};
+use crate::net::net_timer;
 use crate::plic::plic_intr;
-use crate::proc::{my_proc, yield_cpu, ProcState};
+use crate::proc::{cpu_id, my_proc, yield_cpu, ProcState};
 use crate::riscv::{
     intr_off, make_satp, rsatp, rscause, rsepc, rsip, rsstatus, rstval, rtp, wsepc, wsip, wsstatus,
     wstvec, PAGE_SIZE, SSTATUS_SIE, SSTATUS_SPIE, SSTATUS_SPP,
 };
+use crate::syscall::syscall;
 use core::fmt::Write;
 use core::mem::transmute;
 
@@ -12,6 +14,18 @@ extern "C" {
     pub fn kernel_vec();
 }
 
+// timer interrupts since boot
+pub static mut TICKS: u64 = 0;
+
+fn clock_intr() {
+    if cpu_id() == 0 {
+        unsafe {
+            TICKS += 1;
+        }
+        net_timer();
+    }
+}
+
 pub fn trap_init_hart() {
     wstvec(kernel_vec as u64);
 }
@@ -38,6 +52,7 @@ extern "C" fn kernel_trap() {
         match scause & 0xff {
             1 => {
                 // time interrupt
+                clock_intr();
                 let p = my_proc();
                 if !p.is_null() {
                     // improve?
@@ -107,9 +122,13 @@ fn user_trap() {
     if intr {
         match scause & 0xff {
             1 => {
+                clock_intr();
                 yield_cpu();
                 wsip(rsip() & !2);
             }
+            9 => {
+                plic_intr();
+            }
             _ => {
                 println!("scause 0x{:x}", scause);
                 println!("sepc=0x{:x} stval=0x{:x}", rsepc(), rstval());
@@ -120,10 +139,11 @@ fn user_trap() {
         match scause & 0xff {
             8 => {
                 // syscall
-                print!("/");
                 unsafe {
+                    // return to the next instruction
                     (*(*p).trap_frame).epc += 4;
                 }
+                syscall();
             }
             12 => {
                 panicc!("instruction page fault");
@@ -0,0 +1,233 @@
+use crate::errno::{EADDRINUSE, EMSGSIZE, ENOTCONN};
+use crate::net::{
+    get16, ip_output, ip_payload, ip_src, net_sleep, put16, transport_checksum, IpAddr, IP_HDR_LEN,
+    IP_MAX, IP_PROTO_UDP,
+};
+use crate::proc::{either_copy_in, either_copy_out, my_proc, wakeup};
+use core::cmp::min;
+
+// user datagram protocol, rfc 768.
+//
+// an endpoint is bound to a local port, and may be connected to a
+// remote address and port, after which it only takes datagrams from
+// there. received datagrams wait in a queue of NDGRAM, more are
+// dropped.
+
+const NUDP: usize = 8;
+const NDGRAM: usize = 4;
+
+const UDP_HDR_LEN: usize = 8;
+pub const UDP_MAX: usize = IP_MAX - IP_HDR_LEN - UDP_HDR_LEN; // the largest datagram
+
+// ports given to endpoints that send without binding
+const EPHEMERAL_FIRST: u16 = 49152;
+
+#[derive(Copy, Clone)]
+struct Dgram {
+    src: IpAddr,
+    sport: u16,
+    len: usize,
+    data: [u8; UDP_MAX],
+}
+
+#[derive(Copy, Clone)]
+struct Udp {
+    used: bool,
+    port: u16,     // local, 0 if not bound
+    raddr: IpAddr, // remote, if rport isn't 0
+    rport: u16,
+    queue: [Dgram; NDGRAM],
+    r: usize, // read index
+    w: usize, // write index
+}
+
+static mut UDP: [Udp; NUDP] = [Udp {
+    used: false,
+    port: 0,
+    raddr: 0,
+    rport: 0,
+    queue: [Dgram {
+        src: 0,
+        sport: 0,
+        len: 0,
+        data: [0; UDP_MAX],
+    }; NDGRAM],
+    r: 0,
+    w: 0,
+}; NUDP];
+
+static mut NEXT_PORT: u16 = EPHEMERAL_FIRST;
+
+fn udp(i: usize) -> &'static mut Udp {
+    unsafe { &mut UDP[i] }
+}
+
+fn port_in_use(port: u16) -> bool {
+    unsafe { UDP.iter().any(|u| u.used && u.port == port) }
+}
+
+// a free port from the ephemeral range
+fn ephemeral() -> u16 {
+    loop {
+        let port = unsafe {
+            let port = NEXT_PORT;
+            NEXT_PORT = match NEXT_PORT {
+                u16::MAX => EPHEMERAL_FIRST,
+                _ => NEXT_PORT + 1,
+            };
+            port
+        };
+        if !port_in_use(port) {
+            return port;
+        }
+    }
+}
+
+// a new endpoint, -1 if there are none left
+pub fn udp_alloc() -> i32 {
+    for i in 0..NUDP {
+        let u = udp(i);
+        if !u.used {
+            u.used = true;
+            u.port = 0;
+            u.rport = 0;
+            u.r = 0;
+            u.w = 0;
+            return i as i32;
+        }
+    }
+
+    -1
+}
+
+pub fn udp_free(i: usize) {
+    udp(i).used = false;
+}
+
+// bind i to port, any free one if port is 0.
+// return 0, or -EADDRINUSE
+pub fn udp_bind(i: usize, mut port: u16) -> i32 {
+    if port == 0 {
+        port = ephemeral();
+    } else if port_in_use(port) {
+        return -EADDRINUSE;
+    }
+    udp(i).port = port;
+    0
+}
+
+// only talk to addr and port from now on
+pub fn udp_connect(i: usize, addr: IpAddr, port: u16) -> i32 {
+    let u = udp(i);
+    if u.port == 0 {
+        u.port = ephemeral();
+    }
+    u.raddr = addr;
+    u.rport = port;
+    0
+}
+
+// the local port, and the remote address and port if connected
+pub fn udp_name(i: usize) -> (u16, IpAddr, u16) {
+    let u = udp(i);
+    (u.port, u.raddr, u.rport)
+}
+
+// send n bytes at src to port on dst, or to where i is connected
+// if port is 0. return n, or -1 or -errno
+pub fn udp_send(i: usize, mut dst: IpAddr, mut dport: u16, is_uaddr: u32, src: u64, n: u32) -> i32 {
+    let u = udp(i);
+    if dport == 0 {
+        if u.rport == 0 {
+            return -ENOTCONN;
+        }
+        dst = u.raddr;
+        dport = u.rport;
+    }
+    if n as usize > UDP_MAX {
+        return -EMSGSIZE;
+    }
+    if u.port == 0 {
+        u.port = ephemeral();
+    }
+
+    // an empty datagram is fine, there is just nothing to copy
+    let len = UDP_HDR_LEN + n as usize;
+    let data = ip_payload()[UDP_HDR_LEN..].as_mut_ptr();
+    if either_copy_in(data, is_uaddr, src, n as u64) == -1 {
+        return -1;
+    }
+    let seg = &mut ip_payload()[..len];
+    put16(seg, 0, u.port);
+    put16(seg, 2, dport);
+    put16(seg, 4, len as u16);
+    put16(seg, 6, 0);
+    let sum = match transport_checksum(ip_src(dst), dst, IP_PROTO_UDP, seg) {
+        0 => 0xffff, // 0 would mean no checksum
+        sum => sum,
+    };
+    put16(seg, 6, sum);
+    ip_output(dst, IP_PROTO_UDP, len);
+
+    n as i32
+}
+
+// receive a datagram to dst, at most n bytes of it, the rest is
+// lost. wait for one if none has come. return the number of bytes
+// copied and where the datagram is from, -1 if killed or dst is bad
+pub fn udp_recv(i: usize, is_uaddr: u32, dst: u64, n: u32) -> (i32, IpAddr, u16) {
+    let u = udp(i);
+    let p = my_proc();
+    while u.r == u.w {
+        if unsafe { (*p).killed } != 0 {
+            return (-1, 0, 0);
+        }
+        net_sleep(u as *const Udp as u64);
+    }
+
+    let d = &u.queue[u.r % NDGRAM];
+    u.r += 1;
+    let len = min(n as usize, d.len);
+    if either_copy_out(is_uaddr, dst, &d.data as *const u8, len as u64) == -1 {
+        return (-1, 0, 0);
+    }
+    (len as i32, d.src, d.sport)
+}
+
+pub fn udp_input(src: IpAddr, dst: IpAddr, seg: &[u8]) {
+    if seg.len() < UDP_HDR_LEN {
+        return;
+    }
+    let sport = get16(seg, 0);
+    let dport = get16(seg, 2);
+    let len = get16(seg, 4) as usize;
+    if len < UDP_HDR_LEN || len > seg.len() {
+        return;
+    }
+    let seg = &seg[..len];
+    if get16(seg, 6) != 0 && transport_checksum(src, dst, IP_PROTO_UDP, seg) != 0 {
+        return;
+    }
+
+    for i in 0..NUDP {
+        let u = udp(i);
+        if !u.used || u.port != dport {
+            continue;
+        }
+        if u.rport != 0 && (u.raddr != src || u.rport != sport) {
+            continue;
+        }
+
+        // drop it if the queue is full
+        if u.w - u.r < NDGRAM {
+            let d = &mut u.queue[u.w % NDGRAM];
+            d.src = src;
+            d.sport = sport;
+            d.len = len - UDP_HDR_LEN;
+            d.data[..d.len].copy_from_slice(&seg[UDP_HDR_LEN..]);
+            u.w += 1;
+            wakeup(u as *const Udp as u64);
+        }
+        return;
+    }
+}
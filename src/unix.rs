@@ -0,0 +1,545 @@
+use crate::errno::{EADDRINUSE, ECONNREFUSED, EINVAL, EMSGSIZE, ENOTCONN, EOPNOTSUPP, EPIPE};
+use crate::file::{file_close, file_dup, File};
+use crate::fs::{create, is_socket, path_lookup};
+use crate::kalloc::{kalloc, kfree};
+use crate::proc::{my_proc, sleep, wakeup};
+use crate::ring::{Ring, RING_SIZE};
+use crate::socket::{SOCK_DGRAM, SOCK_STREAM};
+use crate::stat::S_IFSOCK;
+use core::cmp::min;
+use core::ptr::null_mut;
+
+// unix domain sockets, for talking to other processes on the machine.
+//
+// a socket is bound to a path by creating a node of type S_IFSOCK
+// there, and is found through its device and inode number, as FIFOs
+// are (see pipe.rs). the node stays after the socket is closed, until
+// it is unlinked, but no one can connect to it any more.
+//
+// a stream socket connecting to a listening one is paired with a new
+// socket right away, which accept() hands out later. a socket sends
+// to the receive ring of its peer, or of the datagram socket it sends
+// to, in messages that may carry open files. those are held with a
+// reference of their own while in flight, and become descriptors of
+// the receiver. a stream read takes what there is, but the files of
+// a message only come with its first byte, so a read stops before a
+// message with files. a datagram read takes one message.
+
+const NUNIX: usize = 16;
+const NONE: usize = NUNIX;
+const NMSG: usize = 16; // messages waiting on a socket
+
+pub const UNIX_MAXFD: usize = 8; // files in a message
+pub const UNIX_PATH_MAX: usize = 108;
+
+// open files sent along with data
+#[derive(Copy, Clone)]
+pub struct Rights {
+    pub n: usize,
+    pub file: [*mut File; UNIX_MAXFD],
+}
+
+impl Rights {
+    pub const fn new() -> Self {
+        Rights {
+            n: 0,
+            file: [null_mut(); UNIX_MAXFD],
+        }
+    }
+
+    // close the files, no one is going to get them
+    pub fn close(&mut self) {
+        for i in 0..self.n {
+            file_close(self.file[i]);
+        }
+        self.n = 0;
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Msg {
+    len: usize, // bytes of it in the ring
+    // where the sender is bound, src_ino 0 if it isn't
+    src_dev: u32,
+    src_ino: u32,
+    rights: Rights,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum UnixState {
+    Unconnected,
+    Listening,
+    Connected,
+    Disconnected, // the stream peer closed
+}
+
+#[derive(Copy, Clone)]
+struct Unix {
+    used: bool,
+    stype: i32,
+    state: UnixState,
+    // the node bound to, ino 0 if not bound
+    dev: u32,
+    ino: u32,
+    path: [u8; UNIX_PATH_MAX], // '\0' terminated
+    peer: usize,               // of a connected stream socket
+    // of a connection not accepted yet, the listener
+    parent: usize,
+    backlog: usize, // of a listener
+    // where a connected datagram socket sends to
+    dst_dev: u32,
+    dst_ino: u32,
+    eof: bool, // the stream peer sends no more
+    rx: Ring,
+    msg: [Msg; NMSG],
+    msg_r: usize,
+    msg_w: usize,
+}
+
+impl Unix {
+    const fn new() -> Self {
+        Unix {
+            used: false,
+            stype: 0,
+            state: UnixState::Unconnected,
+            dev: 0,
+            ino: 0,
+            path: [0; UNIX_PATH_MAX],
+            peer: NONE,
+            parent: NONE,
+            backlog: 0,
+            dst_dev: 0,
+            dst_ino: 0,
+            eof: false,
+            rx: Ring::new(),
+            msg: [Msg {
+                len: 0,
+                src_dev: 0,
+                src_ino: 0,
+                rights: Rights::new(),
+            }; NMSG],
+            msg_r: 0,
+            msg_w: 0,
+        }
+    }
+
+    // sleep on this for any change
+    fn chan(&self) -> u64 {
+        self as *const Unix as u64
+    }
+}
+
+static mut UNIX: [Unix; NUNIX] = [Unix::new(); NUNIX];
+
+fn unix(i: usize) -> &'static mut Unix {
+    unsafe { &mut UNIX[i] }
+}
+
+fn killed() -> bool {
+    unsafe { (*my_proc()).killed != 0 }
+}
+
+// a new socket with its ring, NONE if there is no room
+fn alloc(stype: i32) -> usize {
+    for i in 0..NUNIX {
+        let u = unix(i);
+        if u.used {
+            continue;
+        }
+        let buf = kalloc() as *mut u8;
+        if buf.is_null() {
+            return NONE;
+        }
+
+        *u = Unix::new();
+        u.used = true;
+        u.stype = stype;
+        u.rx.buf = buf;
+        return i;
+    }
+
+    NONE
+}
+
+// a new socket of type stype, -1 if there are none left
+pub fn unix_alloc(stype: i32) -> i32 {
+    match alloc(stype) {
+        NONE => -1,
+        i => i as i32,
+    }
+}
+
+// the owner is done with i
+pub fn unix_close(i: usize) {
+    let u = unix(i);
+    match u.state {
+        UnixState::Listening => {
+            // the connections never accepted go too
+            for c in 0..NUNIX {
+                if unix(c).used && unix(c).parent == i {
+                    unix_close(c);
+                }
+            }
+        }
+        UnixState::Connected if u.stype == SOCK_STREAM => {
+            let p = unix(u.peer);
+            p.peer = NONE;
+            p.state = UnixState::Disconnected;
+            p.eof = true;
+            wakeup(p.chan());
+        }
+        _ => {}
+    }
+
+    u.ino = 0;
+    while u.msg_r != u.msg_w {
+        u.msg[u.msg_r % NMSG].rights.close();
+        u.msg_r += 1;
+    }
+    kfree(u.rx.buf as *mut u64);
+    u.used = false;
+    wakeup(u.chan());
+}
+
+// the socket of type stype bound to the node dev, ino
+fn bound(dev: u32, ino: u32, stype: i32) -> usize {
+    for i in 0..NUNIX {
+        let u = unix(i);
+        if u.used && u.ino != 0 && u.ino == ino && u.dev == dev && u.stype == stype {
+            return i;
+        }
+    }
+
+    NONE
+}
+
+// the device and inode number of the socket node at path
+fn node_at(path: *mut u8) -> Option<(u32, u32)> {
+    let inode = path_lookup(path, true)?;
+    unsafe {
+        let node = ((*inode).dev(), (*inode).ino());
+        let sock = is_socket(inode);
+        (*inode).put();
+        match sock {
+            true => Some(node),
+            false => None,
+        }
+    }
+}
+
+// the path i is bound to, without the '\0'
+fn path_of(i: usize) -> &'static [u8] {
+    let u = unix(i);
+    let len = u.path.iter().position(|&c| c == 0).unwrap_or(0);
+    &u.path[..len]
+}
+
+// where the peer of the stream socket i is bound, empty if it isn't
+pub fn unix_peer_path(i: usize) -> &'static [u8] {
+    match unix(i).peer {
+        NONE => &[],
+        p => path_of(p),
+    }
+}
+
+// bind i to a new socket node at the '\0' terminated path.
+// return 0, or -1 or -errno
+pub fn unix_bind(i: usize, path: *mut u8) -> i32 {
+    let u = unix(i);
+    if u.ino != 0 {
+        return -EINVAL;
+    }
+    if let Some(inode) = path_lookup(path, false) {
+        unsafe { (*inode).put() };
+        return -EADDRINUSE;
+    }
+    let inode = match create(path, S_IFSOCK | 0o777) {
+        Some(inode) => inode,
+        None => return -1,
+    };
+
+    unsafe {
+        u.dev = (*inode).dev();
+        u.ino = (*inode).ino();
+        (*inode).put();
+        let mut n = 0;
+        while n < UNIX_PATH_MAX - 1 && *path.add(n) != 0 {
+            u.path[n] = *path.add(n);
+            n += 1;
+        }
+        u.path[n] = 0;
+    }
+    0
+}
+
+// accept connections on i, at most backlog of them waiting
+pub fn unix_listen(i: usize, backlog: usize) -> i32 {
+    let u = unix(i);
+    if u.stype != SOCK_STREAM {
+        return -EOPNOTSUPP;
+    }
+    if u.ino == 0 || (u.state != UnixState::Unconnected && u.state != UnixState::Listening) {
+        return -EINVAL;
+    }
+    u.state = UnixState::Listening;
+    u.backlog = backlog.clamp(1, NUNIX);
+    0
+}
+
+// connect i to the socket bound at path. a stream socket is paired
+// with a new one for the listener to accept, a datagram socket only
+// takes note of where to send by default. return 0, or -1 or -errno
+pub fn unix_connect(i: usize, path: *mut u8) -> i32 {
+    let u = unix(i);
+    let (dev, ino) = match node_at(path) {
+        Some(node) => node,
+        None => return -ECONNREFUSED,
+    };
+
+    if u.stype == SOCK_DGRAM {
+        if bound(dev, ino, SOCK_DGRAM) == NONE {
+            return -ECONNREFUSED;
+        }
+        u.dst_dev = dev;
+        u.dst_ino = ino;
+        u.state = UnixState::Connected;
+        return 0;
+    }
+
+    if u.state != UnixState::Unconnected {
+        return -EINVAL;
+    }
+    let l = bound(dev, ino, SOCK_STREAM);
+    if l == NONE || unix(l).state != UnixState::Listening {
+        return -ECONNREFUSED;
+    }
+    let pending = (0..NUNIX)
+        .filter(|&c| unix(c).used && unix(c).parent == l)
+        .count();
+    if pending >= unix(l).backlog {
+        return -ECONNREFUSED;
+    }
+    let c = alloc(SOCK_STREAM);
+    if c == NONE {
+        return -1;
+    }
+
+    let cu = unix(c);
+    cu.state = UnixState::Connected;
+    cu.peer = i;
+    cu.parent = l;
+    u.state = UnixState::Connected;
+    u.peer = c;
+    wakeup(unix(l).chan());
+    0
+}
+
+// wait for a connection on the listening i.
+// return it, or -1 or -errno
+pub fn unix_accept(i: usize) -> i32 {
+    let u = unix(i);
+    loop {
+        if u.state != UnixState::Listening {
+            return -EINVAL;
+        }
+        for c in 0..NUNIX {
+            let cu = unix(c);
+            if cu.used && cu.parent == i {
+                cu.parent = NONE;
+                return c as i32;
+            }
+        }
+        if killed() {
+            return -1;
+        }
+        sleep(u.chan());
+    }
+}
+
+// append a message of len bytes, already in the ring of p, taking
+// references to the files of rights
+fn push(p: &mut Unix, len: usize, src_dev: u32, src_ino: u32, rights: &Rights) {
+    let m = &mut p.msg[p.msg_w % NMSG];
+    m.len = len;
+    m.src_dev = src_dev;
+    m.src_ino = src_ino;
+    m.rights = *rights;
+    for f in m.rights.file[..m.rights.n].iter() {
+        file_dup(*f);
+    }
+    p.msg_w += 1;
+}
+
+// send n bytes from the user address src, with the files of rights.
+// a datagram goes to the socket bound at path, or where i is
+// connected if path is null. return the number of bytes sent, or
+// -1 or -errno
+pub fn unix_send(i: usize, src: u64, n: u32, path: *mut u8, rights: &Rights) -> i32 {
+    let u = unix(i);
+    match u.stype {
+        SOCK_STREAM => stream_send(u, src, n as usize, rights),
+        _ => dgram_send(u, src, n as usize, path, rights),
+    }
+}
+
+fn stream_send(u: &mut Unix, src: u64, n: usize, rights: &Rights) -> i32 {
+    let mut cnt: usize = 0;
+    while cnt < n {
+        let p = match u.state {
+            UnixState::Connected => unix(u.peer),
+            _ if cnt > 0 => break,
+            UnixState::Disconnected => return -EPIPE,
+            _ => return -ENOTCONN,
+        };
+
+        // the files go with the first bytes, in a message of their
+        // own. others are added to the last message if it has none
+        let files = cnt == 0 && rights.n > 0;
+        let last = &mut p.msg[p.msg_w.wrapping_sub(1) % NMSG];
+        let append = !files && p.msg_w != p.msg_r && last.rights.n == 0;
+        if p.rx.space() == 0 || (!append && p.msg_w - p.msg_r == NMSG) {
+            if killed() {
+                return -1;
+            }
+            sleep(p.chan());
+            continue;
+        }
+
+        let len = min(n - cnt, p.rx.space());
+        if p.rx.put_from(1, src + cnt as u64, len) == -1 {
+            return -1;
+        }
+        match (append, files) {
+            (true, _) => last.len += len,
+            (false, true) => push(p, len, u.dev, u.ino, rights),
+            (false, false) => push(p, len, u.dev, u.ino, &Rights::new()),
+        }
+        cnt += len;
+        wakeup(p.chan());
+    }
+
+    cnt as i32
+}
+
+fn dgram_send(u: &mut Unix, src: u64, n: usize, path: *mut u8, rights: &Rights) -> i32 {
+    let (dev, ino) = if path.is_null() {
+        if u.state != UnixState::Connected {
+            return -ENOTCONN;
+        }
+        (u.dst_dev, u.dst_ino)
+    } else {
+        match node_at(path) {
+            Some(node) => node,
+            None => return -ECONNREFUSED,
+        }
+    };
+    if n > RING_SIZE {
+        return -EMSGSIZE;
+    }
+
+    loop {
+        // looked up again after sleeping, it may be gone
+        let d = bound(dev, ino, SOCK_DGRAM);
+        if d == NONE {
+            return -ECONNREFUSED;
+        }
+        let p = unix(d);
+        if p.rx.space() >= n && p.msg_w - p.msg_r < NMSG {
+            if p.rx.put_from(1, src, n) == -1 {
+                return -1;
+            }
+            push(p, n, u.dev, u.ino, rights);
+            wakeup(p.chan());
+            return n as i32;
+        }
+        if killed() {
+            return -1;
+        }
+        sleep(p.chan());
+    }
+}
+
+// receive at most n bytes to the user address dst, and the files
+// sent along to rights. the path of the datagram's sender goes to
+// from, empty if it isn't bound. return the number of bytes
+// received, 0 at end of file, or -1 or -errno
+pub fn unix_recv(
+    i: usize,
+    dst: u64,
+    n: u32,
+    rights: &mut Rights,
+    from: &mut [u8; UNIX_PATH_MAX],
+) -> i32 {
+    let u = unix(i);
+    let n = n as usize;
+    rights.n = 0;
+    from[0] = 0;
+    while u.msg_r == u.msg_w {
+        if u.eof {
+            return 0;
+        }
+        if u.stype == SOCK_STREAM && u.state != UnixState::Connected {
+            return -ENOTCONN;
+        }
+        if killed() {
+            return -1;
+        }
+        sleep(u.chan());
+    }
+
+    let mut cnt: usize = 0;
+    while u.msg_r != u.msg_w {
+        let m = &mut u.msg[u.msg_r % NMSG];
+        if m.rights.n > 0 && cnt > 0 {
+            break;
+        }
+        let len = min(n - cnt, m.len);
+        if u.rx.get_to(1, dst + cnt as u64, len) == -1 {
+            return -1;
+        }
+        cnt += len;
+        m.len -= len;
+        if m.rights.n > 0 {
+            *rights = m.rights;
+            m.rights.n = 0;
+        }
+
+        if u.stype == SOCK_DGRAM {
+            let s = bound(m.src_dev, m.src_ino, SOCK_DGRAM);
+            if s != NONE {
+                let p = path_of(s);
+                from[..p.len()].copy_from_slice(p);
+                from[p.len()] = 0;
+            }
+            // what didn't fit is lost
+            u.rx.consume(m.len);
+            u.msg_r += 1;
+            break;
+        }
+        if m.len > 0 {
+            break;
+        }
+        u.msg_r += 1;
+    }
+    wakeup(u.chan());
+
+    cnt as i32
+}
+
+// i sends no more, the stream peer reads the end of file.
+// return 0, or -ENOTCONN
+pub fn unix_shutdown(i: usize, wr: bool) -> i32 {
+    let u = unix(i);
+    if u.stype != SOCK_STREAM {
+        return 0;
+    }
+    if u.state != UnixState::Connected && u.state != UnixState::Disconnected {
+        return -ENOTCONN;
+    }
+    if wr && u.peer != NONE {
+        let p = unix(u.peer);
+        p.eof = true;
+        wakeup(p.chan());
+    }
+    0
+}
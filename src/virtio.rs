@@ -0,0 +1,608 @@
+use crate::mem_layout::{virtio_irq, virtio_mmio, VIRTIO0_IRQ, VIRTIO_NSLOT};
+use crate::proc::wakeup;
+use crate::riscv::{PAGE_SHIFT, PAGE_SIZE};
+use core::fmt::Write;
+use core::mem::size_of;
+use core::ptr::{null_mut, read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+// what the virtio drivers share: the mmio transport, feature
+// negotiation and split virtqueues.
+//
+// a driver finds its device with Mmio::probe(), calls begin() and
+// negotiate(), sets up each of its queues with Virtqueue::init(),
+// then calls driver_ok() and virtio_set_intr(). a request is a chain of buffers put in a
+// queue with add() or add_indirect(), handed to the device with
+// submit(), and found again by pop_used() once the device is done
+// with it, usually in the interrupt handler of the driver.
+//
+// with VIRTIO_RING_F_EVENT_IDX, each side tells the other at which
+// ring index it wants to hear about new entries: the device writes
+// avail_event after the used ring, the driver used_event after the
+// available one. requests queued while the device is still busy with
+// the previous ones are then not notified, and completions coming in
+// while the driver is draining the used ring raise no interrupt of
+// their own.
+
+// https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.pdf
+// pdf 4.2.2, register offsets from the base of a slot.
+// version 1 (legacy) devices give the queue as a page number,
+// version 2 (modern) ones take the address of each of its parts
+pub const VIRTIO_MMIO_MAGIC_VALUE: u64 = 0x000;
+pub const VIRTIO_MMIO_VERSION: u64 = 0x004;
+pub const VIRTIO_MMIO_DEVICE_ID: u64 = 0x008;
+pub const VIRTIO_MMIO_VENDOR_ID: u64 = 0x00c;
+pub const VIRTIO_MMIO_DEVICE_FEATURES: u64 = 0x010;
+pub const VIRTIO_MMIO_DEVICE_FEATURES_SEL: u64 = 0x014;
+pub const VIRTIO_MMIO_DRIVER_FEATURES: u64 = 0x020;
+pub const VIRTIO_MMIO_DRIVER_FEATURES_SEL: u64 = 0x024;
+pub const VIRTIO_MMIO_GUEST_PAGE_SIZE: u64 = 0x028; // legacy
+pub const VIRTIO_MMIO_QUEUE_SEL: u64 = 0x030;
+pub const VIRTIO_MMIO_QUEUE_NUM_MAX: u64 = 0x034;
+pub const VIRTIO_MMIO_QUEUE_NUM: u64 = 0x038;
+pub const VIRTIO_MMIO_QUEUE_ALIGN: u64 = 0x03c; // legacy
+pub const VIRTIO_MMIO_QUEUE_PFN: u64 = 0x040; // legacy
+pub const VIRTIO_MMIO_QUEUE_READY: u64 = 0x044;
+pub const VIRTIO_MMIO_QUEUE_NOTIFY: u64 = 0x050;
+pub const VIRTIO_MMIO_INTERRUPT_STATUS: u64 = 0x060;
+pub const VIRTIO_MMIO_INTERRUPT_ACK: u64 = 0x064;
+pub const VIRTIO_MMIO_STATUS: u64 = 0x070;
+pub const VIRTIO_MMIO_QUEUE_DESC_LOW: u64 = 0x080;
+pub const VIRTIO_MMIO_QUEUE_DESC_HIGH: u64 = 0x084;
+pub const VIRTIO_MMIO_QUEUE_DRIVER_LOW: u64 = 0x090; // the available ring
+pub const VIRTIO_MMIO_QUEUE_DRIVER_HIGH: u64 = 0x094;
+pub const VIRTIO_MMIO_QUEUE_DEVICE_LOW: u64 = 0x0a0; // the used ring
+pub const VIRTIO_MMIO_QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+pub const VIRTIO_MMIO_CONFIG: u64 = 0x100; // device specific configuration space
+
+// the causes in VIRTIO_MMIO_INTERRUPT_STATUS
+pub const VIRTIO_MMIO_INT_VRING: u32 = 1; // a used ring was updated
+pub const VIRTIO_MMIO_INT_CONFIG: u32 = 2; // the configuration changed
+
+const VIRTIO_MAGIC: u32 = 0x74726976; // "virt"
+const VIRTIO_VENDOR_QEMU: u32 = 0x554d4551;
+
+// pdf 5, device ids
+pub const VIRTIO_ID_NET: u32 = 1;
+pub const VIRTIO_ID_BLOCK: u32 = 2;
+pub const VIRTIO_ID_CONSOLE: u32 = 3;
+pub const VIRTIO_ID_RNG: u32 = 4;
+pub const VIRTIO_ID_INPUT: u32 = 18;
+
+pub const VIRTIO_CONFIG_S_ACKNOWLEDGE: u32 = 1;
+pub const VIRTIO_CONFIG_S_DRIVER: u32 = 2;
+pub const VIRTIO_CONFIG_S_DRIVER_OK: u32 = 4;
+pub const VIRTIO_CONFIG_S_FEATURES_OK: u32 = 8;
+
+// pdf 6, feature bits of every device type
+pub const VIRTIO_F_ANY_LAYOUT: u32 = 27;
+pub const VIRTIO_RING_F_INDIRECT_DESC: u32 = 28;
+pub const VIRTIO_RING_F_EVENT_IDX: u32 = 29;
+pub const VIRTIO_F_VERSION_1: u32 = 32; // required of a modern device
+
+// the largest queue, the one used is QUEUE_NUM_MAX of the device
+// rounded down to a power of 2 if that is smaller.
+// its descriptors and available ring fit in the first page
+pub const QUEUE_SIZE: usize = 128;
+
+// the longest chain add() and add_indirect() take
+pub const MAX_CHAIN: usize = 32;
+
+// pdf 2.6
+pub const VIRTQ_DESC_F_NEXT: u16 = 1; // marks a buffer as continuing via the next field
+pub const VIRTQ_DESC_F_WRITE: u16 = 2; // marks a buffer as device write-only
+pub const VIRTQ_DESC_F_INDIRECT: u16 = 4; // the buffer contains a list of buffer descriptors
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct VirtqDesc {
+    addr: u64, // guest physical
+    len: u32,
+    flags: u16, // the flags as indicated above
+    next: u16,  // next field if flags & NEXT
+}
+
+impl VirtqDesc {
+    pub const fn new() -> Self {
+        VirtqDesc {
+            addr: 0,
+            len: 0,
+            flags: 0,
+            next: 0,
+        }
+    }
+}
+
+#[repr(C)]
+struct VirtqAvail {
+    flags: u16,
+
+    // indicates where the driver would put the next descriptor entry in the ring
+    // (modulo the queue size)
+    idx: u16,
+
+    // each ring entry refers to the head of a descriptor chain.
+    // only the first num entries are used, followed by used_event
+    ring: [u16; QUEUE_SIZE],
+}
+
+#[repr(C)]
+struct VirtqUsed {
+    flags: u16,
+    idx: u16,
+    // num entries used, followed by avail_event
+    ring: [VirtqUsedElem; QUEUE_SIZE],
+}
+
+#[repr(C)]
+struct VirtqUsedElem {
+    id: u32,  // index of start of used descriptor chain
+    len: u32, // total length of the descriptor chain which was used (written to)
+}
+
+// a buffer of a request
+#[derive(Copy, Clone)]
+pub struct VirtqBuf {
+    pub addr: u64,
+    pub len: u32,
+    pub write: bool, // written by the device, read if not
+}
+
+// the registers of a device in a virtio mmio slot
+#[derive(Copy, Clone)]
+pub struct Mmio {
+    pub base: u64, // 0 if there is no device
+    pub version: u32,
+    pub irq: u64,
+}
+
+impl Mmio {
+    pub const fn new() -> Self {
+        Mmio {
+            base: 0,
+            version: 0,
+            irq: 0,
+        }
+    }
+
+    // the device of type id in slot, None if the slot holds
+    // something else or a device this driver doesn't support
+    pub fn probe(slot: u64, id: u32) -> Option<Mmio> {
+        let m = Mmio {
+            base: virtio_mmio(slot),
+            version: 0,
+            irq: virtio_irq(slot),
+        };
+        if m.read(VIRTIO_MMIO_MAGIC_VALUE) != VIRTIO_MAGIC || m.read(VIRTIO_MMIO_DEVICE_ID) != id {
+            return None;
+        }
+        let version = m.read(VIRTIO_MMIO_VERSION);
+        if (version != 1 && version != 2) || m.read(VIRTIO_MMIO_VENDOR_ID) != VIRTIO_VENDOR_QEMU {
+            println!("virtio: slot {} unsupported", slot);
+            return None;
+        }
+
+        Some(Mmio { version, ..m })
+    }
+
+    pub fn read(&self, off: u64) -> u32 {
+        unsafe { read_volatile((self.base + off) as *const u32) }
+    }
+
+    pub fn write(&self, off: u64, val: u32) {
+        unsafe { write_volatile((self.base + off) as *mut u32, val) }
+    }
+
+    // write the address of a queue part to a low and high register pair
+    fn write_addr(&self, low: u64, high: u64, addr: u64) {
+        self.write(low, addr as u32);
+        self.write(high, (addr >> 32) as u32);
+    }
+
+    // fields of the device specific configuration space
+    pub fn config32(&self, off: u64) -> u32 {
+        self.read(VIRTIO_MMIO_CONFIG + off)
+    }
+
+    pub fn config8(&self, off: u64) -> u8 {
+        unsafe { read_volatile((self.base + VIRTIO_MMIO_CONFIG + off) as *const u8) }
+    }
+
+    pub fn set_config8(&self, off: u64, val: u8) {
+        unsafe { write_volatile((self.base + VIRTIO_MMIO_CONFIG + off) as *mut u8, val) }
+    }
+
+    pub fn modern(&self) -> bool {
+        self.version == 2
+    }
+
+    fn add_status(&self, bit: u32) {
+        self.write(VIRTIO_MMIO_STATUS, self.read(VIRTIO_MMIO_STATUS) | bit);
+    }
+
+    // reset the device and tell it a driver has found it, pdf 3.1.1
+    pub fn begin(&self) {
+        self.write(VIRTIO_MMIO_STATUS, 0);
+        self.add_status(VIRTIO_CONFIG_S_ACKNOWLEDGE);
+        self.add_status(VIRTIO_CONFIG_S_DRIVER);
+    }
+
+    // accept the features of the device that are in supported, as bit
+    // numbers shifted. return the negotiated ones, None if the device
+    // doesn't agree
+    pub fn negotiate(&self, supported: u64) -> Option<u64> {
+        let mut device: u64 = 0;
+        for sel in 0..2 {
+            self.write(VIRTIO_MMIO_DEVICE_FEATURES_SEL, sel);
+            device |= (self.read(VIRTIO_MMIO_DEVICE_FEATURES) as u64) << (32 * sel);
+        }
+
+        // bits 32 and up are only known to a modern device,
+        // which must offer VERSION_1
+        let mut features = device & supported;
+        if self.modern() {
+            if device & 1 << VIRTIO_F_VERSION_1 == 0 {
+                println!("virtio: no VIRTIO_F_VERSION_1 at {:#x}", self.base);
+                return None;
+            }
+            features |= 1 << VIRTIO_F_VERSION_1;
+        } else {
+            features &= 0xffff_ffff;
+        }
+        for sel in 0..2 {
+            self.write(VIRTIO_MMIO_DRIVER_FEATURES_SEL, sel);
+            self.write(VIRTIO_MMIO_DRIVER_FEATURES, (features >> (32 * sel)) as u32);
+        }
+
+        // the device may still refuse the combination
+        self.add_status(VIRTIO_CONFIG_S_FEATURES_OK);
+        if self.read(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_FEATURES_OK == 0 {
+            return None;
+        }
+
+        Some(features)
+    }
+
+    // the device is live from now on
+    pub fn driver_ok(&self) {
+        self.add_status(VIRTIO_CONFIG_S_DRIVER_OK);
+    }
+
+    // tell the device there are new buffers in queue
+    pub fn notify(&self, queue: u32) {
+        self.write(VIRTIO_MMIO_QUEUE_NOTIFY, queue);
+    }
+
+    // acknowledge an interrupt, return its causes
+    pub fn ack_intr(&self) -> u32 {
+        let status = self.read(VIRTIO_MMIO_INTERRUPT_STATUS) & 0x3;
+        self.write(VIRTIO_MMIO_INTERRUPT_ACK, status);
+        fence(Ordering::SeqCst);
+        status
+    }
+}
+
+// the interrupt handler of the driver of each slot
+static mut INTR: [Option<fn(u32)>; VIRTIO_NSLOT as usize] = [None; VIRTIO_NSLOT as usize];
+
+// have the interrupts of the device at mmio handled by handler
+pub fn virtio_set_intr(mmio: &Mmio, handler: fn(u32)) {
+    unsafe {
+        INTR[(mmio.irq - VIRTIO0_IRQ) as usize] = Some(handler);
+    }
+}
+
+// called by plic_intr() for the irq of a virtio slot
+pub fn virtio_intr(irq: u32) {
+    match unsafe { INTR[(irq as u64 - VIRTIO0_IRQ) as usize] } {
+        Some(handler) => handler(irq),
+        None => {
+            println!("virtio: no driver for irq {}", irq);
+        }
+    }
+}
+
+// does moving an index from old to new pass event, pdf 2.6.7.2
+fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
+#[repr(C, align(4096))] // shuold be PAGE_SIZE here
+#[derive(Copy, Clone)]
+pub struct Virtqueue {
+    // contiguous memory for queue
+    pages: [u8; 2 * PAGE_SIZE as usize],
+
+    // The actual descriptors (16 bytes each)
+    desc: *mut VirtqDesc,
+
+    // A ring of available descriptor heads with free-running index.
+    avail: *mut VirtqAvail,
+
+    // A ring of used descriptor heads with free-running index.
+    used: *mut VirtqUsed,
+
+    num: u16,        // queue size in use
+    index: u32,      // of the queue on its device
+    event_idx: bool, // VIRTIO_RING_F_EVENT_IDX negotiated
+
+    // record free descriptors
+    is_free: [bool; QUEUE_SIZE],
+
+    // how far pop_used() has looked in the used ring
+    used_idx: u16,
+}
+
+impl Virtqueue {
+    pub const fn new() -> Self {
+        Virtqueue {
+            pages: [0; 2 * PAGE_SIZE as usize],
+            desc: null_mut(),
+            avail: null_mut(),
+            used: null_mut(),
+            num: 0,
+            index: 0,
+            event_idx: false,
+            is_free: [true; QUEUE_SIZE],
+            used_idx: 0,
+        }
+    }
+
+    // set up the queue as queue index of the device at mmio, once
+    // features are negotiated. the queue must not move afterwards
+    pub fn init(&mut self, mmio: &Mmio, index: u32, features: u64) {
+        self.index = index;
+        self.event_idx = features & 1 << VIRTIO_RING_F_EVENT_IDX != 0;
+
+        // select the virtual queue that the following operations apply to
+        mmio.write(VIRTIO_MMIO_QUEUE_SEL, index);
+
+        // the queue must not be in use
+        if mmio.modern() && mmio.read(VIRTIO_MMIO_QUEUE_READY) != 0 {
+            panicc!("virtio: queue should not be ready");
+        }
+
+        // the max size of the queue
+        let max_num = mmio.read(VIRTIO_MMIO_QUEUE_NUM_MAX);
+        if max_num == 0 {
+            panicc!("virtio: queue not available");
+        }
+        let mut num = QUEUE_SIZE as u32;
+        while num > max_num {
+            num /= 2;
+        }
+        if num < 3 {
+            panicc!("virtio: max size to small");
+        }
+        self.num = num as u16;
+        mmio.write(VIRTIO_MMIO_QUEUE_NUM, num);
+
+        // the descriptors and the available ring in the first page,
+        // the used ring in the second, as the legacy layout wants
+        let pages = &mut self.pages as *mut u8;
+        unsafe {
+            self.desc = pages as *mut VirtqDesc;
+            self.avail = pages.add(num as usize * size_of::<VirtqDesc>()) as *mut VirtqAvail;
+            self.used = pages.add(PAGE_SIZE as usize) as *mut VirtqUsed;
+        }
+
+        if mmio.modern() {
+            mmio.write_addr(
+                VIRTIO_MMIO_QUEUE_DESC_LOW,
+                VIRTIO_MMIO_QUEUE_DESC_HIGH,
+                self.desc as u64,
+            );
+            mmio.write_addr(
+                VIRTIO_MMIO_QUEUE_DRIVER_LOW,
+                VIRTIO_MMIO_QUEUE_DRIVER_HIGH,
+                self.avail as u64,
+            );
+            mmio.write_addr(
+                VIRTIO_MMIO_QUEUE_DEVICE_LOW,
+                VIRTIO_MMIO_QUEUE_DEVICE_HIGH,
+                self.used as u64,
+            );
+            mmio.write(VIRTIO_MMIO_QUEUE_READY, 1);
+        } else {
+            // used by device to calculate the Guest address of the first queue page
+            mmio.write(VIRTIO_MMIO_GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+            // set guest physical page number of the virtual queue
+            mmio.write(VIRTIO_MMIO_QUEUE_PFN, (pages as u64 >> PAGE_SHIFT) as u32);
+        }
+    }
+
+    // queue size in use
+    pub fn num(&self) -> usize {
+        self.num as usize
+    }
+
+    // sleep on this for free descriptors
+    pub fn free_chan(&self) -> u64 {
+        &self.is_free as *const bool as u64
+    }
+
+    fn alloc_desc(&mut self) -> Option<usize> {
+        for i in 0..self.num as usize {
+            if self.is_free[i] {
+                self.is_free[i] = false;
+                return Some(i);
+            }
+        }
+
+        None
+    }
+
+    // alloc idx.len() descriptors, all or none
+    fn alloc(&mut self, idx: &mut [usize]) -> bool {
+        for i in 0..idx.len() {
+            match self.alloc_desc() {
+                Some(d) => idx[i] = d,
+                None => {
+                    for j in 0..i {
+                        self.free_desc(idx[j]);
+                    }
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    fn free_desc(&mut self, i: usize) {
+        if self.is_free[i] {
+            panicc!("virtio: free_desc: already free");
+        }
+        unsafe {
+            *self.desc.add(i) = VirtqDesc {
+                addr: 0,
+                len: 0,
+                flags: 0,
+                next: 0,
+            };
+        }
+        self.is_free[i] = true;
+        // wake up whoever waits for descriptors
+        wakeup(self.free_chan());
+    }
+
+    // free the chain starting at head
+    pub fn free_chain(&mut self, mut i: usize) {
+        loop {
+            let d = unsafe { *self.desc.add(i) };
+            self.free_desc(i);
+            match d.flags & VIRTQ_DESC_F_NEXT {
+                0 => break,
+                _ => i = d.next as usize,
+            }
+        }
+    }
+
+    // link chain into the descriptors of table at slot
+    fn fill(table: *mut VirtqDesc, slot: &[usize], chain: &[VirtqBuf]) {
+        for i in 0..chain.len() {
+            let mut flags = match chain[i].write {
+                true => VIRTQ_DESC_F_WRITE,
+                false => 0,
+            };
+            let mut next = 0;
+            if i + 1 < chain.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+                next = slot[i + 1] as u16;
+            }
+            unsafe {
+                *table.add(slot[i]) = VirtqDesc {
+                    addr: chain[i].addr,
+                    len: chain[i].len,
+                    flags,
+                    next,
+                };
+            }
+        }
+    }
+
+    // put chain in the descriptors of the queue. return its head,
+    // None if there aren't enough free
+    pub fn add(&mut self, chain: &[VirtqBuf]) -> Option<usize> {
+        let mut idx: [usize; MAX_CHAIN] = [0; MAX_CHAIN];
+        let n = chain.len();
+        if n == 0 || n > MAX_CHAIN || n > self.num as usize {
+            panicc!("virtio: add: bad chain length");
+        }
+        if !self.alloc(&mut idx[..n]) {
+            return None;
+        }
+
+        Virtqueue::fill(self.desc, &idx[..n], chain);
+        Some(idx[0])
+    }
+
+    // put chain in table, indirect descriptors owned by the caller
+    // until the request is done, and table in a descriptor of the
+    // queue. needs VIRTIO_RING_F_INDIRECT_DESC
+    pub fn add_indirect(&mut self, table: *mut VirtqDesc, chain: &[VirtqBuf]) -> Option<usize> {
+        let n = chain.len();
+        if n == 0 || n > MAX_CHAIN {
+            panicc!("virtio: add_indirect: bad chain length");
+        }
+        let head = self.alloc_desc()?;
+
+        let mut slot: [usize; MAX_CHAIN] = [0; MAX_CHAIN];
+        for i in 0..n {
+            slot[i] = i;
+        }
+        Virtqueue::fill(table, &slot[..n], chain);
+        unsafe {
+            *self.desc.add(head) = VirtqDesc {
+                addr: table as u64,
+                len: (n * size_of::<VirtqDesc>()) as u32,
+                flags: VIRTQ_DESC_F_INDIRECT,
+                next: 0,
+            };
+        }
+        Some(head)
+    }
+
+    // used_event, after the available ring
+    fn used_event(&self) -> *mut u16 {
+        unsafe { (&mut (*self.avail).ring as *mut u16).add(self.num as usize) }
+    }
+
+    // avail_event, after the used ring
+    fn avail_event(&self) -> *mut u16 {
+        unsafe { (&mut (*self.used).ring as *mut VirtqUsedElem).add(self.num as usize) as *mut u16 }
+    }
+
+    // hand the chain starting at head to the device of mmio,
+    // return whether the device had to be notified
+    pub fn submit(&mut self, mmio: &Mmio, head: usize) -> bool {
+        unsafe {
+            // write the desc index into the available ring
+            let avail = self.avail;
+            (*avail).ring[(*avail).idx as usize % self.num as usize] = head as u16;
+
+            // the device must see the ring entry before the new index
+            fence(Ordering::SeqCst);
+            let old = (*avail).idx;
+            (*avail).idx = old.wrapping_add(1);
+            fence(Ordering::SeqCst);
+
+            // a device still working on the ring will find the entry by itself
+            if self.event_idx && !need_event(read_volatile(self.avail_event()), (*avail).idx, old) {
+                return false;
+            }
+        }
+
+        // the value written is the queue index
+        mmio.notify(self.index);
+        true
+    }
+
+    // the next chain the device is done with, as its head and the
+    // number of bytes the device wrote. the chain stays allocated
+    pub fn pop_used(&mut self) -> Option<(usize, u32)> {
+        unsafe {
+            if self.used_idx == read_volatile(&(*self.used).idx) {
+                return None;
+            }
+            fence(Ordering::SeqCst);
+            let e = &(*self.used).ring[self.used_idx as usize % self.num as usize];
+            self.used_idx = self.used_idx.wrapping_add(1);
+            Some((e.id as usize, e.len))
+        }
+    }
+
+    // ask for an interrupt at the next used entry. false if one came
+    // before the device could see that, so pop_used() must be called
+    // again
+    pub fn enable_intr(&mut self) -> bool {
+        if !self.event_idx {
+            return true;
+        }
+
+        unsafe {
+            write_volatile(self.used_event(), self.used_idx);
+            fence(Ordering::SeqCst);
+            read_volatile(&(*self.used).idx) == self.used_idx
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use crate::console::{console_intr, console_set_log};
+use crate::mem_layout::{clint_mtime, VIRTIO_NSLOT};
+use crate::param::NVPORT;
+use crate::riscv::{intr_get, intr_off, intr_on};
+use crate::timer::FREQ;
+use crate::virtio::{virtio_set_intr, Mmio, VirtqBuf, Virtqueue, QUEUE_SIZE, VIRTIO_ID_CONSOLE};
+use core::cmp::min;
+use core::fmt::Write;
+use core::ptr::read_volatile;
+use core::str::from_utf8;
+
+// virtio console, pdf 5.3.
+//
+// each port of the device is a tty, port i is tty 1 + i (see
+// console.rs). a port has a receive and a transmit queue. with
+// VIRTIO_CONSOLE_F_MULTIPORT there are up to max_nr_ports of them,
+// and a pair of control queues over which the device reports ports
+// coming and going, and their names. without it there is port 0
+// only.
+//
+// every receive queue holds NRX buffers the device fills with input,
+// the interrupt handler passes it to console_intr() and puts the
+// buffer back. output is sent one buffer at a time, waiting with
+// interrupts off until the device is done with it, so the kernel
+// log can use a port from anywhere. a host that stops reading
+// would hang the kernel, so the wait gives up after TX_WAIT. the
+// buffer then stays the device's, and output to the port is
+// dropped until the device gives it back.
+//
+// a port the host names LOG_NAME, as with
+// -device virtserialport,name=log, gets the kernel log.
+
+// pdf 5.3.3, feature bits
+const VIRTIO_CONSOLE_F_MULTIPORT: u32 = 1;
+
+// pdf 5.3.4, device configuration layout
+const VIRTIO_CONSOLE_CFG_MAX_NR_PORTS: u64 = 4;
+
+// pdf 5.3.6.2, events of control messages
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+// a control message is id (u32), event (u16) and value (u16),
+// followed by the name for PORT_NAME
+const CONTROL_LEN: usize = 8;
+
+const NRX: usize = 4; // receive buffers per queue
+const RX_BUF: usize = 64;
+const TX_BUF: usize = 128;
+const TX_WAIT: u64 = FREQ / 10; // mtime ticks to wait for the device to take output
+
+const LOG_NAME: &[u8] = b"log";
+
+// a port, or the control queues
+#[derive(Copy, Clone)]
+struct Port {
+    rx: Virtqueue,
+    tx: Virtqueue,
+    rx_buf: [[u8; RX_BUF]; NRX],
+    rx_of: [usize; QUEUE_SIZE], // rx_buf of each chain head
+    tx_buf: [u8; TX_BUF],
+    tx_busy: bool, // the device still has tx_buf from a send that gave up
+    present: bool, // the device has added the port
+}
+
+impl Port {
+    const fn new() -> Self {
+        Port {
+            rx: Virtqueue::new(),
+            tx: Virtqueue::new(),
+            rx_buf: [[0; RX_BUF]; NRX],
+            rx_of: [0; QUEUE_SIZE],
+            tx_buf: [0; TX_BUF],
+            tx_busy: false,
+            present: false,
+        }
+    }
+}
+
+struct Console {
+    mmio: Mmio, // base 0 if there is no device
+    multiport: bool,
+    nport: usize,
+    port: [Port; NVPORT],
+    control: Port,
+}
+
+static mut CONSOLE: Console = Console {
+    mmio: Mmio::new(),
+    multiport: false,
+    nport: 0,
+    port: [Port::new(); NVPORT],
+    control: Port::new(),
+};
+
+// queue numbers of port i, pdf 5.3.2
+const fn rx_queue(i: usize) -> u32 {
+    match i {
+        0 => 0,
+        _ => 2 * i as u32 + 2,
+    }
+}
+
+const CONTROL_RX_QUEUE: u32 = 2;
+
+pub fn virtio_console_init() {
+    for slot in 0..VIRTIO_NSLOT {
+        if let Some(mmio) = Mmio::probe(slot, VIRTIO_ID_CONSOLE) {
+            console_init(mmio);
+            return;
+        }
+    }
+}
+
+// set up the device at mmio, pdf 3.1.1
+fn console_init(mmio: Mmio) {
+    let c = unsafe { &mut CONSOLE };
+
+    mmio.begin();
+    let features = match mmio.negotiate(1 << VIRTIO_CONSOLE_F_MULTIPORT) {
+        Some(features) => features,
+        None => {
+            println!("virtio_console: features aren't supported");
+            return;
+        }
+    };
+    c.multiport = features & 1 << VIRTIO_CONSOLE_F_MULTIPORT != 0;
+    c.nport = 1;
+    if c.multiport {
+        let max = mmio.config32(VIRTIO_CONSOLE_CFG_MAX_NR_PORTS) as usize;
+        c.nport = min(max, NVPORT);
+    }
+
+    for i in 0..c.nport {
+        c.port[i].rx.init(&mmio, rx_queue(i), features);
+        c.port[i].tx.init(&mmio, rx_queue(i) + 1, features);
+    }
+    if c.multiport {
+        c.control.rx.init(&mmio, CONTROL_RX_QUEUE, features);
+        c.control.tx.init(&mmio, CONTROL_RX_QUEUE + 1, features);
+    }
+    c.mmio = mmio;
+    mmio.driver_ok();
+    virtio_set_intr(&mmio, virtio_console_intr);
+
+    for i in 0..c.nport {
+        fill(&c.mmio, &mut c.port[i]);
+    }
+    if c.multiport {
+        // the device adds the ports once it knows the driver is ready
+        fill(&c.mmio, &mut c.control);
+        control_send(c, 0, VIRTIO_CONSOLE_DEVICE_READY, 1);
+    } else {
+        c.port[0].present = true;
+    }
+
+    println!(
+        "virtio_console: at {:#x} v{}, {} ports",
+        mmio.base, mmio.version, c.nport
+    );
+}
+
+// the number of ports, which have ttys 1 to nport
+pub fn virtio_console_nport() -> usize {
+    unsafe { CONSOLE.nport }
+}
+
+// put all receive buffers of port in its queue
+fn fill(mmio: &Mmio, port: &mut Port) {
+    for i in 0..NRX {
+        rx_post(mmio, port, i);
+    }
+}
+
+// hand receive buffer i of port to the device
+fn rx_post(mmio: &Mmio, port: &mut Port, i: usize) {
+    let chain = [VirtqBuf {
+        addr: &port.rx_buf[i] as *const u8 as u64,
+        len: RX_BUF as u32,
+        write: true,
+    }];
+    let head = match port.rx.add(&chain) {
+        Some(head) => head,
+        None => {
+            panicc!("virtio_console: rx queue full");
+        }
+    };
+    port.rx_of[head] = i;
+    port.rx.submit(mmio, head);
+}
+
+fn mtime() -> u64 {
+    unsafe { read_volatile(clint_mtime() as *const u64) }
+}
+
+// free the buffers the device is done sending
+fn tx_reap(port: &mut Port) -> bool {
+    let mut done = false;
+    while let Some((head, _)) = port.tx.pop_used() {
+        port.tx.free_chain(head);
+        port.tx_busy = false;
+        done = true;
+    }
+    done
+}
+
+// send data on port, and wait until the device has taken it.
+// false if the device didn't take it in time, and the rest is
+// dropped
+fn send(mmio: &Mmio, port: &mut Port, data: &[u8]) -> bool {
+    // nothing else may use tx_buf meanwhile
+    let old = intr_get();
+    intr_off();
+
+    tx_reap(port);
+    let mut data = data;
+    while !port.tx_busy && !data.is_empty() {
+        let n = min(data.len(), TX_BUF);
+        port.tx_buf[..n].copy_from_slice(&data[..n]);
+        data = &data[n..];
+        let chain = [VirtqBuf {
+            addr: &port.tx_buf as *const u8 as u64,
+            len: n as u32,
+            write: false,
+        }];
+        let head = match port.tx.add(&chain) {
+            Some(head) => head,
+            None => {
+                panicc!("virtio_console: tx queue full");
+            }
+        };
+        port.tx.submit(mmio, head);
+
+        let start = mtime();
+        while !tx_reap(port) {
+            if mtime() - start > TX_WAIT {
+                port.tx_busy = true;
+                break;
+            }
+        }
+    }
+
+    if old != 0 {
+        intr_on();
+    }
+    !port.tx_busy
+}
+
+fn control_send(c: &mut Console, id: u32, event: u16, value: u16) {
+    let mut msg: [u8; CONTROL_LEN] = [0; CONTROL_LEN];
+    msg[0..4].copy_from_slice(&id.to_le_bytes());
+    msg[4..6].copy_from_slice(&event.to_le_bytes());
+    msg[6..8].copy_from_slice(&value.to_le_bytes());
+    send(&c.mmio, &mut c.control, &msg);
+}
+
+// write data to port, false if there is no such port or it
+// didn't take the data
+pub fn virtio_console_write(port: usize, data: &[u8]) -> bool {
+    let c = unsafe { &mut CONSOLE };
+    if c.mmio.base == 0 || port >= c.nport || !c.port[port].present {
+        return false;
+    }
+
+    send(&c.mmio, &mut c.port[port], data)
+}
+
+// handle a control message from the device
+fn control_recv(c: &mut Console, msg: &[u8]) {
+    if msg.len() < CONTROL_LEN {
+        return;
+    }
+    let id = u32::from_le_bytes([msg[0], msg[1], msg[2], msg[3]]);
+    let event = u16::from_le_bytes([msg[4], msg[5]]);
+
+    let i = id as usize;
+    if i >= c.nport {
+        // more ports than NVPORT, refuse them
+        if event == VIRTIO_CONSOLE_DEVICE_ADD {
+            control_send(c, id, VIRTIO_CONSOLE_PORT_READY, 0);
+        }
+        return;
+    }
+
+    match event {
+        VIRTIO_CONSOLE_DEVICE_ADD => {
+            c.port[i].present = true;
+            control_send(c, id, VIRTIO_CONSOLE_PORT_READY, 1);
+            // the tty is always open on this side
+            control_send(c, id, VIRTIO_CONSOLE_PORT_OPEN, 1);
+        }
+        VIRTIO_CONSOLE_DEVICE_REMOVE => {
+            c.port[i].present = false;
+        }
+        VIRTIO_CONSOLE_CONSOLE_PORT => {
+            println!("virtio_console: port {} is a console", i);
+        }
+        VIRTIO_CONSOLE_PORT_NAME => {
+            let name = &msg[CONTROL_LEN..];
+            println!(
+                "virtio_console: port {} is {}",
+                i,
+                from_utf8(name).unwrap_or("?")
+            );
+            if name == LOG_NAME {
+                console_set_log(1 + i);
+            }
+        }
+        _ => {}
+    }
+}
+
+pub fn virtio_console_intr(_irq: u32) {
+    let c = unsafe { &mut CONSOLE };
+    c.mmio.ack_intr();
+
+    // output is reaped by send(), but for buffers it gave up on
+    for i in 0..c.nport {
+        tx_reap(&mut c.port[i]);
+        while let Some((head, len)) = c.port[i].rx.pop_used() {
+            let port = &mut c.port[i];
+            let b = port.rx_of[head];
+            port.rx.free_chain(head);
+            if port.present {
+                for j in 0..min(len as usize, RX_BUF) {
+                    console_intr(1 + i, port.rx_buf[b][j]);
+                }
+            }
+            rx_post(&c.mmio, &mut c.port[i], b);
+        }
+    }
+
+    if c.multiport {
+        tx_reap(&mut c.control);
+        while let Some((head, len)) = c.control.rx.pop_used() {
+            let b = c.control.rx_of[head];
+            c.control.rx.free_chain(head);
+            let mut msg: [u8; RX_BUF] = [0; RX_BUF];
+            let len = min(len as usize, RX_BUF);
+            msg[..len].copy_from_slice(&c.control.rx_buf[b][..len]);
+            rx_post(&c.mmio, &mut c.control, b);
+            control_recv(c, &msg[..len]);
+        }
+    }
+}
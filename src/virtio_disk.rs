@@ -1,125 +1,180 @@
 use crate::block_cache::Buf;
 use crate::fs::BLOCK_SIZE;
-use crate::riscv::{PAGE_SHIFT, PAGE_SIZE};
+use crate::mem_layout::VIRTIO_NSLOT;
+use crate::param::{NDISK, NSEG, ROOT_DEV};
+use crate::proc::{my_proc, sleep, wakeup};
+use crate::virtio::{
+    virtio_set_intr, Mmio, VirtqBuf, VirtqDesc, Virtqueue, QUEUE_SIZE, VIRTIO_ID_BLOCK,
+    VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC,
+};
+use core::cmp::min;
 use core::fmt::Write;
 use core::mem::size_of;
-use core::ptr::null_mut;
-
-// https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.pdf
-// pdf 4.2.2
-pub const VIRTIO_MMIO_MAGIC_VALUE: *const u32 = 0x1000_1000 as *const u32;
-pub const VIRTIO_MMIO_VERSION: *const u32 = 0x1000_1004 as *const u32;
-pub const VIRTIO_MMIO_DEVICE_ID: *const u32 = 0x1000_1008 as *const u32;
-pub const VIRTIO_MMIO_VENDOR_ID: *const u32 = 0x1000_100c as *const u32;
-pub const VIRTIO_MMIO_DEVICE_FEATURES: *const u32 = 0x1000_1010 as *const u32;
-pub const VIRTIO_MMIO_DRIVER_FEATURES: *mut u32 = 0x1000_1020 as *mut u32;
-pub const VIRTIO_MMIO_GUEST_PAGE_SIZE: *mut u32 = 0x1000_1028 as *mut u32;
-pub const VIRTIO_MMIO_QUEUE_SEL: *mut u32 = 0x1000_1030 as *mut u32;
-pub const VIRTIO_MMIO_QUEUE_NUM_MAX: *const u32 = 0x1000_1034 as *const u32;
-pub const VIRTIO_MMIO_QUEUE_NUM: *mut u32 = 0x1000_1038 as *mut u32;
-pub const VIRTIO_MMIO_QUEUE_ALIGN: *mut u32 = 0x1000_103c as *mut u32;
-pub const VIRTIO_MMIO_QUEUE_PFN: *mut u32 = 0x1000_1040 as *mut u32;
-pub const VIRTIO_MMIO_QUEUE_READY: u64 = 0x1000_1044;
-pub const VIRTIO_MMIO_QUEUE_NOTIFY: *mut u32 = 0x1000_1050 as *mut u32;
-pub const VIRTIO_MMIO_INTERRUPT_STATUS: *mut u32 = 0x1000_1060 as *mut u32;
-pub const VIRTIO_MMIO_INTERRUPT_ACK: *mut u32 = 0x1000_1064 as *mut u32;
-pub const VIRTIO_MMIO_STATUS: *mut u32 = 0x1000_1070 as *mut u32;
-
-pub const VIRTIO_CONFIG_S_ACKNOWLEDGE: u32 = 1;
-pub const VIRTIO_CONFIG_S_DRIVER: u32 = 2;
-pub const VIRTIO_CONFIG_S_DRIVER_OK: u32 = 4;
-pub const VIRTIO_CONFIG_S_FEATURES_OK: u32 = 8;
+use core::ptr::{null_mut, read_volatile};
+
+// virtio block devices.
+//
+// every virtio mmio slot is probed at boot, and each block device
+// found gets a Disk and a device number, in slot order starting
+// from ROOT_DEV, so the disk in the lowest slot holds the root.
+//
+// requests from different processes can be in flight at once, each
+// one sleeps until virtio_disk_intr() finds its chain in the used
+// ring. before the first process runs, the caller spins with
+// interrupts on instead.
+//
+// a request moves up to NSEG buffers of consecutive blocks. if the
+// device supports indirect descriptors its chain is put in a table
+// of its own, taking a single descriptor of the queue.
+//
+// flush, discard and write-zeroes requests carry no buffer, their
+// caller sleeps on the request's Info until it is marked done.
+
+// pdf 5.2.4, offsets in the configuration space of a block device
+const VIRTIO_BLK_CFG_CAPACITY: u64 = 0x00; // 64 bits, in 512-byte sectors
+const VIRTIO_BLK_CFG_WRITEBACK: u64 = 0x20; // 8 bits, 1 for a write-back cache
+const VIRTIO_BLK_CFG_MAX_DISCARD_SECTORS: u64 = 0x24;
+const VIRTIO_BLK_CFG_MAX_WRITE_ZEROES_SECTORS: u64 = 0x30;
 
 // pdf 5.2.3
 // block device feature bits
 pub const VIRTIO_BLK_F_RO: u32 = 5;
 pub const VIRTIO_BLK_F_SCSI: u32 = 7;
-pub const VIRTIO_BLK_F_CONFIG_WCE: u32 = 11;
+pub const VIRTIO_BLK_F_FLUSH: u32 = 9; // the cache can be flushed
+pub const VIRTIO_BLK_F_CONFIG_WCE: u32 = 11; // the cache mode is writable
 pub const VIRTIO_BLK_F_MQ: u32 = 12;
-pub const VIRTIO_F_ANY_LAYOUT: u32 = 27;
-pub const VIRTIO_RING_F_INDIRECT_DESC: u32 = 28;
-pub const VIRTIO_RING_F_EVENT_IDX: u32 = 29;
-
-pub const QUEUE_SIZE: u64 = 8;
-
-// pdf 2.6
-pub const VIRTQ_DESC_F_NEXT: u16 = 1; // marks a buffer as continuing via the next field
-pub const VIRTQ_DESC_F_WRITE: u16 = 2; // marks a buffer as device write-only
-struct VirtqDesc {
-    addr: u64, // guest physical
-    len: u32,
-    flags: u16, // the flags as indicated above
-    next: u16,  // next field if flags & NEXT
-}
+pub const VIRTIO_BLK_F_DISCARD: u32 = 13;
+pub const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 14;
 
-#[repr(C)]
-struct VirtqAvail {
-    flags: u16,
+// the features the driver uses if the device offers them
+const DISK_FEATURES: u64 = 1 << VIRTIO_BLK_F_FLUSH
+    | 1 << VIRTIO_BLK_F_CONFIG_WCE
+    | 1 << VIRTIO_BLK_F_DISCARD
+    | 1 << VIRTIO_BLK_F_WRITE_ZEROES
+    | 1 << VIRTIO_RING_F_INDIRECT_DESC
+    | 1 << VIRTIO_RING_F_EVENT_IDX;
 
-    // indicates where the driver would put the next descriptor entry in the ring
-    // (modulo the queue size)
-    idx: u16,
+#[derive(Copy, Clone)]
+struct Disk {
+    vq: Virtqueue,
+    mmio: Mmio, // base 0 if the Disk is unused
 
-    // each ring entry refers to the head of a descriptor chain
-    ring: [u16; QUEUE_SIZE as usize],
-    used_event: u16,
-}
+    indirect: bool, // VIRTIO_RING_F_INDIRECT_DESC negotiated
+    flush: bool,    // VIRTIO_BLK_F_FLUSH negotiated
 
-#[repr(C)]
-struct VirtqUsed {
-    flags: u16,
-    idx: u16,
-    ring: [VirtqUsedElem; QUEUE_SIZE as usize],
+    // sectors a discard or write-zeroes request may cover, 0 if
+    // the feature wasn't negotiated
+    max_discard: u32,
+    max_write_zeroes: u32,
+
+    // the requests, and which of them has its chain starting at
+    // each descriptor of the queue
+    info: [Info; QUEUE_SIZE],
+    info_of: [usize; QUEUE_SIZE],
+
+    dev: u32, // device number of the disk
+
+    stat: DiskStat,
 }
 
-#[repr(C)]
-struct VirtqUsedElem {
-    id: u32,  // index of start of used descriptor chain
-    len: u32, // total length of the descriptor chain which was used (written to)
+// counters of a disk, to see how many notifications and interrupts
+// event indexes save
+#[derive(Copy, Clone)]
+pub struct DiskStat {
+    pub requests: u64,    // put in the available ring
+    pub notifies: u64,    // of the device about new requests
+    pub completions: u64, // taken from the used ring
+    pub intrs: u64,       // from the device
 }
 
-#[repr(C, align(4096))] // shuold be PAGE_SIZE here
-struct Disk {
-    /*-- virt queue --*/
-    // contiguous memory for queue
-    pages: [u8; 2 * PAGE_SIZE as usize],
+impl DiskStat {
+    const fn new() -> Self {
+        DiskStat {
+            requests: 0,
+            notifies: 0,
+            completions: 0,
+            intrs: 0,
+        }
+    }
+}
 
-    // The actual descriptors (16 bytes each)
-    desc: *mut VirtqDesc,
+impl Disk {
+    const fn new() -> Self {
+        Disk {
+            vq: Virtqueue::new(),
+            mmio: Mmio::new(),
+            indirect: false,
+            flush: false,
+            max_discard: 0,
+            max_write_zeroes: 0,
+            info: [Info::new(); QUEUE_SIZE],
+            info_of: [0; QUEUE_SIZE],
+            dev: 0,
+            stat: DiskStat::new(),
+        }
+    }
+}
 
-    // A ring of available descriptor heads with free-running index.
-    avail: *mut VirtqAvail,
+// a request, what the device reads and writes besides the data
+// buffers is kept here
+#[derive(Copy, Clone)]
+struct Info {
+    used: bool,
+    req: VirtioBlkReq,
+    range: VirtioBlkRange, // of discard and write-zeroes
+    b: [*mut Buf; NSEG],
+    n: usize,
+    table: [VirtqDesc; NSEG + 2], // indirect descriptors of the chain
+    status: u8,                   // written by device describing the status after a request
+    done: bool,                   // a request without buffers is complete
+}
 
-    // A ring of used descriptor heads with free-running index.
-    used: *mut VirtqUsed,
+impl Info {
+    const fn new() -> Self {
+        Info {
+            used: false,
+            req: VirtioBlkReq::new(),
+            range: VirtioBlkRange::new(),
+            b: [null_mut(); NSEG],
+            n: 0,
+            table: [VirtqDesc::new(); NSEG + 2],
+            status: 0,
+            done: false,
+        }
+    }
+}
 
-    /*-- other things for convenience --*/
-    // record free descriptors
-    is_free: [u8; QUEUE_SIZE as usize],
+static mut DISK: [Disk; NDISK] = [Disk::new(); NDISK];
 
-    used_idx: u16,
+// the disk with device number dev
+fn disk_of(dev: u32) -> Option<*mut Disk> {
+    unsafe {
+        for i in 0..NDISK {
+            if DISK[i].mmio.base != 0 && DISK[i].dev == dev {
+                return Some(&mut DISK[i] as *mut Disk);
+            }
+        }
+    }
 
-    req: [VirtioBlkReq; QUEUE_SIZE as usize],
+    None
+}
 
-    // written by device describing the status after a request
-    status: [u8; QUEUE_SIZE as usize],
+// is there a disk with device number dev
+pub fn virtio_disk_present(dev: u32) -> bool {
+    disk_of(dev).is_some()
 }
 
-static mut DISK: Disk = Disk {
-    pages: [0; 2 * PAGE_SIZE as usize],
-    desc: null_mut(),
-    avail: null_mut(),
-    used: null_mut(),
-    is_free: [1; QUEUE_SIZE as usize],
-    used_idx: 0,
-    req: [VirtioBlkReq::new(); QUEUE_SIZE as usize],
-    status: [0; QUEUE_SIZE as usize],
-};
+// the counters of disk dev
+pub fn virtio_disk_stat(dev: u32) -> Option<DiskStat> {
+    disk_of(dev).map(|disk| unsafe { (*disk).stat })
+}
 
 // pdf 5.2.6
 const VIRTIO_BLK_T_IN: u32 = 0;
 const VIRTIO_BLK_T_OUT: u32 = 1;
 const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
 
 // status
 const VIRTIO_BLK_S_OK: u8 = 0;
@@ -148,210 +203,465 @@ impl VirtioBlkReq {
     }
 }
 
-fn alloc_desc() -> usize {
-    for i in 0..QUEUE_SIZE as usize {
+// the data of a discard or write-zeroes request
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct VirtioBlkRange {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32, // VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP
+}
+
+const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1; // the zeroed sectors may be deallocated
+
+impl VirtioBlkRange {
+    const fn new() -> VirtioBlkRange {
+        VirtioBlkRange {
+            sector: 0,
+            num_sectors: 0,
+            flags: 0,
+        }
+    }
+}
+
+// a free Info of disk, waiting for one if needed
+fn info_alloc(disk: *mut Disk) -> usize {
+    loop {
         unsafe {
-            if DISK.is_free[i] == 1 {
-                DISK.is_free[i] = 0;
-                return i;
+            for i in 0..QUEUE_SIZE {
+                if !(*disk).info[i].used {
+                    (*disk).info[i] = Info::new();
+                    (*disk).info[i].used = true;
+                    return i;
+                }
             }
+            disk_wait(&(*disk).info_of as *const usize as u64);
         }
     }
+}
 
-    QUEUE_SIZE as usize
+fn info_free(disk: *mut Disk, i: usize) {
+    unsafe {
+        (*disk).info[i].used = false;
+        wakeup(&(*disk).info_of as *const usize as u64);
+    }
 }
 
-fn alloc_3desc(idx: &mut [usize; 3]) -> i32 {
-    for i in 0..3 {
-        let index = alloc_desc();
-        if index >= QUEUE_SIZE as usize {
-            for j in 0..i {
-                free_desc(idx[j]);
+// put chain in the queue of disk as the request of info, either in
+// table, an indirect table, or directly. wait for descriptors if
+// needed, then hand the chain to the device. return its head
+fn disk_submit(disk: *mut Disk, info: usize, table: *mut VirtqDesc, chain: &[VirtqBuf]) -> usize {
+    unsafe {
+        let vq = &mut (*disk).vq;
+        let head = loop {
+            let head = match table.is_null() {
+                true => vq.add(chain),
+                false => vq.add_indirect(table, chain),
+            };
+            match head {
+                Some(head) => break head,
+                None => disk_wait(vq.free_chan()),
             }
-            return -1;
+        };
+        (*disk).info_of[head] = info;
+
+        (*disk).stat.requests += 1;
+        if vq.submit(&(*disk).mmio, head) {
+            (*disk).stat.notifies += 1;
         }
-        idx[i] = index;
+        head
     }
+}
 
-    0
+// probe every virtio mmio slot and set up the block devices found
+pub fn virtio_disk_init() {
+    let mut dev = ROOT_DEV;
+    for slot in 0..VIRTIO_NSLOT {
+        let mmio = match Mmio::probe(slot, VIRTIO_ID_BLOCK) {
+            Some(mmio) => mmio,
+            None => continue,
+        };
+
+        if (dev - ROOT_DEV) as usize >= NDISK {
+            println!("virtio_disk: too many disks");
+            break;
+        }
+        let disk = unsafe { &mut DISK[(dev - ROOT_DEV) as usize] as *mut Disk };
+        disk_init(disk, mmio, dev);
+        println!(
+            "virtio_disk: dev {} at {:#x} v{}, {} sectors",
+            dev,
+            mmio.base,
+            mmio.version,
+            virtio_disk_capacity(dev)
+        );
+        dev += 1;
+    }
+
+    if dev == ROOT_DEV {
+        panicc!("no virtio disk");
+    }
 }
 
-// do more in xv6
-fn free_desc(i: usize) {
+// set up the device at mmio as disk, pdf 3.1.1
+fn disk_init(disk: *mut Disk, mmio: Mmio, dev: u32) {
     unsafe {
-        DISK.is_free[i] = 1;
+        (*disk).mmio = mmio;
+        (*disk).dev = dev;
+
+        mmio.begin();
+        let features = match mmio.negotiate(DISK_FEATURES) {
+            Some(features) => features,
+            None => {
+                panicc!("virtio_disk_init: features aren't supported");
+            }
+        };
+        (*disk).indirect = features & 1 << VIRTIO_RING_F_INDIRECT_DESC != 0;
+
+        // with a flushable cache, ask for write-back mode. the file
+        // systems flush where they need their writes durable
+        (*disk).flush = features & 1 << VIRTIO_BLK_F_FLUSH != 0;
+        if (*disk).flush && features & 1 << VIRTIO_BLK_F_CONFIG_WCE != 0 {
+            mmio.set_config8(VIRTIO_BLK_CFG_WRITEBACK, 1);
+        }
+        if features & 1 << VIRTIO_BLK_F_DISCARD != 0 {
+            (*disk).max_discard = mmio.config32(VIRTIO_BLK_CFG_MAX_DISCARD_SECTORS);
+        }
+        if features & 1 << VIRTIO_BLK_F_WRITE_ZEROES != 0 {
+            (*disk).max_write_zeroes = mmio.config32(VIRTIO_BLK_CFG_MAX_WRITE_ZEROES_SECTORS);
+        }
+
+        (*disk).vq.init(&mmio, 0, features);
+        mmio.driver_ok();
+        virtio_set_intr(&mmio, virtio_disk_intr);
     }
 }
 
-fn free_chain(mut i: usize) {
-    loop {
-        let flags;
-        let next;
+// pdf 5.2.4
+// the capacity of disk dev in 512-byte sectors, 0 if there is no such disk
+pub fn virtio_disk_capacity(dev: u32) -> u64 {
+    let mmio = match disk_of(dev) {
+        Some(disk) => unsafe { (*disk).mmio },
+        None => return 0,
+    };
+    let low = mmio.config32(VIRTIO_BLK_CFG_CAPACITY) as u64;
+    let high = mmio.config32(VIRTIO_BLK_CFG_CAPACITY + 4) as u64;
+    high << 32 | low
+}
+
+// read or write buf on the disk of buf.dev
+pub fn virtio_disk_rw(buf: *mut Buf, write: u32) {
+    virtio_disk_rw_n(&[buf], write);
+}
+
+// read or write bufs in one request. they must be of the same disk,
+// at most NSEG of them, and hold consecutive blocks in order
+pub fn virtio_disk_rw_n(bufs: &[*mut Buf], write: u32) {
+    let n = bufs.len();
+    if n == 0 || n > NSEG {
+        panicc!("virtio_disk_rw: bad buffer count");
+    }
+    let dev = unsafe { (*bufs[0]).dev };
+    for i in 1..n {
         unsafe {
-            flags = (*DISK.desc.add(i)).flags;
-            next = (*DISK.desc.add(i)).next;
+            if (*bufs[i]).dev != dev || (*bufs[i]).block_no != (*bufs[0]).block_no + i as u32 {
+                panicc!("virtio_disk_rw: buffers not consecutive");
+            }
         }
-        free_desc(i);
-        match flags & VIRTQ_DESC_F_NEXT {
-            0 => break,
-            _ => i = next as usize,
+    }
+    let disk = match disk_of(dev) {
+        Some(disk) => disk,
+        None => {
+            panicc!("virtio_disk_rw: no such disk");
+        }
+    };
+
+    // a direct chain must fit in the queue
+    let max = unsafe { (*disk).vq.num() - 2 };
+    if !unsafe { (*disk).indirect } && n > max {
+        for chunk in bufs.chunks(max) {
+            virtio_disk_rw_n(chunk, write);
+        }
+        return;
+    }
+
+    // other requests may be using the buffers
+    for &b in bufs {
+        while unsafe { read_volatile(&(*b).disk) } {
+            disk_wait(b as u64);
         }
     }
-}
 
-pub fn virtio_disk_init() {
     unsafe {
-        if *VIRTIO_MMIO_MAGIC_VALUE != 0x74726976
-            || *VIRTIO_MMIO_VERSION != 1
-            || *VIRTIO_MMIO_DEVICE_ID != 2
-            || *VIRTIO_MMIO_VENDOR_ID != 0x554d4551
-        {
-            panicc!("no virtio disk");
+        let i = info_alloc(disk);
+        let info = &mut (*disk).info[i];
+        info.req.req_type = match write {
+            0 => VIRTIO_BLK_T_IN,
+            _ => VIRTIO_BLK_T_OUT,
+        };
+        info.req.reserved = 0;
+        info.req.sector = (*bufs[0]).block_no as u64 * (BLOCK_SIZE / 512) as u64;
+        info.status = 0xff; // written by the device
+
+        // the header, the data and the status byte
+        let mut chain: [VirtqBuf; NSEG + 2] = [VirtqBuf {
+            addr: &mut info.req as *mut VirtioBlkReq as u64,
+            len: size_of::<VirtioBlkReq>() as u32,
+            write: false,
+        }; NSEG + 2];
+        for j in 0..n {
+            chain[j + 1] = VirtqBuf {
+                addr: &mut (*bufs[j]).data as *mut u8 as u64,
+                len: BLOCK_SIZE as u32,
+                write: write == 0,
+            };
         }
+        chain[n + 1] = VirtqBuf {
+            addr: &mut info.status as *mut u8 as u64,
+            len: 1,
+            write: true,
+        };
 
-        let mut status: u32 = 0;
-        // set ACKNOWLEDGE bit
-        status |= VIRTIO_CONFIG_S_ACKNOWLEDGE;
-        *VIRTIO_MMIO_STATUS = status;
-
-        // set DRIVER status bit
-        status |= VIRTIO_CONFIG_S_DRIVER;
-        *VIRTIO_MMIO_STATUS = status;
-
-        // write feature bits
-        let mut features: u32 = *VIRTIO_MMIO_DEVICE_FEATURES;
-        features &= !(1 << VIRTIO_BLK_F_RO);
-        features &= !(1 << VIRTIO_BLK_F_SCSI);
-        features &= !(1 << VIRTIO_BLK_F_CONFIG_WCE);
-        features &= !(1 << VIRTIO_BLK_F_MQ);
-        features &= !(1 << VIRTIO_F_ANY_LAYOUT);
-        features &= !(1 << VIRTIO_RING_F_EVENT_IDX);
-        features &= !(1 << VIRTIO_RING_F_INDIRECT_DESC);
-        *VIRTIO_MMIO_DRIVER_FEATURES = features;
-
-        // set the FEATURES_OK status bit
-        status |= VIRTIO_CONFIG_S_FEATURES_OK;
-        *VIRTIO_MMIO_STATUS = status;
-
-        // ensure FEATURES_OK bit is still set
-        if *(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_FEATURES_OK == 0 {
-            panicc!("virtio_disk_init: features aren't supported");
+        // record the request for virtio_disk_intr()
+        for j in 0..n {
+            (*bufs[j]).disk = true;
+            info.b[j] = bufs[j];
         }
+        info.n = n;
 
-        // set the DRIVER_OK status bit
-        status |= VIRTIO_CONFIG_S_DRIVER_OK;
-        *VIRTIO_MMIO_STATUS = status;
+        // in the indirect table of info behind one descriptor of the
+        // queue if possible, chained in the queue if not
+        let table = match (*disk).indirect {
+            true => &mut info.table as *mut VirtqDesc,
+            false => null_mut(),
+        };
+        let head = disk_submit(disk, i, table, &chain[..n + 2]);
 
-        // used by device to calculate the Guest address of the first queue page
-        *VIRTIO_MMIO_GUEST_PAGE_SIZE = PAGE_SIZE as u32;
+        // wait for virtio_disk_intr() to say the request is done
+        for &b in bufs {
+            while read_volatile(&(*b).disk) {
+                disk_wait(b as u64);
+            }
+        }
 
-        // select the virtual queue that the following operations apply to
-        *VIRTIO_MMIO_QUEUE_SEL = 0;
+        (*disk).vq.free_chain(head);
+        info_free(disk, i);
+    }
+}
 
-        // the max size of the queue
-        let max_num = *VIRTIO_MMIO_QUEUE_NUM_MAX;
-        if max_num == 0 {
-            panicc!("virtio_disk_init: queue not available");
-        }
-        if max_num < QUEUE_SIZE as u32 {
-            panicc!("virtio_disk_init: max size to small");
+// send a request without buffers and wait for it, range is the data
+// of a discard or write-zeroes. return -1 if the device failed it
+fn disk_cmd(disk: *mut Disk, req_type: u32, range: Option<VirtioBlkRange>) -> i32 {
+    unsafe {
+        let i = info_alloc(disk);
+        let info = &mut (*disk).info[i];
+        info.req.req_type = req_type;
+        info.req.reserved = 0;
+        info.req.sector = 0;
+        info.status = 0xff;
+
+        let mut chain: [VirtqBuf; 3] = [VirtqBuf {
+            addr: &mut info.req as *mut VirtioBlkReq as u64,
+            len: size_of::<VirtioBlkReq>() as u32,
+            write: false,
+        }; 3];
+        let mut n = 1;
+        if let Some(range) = range {
+            info.range = range;
+            chain[n] = VirtqBuf {
+                addr: &mut info.range as *mut VirtioBlkRange as u64,
+                len: size_of::<VirtioBlkRange>() as u32,
+                write: false,
+            };
+            n += 1;
         }
-        *VIRTIO_MMIO_QUEUE_NUM = QUEUE_SIZE as u32;
+        chain[n] = VirtqBuf {
+            addr: &mut info.status as *mut u8 as u64,
+            len: 1,
+            write: true,
+        };
+        n += 1;
+
+        let head = disk_submit(disk, i, null_mut(), &chain[..n]);
 
-        // set guest physical page number of the virtual queue
-        *VIRTIO_MMIO_QUEUE_PFN = (&DISK.pages as *const u8 as u32) >> PAGE_SHIFT;
+        while !read_volatile(&info.done) {
+            disk_wait(info as *mut Info as u64);
+        }
+        let status = info.status;
+        (*disk).vq.free_chain(head);
+        info_free(disk, i);
 
-        DISK.desc = &mut DISK.pages as *mut u8 as u64 as *mut VirtqDesc;
-        DISK.avail = (&mut DISK.pages as *mut u8).add(QUEUE_SIZE as usize * size_of::<VirtqDesc>())
-            as *mut VirtqAvail;
-        DISK.used = (&mut DISK.pages as *mut u8).add(PAGE_SIZE as usize) as *mut VirtqUsed;
+        match status {
+            VIRTIO_BLK_S_OK => 0,
+            _ => -1,
+        }
     }
 }
 
-pub fn virtio_disk_rw(buf: *mut Buf, write: u32) {
-    let mut idx: [usize; 3] = [0; 3];
+// write the volatile cache of disk dev to stable storage, so every
+// write completed before is durable
+pub fn virtio_disk_flush(dev: u32) -> i32 {
+    let disk = match disk_of(dev) {
+        Some(disk) => disk,
+        None => return -1,
+    };
+    // without the feature the device writes through
+    if !unsafe { (*disk).flush } {
+        return 0;
+    }
 
-    // xv6 wait until find 3 desc here
-    if alloc_3desc(&mut idx) == -1 {
-        panicc!("virtio_disk_rw: no free desc");
+    disk_cmd(disk, VIRTIO_BLK_T_FLUSH, None)
+}
+
+// flush every disk, return -1 if any failed
+pub fn virtio_disk_flush_all() -> i32 {
+    let mut r = 0;
+    for i in 0..NDISK {
+        let dev = ROOT_DEV + i as u32;
+        if virtio_disk_present(dev) && virtio_disk_flush(dev) < 0 {
+            r = -1;
+        }
     }
 
-    unsafe {
-        let req = &mut DISK.req[idx[0]];
+    r
+}
 
-        req.req_type = match write {
-            0 => VIRTIO_BLK_T_IN,
-            _ => VIRTIO_BLK_T_OUT,
+// send req_type for n blocks from block_no, max sectors per request
+fn disk_range(disk: *mut Disk, req_type: u32, max: u32, block_no: u32, n: u32, flags: u32) -> i32 {
+    let spb = BLOCK_SIZE / 512;
+    let mut sector = block_no as u64 * spb as u64;
+    let mut left = n * spb;
+    while left > 0 {
+        // whole blocks at a time
+        let cnt = min(left, max / spb * spb);
+        if cnt == 0 {
+            return -1;
+        }
+        let range = VirtioBlkRange {
+            sector,
+            num_sectors: cnt,
+            flags,
         };
+        if disk_cmd(disk, req_type, Some(range)) < 0 {
+            return -1;
+        }
+        sector += cnt as u64;
+        left -= cnt;
+    }
 
-        req.reserved = 0;
-        req.sector = (*buf).block_no as u64 * (BLOCK_SIZE / 512) as u64;
+    0
+}
 
-        (*DISK.desc.add(idx[0])).addr = req as *mut VirtioBlkReq as u64;
-        (*DISK.desc.add(idx[0])).len = size_of::<VirtioBlkReq>() as u32;
-        (*DISK.desc.add(idx[0])).flags = VIRTQ_DESC_F_NEXT;
-        (*DISK.desc.add(idx[0])).next = idx[1] as u16;
+// tell disk dev that n blocks from block_no hold no data anymore, so
+// the host may deallocate them. the buffer cache is bypassed
+pub fn virtio_disk_discard(dev: u32, block_no: u32, n: u32) -> i32 {
+    let disk = match disk_of(dev) {
+        Some(disk) => disk,
+        None => return -1,
+    };
+    let max = unsafe { (*disk).max_discard };
+    if max == 0 {
+        return -1;
+    }
 
-        (*DISK.desc.add(idx[1])).addr = &mut (*buf).data as *mut u8 as u64;
-        (*DISK.desc.add(idx[1])).len = BLOCK_SIZE as u32;
-        (*DISK.desc.add(idx[1])).flags = match write {
-            0 => VIRTQ_DESC_F_WRITE,
-            _ => 0,
-        };
-        (*DISK.desc.add(idx[1])).flags |= VIRTQ_DESC_F_NEXT;
-        (*DISK.desc.add(idx[1])).next = idx[2] as u16;
-
-        DISK.status[idx[0]] = 0xf; // written by the device
-        (*DISK.desc.add(idx[2])).addr = &mut DISK.status[idx[0]] as *mut u8 as u64;
-        (*DISK.desc.add(idx[2])).len = 1;
-        (*DISK.desc.add(idx[2])).flags = VIRTQ_DESC_F_WRITE;
-        (*DISK.desc.add(idx[2])).next = 0;
-
-        // write the desc index into the available ring
-        (*DISK.avail).ring[(*DISK.avail).idx as usize % QUEUE_SIZE as usize] = idx[0] as u16;
-        (*DISK.avail).idx += 1; // even it overflows the res seems to stay the same (max_u16+1 % 8 = 0)
-
-        // notify the device that there are new buffers to process in a queue
-        // the value written is the queue index (when..)
-        *VIRTIO_MMIO_QUEUE_NOTIFY = 0;
-
-        // wait for intr to say finished, just a loop yet
-        let mut timer: u64 = 0;
-        while timer < 1_000_000 {
-            timer += 1;
-        }
+    disk_range(disk, VIRTIO_BLK_T_DISCARD, max, block_no, n, 0)
+}
 
-        free_chain(idx[0]);
+// zero n blocks from block_no on disk dev, without moving the data.
+// the buffer cache is bypassed, the caller fixes it with bzeroed()
+pub fn virtio_disk_write_zeroes(dev: u32, block_no: u32, n: u32) -> i32 {
+    let disk = match disk_of(dev) {
+        Some(disk) => disk,
+        None => return -1,
+    };
+    let max = unsafe { (*disk).max_write_zeroes };
+    if max == 0 {
+        return -1;
     }
+
+    disk_range(
+        disk,
+        VIRTIO_BLK_T_WRITE_ZEROES,
+        max,
+        block_no,
+        n,
+        VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+    )
 }
 
-pub fn virtio_disk_intr() {
+// sleep on chan. before the first process runs there is nobody to
+// put to sleep, so spin until an interrupt changes what is waited for
+fn disk_wait(chan: u64) {
+    if !my_proc().is_null() {
+        sleep(chan);
+    }
+}
+
+// handle an interrupt from the disk with irq
+pub fn virtio_disk_intr(irq: u32) {
+    let mut disk: *mut Disk = null_mut();
+    unsafe {
+        for i in 0..NDISK {
+            if DISK[i].mmio.base != 0 && DISK[i].mmio.irq == irq as u64 {
+                disk = &mut DISK[i] as *mut Disk;
+            }
+        }
+    }
+    if disk.is_null() {
+        println!("virtio_disk_intr: no disk for irq {}", irq);
+        return;
+    }
+
     unsafe {
         // notify the device that events causing the interrupt have been handled
-        *VIRTIO_MMIO_INTERRUPT_ACK = *VIRTIO_MMIO_INTERRUPT_STATUS & 0x3;
+        (*disk).mmio.ack_intr();
+        (*disk).stat.intrs += 1;
 
         // When the device has finished a buffer,
         // it writes the descriptor index into the used ring
-        while DISK.used_idx != (*DISK.used).idx {
-            let id = (*DISK.used).ring[(*DISK.used).idx as usize % QUEUE_SIZE as usize].id as usize;
-
-            if DISK.status[id] != VIRTIO_BLK_S_OK {
-                match DISK.status[id] {
-                    VIRTIO_BLK_S_IOERR => {
-                        panicc!("virtio_disk_intr: device or driver error");
-                    }
-                    VIRTIO_BLK_S_UNSUPP => {
-                        panicc!("virtio_disk_intr: request unsupported by device");
-                    }
-                    _ => {
-                        panicc!("virtio_disk_intr: unknown status");
-                    }
-                }
+        loop {
+            while let Some((head, _)) = (*disk).vq.pop_used() {
+                complete(disk, (*disk).info_of[head]);
+                (*disk).stat.completions += 1;
+            }
+            if (*disk).vq.enable_intr() {
+                break;
             }
+        }
+    }
+}
 
-            // wake up..
+// the request of info i is done
+fn complete(disk: *mut Disk, i: usize) {
+    unsafe {
+        let info = &mut (*disk).info[i];
+
+        // a request without buffers, its caller looks at the status
+        if info.n == 0 {
+            info.done = true;
+            wakeup(info as *mut Info as u64);
+            return;
+        }
+
+        if info.status != VIRTIO_BLK_S_OK {
+            match info.status {
+                VIRTIO_BLK_S_IOERR => {
+                    panicc!("virtio_disk_intr: device or driver error");
+                }
+                VIRTIO_BLK_S_UNSUPP => {
+                    panicc!("virtio_disk_intr: request unsupported by device");
+                }
+                _ => {
+                    panicc!("virtio_disk_intr: unknown status");
+                }
+            }
+        }
 
-            DISK.used_idx += 1;
+        // the request is done, hand the buffers back
+        for j in 0..info.n {
+            let b = info.b[j];
+            (*b).disk = false;
+            wakeup(b as u64);
         }
     }
 }
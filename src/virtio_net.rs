@@ -0,0 +1,316 @@
+use crate::mem_layout::VIRTIO_NSLOT;
+use crate::net::net_input;
+use crate::proc::{either_copy_in, either_copy_out, wakeup};
+use crate::virtio::{virtio_set_intr, Mmio, VirtqBuf, Virtqueue, QUEUE_SIZE, VIRTIO_ID_NET};
+use core::cmp::min;
+use core::fmt::Write;
+use core::slice::from_raw_parts;
+
+// virtio network device, pdf 5.1.
+//
+// queue 0 receives and queue 1 transmits ethernet frames, each
+// preceded by a virtio_net_hdr. no offloads are negotiated, so the
+// header is all zeros on the way out and ignored on the way in.
+//
+// the receive queue holds NRX buffers. the interrupt handler passes
+// each frame to the network stack (net.rs) and gives the buffer back
+// to the device. frames the stack has no use for go to the ready
+// list instead, up to NRAW of them, where virtio_net_recv() copies
+// them out before giving their buffers back. a frame to send is
+// copied to one of NTX transmit buffers, freed again once the device
+// is done with it.
+//
+// only the first device found is used.
+
+// pdf 5.1.3, feature bits
+const VIRTIO_NET_F_MAC: u32 = 5;
+const VIRTIO_NET_F_STATUS: u32 = 16;
+
+// pdf 5.1.4, device configuration layout
+const VIRTIO_NET_CFG_MAC: u64 = 0;
+const VIRTIO_NET_CFG_STATUS: u64 = 6;
+const VIRTIO_NET_S_LINK_UP: u8 = 1;
+
+// pdf 5.1.6, the header is 12 bytes with VIRTIO_F_VERSION_1,
+// 10 for a legacy device without VIRTIO_NET_F_MRG_RXBUF
+const HDR_LEN_MODERN: usize = 12;
+const HDR_LEN_LEGACY: usize = 10;
+
+pub const ETH_FRAME_MAX: usize = 1514; // without the frame check sequence
+
+const NRX: usize = 16;
+const NRAW: usize = NRX / 2; // the rest stay free for the stack
+const NTX: usize = 16;
+const NET_BUF: usize = 2048; // a header and a frame
+
+const RX_QUEUE: u32 = 0;
+const TX_QUEUE: u32 = 1;
+
+// where the device put a frame
+#[derive(Copy, Clone)]
+struct Ready {
+    buf: usize, // index in rx_buf
+    len: usize, // of the frame, without the header
+}
+
+struct Net {
+    mmio: Mmio, // base 0 if there is no device
+    mac: [u8; 6],
+    status: bool, // VIRTIO_NET_F_STATUS negotiated
+    hdr_len: usize,
+
+    rx: Virtqueue,
+    rx_buf: [[u8; NET_BUF]; NRX],
+    rx_of: [usize; QUEUE_SIZE], // rx_buf of each chain head
+    // filled buffers, in the order the device used them
+    ready: [Ready; NRX],
+    ready_r: usize,
+    ready_w: usize,
+
+    tx: Virtqueue,
+    tx_buf: [[u8; NET_BUF]; NTX],
+    tx_busy: [bool; NTX],
+    tx_of: [usize; QUEUE_SIZE], // tx_buf of each chain head
+}
+
+static mut NET: Net = Net {
+    mmio: Mmio::new(),
+    mac: [0; 6],
+    status: false,
+    hdr_len: 0,
+    rx: Virtqueue::new(),
+    rx_buf: [[0; NET_BUF]; NRX],
+    rx_of: [0; QUEUE_SIZE],
+    ready: [Ready { buf: 0, len: 0 }; NRX],
+    ready_r: 0,
+    ready_w: 0,
+    tx: Virtqueue::new(),
+    tx_buf: [[0; NET_BUF]; NTX],
+    tx_busy: [false; NTX],
+    tx_of: [0; QUEUE_SIZE],
+};
+
+pub fn virtio_net_init() {
+    for slot in 0..VIRTIO_NSLOT {
+        if let Some(mmio) = Mmio::probe(slot, VIRTIO_ID_NET) {
+            net_init(mmio);
+            return;
+        }
+    }
+}
+
+// set up the device at mmio, pdf 3.1.1
+fn net_init(mmio: Mmio) {
+    let net = unsafe { &mut NET };
+
+    mmio.begin();
+    let features = match mmio.negotiate(1 << VIRTIO_NET_F_MAC | 1 << VIRTIO_NET_F_STATUS) {
+        Some(features) => features,
+        None => {
+            println!("virtio_net: features aren't supported");
+            return;
+        }
+    };
+
+    if features & 1 << VIRTIO_NET_F_MAC != 0 {
+        for i in 0..6 {
+            net.mac[i] = mmio.config8(VIRTIO_NET_CFG_MAC + i as u64);
+        }
+    } else {
+        // locally administered, qemu's default
+        net.mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    }
+    net.status = features & 1 << VIRTIO_NET_F_STATUS != 0;
+    net.hdr_len = match mmio.modern() {
+        true => HDR_LEN_MODERN,
+        false => HDR_LEN_LEGACY,
+    };
+
+    net.rx.init(&mmio, RX_QUEUE, features);
+    net.tx.init(&mmio, TX_QUEUE, features);
+    net.mmio = mmio;
+    mmio.driver_ok();
+    virtio_set_intr(&mmio, virtio_net_intr);
+
+    for i in 0..NRX {
+        if !rx_post(net, i) {
+            break;
+        }
+    }
+
+    let m = net.mac;
+    println!(
+        "virtio_net: at {:#x} v{}, mac {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+        mmio.base, mmio.version, m[0], m[1], m[2], m[3], m[4], m[5]
+    );
+}
+
+// the header and the frame as separate buffers, as a legacy device
+// without VIRTIO_F_ANY_LAYOUT wants
+fn chain(net: &Net, buf: *const u8, len: usize, write: bool) -> [VirtqBuf; 2] {
+    let addr = buf as u64;
+    [
+        VirtqBuf {
+            addr,
+            len: net.hdr_len as u32,
+            write,
+        },
+        VirtqBuf {
+            addr: addr + net.hdr_len as u64,
+            len: len as u32,
+            write,
+        },
+    ]
+}
+
+// hand receive buffer i to the device, false if the queue is full
+fn rx_post(net: &mut Net, i: usize) -> bool {
+    let c = chain(
+        net,
+        &net.rx_buf[i] as *const u8,
+        NET_BUF - net.hdr_len,
+        true,
+    );
+    let head = match net.rx.add(&c) {
+        Some(head) => head,
+        None => return false,
+    };
+    net.rx_of[head] = i;
+    net.rx.submit(&net.mmio, head);
+    true
+}
+
+// the mac address, None if there is no device
+pub fn virtio_net_mac() -> Option<[u8; 6]> {
+    unsafe {
+        match NET.mmio.base {
+            0 => None,
+            _ => Some(NET.mac),
+        }
+    }
+}
+
+// is the link up, as far as the device tells
+pub fn virtio_net_link_up() -> bool {
+    unsafe {
+        if NET.mmio.base == 0 {
+            return false;
+        }
+        !NET.status || NET.mmio.config8(VIRTIO_NET_CFG_STATUS) & VIRTIO_NET_S_LINK_UP != 0
+    }
+}
+
+// sleep on this for a received frame
+pub fn virtio_net_rx_chan() -> u64 {
+    unsafe { &NET.ready as *const Ready as u64 }
+}
+
+// sleep on this for a free transmit buffer
+pub fn virtio_net_tx_chan() -> u64 {
+    unsafe { &NET.tx_busy as *const bool as u64 }
+}
+
+// copy the next received frame to dst, at most n bytes of it, the
+// rest is lost. return the number of bytes copied, 0 if no frame
+// has come in, -1 if there is no device or dst is bad
+pub fn virtio_net_recv(is_uaddr: u32, dst: u64, n: u32) -> i32 {
+    let net = unsafe { &mut NET };
+    if net.mmio.base == 0 {
+        return -1;
+    }
+    if net.ready_r == net.ready_w {
+        return 0;
+    }
+
+    let r = net.ready[net.ready_r % NRX];
+    net.ready_r += 1;
+    let len = min(n as usize, r.len);
+    let src = unsafe { (&net.rx_buf[r.buf] as *const u8).add(net.hdr_len) };
+    let ret = either_copy_out(is_uaddr, dst, src, len as u64);
+    rx_post(net, r.buf);
+
+    match ret {
+        -1 => -1,
+        _ => len as i32,
+    }
+}
+
+// send the frame of n bytes at src. return n, 0 if all transmit
+// buffers are in use, -1 if there is no device, the frame is empty
+// or too long, or src is bad
+pub fn virtio_net_send(is_uaddr: u32, src: u64, n: u32) -> i32 {
+    let net = unsafe { &mut NET };
+    if net.mmio.base == 0 || n == 0 || n as usize > ETH_FRAME_MAX {
+        return -1;
+    }
+
+    if net.tx_busy.iter().all(|&busy| busy) && tx_reap(net) {
+        wakeup(virtio_net_tx_chan());
+    }
+    let i = match net.tx_busy.iter().position(|&busy| !busy) {
+        Some(i) => i,
+        None => return 0,
+    };
+    let buf = &mut net.tx_buf[i] as *mut u8;
+    unsafe {
+        // no offloads
+        for j in 0..net.hdr_len {
+            *buf.add(j) = 0;
+        }
+        if either_copy_in(buf.add(net.hdr_len), is_uaddr, src, n as u64) == -1 {
+            return -1;
+        }
+    }
+
+    let c = chain(net, buf, n as usize, false);
+    let head = match net.tx.add(&c) {
+        Some(head) => head,
+        None => return 0,
+    };
+    net.tx_busy[i] = true;
+    net.tx_of[head] = i;
+    net.tx.submit(&net.mmio, head);
+
+    n as i32
+}
+
+pub fn virtio_net_intr(_irq: u32) {
+    let net = unsafe { &mut NET };
+    net.mmio.ack_intr();
+
+    // first, so that replies find free transmit buffers
+    if tx_reap(net) {
+        wakeup(virtio_net_tx_chan());
+    }
+
+    let mut received = false;
+    while let Some((head, len)) = net.rx.pop_used() {
+        let buf = net.rx_of[head];
+        net.rx.free_chain(head);
+        let len = (len as usize).saturating_sub(net.hdr_len);
+        let frame =
+            unsafe { from_raw_parts((&net.rx_buf[buf] as *const u8).add(net.hdr_len), len) };
+        if len == 0 || net_input(frame) || net.ready_w - net.ready_r >= NRAW {
+            rx_post(net, buf);
+            continue;
+        }
+        net.ready[net.ready_w % NRX] = Ready { buf, len };
+        net.ready_w += 1;
+        received = true;
+    }
+    if received {
+        wakeup(virtio_net_rx_chan());
+    }
+}
+
+// free the transmit buffers the device is done with,
+// return whether there were any
+fn tx_reap(net: &mut Net) -> bool {
+    let mut freed = false;
+    while let Some((head, _)) = net.tx.pop_used() {
+        net.tx_busy[net.tx_of[head]] = false;
+        net.tx.free_chain(head);
+        freed = true;
+    }
+    freed
+}
@@ -0,0 +1,103 @@
+use crate::mem_layout::VIRTIO_NSLOT;
+use crate::random::random_add;
+use crate::virtio::{virtio_set_intr, Mmio, VirtqBuf, Virtqueue, VIRTIO_ID_RNG};
+use core::cmp::min;
+use core::fmt::Write;
+
+// virtio entropy device, pdf 5.4.
+//
+// the device has a single queue and fills whatever buffers are put
+// in it with random bytes. one request of RNG_BUF bytes is in flight
+// at a time, and the interrupt handler mixes the answer into the
+// kernel generator. only the first device found is used.
+
+const RNG_BUF: usize = 64;
+
+struct Rng {
+    vq: Virtqueue,
+    mmio: Mmio,
+    buf: [u8; RNG_BUF], // the device writes here
+    busy: bool,         // a request is in flight
+}
+
+static mut RNG: Rng = Rng {
+    vq: Virtqueue::new(),
+    mmio: Mmio::new(),
+    buf: [0; RNG_BUF],
+    busy: false,
+};
+
+pub fn virtio_rng_init() {
+    for slot in 0..VIRTIO_NSLOT {
+        let mmio = match Mmio::probe(slot, VIRTIO_ID_RNG) {
+            Some(mmio) => mmio,
+            None => continue,
+        };
+
+        // no features are defined for the device
+        mmio.begin();
+        let features = match mmio.negotiate(0) {
+            Some(features) => features,
+            None => {
+                println!("virtio_rng: features aren't supported");
+                continue;
+            }
+        };
+        unsafe {
+            RNG.vq.init(&mmio, 0, features);
+            RNG.mmio = mmio;
+        }
+        mmio.driver_ok();
+        virtio_set_intr(&mmio, virtio_rng_intr);
+        println!("virtio_rng: at {:#x} v{}", mmio.base, mmio.version);
+        return;
+    }
+}
+
+// ask the device for RNG_BUF bytes. false if there is no device or
+// a request is already in flight
+pub fn virtio_rng_request() -> bool {
+    unsafe {
+        if RNG.mmio.base == 0 || RNG.busy {
+            return false;
+        }
+
+        let chain = [VirtqBuf {
+            addr: &RNG.buf as *const u8 as u64,
+            len: RNG_BUF as u32,
+            write: true,
+        }];
+        let head = match RNG.vq.add(&chain) {
+            Some(head) => head,
+            None => return false,
+        };
+        RNG.busy = true;
+        RNG.vq.submit(&RNG.mmio, head);
+    }
+
+    true
+}
+
+// is a request still waiting for the device
+pub fn virtio_rng_busy() -> bool {
+    unsafe { RNG.busy }
+}
+
+pub fn virtio_rng_intr(_irq: u32) {
+    unsafe {
+        RNG.mmio.ack_intr();
+
+        loop {
+            while let Some((head, len)) = RNG.vq.pop_used() {
+                let len = min(len as usize, RNG_BUF);
+                random_add(&RNG.buf[..len]);
+                RNG.buf = [0; RNG_BUF];
+                RNG.vq.free_chain(head);
+                RNG.busy = false;
+            }
+            if RNG.vq.enable_intr() {
+                break;
+            }
+        }
+    }
+}
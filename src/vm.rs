@@ -1,5 +1,7 @@
 use crate::kalloc::{kalloc, kfree};
-use crate::mem_layout::{KERN_BASE, PHY_STOP, PLIC, TRAMPOLINE, UART, VIRTIO};
+use crate::mem_layout::{
+    CLINT, KERN_BASE, PHY_STOP, PLIC, TRAMPOLINE, UART, VIRTIO0, VIRTIO_NSLOT,
+};
 use crate::proc::proc_map_stacks;
 use crate::riscv::{
     make_satp, pa_to_pte, page_round_down, page_round_up, pte_flags, pte_to_pa, sfence_vma, vpn,
@@ -23,10 +25,19 @@ fn kvm_make() -> PageTable {
 
     kvm_map(kpg_tbl, UART, UART, PAGE_SIZE, PTE_R | PTE_W);
 
-    kvm_map(kpg_tbl, VIRTIO, VIRTIO, PAGE_SIZE, PTE_R | PTE_W);
+    kvm_map(
+        kpg_tbl,
+        VIRTIO0,
+        VIRTIO0,
+        VIRTIO_NSLOT * PAGE_SIZE,
+        PTE_R | PTE_W,
+    );
 
     kvm_map(kpg_tbl, PLIC, PLIC, 0x400000, PTE_R | PTE_W);
 
+    // the CLINT is programmed in machine mode, the kernel only reads mtime
+    kvm_map(kpg_tbl, CLINT, CLINT, 0x10000, PTE_R);
+
     unsafe {
         // map kernel text executable and read-only
         kvm_map(
@@ -230,3 +241,153 @@ pub fn uvm_init(page_table: PageTable, src: *const u64, size: u64) {
         PTE_R | PTE_X | PTE_U | PTE_W,
     );
 }
+
+// copy the user memory of a parent into the page table of its child,
+// return -1 (with the pages copied so far freed) on failure
+pub fn uvm_copy(old: PageTable, new: PageTable, size: u64) -> i32 {
+    let mut va = 0;
+    while va < size {
+        let pte = walk(old, va, 0);
+        if pte.is_null() {
+            panicc!("uvm_copy: pte should exist");
+        }
+
+        unsafe {
+            if (*pte) & PTE_V == 0 {
+                panicc!("uvm_copy: page not present");
+            }
+            let pa = pte_to_pa(*pte);
+            let flags = pte_flags(*pte);
+
+            let mem = kalloc();
+            if mem.is_null() {
+                break;
+            }
+            mem_copy(mem, pa as *const u64, PAGE_SIZE);
+            if map_pages(new, va, PAGE_SIZE, mem as u64, flags) != 0 {
+                kfree(mem);
+                break;
+            }
+        }
+        va += PAGE_SIZE;
+    }
+
+    if va < size {
+        uvm_unmap(new, 0, va / PAGE_SIZE, 1);
+        return -1;
+    }
+
+    0
+}
+
+// the flags of the pte mapping va, 0 if not mapped
+pub fn walk_flags(page_table: PageTable, va: u64) -> u64 {
+    if va >= MAX_VA {
+        return 0;
+    }
+
+    let pte = walk(page_table, va, 0);
+    if pte.is_null() {
+        return 0;
+    }
+
+    unsafe { (*pte) & 0x3ff }
+}
+
+// like walk_addr, but only for pages accessible to user mode
+fn walk_user_addr(page_table: PageTable, va: u64) -> u64 {
+    if va >= MAX_VA {
+        return 0;
+    }
+
+    let pte = walk(page_table, va, 0);
+    if pte.is_null() {
+        return 0;
+    }
+
+    unsafe {
+        if (*pte) & PTE_V == 0 || (*pte) & PTE_U == 0 {
+            return 0;
+        }
+
+        pte_to_pa(*pte)
+    }
+}
+
+// copy len bytes from src to the user virtual address dst_va
+pub fn copy_out(page_table: PageTable, mut dst_va: u64, mut src: *const u8, mut len: u64) -> i32 {
+    while len > 0 {
+        let va0 = page_round_down(dst_va);
+        let pa0 = walk_user_addr(page_table, va0);
+        if pa0 == 0 {
+            return -1;
+        }
+
+        let mut n = PAGE_SIZE - (dst_va - va0);
+        if n > len {
+            n = len;
+        }
+        mem_copy((pa0 + (dst_va - va0)) as *mut u64, src as *const u64, n);
+
+        len -= n;
+        unsafe {
+            src = src.add(n as usize);
+        }
+        dst_va = va0 + PAGE_SIZE;
+    }
+
+    0
+}
+
+// copy len bytes from the user virtual address src_va to dst
+pub fn copy_in(page_table: PageTable, mut dst: *mut u8, mut src_va: u64, mut len: u64) -> i32 {
+    while len > 0 {
+        let va0 = page_round_down(src_va);
+        let pa0 = walk_user_addr(page_table, va0);
+        if pa0 == 0 {
+            return -1;
+        }
+
+        let mut n = PAGE_SIZE - (src_va - va0);
+        if n > len {
+            n = len;
+        }
+        mem_copy(dst as *mut u64, (pa0 + (src_va - va0)) as *const u64, n);
+
+        len -= n;
+        unsafe {
+            dst = dst.add(n as usize);
+        }
+        src_va = va0 + PAGE_SIZE;
+    }
+
+    0
+}
+
+// copy a '\0' terminated string from the user virtual address src_va,
+// return -1 if it doesn't fit in max bytes
+pub fn copy_in_str(page_table: PageTable, dst: *mut u8, mut src_va: u64, max: u64) -> i32 {
+    let mut i: u64 = 0;
+    while i < max {
+        let va0 = page_round_down(src_va);
+        let pa0 = walk_user_addr(page_table, va0);
+        if pa0 == 0 {
+            return -1;
+        }
+
+        let mut p = (pa0 + (src_va - va0)) as *const u8;
+        while i < max && src_va < va0 + PAGE_SIZE {
+            unsafe {
+                *dst.add(i as usize) = *p;
+                if *p == 0 {
+                    return 0;
+                }
+                p = p.add(1);
+            }
+            i += 1;
+            src_va += 1;
+        }
+    }
+
+    -1
+}
@@ -0,0 +1,64 @@
+# helpers for the test scripts, sourced by them.
+# they need qemu-system-riscv64, mkfs.minix and fsck.minix
+
+cd "$(dirname "$0")/.." || exit 1
+
+KERNEL=target/riscv64gc-unknown-none-elf/debug/os
+OUT=${TMPDIR:-/tmp}/crate-test.$$
+mkdir -p "$OUT"
+trap 'stop; rm -rf "$OUT"' EXIT
+
+fail() {
+	echo "FAIL: $*"
+	exit 1
+}
+
+# build the kernel with the features given and copy it to file
+build() {
+	cargo build --features "$1" || fail "build $1"
+	cp "$KERNEL" "$2"
+}
+
+# make a minix file system image: image, blocks, mkfs options
+mkimage() {
+	rm -f "$1"
+	dd if=/dev/zero of="$1" bs=1024 count="$2" 2>/dev/null || fail "dd"
+	mkfs.minix $3 "$1" "$2" >/dev/null || fail "mkfs.minix $3"
+}
+
+# boot kernel on image in the background, console output to log
+boot() {
+	qemu-system-riscv64 -machine virt -cpu rv64 -smp 4 -m 128M \
+		-nographic -serial mon:stdio -bios none \
+		-drive if=none,format=raw,file="$2",id=foo \
+		-device virtio-blk-device,drive=foo,bus=virtio-mmio-bus.0 \
+		-kernel "$1" </dev/null >"$3" 2>&1 &
+	QEMU=$!
+}
+
+# kill the running qemu, as a power cut would
+stop() {
+	if [ -n "$QEMU" ]; then
+		kill -9 "$QEMU" 2>/dev/null
+		wait "$QEMU" 2>/dev/null
+		QEMU=
+	fi
+}
+
+# wait up to secs seconds for the extended regex pattern in log, false on timeout
+wait_for() {
+	i=0
+	while ! grep -qE "$2" "$1"; do
+		i=$((i + 1))
+		[ $i -gt $(($3 * 10)) ] && return 1
+		sleep 0.1
+	done
+}
+
+# the image must be clean, fsck exits 0 if nothing was wrong
+fsck_image() {
+	fsck.minix -fn "$1" >"$OUT/fsck" 2>&1 || {
+		cat "$OUT/fsck"
+		fail "fsck.minix $1"
+	}
+}
@@ -0,0 +1,35 @@
+#!/bin/sh
+# kill qemu while a kernel built with the crashtest feature is busy
+# writing files, then boot a plain kernel on the image, which replays
+# the log when it mounts the root, and check the image with fsck.minix.
+# usage: crash.sh [rounds]
+
+. "$(dirname "$0")/common.sh"
+
+build crashtest "$OUT/crash"
+build "" "$OUT/plain"
+img=$OUT/hdd.dsk
+mkimage "$img" 8192 -3
+
+i=0
+while [ $i -lt "${1:-10}" ]; do
+	i=$((i + 1))
+	boot "$OUT/crash" "$img" "$OUT/log"
+	wait_for "$OUT/log" "crashtest: running" 60 || {
+		cat "$OUT/log"
+		fail "round $i: writer didn't start"
+	}
+	# somewhere in the middle of a write
+	sleep "$(awk -v s=$$$i 'BEGIN { srand(s); printf "%.1f", 0.2 + rand() * 3 }')"
+	stop
+	echo "round $i: killed"
+
+	boot "$OUT/plain" "$img" "$OUT/log"
+	wait_for "$OUT/log" "init ok" 60 || {
+		cat "$OUT/log"
+		fail "round $i: no boot after the crash"
+	}
+	stop
+	fsck_image "$img"
+done
+echo "crash: passed"
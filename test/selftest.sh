@@ -0,0 +1,23 @@
+#!/bin/sh
+# boot a kernel built with the selftest feature on fresh minix
+# images of each version, then check each image with fsck.minix.
+# the big v3 image has room for the big file test
+
+. "$(dirname "$0")/common.sh"
+
+build selftest "$OUT/os"
+for image in "-1 8192" "-2 8192" "-3 8192" "-3 81920"; do
+	set -- $image
+	img=$OUT/hdd.dsk
+	mkimage "$img" "$2" "$1"
+	boot "$OUT/os" "$img" "$OUT/log"
+	wait_for "$OUT/log" "selftest: (ok|[0-9]+ failed)" 1800 || {
+		cat "$OUT/log"
+		fail "minix $image: no result"
+	}
+	stop
+	grep "selftest:" "$OUT/log"
+	grep -q "selftest: ok" "$OUT/log" || fail "minix $image"
+	fsck_image "$img"
+done
+echo "selftest: passed"